@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use chrono::Duration;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+use url::Url;
+
+use crate::http_trace::SendTraced;
+use crate::io_tuning::{IoOptions, NoCacheFile};
+use crate::transport;
+use crate::url_policy;
+
+/// Typical size of the database over the size of its zstd archive.
+const COMPRESSION_RATIO: f64 = 3.0;
+/// Longest the download speed is measured for.
+pub const DOWNLOAD_TIME: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// Speeds measured by `bench`, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughputs {
+  pub disk_write: f64,
+  /// Of the unpacked data.
+  pub decompression: f64,
+  pub hashing: f64,
+  /// Unknown offline.
+  pub download: Option<f64>,
+}
+
+/// Data compressing about as well as a database: runs of repeated bytes
+/// between pseudo-random ones.
+fn sample_data(len: usize) -> Vec<u8> {
+  let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+  (0..len)
+    .map(|i| {
+      if i % 64 < 40 {
+        (i / 4096) as u8
+      } else {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+      }
+    })
+    .collect()
+}
+
+fn per_sec(bytes: usize, start: Instant) -> f64 {
+  bytes as f64 / start.elapsed().as_secs_f64().max(0.001)
+}
+
+/// Writes the data to a file in `dir` the way the database is unpacked, and
+/// syncs it to the disk.
+fn measure_disk_write(dir: &Path, data: &[u8], io: IoOptions) -> Result<f64> {
+  let path = dir.join("quicksync-bench.tmp");
+  let start = Instant::now();
+  let written = File::create(&path)
+    .and_then(|file| NoCacheFile::new(file, io.no_page_cache))
+    .and_then(|file| {
+      let mut writer = BufWriter::with_capacity(io.buffer_size, file);
+      writer.write_all(data)?;
+      writer.flush()?;
+      writer.get_ref().sync_data()
+    });
+  let speed = per_sec(data.len(), start);
+  let removed = std::fs::remove_file(&path);
+  written.with_context(|| format!("writing {}", path.display()))?;
+  removed.with_context(|| format!("removing {}", path.display()))?;
+  Ok(speed)
+}
+
+fn measure_decompression(data: &[u8]) -> Result<f64> {
+  let archive = zstd::encode_all(data, 3)?;
+  let start = Instant::now();
+  let unpacked = zstd::decode_all(&archive[..])?;
+  Ok(per_sec(unpacked.len(), start))
+}
+
+fn measure_hashing(data: &[u8]) -> f64 {
+  let start = Instant::now();
+  std::hint::black_box(md5::compute(data));
+  per_sec(data.len(), start)
+}
+
+/// Measures the speed of the disk of `dir`, of unpacking and of hashing with
+/// `sample_size` bytes of data.
+pub fn measure_local(dir: &Path, sample_size: usize, io: IoOptions) -> Result<Throughputs> {
+  let data = sample_data(sample_size);
+  Ok(Throughputs {
+    disk_write: measure_disk_write(dir, &data, io)?,
+    decompression: measure_decompression(&data)?,
+    hashing: measure_hashing(&data),
+    download: None,
+  })
+}
+
+/// Downloads up to `max_bytes` of the archive at `url`, for at most
+/// `max_time`, and returns the bytes downloaded and their throughput. The
+/// time before the first byte isn't counted, it's paid once per download.
+pub async fn measure_download(
+  url: &Url,
+  max_bytes: u64,
+  max_time: std::time::Duration,
+) -> Result<(u64, f64)> {
+  let client = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let mut response = client
+    .get(url.clone())
+    .header("Range", format!("bytes=0-{}", max_bytes.max(1) - 1))
+    .send_traced()
+    .await?
+    .error_for_status()?;
+  let mut downloaded = 0;
+  let mut first_byte: Option<Instant> = None;
+  loop {
+    let left = max_time.saturating_sub(first_byte.map_or_else(Default::default, |t| t.elapsed()));
+    let Ok(chunk) = tokio::time::timeout(left, response.chunk()).await else {
+      break;
+    };
+    let Some(chunk) = chunk? else {
+      break;
+    };
+    first_byte.get_or_insert_with(Instant::now);
+    downloaded += chunk.len() as u64;
+    if downloaded >= max_bytes {
+      break;
+    }
+  }
+  let start = first_byte.context("no data received")?;
+  Ok((downloaded, per_sec(downloaded as usize, start)))
+}
+
+impl Throughputs {
+  /// Estimated time of `download` with an archive of `archive_bytes`. The
+  /// archive is unpacked and both are hashed while it's downloaded, so the
+  /// slowest of these bounds it.
+  pub fn full_sync_time(&self, archive_bytes: u64) -> Option<Duration> {
+    let archive = archive_bytes as f64;
+    let db = archive * COMPRESSION_RATIO;
+    let secs = [
+      archive / self.download.filter(|d| *d > 0.0)?,
+      db / self.decompression,
+      (archive + db) / self.disk_write,
+      // The archive and the database are hashed on different threads
+      db / self.hashing,
+    ]
+    .into_iter()
+    .fold(0.0, f64::max);
+    Some(Duration::milliseconds((secs * 1000.0) as i64))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MB: f64 = 1_024_000.0;
+
+  #[tokio::test]
+  async fn measuring_download() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/snapshot.zst")
+      .match_header("Range", "bytes=0-4095")
+      .with_status(206)
+      .with_body(vec![0u8; 4096])
+      .create_async()
+      .await;
+    let url = Url::parse(&format!("{}/snapshot.zst", server.url())).unwrap();
+    let (downloaded, speed) = measure_download(&url, 4096, DOWNLOAD_TIME).await.unwrap();
+    mock.assert_async().await;
+    assert_eq!(downloaded, 4096);
+    assert!(speed > 0.0);
+  }
+
+  #[test]
+  fn estimating_full_sync() {
+    let mut speeds = Throughputs {
+      disk_write: 200.0 * MB,
+      decompression: 600.0 * MB,
+      hashing: 500.0 * MB,
+      download: None,
+    };
+    assert_eq!(speeds.full_sync_time(10_000 * MB as u64), None);
+    // Bound by the download
+    speeds.download = Some(10.0 * MB);
+    assert_eq!(
+      speeds.full_sync_time(10_000 * MB as u64),
+      Some(Duration::seconds(1000))
+    );
+    // Bound by the disk writing the archive and the database
+    speeds.download = Some(100.0 * MB);
+    assert_eq!(
+      speeds.full_sync_time(10_000 * MB as u64),
+      Some(Duration::seconds(200))
+    );
+  }
+
+  #[test]
+  fn measuring_locally() {
+    let dir = tempfile::tempdir().unwrap();
+    let io = IoOptions::for_tests();
+    let speeds = measure_local(dir.path(), 100_000, io).unwrap();
+    assert!(speeds.disk_write > 0.0);
+    assert!(speeds.decompression > 0.0);
+    assert!(speeds.hashing > 0.0);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+  }
+}
@@ -0,0 +1,222 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Size of the blocks of a partial download hashed separately.
+pub const BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// File next to the partial download with the BLAKE3 hashes of its complete
+/// blocks, one per line. They're only for checking our own temp files,
+/// the server is compared with MD5.
+pub fn record_path(path: &Path) -> PathBuf {
+  path.with_extension("blocks")
+}
+
+fn read_record(path: &Path) -> Result<Vec<String>> {
+  match std::fs::read_to_string(path) {
+    Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+    Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+  }
+}
+
+fn write_record(path: &Path, hashes: &[String]) -> Result<()> {
+  let content: String = hashes.iter().map(|h| format!("{h}\n")).collect();
+  std::fs::write(path, content).with_context(|| format!("writing {}", path.display()))
+}
+
+fn hash_block(file: &mut File, index: usize, block_size: u64) -> io::Result<String> {
+  file.seek(SeekFrom::Start(index as u64 * block_size))?;
+  let mut hasher = blake3::Hasher::new();
+  io::copy(&mut file.take(block_size), &mut hasher)?;
+  Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Verifies the recorded blocks of the partial download at `path` on all cores
+/// and cuts it off before the first corrupted one. Returns the new length of
+/// the file if it was cut off.
+pub fn verify(path: &Path, block_size: u64) -> Result<Option<u64>> {
+  let record = record_path(path);
+  let mut hashes = read_record(&record)?;
+  let len = std::fs::metadata(path)?.len();
+  // Blocks recorded past the end of the file are missing
+  let recorded = hashes.len().min((len / block_size) as usize);
+  let next = AtomicUsize::new(0);
+  let first_bad = AtomicUsize::new(recorded);
+  let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+  std::thread::scope(|s| {
+    let workers: Vec<_> = (0..threads.min(recorded))
+      .map(|_| {
+        s.spawn(|| -> io::Result<()> {
+          let mut file = File::open(path)?;
+          loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= recorded {
+              return Ok(());
+            }
+            if hash_block(&mut file, i, block_size)? != hashes[i] {
+              first_bad.fetch_min(i, Ordering::Relaxed);
+            }
+          }
+        })
+      })
+      .collect();
+    workers
+      .into_iter()
+      .try_for_each(|w| w.join().expect("hashing thread panicked"))
+  })
+  .with_context(|| format!("hashing {}", path.display()))?;
+
+  let intact = first_bad.into_inner();
+  if intact < hashes.len() {
+    hashes.truncate(intact);
+    write_record(&record, &hashes)?;
+  }
+  if intact == recorded {
+    return Ok(None);
+  }
+  let kept = intact as u64 * block_size;
+  OpenOptions::new()
+    .write(true)
+    .open(path)
+    .and_then(|f| f.set_len(kept))
+    .with_context(|| format!("truncating {}", path.display()))?;
+  Ok(Some(kept))
+}
+
+/// Records the hashes of the blocks appended to the partial download.
+pub struct BlockHashWriter<W> {
+  inner: W,
+  record: File,
+  hasher: blake3::Hasher,
+  /// Bytes of the current block hashed so far.
+  hashed: u64,
+  block_size: u64,
+}
+
+impl<W: Write + Seek> BlockHashWriter<W> {
+  /// Wraps `inner`, the partial download at `path` opened for appending.
+  /// The data after the last recorded block is hashed again.
+  pub fn new(inner: W, path: &Path, block_size: u64) -> Result<Self> {
+    let record_path = record_path(path);
+    let recorded = read_record(&record_path)?.len() as u64;
+    let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    anyhow::ensure!(
+      file.metadata()?.len() >= recorded * block_size,
+      "{} is shorter than its recorded blocks",
+      path.display()
+    );
+    let record = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&record_path)
+      .with_context(|| format!("opening {}", record_path.display()))?;
+    let mut writer = Self {
+      inner,
+      record,
+      hasher: blake3::Hasher::new(),
+      hashed: 0,
+      block_size,
+    };
+    file.seek(SeekFrom::Start(recorded * block_size))?;
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+      let read = file.read(&mut buf)?;
+      if read == 0 {
+        break;
+      }
+      writer.hash(&buf[..read])?;
+    }
+    Ok(writer)
+  }
+
+  fn hash(&mut self, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+      let take = data.len().min((self.block_size - self.hashed) as usize);
+      self.hasher.update(&data[..take]);
+      self.hashed += take as u64;
+      data = &data[take..];
+      if self.hashed == self.block_size {
+        writeln!(self.record, "{}", self.hasher.finalize().to_hex())?;
+        self.hasher.reset();
+        self.hashed = 0;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<W: Write + Seek> Write for BlockHashWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.hash(&buf[..written])?;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<W: Write + Seek> Seek for BlockHashWriter<W> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.inner.seek(pos)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn append(path: &Path, data: &[u8]) {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .unwrap();
+    let mut writer = BlockHashWriter::new(file, path, 1000).unwrap();
+    // Writes crossing the block boundaries
+    for chunk in data.chunks(700) {
+      writer.write_all(chunk).unwrap();
+    }
+  }
+
+  #[test]
+  fn resuming_with_block_hashes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.download");
+    let data: Vec<u8> = (0..5_500u32).map(|i| (i % 251) as u8).collect();
+
+    append(&path, &data[..2_500]);
+    append(&path, &data[2_500..]);
+    let expected: Vec<String> = data
+      .chunks_exact(1000)
+      .map(|block| blake3::hash(block).to_hex().to_string())
+      .collect();
+    assert_eq!(read_record(&record_path(&path)).unwrap(), expected);
+    assert_eq!(verify(&path, 1000).unwrap(), None);
+
+    let mut corrupted = data.clone();
+    corrupted[3_100] ^= 0xFF;
+    corrupted[4_100] ^= 0xFF;
+    std::fs::write(&path, &corrupted).unwrap();
+    assert_eq!(verify(&path, 1000).unwrap(), Some(3_000));
+    assert_eq!(std::fs::read(&path).unwrap(), data[..3_000]);
+    assert_eq!(read_record(&record_path(&path)).unwrap(), expected[..3]);
+
+    append(&path, &data[3_000..]);
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+    assert_eq!(read_record(&record_path(&path)).unwrap(), expected);
+  }
+
+  #[test]
+  fn verifying_without_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.download");
+    std::fs::write(&path, [1; 2_500]).unwrap();
+    assert_eq!(verify(&path, 1000).unwrap(), None);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 2_500);
+  }
+}
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::tls::TlsInfo;
+use reqwest::Response;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::events::{self, Event};
+use crate::exit_error::ExitError;
+use crate::status;
+
+/// Pinned keys, unset unless `--tofu` is passed.
+static PINS: OnceLock<Pins> = OnceLock::new();
+
+struct Pins {
+  path: PathBuf,
+  /// Fail instead of warning when a key changed.
+  strict: bool,
+}
+
+/// Starts pinning the certificate keys of the snapshot servers in `path`.
+/// With `strict`, a changed key fails the download.
+pub fn enable(path: PathBuf, strict: bool) {
+  let _ = PINS.set(Pins { path, strict });
+}
+
+/// Whether the HTTP clients need to keep the certificates of the servers.
+pub fn enabled() -> bool {
+  PINS.get().is_some()
+}
+
+/// The key of a server compared to the one pinned for it.
+#[derive(Debug, PartialEq, Eq)]
+enum Pin {
+  /// Pinned now, on first use.
+  New,
+  Same,
+  /// Another key, with the pinned one.
+  Changed(String),
+}
+
+/// SHA-256 of the public key (SubjectPublicKeyInfo) of a DER certificate.
+/// It stays the same when the certificate is renewed with the same key.
+fn key_pin(der: &[u8]) -> Result<String> {
+  let (_, cert) = x509_parser::parse_x509_certificate(der)
+    .map_err(|e| anyhow!("parsing the certificate of the server: {e}"))?;
+  Ok(hex::encode(Sha256::digest(cert.public_key().raw)))
+}
+
+/// Compares `pin` to the one pinned for `host` in `path`, lines of
+/// `<host>:<port> <pin>`, pinning it if there's none.
+fn record(path: &Path, host: &str, pin: &str) -> Result<Pin> {
+  let pins = match std::fs::read_to_string(path) {
+    Ok(pins) => pins,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+    Err(e) => return Err(e.into()),
+  };
+  let pinned = pins
+    .lines()
+    .filter(|line| !line.starts_with('#'))
+    .filter_map(|line| line.split_once(' '))
+    .find(|(h, _)| *h == host)
+    .map(|(_, pinned)| pinned.trim());
+  match pinned {
+    Some(pinned) if pinned == pin => Ok(Pin::Same),
+    Some(pinned) => Ok(Pin::Changed(pinned.to_string())),
+    None => {
+      if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+      }
+      let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+      writeln!(file, "{host} {pin}")?;
+      Ok(Pin::New)
+    }
+  }
+}
+
+/// Checks the certificate key of the server that sent `response` against the
+/// one pinned on first use, warning loudly if it changed, or failing with
+/// `--tofu-strict`. Does nothing without `--tofu` or over plain HTTP.
+pub fn check(response: &Response) -> Result<()> {
+  let Some(pins) = PINS.get() else {
+    return Ok(());
+  };
+  let Some(der) = response
+    .extensions()
+    .get::<TlsInfo>()
+    .and_then(TlsInfo::peer_certificate)
+  else {
+    return Ok(());
+  };
+  let url = response.url();
+  let host = format!(
+    "{}:{}",
+    url.host_str().unwrap_or_default(),
+    url.port_or_known_default().unwrap_or(443)
+  );
+  check_key(pins, &host, &key_pin(der)?)
+}
+
+fn check_key(pins: &Pins, host: &str, pin: &str) -> Result<()> {
+  let path = &pins.path;
+  match record(path, host, pin).with_context(|| format!("pinning in {}", path.display()))? {
+    Pin::Same => {}
+    Pin::New => status::print_line(format_args!(
+      "Pinned the certificate key of {host} (sha256 {pin}) in {}",
+      path.display()
+    )),
+    Pin::Changed(pinned) => {
+      status::end();
+      eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+      eprintln!("WARNING: THE CERTIFICATE KEY OF {host} HAS CHANGED!");
+      eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+      eprintln!("Pinned on first use: sha256 {pinned}");
+      eprintln!("Presented now:       sha256 {pin}");
+      eprintln!("Someone may be intercepting the connection to replace the database,");
+      eprintln!("or the server got a new key. Once the operator confirms the new key,");
+      eprintln!(
+        "remove the line of {host} from {} to pin it.",
+        path.display()
+      );
+      events::emit(Event::CertificateChanged {
+        host,
+        pinned: &pinned,
+        presented: pin,
+      });
+      if pins.strict {
+        return Err(
+          ExitError::new(21, format!("The certificate key of {host} has changed")).into(),
+        );
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pinning_on_first_use() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("pins").join("known-keys");
+    let host = "quicksync.spacemesh.network:443";
+    assert_eq!(record(&path, host, "aaaa").unwrap(), Pin::New);
+    assert_eq!(record(&path, host, "aaaa").unwrap(), Pin::Same);
+    assert_eq!(
+      record(&path, "cdn.example.com:443", "bbbb").unwrap(),
+      Pin::New
+    );
+    assert_eq!(
+      record(&path, host, "cccc").unwrap(),
+      Pin::Changed("aaaa".to_string())
+    );
+    // The changed key isn't pinned in place of the first one
+    assert_eq!(
+      record(&path, host, "cccc").unwrap(),
+      Pin::Changed("aaaa".to_string())
+    );
+    assert_eq!(
+      std::fs::read_to_string(&path).unwrap(),
+      "quicksync.spacemesh.network:443 aaaa\ncdn.example.com:443 bbbb\n"
+    );
+  }
+
+  #[test]
+  fn checking_changed_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let host = "quicksync.spacemesh.network:443";
+    let mut pins = Pins {
+      path: dir.path().join("known-keys"),
+      strict: false,
+    };
+    check_key(&pins, host, "aaaa").unwrap();
+    check_key(&pins, host, "aaaa").unwrap();
+    // Only warned about
+    check_key(&pins, host, "bbbb").unwrap();
+
+    pins.strict = true;
+    check_key(&pins, host, "aaaa").unwrap();
+    let err = check_key(&pins, host, "bbbb").unwrap_err();
+    assert_eq!(ExitError::find(&err).map(|e| e.code), Some(21));
+  }
+}
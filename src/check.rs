@@ -0,0 +1,225 @@
+use chrono::Duration;
+
+/// How far the local database is behind the network.
+#[derive(Debug)]
+pub struct SyncStatus {
+  /// Latest layer with an applied block, including the WAL.
+  pub applied_layer: i64,
+  /// Layers at the end of the database that the node syncs again anyway.
+  pub untrusted_layers: u32,
+  pub network_layer: i64,
+  /// Latest layer available as a quicksync snapshot, unknown offline.
+  pub snapshot_layer: Option<i64>,
+}
+
+/// Size of the download of a way to catch up, and the layer it brings the
+/// database to. The node syncs the layers after it normally.
+#[derive(Debug, Clone, Copy)]
+pub struct Download {
+  pub bytes: u64,
+  pub layer: i64,
+}
+
+/// Estimated time of each way to catch up with the network.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Estimates {
+  /// Downloading the snapshot with `download`.
+  pub full_download: Option<Duration>,
+  /// Downloading the restore points with `incremental`.
+  pub partial_restore: Option<Duration>,
+  /// Syncing from peers.
+  pub sync: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Recommendation {
+  /// The node is caught up, apart from the untrusted layers.
+  UpToDate,
+  Sync,
+  Quicksync,
+  Incremental,
+}
+
+impl std::fmt::Display for Recommendation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Recommendation::UpToDate => write!(f, "the node is up to date, quicksync is not needed"),
+      Recommendation::Sync => write!(f, "let the node catch up with normal sync"),
+      Recommendation::Quicksync => write!(f, "quicksync is recommended (`download`)"),
+      Recommendation::Incremental => {
+        write!(f, "incremental quicksync is recommended (`incremental`)")
+      }
+    }
+  }
+}
+
+fn transfer_time(bytes: u64, bytes_per_sec: f64) -> Duration {
+  Duration::milliseconds((bytes as f64 / bytes_per_sec * 1000.0) as i64)
+}
+
+impl SyncStatus {
+  /// Latest layer trusted to be fully synced.
+  pub fn trusted_layer(&self) -> i64 {
+    (self.applied_layer - i64::from(self.untrusted_layers)).max(0)
+  }
+
+  /// Layers the node has to sync to catch up with the network.
+  pub fn layers_behind(&self) -> i64 {
+    (self.network_layer - self.trusted_layer()).max(0)
+  }
+
+  /// Time of normal sync from `layer` to the network layer, at `time_per_layer`.
+  fn sync_time_from(&self, layer: i64, time_per_layer: Duration) -> Duration {
+    let layers = (self.network_layer - layer).max(0);
+    time_per_layer * i32::try_from(layers).unwrap_or(i32::MAX)
+  }
+
+  /// Estimated time of catching up with normal sync, at `time_per_layer`.
+  pub fn catch_up_time(&self, time_per_layer: Duration) -> Duration {
+    self.sync_time_from(self.trusted_layer(), time_per_layer)
+  }
+
+  /// Estimates how long each way to catch up takes: downloading at
+  /// `bytes_per_sec` and then syncing the remaining layers at `time_per_layer`.
+  /// Downloads not bringing the database past the trusted layer are left out.
+  pub fn estimate(
+    &self,
+    time_per_layer: Duration,
+    bytes_per_sec: Option<f64>,
+    snapshot: Option<Download>,
+    restore: Option<Download>,
+  ) -> Estimates {
+    let download_time = |download: Option<Download>| {
+      let download = download.filter(|d| d.layer > self.trusted_layer())?;
+      let bytes_per_sec = bytes_per_sec.filter(|b| *b > 0.0)?;
+      Some(
+        transfer_time(download.bytes, bytes_per_sec)
+          + self.sync_time_from(download.layer, time_per_layer),
+      )
+    };
+    Estimates {
+      full_download: download_time(snapshot),
+      partial_restore: download_time(restore),
+      sync: self.catch_up_time(time_per_layer),
+    }
+  }
+
+  /// Recommends the fastest way to catch up, preferring normal sync on a tie.
+  pub fn recommend(&self, estimates: &Estimates) -> Recommendation {
+    if self.layers_behind() <= i64::from(self.untrusted_layers) {
+      return Recommendation::UpToDate;
+    }
+    [
+      (Some(estimates.sync), Recommendation::Sync),
+      (estimates.partial_restore, Recommendation::Incremental),
+      (estimates.full_download, Recommendation::Quicksync),
+    ]
+    .into_iter()
+    .filter_map(|(time, recommendation)| Some((time?, recommendation)))
+    .min_by_key(|(time, _)| *time)
+    .map_or(Recommendation::Sync, |(_, recommendation)| recommendation)
+  }
+}
+
+/// Formats the duration as e.g. `2d 3h 15m`.
+pub fn format_duration(duration: Duration) -> String {
+  let minutes = duration.num_minutes().max(0);
+  let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+  match (days, hours) {
+    (0, 0) => format!("{minutes}m"),
+    (0, _) => format!("{hours}h {minutes}m"),
+    _ => format!("{days}d {hours}h {minutes}m"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MB: f64 = 1_024_000.0;
+
+  fn status(applied_layer: i64, snapshot_layer: i64) -> SyncStatus {
+    SyncStatus {
+      applied_layer,
+      untrusted_layers: 10,
+      network_layer: 10_000,
+      snapshot_layer: Some(snapshot_layer),
+    }
+  }
+
+  fn download(megabytes: u64, layer: i64) -> Option<Download> {
+    Some(Download {
+      bytes: megabytes * MB as u64,
+      layer,
+    })
+  }
+
+  #[test]
+  fn recommending_offline() {
+    let status = SyncStatus {
+      snapshot_layer: None,
+      ..status(9_000, 9_500)
+    };
+    let estimates = status.estimate(Duration::seconds(2), None, None, None);
+    assert_eq!(estimates.full_download, None);
+    assert_eq!(status.recommend(&estimates), Recommendation::Sync);
+  }
+
+  #[test]
+  fn checking_layers_behind() {
+    let per_layer = Duration::seconds(2);
+    let caught_up = status(9_995, 9_900);
+    assert_eq!(caught_up.trusted_layer(), 9_985);
+    assert_eq!(caught_up.layers_behind(), 15);
+    assert_eq!(caught_up.catch_up_time(per_layer), Duration::seconds(30));
+    let estimates = caught_up.estimate(per_layer, Some(MB), download(10_000, 9_900), None);
+    // the snapshot is older than the database
+    assert_eq!(estimates.full_download, None);
+    assert_eq!(caught_up.recommend(&estimates), Recommendation::Sync);
+
+    let up_to_date = status(10_000, 9_900);
+    let estimates = up_to_date.estimate(per_layer, Some(MB), None, None);
+    assert_eq!(up_to_date.recommend(&estimates), Recommendation::UpToDate);
+  }
+
+  #[test]
+  fn recommending_fastest() {
+    let per_layer = Duration::seconds(2);
+    let behind = status(1_000, 9_900);
+    assert_eq!(behind.layers_behind(), 9_010);
+
+    // 10 GB at 10 MB/s take 1000s, then 100 layers of sync take 200s
+    let estimates = behind.estimate(
+      per_layer,
+      Some(10.0 * MB),
+      download(10_000, 9_900),
+      download(2_000, 9_999),
+    );
+    assert_eq!(
+      estimates,
+      Estimates {
+        full_download: Some(Duration::seconds(1_200)),
+        partial_restore: Some(Duration::seconds(202)),
+        sync: Duration::seconds(18_020),
+      }
+    );
+    assert_eq!(behind.recommend(&estimates), Recommendation::Incremental);
+
+    let estimates = behind.estimate(per_layer, Some(10.0 * MB), download(10_000, 9_900), None);
+    assert_eq!(behind.recommend(&estimates), Recommendation::Quicksync);
+    // on a slow connection the node syncs faster
+    let estimates = behind.estimate(per_layer, Some(0.1 * MB), download(10_000, 9_900), None);
+    assert_eq!(behind.recommend(&estimates), Recommendation::Sync);
+    // without a measured speed downloads can't be estimated
+    let estimates = behind.estimate(per_layer, None, download(10_000, 9_900), None);
+    assert_eq!(estimates.full_download, None);
+    assert_eq!(behind.recommend(&estimates), Recommendation::Sync);
+  }
+
+  #[test]
+  fn formatting_durations() {
+    assert_eq!(format_duration(Duration::seconds(59)), "0m");
+    assert_eq!(format_duration(Duration::minutes(75)), "1h 15m");
+    assert_eq!(format_duration(Duration::hours(50)), "2d 2h 0m");
+  }
+}
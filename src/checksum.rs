@@ -1,24 +1,32 @@
-use anyhow::Result;
-use reqwest::blocking::{Client, Response};
+use anyhow::{anyhow, Result};
+use reqwest::StatusCode;
 use std::{
   fs::File,
   io::{BufRead, BufReader},
   path::Path,
+  sync::{Condvar, Mutex},
+  time::Duration,
 };
 use url::Url;
 
 use crate::{
-  read_error_response::read_error_response, user_agent::APP_USER_AGENT,
-  utils::strip_trailing_newline,
+  events::{self, Event},
+  http_cache,
+  io_tuning::IoOptions,
+  read_error_response::read_error_response,
+  transport,
+  unpack::ARCHIVE_EXTENSIONS,
+  url_policy,
 };
 
 fn get_link_to_db_md5(url: &Url) -> Result<Url> {
   let url_str = url.as_str();
-  if url_str.ends_with(".sql.zst") {
-    let new_url_str = url_str.replace(".sql.zst", ".sql.md5");
-    Ok(Url::parse(&new_url_str)?)
-  } else {
-    anyhow::bail!("URL does not end with .sql.zst")
+  let base = ARCHIVE_EXTENSIONS
+    .iter()
+    .find_map(|ext| url_str.strip_suffix(&format!(".sql.{ext}")));
+  match base {
+    Some(base) => Ok(Url::parse(&format!("{base}.sql.md5"))?),
+    None => anyhow::bail!("URL does not end with .sql.zst, .sql.zip, .sql.gz or .sql.xz"),
   }
 }
 
@@ -30,28 +38,195 @@ fn get_link_to_archive_md5(url: &Url) -> Result<Url> {
   Ok(Url::parse(&md5_url)?)
 }
 
-pub fn download_checksum(url: Url) -> Result<String> {
-  let client = Client::builder()
-    .user_agent(APP_USER_AGENT)
-    .timeout(std::time::Duration::from_secs(30))
+/// Name of the file the checksum at `url` is for, e.g. `100.sql.zst` for `100.sql.zst.md5`.
+fn checksum_file_name(url: &Url) -> &str {
+  let name = url.path().rsplit('/').next().unwrap_or_default();
+  name
+    .strip_suffix(".md5")
+    .or_else(|| name.strip_suffix(".sha256"))
+    .unwrap_or(name)
+}
+
+/// Hex characters of the checksum at `url`: 64 for SHA-256, 32 for MD5.
+fn checksum_len(url: &Url) -> usize {
+  match url.path().ends_with(".sha256") {
+    true => 64,
+    false => 32,
+  }
+}
+
+/// Reads the checksum of `file_name`, of `len` hex characters, from a checksum
+/// file: either a bare hash or `<hash>  <file name>` lines written by `md5sum`.
+fn parse_checksum(content: &str, file_name: &str, len: usize) -> Result<String> {
+  let lines: Vec<&str> = content
+    .lines()
+    .map(str::trim)
+    .filter(|l| !l.is_empty())
+    .collect();
+  let entries: Vec<(&str, &str)> = lines
+    .iter()
+    .map(|line| match line.split_once(char::is_whitespace) {
+      // `*` marks files hashed in binary mode
+      Some((hash, name)) => (hash, name.trim().trim_start_matches('*')),
+      None => (*line, ""),
+    })
+    .collect();
+  let hash = match entries[..] {
+    [] => anyhow::bail!("the checksum file is empty"),
+    // a single entry is for the file, whatever its name
+    [(hash, _)] => hash,
+    _ => entries
+      .iter()
+      .find(|(_, name)| name.rsplit('/').next() == Some(file_name))
+      .map(|(hash, _)| *hash)
+      .ok_or_else(|| anyhow!("the checksum file has no entry for {file_name}"))?,
+  };
+  anyhow::ensure!(
+    hash.len() == len && hash.chars().all(|c| c.is_ascii_hexdigit()),
+    "invalid checksum '{hash}', expected {len} hex characters"
+  );
+  Ok(hash.to_lowercase())
+}
+
+/// How checksums are downloaded.
+#[derive(Debug, Clone)]
+pub struct ChecksumOptions {
+  /// Retries after a network or server error.
+  pub max_retries: u32,
+  pub retry_delay: Duration,
+  /// Timeout of each attempt.
+  pub timeout: Duration,
+  /// URL of the archive checksum, for mirrors not publishing it as `{archive}.md5`.
+  pub archive_url: Option<Url>,
+  /// URL of the database checksum, for mirrors not publishing it as `{layer}.sql.md5`.
+  pub db_url: Option<Url>,
+}
+
+impl Default for ChecksumOptions {
+  fn default() -> Self {
+    Self {
+      max_retries: 10,
+      retry_delay: Duration::from_secs(5),
+      timeout: Duration::from_secs(30),
+      archive_url: None,
+      db_url: None,
+    }
+  }
+}
+
+/// The archive named like `archive` next to the snapshot `server_snapshot` on
+/// the download server.
+fn on_server(server_snapshot: &Url, archive: &Url) -> Result<Url> {
+  let name = archive
+    .path_segments()
+    .and_then(|mut segments| segments.next_back())
+    .filter(|name| !name.is_empty())
+    .ok_or_else(|| anyhow!("{archive} has no file name"))?;
+  Ok(server_snapshot.join(name)?)
+}
+
+/// Reads the URL of the archive saved in the redirect file, if there is one.
+fn read_archive_url(redirect_file_path: &Path) -> Result<Option<Url>> {
+  if !redirect_file_path.try_exists().unwrap_or(false) {
+    return Ok(None);
+  }
+  let archive_url = std::fs::read_to_string(redirect_file_path)?;
+  Ok(Some(Url::parse(archive_url.trim())?))
+}
+
+impl ChecksumOptions {
+  /// Fetches the checksums of `archive`, downloaded from a LAN source or a
+  /// mirror, from the download server serving `server_snapshot` instead, so
+  /// the source can't forge them. Checksum URLs given explicitly are kept.
+  pub fn from_server(&mut self, server_snapshot: &Url, archive: &Url) -> Result<()> {
+    let archive = on_server(server_snapshot, archive)?;
+    if self.archive_url.is_none() {
+      self.archive_url = Some(get_link_to_archive_md5(&archive)?);
+    }
+    if self.db_url.is_none() {
+      self.db_url = Some(get_link_to_db_md5(&archive)?);
+    }
+    Ok(())
+  }
+
+  /// URL of the checksum of the archive: the one given, or the one next to
+  /// the archive saved in the redirect file. `None` if neither is known.
+  pub fn archive_md5_url(&self, redirect_file_path: &Path) -> Result<Option<Url>> {
+    if let Some(url) = &self.archive_url {
+      return Ok(Some(url.clone()));
+    }
+    read_archive_url(redirect_file_path)?
+      .map(|url| get_link_to_archive_md5(&url))
+      .transpose()
+  }
+
+  /// URL of the checksum of the database: the one given, or the one next to
+  /// the archive saved in the redirect file. `None` if neither is known.
+  pub fn db_md5_url(&self, redirect_file_path: &Path) -> Result<Option<Url>> {
+    if let Some(url) = &self.db_url {
+      return Ok(Some(url.clone()));
+    }
+    read_archive_url(redirect_file_path)?
+      .map(|url| get_link_to_db_md5(&url))
+      .transpose()
+  }
+}
+
+pub async fn download_checksum(url: Url, options: &ChecksumOptions) -> Result<String> {
+  url_policy::check(&url)?;
+  let client = transport::small_files_builder()
+    .redirect(url_policy::redirect_policy())
+    .timeout(options.timeout)
     .build()?;
-  let response: Response = client.get(url.clone()).send()?;
+  let mut attempts = 0;
 
-  let status = response.status();
-  if status.is_success() {
-    let md5 = response.text()?;
-    let stripped = strip_trailing_newline(&md5);
-    Ok(stripped.to_string())
-  } else {
-    let err = read_error_response(response.text()?);
-    anyhow::bail!(format!(
-      "Cannot download MD5 checksum from {}: {} {}",
-      url, status, err
-    ));
+  loop {
+    attempts += 1;
+    let error = match http_cache::get_text(&client, url.as_str()).await {
+      Ok(Ok(content)) => {
+        return parse_checksum(&content, checksum_file_name(&url), checksum_len(&url))
+      }
+      Ok(Err(response)) => {
+        let status = response.status();
+        let err = read_error_response(response).await;
+        let error = anyhow!("Cannot download MD5 checksum from {url}: {status} {err}");
+        // The checksum is missing or not allowed, retrying won't help
+        if status.is_client_error()
+          && status != StatusCode::REQUEST_TIMEOUT
+          && status != StatusCode::TOO_MANY_REQUESTS
+        {
+          return Err(error);
+        }
+        error
+      }
+      Err(e) => e.context(format!("Cannot download MD5 checksum from {url}")),
+    };
+    if attempts > options.max_retries {
+      return Err(error);
+    }
+    println!(
+      "Checksum download error: {error:#}. Attempt {attempts} / {}",
+      options.max_retries
+    );
+    events::emit(Event::Retry {
+      attempt: attempts,
+      max_retries: options.max_retries,
+      delay_secs: options.retry_delay.as_secs(),
+      error: format!("{error:#}"),
+    });
+    tokio::time::sleep(options.retry_delay).await;
   }
 }
 
-pub fn calculate_checksum(file_path: &Path) -> Result<String> {
+/// How many chunks the read-ahead threads stay ahead of the hashing.
+const READ_AHEAD_CHUNKS: usize = 8;
+/// Distance between the bytes read to fault in each page of a mapped file.
+const PAGE_SIZE: usize = 4096;
+
+/// Computes the MD5 of the file. With more than one `hash_threads` the file
+/// is memory-mapped and the other threads read it ahead of the hashing, so
+/// it must not be used on files that may be truncated meanwhile.
+pub fn calculate_checksum(file_path: &Path, io: IoOptions) -> Result<String> {
   let file = match File::open(file_path) {
     Ok(file) => file,
     Err(error) => match error.kind() {
@@ -65,7 +240,23 @@ pub fn calculate_checksum(file_path: &Path) -> Result<String> {
     },
   };
 
-  let mut reader = BufReader::with_capacity(16 * 1024 * 1024, file);
+  // Empty files can't be mapped
+  if io.hash_threads > 1 && file.metadata()?.len() > 0 {
+    // SAFETY: callers only map files nothing else writes to
+    match unsafe { memmap2::Mmap::map(&file) } {
+      Ok(mmap) => {
+        #[cfg(unix)]
+        let _ = mmap.advise(memmap2::Advice::Sequential);
+        return Ok(hash_mapped(&mmap, io));
+      }
+      Err(e) => println!(
+        "Cannot memory-map {}, reading it instead: {e}",
+        file_path.display()
+      ),
+    }
+  }
+
+  let mut reader = BufReader::with_capacity(io.buffer_size, file);
   let mut hasher = md5::Context::new();
 
   loop {
@@ -82,24 +273,271 @@ pub fn calculate_checksum(file_path: &Path) -> Result<String> {
   Ok(format!("{:x}", hash))
 }
 
-pub fn verify_archive(redirect_file_path: &Path, archive_path: &Path) -> Result<bool> {
-  let archive_url_str = String::from_utf8(std::fs::read(redirect_file_path)?)?;
-  let archive_url = Url::parse(&archive_url_str)?;
-  let md5_url = get_link_to_archive_md5(&archive_url)?;
+/// MD5 can't be computed in parallel, so the other threads fault in the
+/// chunks of the mapped file the hashing gets to next.
+fn hash_mapped(data: &[u8], io: IoOptions) -> String {
+  let chunk_size = io.buffer_size.max(PAGE_SIZE);
+  let chunks = data.len().div_ceil(chunk_size);
+  let readers = io.hash_threads - 1;
+  // Number of chunks hashed, and the readers waiting for it to grow
+  let hashed = (Mutex::new(0), Condvar::new());
+  let mut hasher = md5::Context::new();
+  std::thread::scope(|s| {
+    for reader in 0..readers {
+      let (progress, advanced) = &hashed;
+      s.spawn(move || {
+        for chunk in (reader..chunks).step_by(readers) {
+          let done = *advanced
+            .wait_while(progress.lock().unwrap(), |done| {
+              chunk >= *done + READ_AHEAD_CHUNKS
+            })
+            .unwrap();
+          if chunk < done {
+            continue;
+          }
+          let end = ((chunk + 1) * chunk_size).min(data.len());
+          let touched = data[chunk * chunk_size..end]
+            .iter()
+            .step_by(PAGE_SIZE)
+            .fold(0u8, |acc, b| acc ^ b);
+          std::hint::black_box(touched);
+        }
+      });
+    }
+    let (progress, advanced) = &hashed;
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+      hasher.consume(chunk);
+      *progress.lock().unwrap() = i + 1;
+      advanced.notify_all();
+    }
+  });
+  format!("{:x}", hasher.compute())
+}
+
+/// Runs `calculate_checksum` on the blocking thread pool so that hashing
+/// huge files doesn't stall the async runtime.
+async fn calculate_checksum_blocking(file_path: &Path, io: IoOptions) -> Result<String> {
+  let file_path = file_path.to_path_buf();
+  tokio::task::spawn_blocking(move || calculate_checksum(&file_path, io)).await?
+}
 
-  let md5_expected = download_checksum(md5_url)?;
-  let md5_actual = calculate_checksum(archive_path)?;
+/// Checks if the database at `db_path` is identical to the one in the snapshot at `snapshot_url`.
+pub async fn db_matches_snapshot(
+  snapshot_url: &Url,
+  db_path: &Path,
+  io: IoOptions,
+  options: &ChecksumOptions,
+) -> Result<bool> {
+  let md5_url = match &options.db_url {
+    Some(url) => url.clone(),
+    None => get_link_to_db_md5(snapshot_url)?,
+  };
+  let md5_expected = download_checksum(md5_url, options).await?;
+  // The node may be writing to its database, so it's read without mapping
+  let io = IoOptions {
+    hash_threads: 1,
+    ..io
+  };
+  let md5_actual = calculate_checksum_blocking(db_path, io).await?;
 
   Ok(md5_actual == md5_expected)
 }
 
-pub fn verify_db(redirect_file_path: &Path, unpacked_file_path: &Path) -> Result<bool> {
-  let archive_url_str = String::from_utf8(std::fs::read(redirect_file_path)?)?;
-  let archive_url = Url::parse(&archive_url_str)?;
-  let md5_url = get_link_to_db_md5(&archive_url)?;
+/// Downloads the checksum of the archive at `archive_url`.
+pub async fn archive_checksum(archive_url: &Url, options: &ChecksumOptions) -> Result<String> {
+  let md5_url = match &options.archive_url {
+    Some(url) => url.clone(),
+    None => get_link_to_archive_md5(archive_url)?,
+  };
+  download_checksum(md5_url, options).await
+}
 
-  let md5_expected = download_checksum(md5_url)?;
-  let md5_actual = calculate_checksum(unpacked_file_path)?;
+/// Checks the archive at `archive_path` against the checksum at `md5_url`.
+pub async fn verify_archive(
+  md5_url: &Url,
+  archive_path: &Path,
+  io: IoOptions,
+  options: &ChecksumOptions,
+) -> Result<bool> {
+  let md5_expected = download_checksum(md5_url.clone(), options).await?;
+  let md5_actual = calculate_checksum_blocking(archive_path, io).await?;
 
   Ok(md5_actual == md5_expected)
 }
+
+/// Checks the unpacked database at `unpacked_file_path` against the checksum
+/// at `md5_url`. Returns the checksum if it matches.
+pub async fn verify_db(
+  md5_url: &Url,
+  unpacked_file_path: &Path,
+  io: IoOptions,
+  options: &ChecksumOptions,
+) -> Result<Option<String>> {
+  let md5_expected = download_checksum(md5_url.clone(), options).await?;
+  let md5_actual = calculate_checksum_blocking(unpacked_file_path, io).await?;
+
+  Ok((md5_actual == md5_expected).then_some(md5_expected))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parsing_checksum_files() {
+    let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+    assert_eq!(
+      parse_checksum(&format!("{md5}\n"), "100.sql", 32).unwrap(),
+      md5
+    );
+    assert_eq!(
+      parse_checksum(&format!("{md5}  ./100.sql.zst\n"), "100.sql.zst", 32).unwrap(),
+      md5
+    );
+    let sums = format!("{}  100.sql\n{md5} *100.sql.zst\n", "0".repeat(32));
+    assert_eq!(parse_checksum(&sums, "100.sql.zst", 32).unwrap(), md5);
+    assert_eq!(
+      parse_checksum(&sums, "100.sql", 32).unwrap(),
+      "0".repeat(32)
+    );
+    assert!(parse_checksum(&sums, "200.sql", 32).is_err());
+    assert!(parse_checksum("\n", "100.sql", 32).is_err());
+    assert!(parse_checksum("<html>", "100.sql", 32).is_err());
+    // hex, but of another length
+    assert!(parse_checksum("abc", "100.sql", 32).is_err());
+    assert!(parse_checksum(md5, "100.sql", 64).is_err());
+    assert!(parse_checksum(&"a".repeat(64), "100.sql", 64).is_ok());
+
+    let url = Url::parse("https://quicksync.spacemesh.network/1/100.sql.zst.md5").unwrap();
+    assert_eq!(checksum_file_name(&url), "100.sql.zst");
+    assert_eq!(checksum_len(&url), 32);
+    let url = Url::parse("https://quicksync.spacemesh.network/1/100.sql.zst.sha256").unwrap();
+    assert_eq!(checksum_file_name(&url), "100.sql.zst");
+    assert_eq!(checksum_len(&url), 64);
+  }
+
+  #[test]
+  fn choosing_checksum_urls() {
+    let dir = tempfile::tempdir().unwrap();
+    let redirect = dir.path().join("state.url");
+    let mut options = ChecksumOptions::default();
+    assert_eq!(options.archive_md5_url(&redirect).unwrap(), None);
+
+    std::fs::write(
+      &redirect,
+      "https://quicksync.spacemesh.network/1/100.sql.zst",
+    )
+    .unwrap();
+    assert_eq!(
+      options
+        .archive_md5_url(&redirect)
+        .unwrap()
+        .unwrap()
+        .as_str(),
+      "https://quicksync.spacemesh.network/1/100.sql.zst.md5"
+    );
+    assert_eq!(
+      options.db_md5_url(&redirect).unwrap().unwrap().as_str(),
+      "https://quicksync.spacemesh.network/1/100.sql.md5"
+    );
+    std::fs::write(&redirect, "https://mirror.org/1/100.sql.zip").unwrap();
+    assert_eq!(
+      options.db_md5_url(&redirect).unwrap().unwrap().as_str(),
+      "https://mirror.org/1/100.sql.md5"
+    );
+
+    options.db_url = Some(Url::parse("https://mirror.org/sums/100.md5").unwrap());
+    assert_eq!(
+      options.db_md5_url(&redirect).unwrap().unwrap().as_str(),
+      "https://mirror.org/sums/100.md5"
+    );
+  }
+
+  #[test]
+  fn fetching_checksums_from_server() {
+    let server = Url::parse("https://quicksync.spacemesh.network/10/61579.sql.zst").unwrap();
+    let lan = Url::parse("http://10.0.0.2:7513/10/61570.sql.zst").unwrap();
+    let mut options = ChecksumOptions::default();
+    options.from_server(&server, &lan).unwrap();
+    assert_eq!(
+      options.archive_url.unwrap().as_str(),
+      "https://quicksync.spacemesh.network/10/61570.sql.zst.md5"
+    );
+    assert_eq!(
+      options.db_url.unwrap().as_str(),
+      "https://quicksync.spacemesh.network/10/61570.sql.md5"
+    );
+  }
+
+  #[tokio::test]
+  async fn retrying_checksum_download() {
+    let mut server = mockito::Server::new_async().await;
+    // Served first, until it's hit once
+    let failure = server
+      .mock("GET", "/1/100.sql.zst.md5")
+      .with_status(500)
+      .expect(1)
+      .create_async()
+      .await;
+    let success = server
+      .mock("GET", "/1/100.sql.zst.md5")
+      .with_body("d41d8cd98f00b204e9800998ecf8427e\n")
+      .create_async()
+      .await;
+    let options = ChecksumOptions {
+      max_retries: 2,
+      retry_delay: Duration::from_millis(10),
+      ..Default::default()
+    };
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    assert_eq!(
+      archive_checksum(&url, &options).await.unwrap(),
+      "d41d8cd98f00b204e9800998ecf8427e"
+    );
+    failure.assert_async().await;
+    success.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn missing_checksum_is_not_retried() {
+    let mut server = mockito::Server::new_async().await;
+    let missing = server
+      .mock("GET", "/1/100.sql.md5")
+      .with_status(404)
+      .expect(1)
+      .create_async()
+      .await;
+    let url = Url::parse(&format!("{}/1/100.sql.md5", server.url())).unwrap();
+    let err = download_checksum(url, &ChecksumOptions::default())
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("404"));
+    missing.assert_async().await;
+  }
+
+  #[test]
+  fn hashing_mapped_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.sql");
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+    let expected = format!("{:x}", md5::compute(&data));
+    for hash_threads in [1, 2, 5] {
+      let io = IoOptions {
+        hash_threads,
+        ..IoOptions::for_tests()
+      };
+      assert_eq!(calculate_checksum(&path, io).unwrap(), expected);
+    }
+
+    std::fs::write(&path, b"").unwrap();
+    let io = IoOptions {
+      hash_threads: 4,
+      ..IoOptions::for_tests()
+    };
+    assert_eq!(
+      calculate_checksum(&path, io).unwrap(),
+      format!("{:x}", md5::compute(b""))
+    );
+  }
+}
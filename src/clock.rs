@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::DATE;
+use url::Url;
+
+use crate::http_trace::SendTraced;
+use crate::transport;
+use crate::url_policy;
+
+/// Skew of the local clock (in seconds) the current network layer is still
+/// trusted with. The `Date` header has a resolution of a second.
+const MAX_SKEW_SECS: i64 = 30;
+
+/// Offset of the local clock from the server's, assuming the server stamped
+/// the response halfway through the request.
+fn skew(sent: DateTime<Utc>, received: DateTime<Utc>, server_time: DateTime<Utc>) -> Duration {
+  let local_time = sent + (received - sent) / 2;
+  local_time - server_time
+}
+
+/// Measures how far the local clock is ahead of the clock of the server at
+/// `url` (negative if behind), from the `Date` header of its response.
+pub async fn measure_skew(url: &Url) -> Result<Duration> {
+  let client = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .timeout(std::time::Duration::from_secs(30))
+    .build()?;
+  let sent = Utc::now();
+  let response = client.head(url.clone()).send_traced().await?;
+  let received = Utc::now();
+  // Any response is stamped, even an error
+  let date = response
+    .headers()
+    .get(DATE)
+    .context("the server sent no Date header")?
+    .to_str()?;
+  let server_time =
+    DateTime::parse_from_rfc2822(date).with_context(|| format!("parsing Date header {date}"))?;
+  Ok(skew(sent, received, server_time.with_timezone(&Utc)))
+}
+
+/// Describes the skew if it's large enough to get the current layer wrong.
+pub fn describe_significant(skew: Duration) -> Option<String> {
+  let seconds = skew.num_seconds();
+  match seconds {
+    _ if seconds.abs() <= MAX_SKEW_SECS => None,
+    _ if seconds > 0 => Some(format!("{seconds}s ahead of")),
+    _ => Some(format!("{}s behind", -seconds)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn measuring_skew() {
+    let sent = DateTime::parse_from_rfc3339("2024-07-14T08:00:00Z")
+      .unwrap()
+      .with_timezone(&Utc);
+    let received = sent + Duration::seconds(2);
+    let server_time = DateTime::parse_from_rfc2822("Sun, 14 Jul 2024 07:55:00 GMT")
+      .unwrap()
+      .with_timezone(&Utc);
+    assert_eq!(
+      skew(sent, received, server_time),
+      Duration::seconds(5 * 60 + 1)
+    );
+
+    assert_eq!(describe_significant(Duration::seconds(20)), None);
+    assert_eq!(
+      describe_significant(Duration::seconds(301)).unwrap(),
+      "301s ahead of"
+    );
+    assert_eq!(
+      describe_significant(Duration::seconds(-90)).unwrap(),
+      "90s behind"
+    );
+  }
+
+  #[tokio::test]
+  async fn reading_server_date() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("HEAD", "/")
+      .with_status(404)
+      .with_header("Date", "Sun, 14 Jul 2024 07:55:00 GMT")
+      .create_async()
+      .await;
+    let url = Url::parse(&server.url()).unwrap();
+    // The local clock is years ahead of the mocked one
+    assert!(measure_skew(&url).await.unwrap() > Duration::days(365));
+    mock.assert_async().await;
+  }
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::OnceLock;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::watch;
+
+use crate::events::{self, Event};
+use crate::exit_error::ExitError;
+
+/// Exit code of a run cancelled through the control channel.
+pub const CANCELLED_EXIT_CODE: i32 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlState {
+  Running,
+  Paused,
+  Cancelled,
+}
+
+static CONTROL: OnceLock<watch::Sender<ControlState>> = OnceLock::new();
+
+/// Starts accepting `pause`, `resume` and `cancel` commands (one per line)
+/// from `source`: `stdin`, or a path of a unix socket (a named pipe on Windows)
+/// to create. Must be called within the async runtime.
+pub fn listen(source: &str) -> Result<()> {
+  let (sender, _) = watch::channel(ControlState::Running);
+  CONTROL
+    .set(sender)
+    .map_err(|_| anyhow::anyhow!("control channel is already set up"))?;
+  if source == "stdin" {
+    tokio::spawn(read_commands(BufReader::new(tokio::io::stdin())));
+  } else {
+    listen_on(source)?;
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn listen_on(path: &str) -> Result<()> {
+  // A socket left over by a previous run would fail the bind
+  let _ = std::fs::remove_file(path);
+  let listener = tokio::net::UnixListener::bind(path)
+    .map_err(|e| anyhow::anyhow!("creating control socket {path}: {e}"))?;
+  tokio::spawn(async move {
+    while let Ok((stream, _)) = listener.accept().await {
+      tokio::spawn(read_commands(BufReader::new(stream)));
+    }
+  });
+  Ok(())
+}
+
+#[cfg(windows)]
+fn listen_on(path: &str) -> Result<()> {
+  use tokio::net::windows::named_pipe::ServerOptions;
+
+  let path = path.to_string();
+  let mut server = ServerOptions::new()
+    .first_pipe_instance(true)
+    .create(&path)
+    .map_err(|e| anyhow::anyhow!("creating control pipe {path}: {e}"))?;
+  tokio::spawn(async move {
+    while server.connect().await.is_ok() {
+      let Ok(next) = ServerOptions::new().create(&path) else {
+        break;
+      };
+      let connected = std::mem::replace(&mut server, next);
+      tokio::spawn(read_commands(BufReader::new(connected)));
+    }
+  });
+  Ok(())
+}
+
+async fn read_commands<R: AsyncBufRead + Unpin>(reader: R) {
+  let mut lines = reader.lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    match parse_command(&line) {
+      Some(state) => apply(state),
+      None if line.trim().is_empty() => {}
+      None => println!("Unknown control command: {}", line.trim()),
+    }
+  }
+}
+
+fn parse_command(line: &str) -> Option<ControlState> {
+  match line.trim().to_lowercase().as_str() {
+    "pause" => Some(ControlState::Paused),
+    "resume" => Some(ControlState::Running),
+    "cancel" => Some(ControlState::Cancelled),
+    _ => None,
+  }
+}
+
+fn apply(state: ControlState) {
+  let Some(sender) = CONTROL.get() else {
+    return;
+  };
+  // Cancelling is final
+  let changed = sender.send_if_modified(|current| {
+    if *current == state || *current == ControlState::Cancelled {
+      return false;
+    }
+    *current = state;
+    true
+  });
+  if changed {
+    match state {
+      ControlState::Running => println!("Resumed"),
+      ControlState::Paused => println!("Paused, send `resume` to continue"),
+      ControlState::Cancelled => println!("Cancelling..."),
+    }
+    events::emit(Event::Control { state });
+  }
+}
+
+/// Waits while paused and fails when cancelled.
+/// Long-running loops call it between steps that are safe to suspend or abort at.
+pub async fn checkpoint() -> Result<()> {
+  let Some(sender) = CONTROL.get() else {
+    return Ok(());
+  };
+  let mut receiver = sender.subscribe();
+  loop {
+    match *receiver.borrow_and_update() {
+      ControlState::Running => return Ok(()),
+      ControlState::Cancelled => {
+        return Err(ExitError::new(CANCELLED_EXIT_CODE, "Cancelled").into());
+      }
+      ControlState::Paused => {}
+    }
+    receiver.changed().await?;
+  }
+}
+
+/// Completes once the run is cancelled through the control channel, never
+/// without one. For waits that [`checkpoint`] would only end afterwards.
+pub async fn cancelled() {
+  let Some(sender) = CONTROL.get() else {
+    return std::future::pending().await;
+  };
+  let mut receiver = sender.subscribe();
+  let _ = receiver
+    .wait_for(|state| *state == ControlState::Cancelled)
+    .await;
+}
+
+/// Completes once the service manager asks the process to stop (SIGTERM),
+/// never where there's no such signal. Ctrl+C stops any run already.
+pub async fn terminated() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+      terminate.recv().await;
+      return;
+    }
+  }
+  std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_command, ControlState};
+
+  #[test]
+  fn parsing_commands() {
+    assert_eq!(parse_command("pause\n"), Some(ControlState::Paused));
+    assert_eq!(parse_command(" Resume "), Some(ControlState::Running));
+    assert_eq!(parse_command("cancel"), Some(ControlState::Cancelled));
+    assert_eq!(parse_command("stop"), None);
+  }
+}
@@ -0,0 +1,369 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::control;
+use crate::events::{self, Event, Stage};
+use crate::http_trace::SendTraced;
+use crate::read_error_response::read_error_response;
+use crate::source::SnapshotSource;
+use crate::status;
+use crate::transport;
+use crate::url_policy;
+
+/// Suffix of the chunk index published next to a chunked snapshot.
+pub const INDEX_SUFFIX: &str = ".chunks.json";
+
+/// Largest chunk accepted from the server, as buffers are sized from it.
+pub const MAX_CHUNK_SIZE: u64 = 256 * 1024 * 1024;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Index of a chunked snapshot. The archive is a sequence of independent zstd
+/// frames, one per chunk of the database, so it unpacks as usual and each
+/// chunk can also be fetched and unpacked on its own.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkIndex {
+  /// Size of the uncompressed chunks, only the last one may be smaller.
+  pub chunk_size: u64,
+  pub db_size: u64,
+  pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Chunk {
+  /// Position of the frame in the archive.
+  pub offset: u64,
+  /// Size of the frame in the archive.
+  pub length: u64,
+  /// SHA-256 of the uncompressed chunk.
+  pub sha256: String,
+}
+
+impl ChunkIndex {
+  fn validate(&self) -> Result<()> {
+    anyhow::ensure!(self.chunk_size > 0, "chunk size is zero");
+    anyhow::ensure!(
+      self.chunk_size <= MAX_CHUNK_SIZE,
+      "chunk size of {} bytes is over the limit of {MAX_CHUNK_SIZE} bytes",
+      self.chunk_size
+    );
+    anyhow::ensure!(
+      self.chunks.len() as u64 == self.db_size.div_ceil(self.chunk_size),
+      "{} chunks of {} bytes don't add up to {} bytes",
+      self.chunks.len(),
+      self.chunk_size,
+      self.db_size
+    );
+    anyhow::ensure!(
+      self.chunks.iter().all(|c| c.length > 0),
+      "empty chunk in the index"
+    );
+    // A frame is at most a little bigger than its chunk, if it doesn't compress
+    let max_length = zstd::zstd_safe::compress_bound(self.chunk_size as usize) as u64;
+    anyhow::ensure!(
+      self.chunks.iter().all(|c| c.length <= max_length),
+      "chunk frame bigger than {max_length} bytes in the index"
+    );
+    Ok(())
+  }
+
+  /// Size of the uncompressed chunk `i`.
+  fn chunk_len(&self, i: usize) -> u64 {
+    self
+      .chunk_size
+      .min(self.db_size - i as u64 * self.chunk_size)
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct DeltaStats {
+  pub reused: usize,
+  pub fetched: usize,
+  /// Compressed bytes downloaded.
+  pub fetched_bytes: u64,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+  hex::encode(Sha256::digest(data))
+}
+
+fn client() -> Result<Client> {
+  Ok(
+    transport::builder()
+      .redirect(url_policy::redirect_policy())
+      .timeout(Duration::from_secs(300))
+      .build()?,
+  )
+}
+
+/// Fetches the chunk index of the snapshot at `snapshot_url`.
+/// Returns `None` if the snapshot isn't chunked.
+pub async fn fetch_index(snapshot_url: &Url) -> Result<Option<ChunkIndex>> {
+  let url =
+    Url::parse(&format!("{snapshot_url}{INDEX_SUFFIX}")).context("composing chunk index URL")?;
+  let response = client()?.get(url.clone()).send_traced().await?;
+  let status = response.status();
+  if status == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  if !status.is_success() {
+    let err = read_error_response(response).await;
+    anyhow::bail!("Cannot download chunk index from {url}: {status} {err}");
+  }
+  let index: ChunkIndex = response
+    .json()
+    .await
+    .with_context(|| format!("parsing chunk index from {url}"))?;
+  index
+    .validate()
+    .with_context(|| format!("invalid chunk index at {url}"))?;
+  Ok(Some(index))
+}
+
+/// Hashes the chunks of the file, returning the offset of each distinct chunk.
+fn hash_chunks(path: &Path, chunk_size: u64) -> Result<HashMap<String, u64>> {
+  let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+  let mut reader = BufReader::new(file);
+  let mut chunks = HashMap::new();
+  let mut buf = Vec::with_capacity(chunk_size as usize);
+  let mut offset = 0;
+  loop {
+    buf.clear();
+    (&mut reader).take(chunk_size).read_to_end(&mut buf)?;
+    if buf.is_empty() {
+      break;
+    }
+    chunks.entry(sha256_hex(&buf)).or_insert(offset);
+    offset += buf.len() as u64;
+  }
+  Ok(chunks)
+}
+
+async fn try_fetch_chunk(
+  source: &dyn SnapshotSource,
+  url: &Url,
+  chunk: &Chunk,
+  len: u64,
+) -> Result<Vec<u8>> {
+  let frame = source.fetch_range(url, chunk.offset, chunk.length).await?;
+  let data = zstd::bulk::decompress(&frame, len as usize).context("unpacking chunk")?;
+  anyhow::ensure!(
+    data.len() as u64 == len && sha256_hex(&data) == chunk.sha256,
+    "chunk checksum mismatch"
+  );
+  Ok(data)
+}
+
+async fn fetch_chunk(
+  source: &dyn SnapshotSource,
+  url: &Url,
+  chunk: &Chunk,
+  len: u64,
+  max_retries: u32,
+) -> Result<Vec<u8>> {
+  let mut attempt = 0;
+  loop {
+    match try_fetch_chunk(source, url, chunk, len).await {
+      Ok(data) => return Ok(data),
+      Err(e) if attempt < max_retries => {
+        attempt += 1;
+        status::print_line(format_args!(
+          "Cannot download the chunk at {}: {e:#}. Retrying ({attempt}/{max_retries})...",
+          chunk.offset
+        ));
+        tokio::time::sleep(RETRY_DELAY).await;
+      }
+      Err(e) => return Err(e.context(format!("downloading the chunk at {}", chunk.offset))),
+    }
+  }
+}
+
+/// Rebuilds the database of the chunked snapshot at `snapshot_url` in `out_path`,
+/// taking the chunks that are already in `local_db` from there and downloading
+/// only the others from `source`.
+pub async fn download(
+  source: &dyn SnapshotSource,
+  snapshot_url: &Url,
+  index: &ChunkIndex,
+  local_db: &Path,
+  out_path: &Path,
+  max_retries: u32,
+) -> Result<DeltaStats> {
+  println!("Comparing the local database with the snapshot, it may take some time...");
+  let local = {
+    let (db, chunk_size) = (local_db.to_path_buf(), index.chunk_size);
+    tokio::task::spawn_blocking(move || hash_chunks(&db, chunk_size)).await??
+  };
+
+  let mut local_file = File::open(local_db)?;
+  let out_file =
+    File::create(out_path).with_context(|| format!("creating file: {}", out_path.display()))?;
+  let mut out = BufWriter::new(out_file);
+  let mut stats = DeltaStats::default();
+  let mut buf = Vec::new();
+  let mut done = 0;
+  let mut last_report = Instant::now();
+  for (i, chunk) in index.chunks.iter().enumerate() {
+    control::checkpoint().await?;
+    let len = index.chunk_len(i);
+    match local.get(&chunk.sha256) {
+      Some(&offset) => {
+        local_file.seek(SeekFrom::Start(offset))?;
+        buf.clear();
+        (&mut local_file).take(len).read_to_end(&mut buf)?;
+        out.write_all(&buf)?;
+        stats.reused += 1;
+      }
+      None => {
+        let data = fetch_chunk(source, snapshot_url, chunk, len, max_retries).await?;
+        out.write_all(&data)?;
+        stats.fetched += 1;
+        stats.fetched_bytes += chunk.length;
+      }
+    }
+    done += len;
+
+    if last_report.elapsed() >= PROGRESS_INTERVAL {
+      last_report = Instant::now();
+      let line = format!(
+        "Rebuilding the database... {:.2}% (chunks reused: {}, downloaded: {})",
+        done as f64 / index.db_size.max(1) as f64 * 100.0,
+        stats.reused,
+        stats.fetched
+      );
+      status::progress(Stage::Download, done, Some(index.db_size), &line);
+      events::emit(Event::Progress {
+        stage: Stage::Download,
+        done,
+        total: Some(index.db_size),
+        bytes_per_sec: None,
+      });
+    }
+  }
+  out.into_inner()?.sync_all()?;
+  Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::source::HttpSource;
+
+  fn index_for(data: &[u8], chunk_size: usize) -> (ChunkIndex, Vec<u8>) {
+    let mut archive = Vec::new();
+    let mut chunks = Vec::new();
+    for chunk in data.chunks(chunk_size) {
+      let frame = zstd::bulk::compress(chunk, 3).unwrap();
+      chunks.push(Chunk {
+        offset: archive.len() as u64,
+        length: frame.len() as u64,
+        sha256: sha256_hex(chunk),
+      });
+      archive.extend_from_slice(&frame);
+    }
+    let index = ChunkIndex {
+      chunk_size: chunk_size as u64,
+      db_size: data.len() as u64,
+      chunks,
+    };
+    (index, archive)
+  }
+
+  #[tokio::test]
+  async fn downloading_only_changed_chunks() {
+    let old: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let mut new = old.clone();
+    new[5_000] ^= 0xFF;
+    new.extend_from_slice(&[7; 500]);
+    let (index, archive) = index_for(&new, 1024);
+    index.validate().unwrap();
+    // The concatenated frames unpack as a whole too
+    assert_eq!(zstd::decode_all(archive.as_slice()).unwrap(), new);
+
+    let changed = &index.chunks[4];
+    let mut server = mockito::Server::new_async().await;
+    let index_mock = server
+      .mock("GET", "/1/100.sql.zst.chunks.json")
+      .with_body(serde_json::to_string(&index).unwrap())
+      .create_async()
+      .await;
+    let range = |c: &Chunk| format!("bytes={}-{}", c.offset, c.offset + c.length - 1);
+    let mut chunk_mocks = Vec::new();
+    for chunk in [changed, &index.chunks[9], &index.chunks[10]] {
+      let start = chunk.offset as usize;
+      chunk_mocks.push(
+        server
+          .mock("GET", "/1/100.sql.zst")
+          .match_header("Range", range(chunk).as_str())
+          .with_status(206)
+          .with_body(&archive[start..start + chunk.length as usize])
+          .create_async()
+          .await,
+      );
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let local_db = dir.path().join("state.sql");
+    let out = dir.path().join("state_downloaded.sql");
+    std::fs::write(&local_db, &old).unwrap();
+
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    let index = fetch_index(&url).await.unwrap().unwrap();
+    let stats = download(
+      &HttpSource::new().unwrap(),
+      &url,
+      &index,
+      &local_db,
+      &out,
+      0,
+    )
+    .await
+    .unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), new);
+    assert_eq!(stats.fetched, 3);
+    assert_eq!(stats.reused, 8);
+
+    index_mock.assert_async().await;
+    for mock in chunk_mocks {
+      mock.assert_async().await;
+    }
+  }
+
+  #[test]
+  fn rejecting_oversized_chunks() {
+    let (mut index, _) = index_for(&[1; 100], 10);
+    index.chunks[3].length = 1 << 20;
+    assert!(index.validate().is_err());
+    let index = ChunkIndex {
+      chunk_size: MAX_CHUNK_SIZE + 1,
+      db_size: MAX_CHUNK_SIZE + 1,
+      chunks: vec![Chunk {
+        offset: 0,
+        length: 1,
+        sha256: String::new(),
+      }],
+    };
+    assert!(index.validate().is_err());
+  }
+
+  #[tokio::test]
+  async fn unchunked_snapshot_has_no_index() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/1/100.sql.zst.chunks.json")
+      .with_status(404)
+      .create_async()
+      .await;
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    assert!(fetch_index(&url).await.unwrap().is_none());
+    mock.assert_async().await;
+  }
+}
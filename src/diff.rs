@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::incremental_quicksync::{
+  file_url, get_latest_from_db, get_previous_hash, get_user_version, Database, RestorePoint,
+};
+
+/// What the restore point is computed against.
+#[derive(Clone, Copy)]
+pub enum DiffBase<'a> {
+  /// An older copy of the database. The diff holds the rows missing in it
+  /// and covers layers after the latest one applied in it.
+  Database(&'a Path),
+  /// The first layer to include. The diff holds the rows of layers >= `from`
+  /// of tables with a layer column and all rows of the other tables.
+  Layer(u32),
+}
+
+fn table_names(conn: &Connection, schema: &str) -> Result<Vec<(String, String)>> {
+  let mut stmt = conn.prepare(&format!(
+    "SELECT name, sql FROM {schema}.sqlite_master
+     WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name"
+  ))?;
+  let tables = stmt
+    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+    .collect::<rusqlite::Result<_>>()?;
+  Ok(tables)
+}
+
+fn columns(conn: &Connection, schema: &str, table: &str) -> Result<Vec<String>> {
+  let mut stmt = conn.prepare(&format!("PRAGMA {schema}.table_info(\"{table}\")"))?;
+  let columns = stmt
+    .query_map([], |row| row.get(1))?
+    .collect::<rusqlite::Result<_>>()?;
+  Ok(columns)
+}
+
+/// Column holding the layer of the rows in `table`, if any.
+fn layer_column(table: &str, columns: &[String]) -> Option<&'static str> {
+  if table == "layers" {
+    Some("id")
+  } else if columns.iter().any(|c| c == "layer") {
+    Some("layer")
+  } else {
+    None
+  }
+}
+
+/// Generates a restore point of `db_path` in the layout served for incremental
+/// quicksync under `out_dir`:
+/// - `{user_version}/{from}_{to}_{hash}/state.sql_diff.{from}_{to}.sql[.zst]` - the diff database,
+/// - `{user_version}/metadata.csv` - the restore point line (metadata v2, with
+///   the size of the diff file) gets appended to it.
+///
+/// Layers up to the latest applied one in `db_path` are covered. Returns the metadata line.
+pub fn generate_diff(
+  db_path: &Path,
+  base: DiffBase,
+  out_dir: &Path,
+  compress: bool,
+) -> Result<String> {
+  let conn = Connection::open(db_path).context("opening database")?;
+  let user_version = get_user_version(&conn)?;
+  let to = get_latest_from_db(&conn)? + 1;
+  let from = match base {
+    DiffBase::Database(old) => {
+      let old_conn = Connection::open(old).context("opening the older database")?;
+      let old_version = get_user_version(&old_conn)?;
+      anyhow::ensure!(
+        old_version == user_version,
+        "schema versions differ: {old_version} != {user_version}"
+      );
+      get_latest_from_db(&old_conn)? + 1
+    }
+    DiffBase::Layer(from) => from,
+  };
+  anyhow::ensure!(from < to, "no layers to diff: {from}..{to}");
+  // The consumer checks the hash of the layer before the restore point to be
+  // sure the diff continues its database
+  let hash = match from {
+    0 => "0000".to_string(),
+    from => get_previous_hash(from, &conn)?,
+  };
+  let mut point = RestorePoint {
+    from,
+    to,
+    hash,
+    size: None,
+    url: None,
+  };
+
+  let version_dir = out_dir.join(user_version.to_string());
+  let metadata_path = version_dir.join("metadata.csv");
+  check_continuity(&metadata_path, &point)?;
+
+  let diff_path = out_dir.join(file_url(Database::State, user_version, &point, None));
+  let diff_dir = diff_path.parent().unwrap();
+  std::fs::create_dir_all(diff_dir)
+    .with_context(|| format!("creating directory: {}", diff_dir.display()))?;
+  if diff_path.try_exists()? {
+    std::fs::remove_file(&diff_path)?;
+  }
+  println!(
+    "Generating restore point {} to {} into {}...",
+    from,
+    to,
+    diff_path.display()
+  );
+  drop(conn);
+  write_diff(db_path, base, &diff_path, user_version, from, to)?;
+
+  let published_path = if compress {
+    let archive_path = out_dir.join(file_url(
+      Database::State,
+      user_version,
+      &point,
+      Some(".zst"),
+    ));
+    compress_file(&diff_path, &archive_path)?;
+    std::fs::remove_file(&diff_path)
+      .with_context(|| format!("removing {}", diff_path.display()))?;
+    archive_path
+  } else {
+    diff_path
+  };
+  // Metadata v2: the size lets clients estimate the restore time
+  point.size = Some(std::fs::metadata(&published_path)?.len());
+
+  let line = point.to_string();
+  let mut metadata = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(&metadata_path)
+    .with_context(|| format!("opening {}", metadata_path.display()))?;
+  writeln!(metadata, "{line}")?;
+  println!(
+    "Added restore point to {}: {}",
+    metadata_path.display(),
+    line
+  );
+  Ok(line)
+}
+
+/// Restore points must be ordered and must not overlap, so a new point has
+/// to start where the last one ends.
+fn check_continuity(metadata_path: &Path, point: &RestorePoint) -> Result<()> {
+  let metadata = match std::fs::read_to_string(metadata_path) {
+    Ok(metadata) => metadata,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e).context("reading metadata.csv"),
+  };
+  if let Some(last) = metadata.trim().lines().last() {
+    let last: RestorePoint = last
+      .trim()
+      .parse()
+      .with_context(|| format!("parsing restore point '{last}'"))?;
+    anyhow::ensure!(
+      last.to == point.from,
+      "restore point {}..{} doesn't continue the last one in metadata.csv ({}..{})",
+      point.from,
+      point.to,
+      last.from,
+      last.to
+    );
+  }
+  Ok(())
+}
+
+fn write_diff(
+  db_path: &Path,
+  base: DiffBase,
+  diff_path: &Path,
+  user_version: usize,
+  from: u32,
+  to: u32,
+) -> Result<()> {
+  let diff = Connection::open(diff_path).context("creating diff database")?;
+  diff.execute("ATTACH DATABASE ? AS src", [db_path.to_string_lossy()])?;
+  if let DiffBase::Database(old) = base {
+    diff.execute("ATTACH DATABASE ? AS old", [old.to_string_lossy()])?;
+  }
+  diff.pragma_update(None, "user_version", user_version)?;
+
+  let old_tables = match base {
+    DiffBase::Database(_) => table_names(&diff, "old")?,
+    DiffBase::Layer(_) => Vec::new(),
+  };
+  for (table, sql) in table_names(&diff, "src")? {
+    diff
+      .execute_batch(&sql)
+      .with_context(|| format!("creating table {table}"))?;
+    let columns = columns(&diff, "src", &table)?;
+    let mut query = format!("INSERT INTO main.\"{table}\" SELECT * FROM src.\"{table}\"");
+    match base {
+      DiffBase::Database(_) => {
+        let same_table = old_tables.iter().any(|(name, _)| name == &table)
+          && columns(&diff, "old", &table)? == columns;
+        if same_table {
+          query.push_str(&format!(" EXCEPT SELECT * FROM old.\"{table}\""));
+        }
+      }
+      DiffBase::Layer(_) => {
+        if let Some(column) = layer_column(&table, &columns) {
+          query.push_str(&format!(
+            " WHERE \"{column}\" >= {from} AND \"{column}\" < {to}"
+          ));
+        }
+      }
+    }
+    let rows = diff
+      .execute(&query, [])
+      .with_context(|| format!("copying rows of {table}"))?;
+    println!("{table}: {rows} rows");
+  }
+  diff.execute_batch("DETACH DATABASE src")?;
+  if let DiffBase::Database(_) = base {
+    diff.execute_batch("DETACH DATABASE old")?;
+  }
+  diff.execute_batch("VACUUM")?;
+  diff.close().map_err(|(_, e)| e)?;
+  Ok(())
+}
+
+fn compress_file(input_path: &Path, output_path: &Path) -> Result<()> {
+  let input = File::open(input_path).context("opening diff database")?;
+  let output =
+    File::create(output_path).with_context(|| format!("creating {}", output_path.display()))?;
+  let mut reader = BufReader::new(input);
+  let mut encoder = zstd::stream::write::Encoder::new(BufWriter::new(output), 19)?;
+  std::io::copy(&mut reader, &mut encoder).context("compressing diff database")?;
+  encoder.finish()?.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{generate_diff, DiffBase};
+  use rusqlite::Connection;
+  use std::path::Path;
+
+  fn create_db(path: &Path, layers: std::ops::Range<u32>) {
+    let conn = Connection::open(path).unwrap();
+    conn
+      .execute_batch(
+        "PRAGMA user_version = 1;
+        CREATE TABLE IF NOT EXISTS layers (id INTEGER PRIMARY KEY, applied_block INTEGER, aggregated_hash BLOB);
+        CREATE TABLE IF NOT EXISTS ballots (id INTEGER PRIMARY KEY, layer INTEGER);",
+      )
+      .unwrap();
+    for layer in layers {
+      conn
+        .execute(
+          "INSERT INTO layers VALUES (?1, 1, ?2)",
+          rusqlite::params![layer, vec![0xAB, 0xCD, layer as u8]],
+        )
+        .unwrap();
+      conn
+        .execute("INSERT INTO ballots VALUES (?1, ?1)", [layer])
+        .unwrap();
+    }
+  }
+
+  fn count(path: &Path, table: &str) -> u32 {
+    let conn = Connection::open(path).unwrap();
+    conn
+      .query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+        row.get(0)
+      })
+      .unwrap()
+  }
+
+  #[test]
+  fn diff_against_older_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let old = dir.path().join("old.sql");
+    let new = dir.path().join("new.sql");
+    create_db(&old, 0..10);
+    create_db(&new, 0..15);
+
+    let out = dir.path().join("out");
+    let line = generate_diff(&new, DiffBase::Database(&old), &out, false).unwrap();
+
+    let diff = out.join("1/10_15_abcd/state.sql_diff.10_15.sql");
+    assert_eq!(
+      line,
+      format!("10,15,abcd,{}", std::fs::metadata(&diff).unwrap().len())
+    );
+    assert_eq!(count(&diff, "layers"), 5);
+    assert_eq!(count(&diff, "ballots"), 5);
+    let metadata = std::fs::read_to_string(out.join("1/metadata.csv")).unwrap();
+    assert_eq!(metadata, format!("{line}\n"));
+  }
+
+  #[test]
+  fn diff_of_layer_range_appends_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("state.sql");
+    create_db(&db, 0..10);
+    let out = dir.path().join("out");
+    let first = generate_diff(&db, DiffBase::Layer(0), &out, true).unwrap();
+    let archive = out.join("1/0_10_0000/state.sql_diff.0_10.sql.zst");
+    assert_eq!(
+      first,
+      format!("0,10,0000,{}", std::fs::metadata(&archive).unwrap().len())
+    );
+
+    create_db(&db, 10..20);
+    // doesn't continue the last restore point
+    assert!(generate_diff(&db, DiffBase::Layer(15), &out, false).is_err());
+    let second = generate_diff(&db, DiffBase::Layer(10), &out, false).unwrap();
+    assert!(second.starts_with("10,20,abcd,"));
+    let diff = out.join("1/10_20_abcd/state.sql_diff.10_20.sql");
+    assert_eq!(count(&diff, "ballots"), 10);
+    let metadata = std::fs::read_to_string(out.join("1/metadata.csv")).unwrap();
+    assert_eq!(metadata, format!("{first}\n{second}\n"));
+  }
+}
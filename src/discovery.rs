@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use url::Url;
+
+use crate::checksum::{download_checksum, ChecksumOptions};
+use crate::mirrors;
+use crate::url_policy;
+use crate::variant::Variant;
+
+/// mDNS service type advertised by `quicksync serve`.
+const SERVICE_TYPE: &str = "_quicksync._tcp.local.";
+/// TXT property with the layer of the served snapshot.
+const LAYER_PROPERTY: &str = "layer";
+
+/// A snapshot source found on the LAN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+  pub url: Url,
+  pub layer: u64,
+}
+
+/// Advertises the snapshot of `layer` served at `port` on the LAN for as long
+/// as the returned daemon is kept.
+pub fn advertise(port: u16, layer: u64) -> Result<ServiceDaemon> {
+  let daemon = ServiceDaemon::new().context("starting mDNS")?;
+  let name = format!("quicksync-{}", std::process::id());
+  let host = format!("{name}.local.");
+  let properties = HashMap::from([(LAYER_PROPERTY.to_string(), layer.to_string())]);
+  let info = ServiceInfo::new(SERVICE_TYPE, &name, &host, "", port, properties)
+    .context("describing the mDNS service")?
+    .enable_addr_auto();
+  daemon.register(info).context("advertising over mDNS")?;
+  Ok(daemon)
+}
+
+/// Looks for snapshot sources advertised on the LAN for the `wait` time.
+pub async fn discover(wait: Duration) -> Result<Vec<Source>> {
+  let daemon = ServiceDaemon::new().context("starting mDNS")?;
+  let events = daemon.browse(SERVICE_TYPE).context("browsing mDNS")?;
+  let mut sources = Vec::new();
+  let deadline = tokio::time::Instant::now() + wait;
+  while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+    let ServiceEvent::ServiceResolved(info) = event else {
+      continue;
+    };
+    let layer = info
+      .get_property_val_str(LAYER_PROPERTY)
+      .and_then(|layer| layer.parse().ok());
+    // IPv4 is preferred, IPv6 link-local addresses aren't reachable without a scope
+    let ip = info
+      .get_addresses()
+      .iter()
+      .min_by_key(|ip| !ip.is_ipv4())
+      .copied();
+    if let (Some(layer), Some(ip)) = (layer, ip) {
+      let url = Url::parse(&format!("http://{}", SocketAddr::new(ip, info.get_port())))?;
+      if !sources.iter().any(|s: &Source| s.url == url) {
+        sources.push(Source { url, layer });
+      }
+    }
+  }
+  let _ = daemon.shutdown();
+  Ok(sources)
+}
+
+/// Picks the freshest source at most `max_lag` layers behind the `latest` layer.
+fn freshest(sources: &[Source], latest: u64, max_lag: u64) -> Option<&Source> {
+  sources
+    .iter()
+    .filter(|s| s.layer + max_lag >= latest)
+    .max_by_key(|s| s.layer)
+}
+
+/// Picks the LAN source to download the snapshot from instead of `download_url`,
+/// if one is fresh enough and the download server publishes the checksums of
+/// its snapshot. Returns the URL of the snapshot on it, allowed over plain HTTP,
+/// and points `checksum` at the checksums on the server.
+pub async fn pick(
+  sources: &[Source],
+  max_lag: u64,
+  download_url: Url,
+  version: &str,
+  variant: Variant,
+  checksum: &mut ChecksumOptions,
+) -> Option<Url> {
+  // The sources aren't authenticated, the snapshot is checked against the server
+  let server = match mirrors::probe(download_url, version.to_string(), variant).await {
+    Ok(probe) => probe,
+    Err(e) => {
+      println!("Not using the LAN: cannot reach the download server: {e:#}");
+      return None;
+    }
+  };
+  let source = freshest(sources, server.layer, max_lag)?;
+  url_policy::allow_lan_source(&source.url);
+  let probe = match mirrors::probe(source.url.clone(), version.to_string(), variant).await {
+    Ok(probe) => probe,
+    Err(e) => {
+      println!("{} found on the LAN is unavailable: {e:#}", source.url);
+      return None;
+    }
+  };
+  let mut from_server = checksum.clone();
+  let published = match from_server.from_server(&server.url, &probe.url) {
+    Ok(()) => match from_server.archive_url.clone() {
+      Some(md5_url) => download_checksum(md5_url, &from_server).await.map(|_| ()),
+      None => Ok(()),
+    },
+    Err(e) => Err(e),
+  };
+  if let Err(e) = published {
+    println!(
+      "Not using {} found on the LAN: no checksum of its snapshot on the download server: {e:#}",
+      source.url
+    );
+    return None;
+  }
+  *checksum = from_server;
+  println!(
+    "Using {} found on the LAN (layer {})",
+    source.url, probe.layer
+  );
+  Some(probe.url)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn source(host: &str, layer: u64) -> Source {
+    Source {
+      url: Url::parse(&format!("http://{host}:7513")).unwrap(),
+      layer,
+    }
+  }
+
+  #[test]
+  fn picking_fresh_lan_source() {
+    let sources = vec![source("10.0.0.2", 90), source("10.0.0.3", 100)];
+    assert_eq!(freshest(&sources, 100, 0), Some(&sources[1]));
+    assert_eq!(freshest(&sources, 110, 0), None);
+    assert_eq!(freshest(&sources, 110, 10), Some(&sources[1]));
+    assert_eq!(freshest(&[], 0, 0), None);
+  }
+}
@@ -1,133 +1,472 @@
 use anyhow::{anyhow, Result};
-use reqwest::blocking::Client;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use reqwest::StatusCode;
-use std::collections::VecDeque;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::time::Instant;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use url::Url;
 
-use crate::eta::Eta;
-use crate::read_error_response::read_error_response;
-use crate::user_agent::APP_USER_AGENT;
+use crate::control;
+use crate::events::{self, Event, Stage};
+use crate::exit_error::ExitError;
+use crate::failpoints;
+use crate::http_trace::SendTraced;
+use crate::progress::{Progress, Reporter};
+use crate::source::{FileStatus, SnapshotSource};
+use crate::speed_meter::SpeedMeter;
+use crate::status;
+use crate::transport;
+use crate::url_policy;
 
-fn download_file<W: Write + Seek>(url: &str, file: &mut W, redirect_path: &Path) -> Result<()> {
+/// Timeout for establishing a connection and receiving response headers.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum time without receiving any data before the transfer is considered stalled.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time window used to calculate the download speed and ETA.
+const SPEED_WINDOW: Duration = Duration::from_secs(30);
+/// How long the download may stay below the minimum speed by default.
+pub const DEFAULT_SLOWDOWN_GRACE: Duration = Duration::from_secs(60);
+/// How long the URL saved in the redirect file is resumed from by default.
+pub const DEFAULT_URL_FILE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Size of the tail of a partial download compared with the server on resume.
+const RESUME_CHECK_SIZE: u64 = 4 * 1024 * 1024;
+/// A failed attempt that downloaded at least this much resets the retries.
+const RETRY_RESET_PROGRESS: u64 = 16 * 1024 * 1024;
+
+/// How many times a failed download is retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryBudget {
+  /// Retries in a row without downloading [`RETRY_RESET_PROGRESS`].
+  pub max_retries: u32,
+  /// Retries in total, however much they downloaded.
+  pub max_total: Option<u32>,
+  /// Time waited before each retry.
+  pub delay: Duration,
+}
+
+impl RetryBudget {
+  /// Whether another retry is allowed after `attempts` failed attempts in a row
+  /// without progress and `total` failed attempts.
+  fn allows(&self, attempts: u32, total: u32) -> bool {
+    attempts <= self.max_retries && self.max_total.map_or(true, |max| total <= max)
+  }
+}
+
+/// The download has been slower than the minimum speed for a while.
+#[derive(Debug)]
+pub struct SlowDownload;
+
+impl std::fmt::Display for SlowDownload {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "download is too slow")
+  }
+}
+
+impl std::error::Error for SlowDownload {}
+
+/// The download is given up with [`SlowDownload`] if it stays slower than
+/// `bytes_per_sec` for the `grace` period.
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedFloor {
+  pub bytes_per_sec: f64,
+  pub grace: Duration,
+}
+
+/// The URL saved in the redirect file is refused, e.g. because the signed URL
+/// expired or the snapshot was moved.
+#[derive(Debug)]
+pub struct StaleUrl(String);
+
+impl std::fmt::Display for StaleUrl {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "the saved download URL is no longer valid ({})", self.0)
+  }
+}
+
+impl std::error::Error for StaleUrl {}
+
+/// The download URL points to another file than the one partially downloaded.
+#[derive(Debug)]
+pub struct SnapshotChanged;
+
+impl std::fmt::Display for SnapshotChanged {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "the snapshot changed since the download started")
+  }
+}
+
+impl std::error::Error for SnapshotChanged {}
+
+/// File next to the redirect file recording the size of the whole download,
+/// so a resumed download can tell if the file on the server changed.
+pub fn size_record_path(redirect_path: &Path) -> PathBuf {
+  redirect_path.with_extension("size")
+}
+
+/// File next to the redirect file recording the ETag of the download, if the
+/// server sends one.
+pub fn etag_record_path(redirect_path: &Path) -> PathBuf {
+  redirect_path.with_extension("etag")
+}
+
+/// File next to the redirect file recording when the URL in it was resolved.
+fn resolved_record_path(redirect_path: &Path) -> PathBuf {
+  redirect_path.with_extension("resolved")
+}
+
+/// Files next to the redirect file recording what is downloaded.
+pub fn record_paths(redirect_path: &Path) -> [PathBuf; 3] {
+  [
+    size_record_path(redirect_path),
+    etag_record_path(redirect_path),
+    resolved_record_path(redirect_path),
+  ]
+}
+
+/// Saves the URL the download continues from in the redirect file, with the
+/// time it's resolved if it changed.
+pub fn save_redirect(redirect_path: &Path, url: &str) -> std::io::Result<()> {
+  if std::fs::read_to_string(redirect_path).is_ok_and(|saved| saved == url) {
+    return Ok(());
+  }
+  std::fs::write(redirect_path, url)?;
+  std::fs::write(resolved_record_path(redirect_path), Utc::now().to_rfc3339())
+}
+
+/// Removes the redirect file if its URL was resolved more than `ttl` ago, as
+/// signed URLs expire and snapshots are replaced. Returns whether it did.
+pub fn expire_redirect(redirect_path: &Path, ttl: Duration) -> Result<bool> {
+  if !redirect_path.try_exists().unwrap_or(false) {
+    return Ok(false);
+  }
+  let resolved = match std::fs::read_to_string(resolved_record_path(redirect_path)) {
+    Ok(time) => DateTime::parse_from_rfc3339(time.trim())?.with_timezone(&Utc),
+    // Saved by an older version, which didn't record it
+    Err(_) => std::fs::metadata(redirect_path)?.modified()?.into(),
+  };
+  // The clock may have been set back since
+  let age = (Utc::now() - resolved).to_std().unwrap_or_default();
+  if age <= ttl {
+    return Ok(false);
+  }
+  std::fs::remove_file(redirect_path)?;
+  let _ = std::fs::remove_file(resolved_record_path(redirect_path));
+  Ok(true)
+}
+
+/// How a file of `size` with the `etag` differs from the one partially
+/// downloaded, by the size and the ETag recorded next to the redirect file.
+fn identity_change(redirect_path: &Path, size: Option<u64>, etag: Option<&str>) -> Option<String> {
+  let recorded_size = std::fs::read_to_string(size_record_path(redirect_path))
+    .ok()
+    .and_then(|s| s.trim().parse::<u64>().ok());
+  if let (Some(recorded), Some(size)) = (recorded_size, size) {
+    if recorded != size {
+      return Some(format!("from {recorded} to {size} bytes"));
+    }
+  }
+  let recorded_etag = std::fs::read_to_string(etag_record_path(redirect_path)).ok();
+  match (recorded_etag, etag) {
+    (Some(recorded), Some(etag)) if recorded != etag => {
+      Some(format!("from ETag {recorded} to {etag}"))
+    }
+    _ => None,
+  }
+}
+
+/// Parses `Content-Range: bytes <start>-<end>/<total>`, the total may be `*`.
+pub fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+  let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+  let (start, _) = range.split_once('-')?;
+  let total = match total {
+    "*" => None,
+    total => Some(total.parse().ok()?),
+  };
+  Some((start.parse().ok()?, total))
+}
+
+/// Downloads `url` from `source` appending to `file`, until it's slower than
+/// the `floor`, reporting the progress to `reporter`.
+async fn download_file<W: Write + Seek>(
+  source: &dyn SnapshotSource,
+  url: &str,
+  file: &mut W,
+  redirect_path: &Path,
+  buffer_size: usize,
+  floor: Option<SpeedFloor>,
+  reporter: &mut Reporter,
+) -> Result<()> {
   let offset = file.seek(SeekFrom::End(0))?;
 
+  let original_url = url;
   let url = if redirect_path.try_exists().unwrap_or(false) {
     std::fs::read_to_string(redirect_path)?
   } else {
     url.to_string()
   };
 
-  let client = Client::builder()
-    .user_agent(APP_USER_AGENT)
-    .timeout(std::time::Duration::from_secs(30))
-    .build()?;
-  let mut response = client
-    .get(&url)
-    .header("Range", format!("bytes={offset}-"))
-    .send()?;
-
-  let code = response.status();
-  match code {
-    StatusCode::PARTIAL_CONTENT => {}
-    _ if code.is_success() => {
-      anyhow::bail!("expected {}, but got {}", StatusCode::PARTIAL_CONTENT, code);
+  let mut body = match source.open(&Url::parse(&url)?, Some(offset)).await {
+    Ok(body) => body,
+    Err(e) => {
+      let Some(FileStatus {
+        status, message, ..
+      }) = e.downcast_ref::<FileStatus>()
+      else {
+        return Err(e);
+      };
+      return Err(match *status {
+        StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::GONE if url != original_url => {
+          StaleUrl(status.to_string()).into()
+        }
+        status => anyhow!("failed to download from {url}: {status} {message}"),
+      });
     }
-    _ => {
-      let err = read_error_response(response.text()?);
-      anyhow::bail!("failed to download from {url}: {code} {err}");
+  };
+  let expected_size = body.size;
+  let etag = body.etag.take();
+
+  if offset > 0 {
+    if let Some(change) = identity_change(redirect_path, expected_size, etag.as_deref()) {
+      // The saved URL may point to another snapshot now
+      if url != original_url {
+        return Err(StaleUrl(format!("the file there changed {change}")).into());
+      }
+      return Err(
+        ExitError::new(
+          1,
+          format!(
+            "The file on the server changed {change} since the download started. \
+             Delete the partially downloaded file to start over"
+          ),
+        )
+        .into(),
+      );
     }
   }
-  let final_url = response.url().clone();
-
-  std::fs::write(redirect_path, final_url.as_str())?;
-
-  let content_len = response
-    .headers()
-    .get(reqwest::header::CONTENT_LENGTH)
-    .and_then(|ct_len| ct_len.to_str().ok())
-    .and_then(|ct_len| ct_len.parse::<u64>().ok())
-    .unwrap_or(0);
-
-  let total_size = content_len + offset;
+  save_redirect(redirect_path, body.url.as_str())?;
+  if let Some(size) = expected_size {
+    std::fs::write(size_record_path(redirect_path), size.to_string())?;
+  }
+  if let Some(etag) = &etag {
+    std::fs::write(etag_record_path(redirect_path), etag)?;
+  }
 
-  const MEASUREMENT_SIZE: usize = 500;
+  let total_size = expected_size.unwrap_or(offset);
 
-  let mut last_reported_progress: Option<f64> = None;
-  let start = Instant::now();
-  let mut measurements = VecDeque::with_capacity(MEASUREMENT_SIZE);
+  let mut speed_meter = SpeedMeter::new(SPEED_WINDOW, Duration::from_secs(1));
   let mut just_downloaded = 0;
+  let mut slow_since: Option<Instant> = None;
 
-  let mut buffer = [0; 16 * 1024];
+  let mut writer = BufWriter::with_capacity(buffer_size, file);
   loop {
-    match response.read(&mut buffer) {
-      Ok(0) => {
-        break;
-      }
-      Ok(bytes_read) => {
-        file.write_all(&buffer[..bytes_read])?;
-        just_downloaded += bytes_read as u64;
-        let downloaded = offset + just_downloaded;
-
-        let elapsed = start.elapsed().as_secs_f64();
-        let speed = if elapsed > 0.0 {
-          just_downloaded as f64 / elapsed
-        } else {
-          0.0
-        };
-        measurements.push_back(speed);
-        if measurements.len() > MEASUREMENT_SIZE {
-          measurements.pop_front();
-        }
-        let avg_speed = measurements.iter().sum::<f64>() / measurements.len() as f64;
-        let eta = if avg_speed > 1.0 && measurements.len() > (MEASUREMENT_SIZE / 2) {
-          Eta::Seconds((total_size as f64 - downloaded as f64) / avg_speed)
-        } else {
-          Eta::Unknown
-        };
-
-        let progress = downloaded as f64 / total_size as f64;
-        if last_reported_progress.is_none()
-          || last_reported_progress.is_some_and(|x| progress > x + 0.001)
-        {
-          println!(
-            "Downloading... {:.2}% ({:.2} MB/{:.2} MB) ETA: {}",
-            progress * 100.0,
-            downloaded as f64 / 1_024_000.00,
-            total_size as f64 / 1_024_000.00,
-            eta
-          );
-          last_reported_progress = Some(progress);
+    control::checkpoint().await?;
+    let chunk = tokio::time::timeout(READ_TIMEOUT, body.chunks.next())
+      .await
+      .map_err(|_| anyhow!("no data received for {} sec", READ_TIMEOUT.as_secs()))?;
+    let Some(chunk) = chunk.transpose()? else {
+      break;
+    };
+    writer.write_all(&chunk)?;
+    failpoints::on_received(chunk.len() as u64)?;
+    just_downloaded += chunk.len() as u64;
+    let downloaded = offset + just_downloaded;
+
+    let now = Instant::now();
+    speed_meter.record(now, chunk.len() as u64);
+    let measured_speed = speed_meter.speed(now);
+    match (floor, measured_speed) {
+      (Some(floor), Some(speed)) if speed < floor.bytes_per_sec => {
+        let since = *slow_since.get_or_insert(now);
+        if now.duration_since(since) >= floor.grace {
+          writer.flush()?;
+          return Err(SlowDownload.into());
         }
       }
-      Err(e) => {
-        return Err(anyhow!(e));
-      }
+      _ => slow_since = None,
     }
+    reporter.update(Progress {
+      stage: Stage::Download,
+      done: downloaded,
+      total: Some(total_size),
+      bytes_per_sec: Some(measured_speed.unwrap_or(0.0)),
+    });
+  }
+
+  writer.flush()?;
+  // The connection may be closed before the whole body is received
+  let downloaded = offset + just_downloaded;
+  if let Some(size) = expected_size {
+    anyhow::ensure!(
+      downloaded == size,
+      "received {downloaded} of {size} bytes before the connection was closed"
+    );
+  }
+  reporter.finish();
+  status::print_line("Download finished");
+
+  Ok(())
+}
+
+/// Compares the last few megabytes of the partially downloaded `path` with the
+/// same range of `url` from `source`, to catch a prefix corrupted by a crash or
+/// a bad disk (or left from another snapshot) before appending to it.
+pub async fn check_partial_download(
+  source: &dyn SnapshotSource,
+  url: &str,
+  path: &Path,
+) -> Result<bool> {
+  let len = std::fs::metadata(path)?.len();
+  if len == 0 {
+    return Ok(true);
   }
+  let start = len.saturating_sub(RESUME_CHECK_SIZE);
+  let url = Url::parse(url)?;
+  url_policy::check(&url)?;
+  let mut local = Vec::with_capacity((len - start) as usize);
+  let mut file = std::fs::File::open(path)?;
+  file.seek(SeekFrom::Start(start))?;
+  file.read_to_end(&mut local)?;
 
-  println!("Download finished");
+  let remote = source.fetch_range(&url, start, len - start).await?;
+  Ok(remote == local)
+}
+
+/// Resolves `url` again: its final URL after the redirects, the size and the
+/// ETag of the file.
+pub async fn resolve_object(url: &str) -> Result<(Url, Option<u64>, Option<String>)> {
+  url_policy::check(&Url::parse(url)?)?;
+  let client = transport::builder()
+    .connect_timeout(CONNECT_TIMEOUT)
+    .timeout(Duration::from_secs(60))
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let response = client
+    .get(url)
+    .header("Range", "bytes=0-0")
+    .send_traced()
+    .await?;
+  let code = response.status();
+  anyhow::ensure!(code.is_success(), "failed to resolve {url}: {code}");
+  let header = |name| {
+    response
+      .headers()
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .map(str::to_string)
+  };
+  let size = match header(reqwest::header::CONTENT_RANGE) {
+    Some(range) => parse_content_range(&range).and_then(|(_, total)| total),
+    None => header(reqwest::header::CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+  };
+  let etag = header(reqwest::header::ETAG);
+  Ok((response.url().clone(), size, etag))
+}
+
+/// Whether a file of `size` with the `etag` is the one partially downloaded.
+fn same_object(redirect_path: &Path, size: Option<u64>, etag: Option<&str>) -> bool {
+  size.is_some()
+    && size_record_path(redirect_path).exists()
+    && identity_change(redirect_path, size, etag).is_none()
+}
 
+/// Replaces the stale URL in the redirect file with where `url` points now,
+/// if it's the same file. Fails with [`SnapshotChanged`] otherwise.
+async fn follow_rotation(url: &str, redirect_path: &Path, stale: StaleUrl) -> Result<()> {
+  status::print_line(format_args!(
+    "Download error: {stale}, resolving {url} again"
+  ));
+  let (new_url, size, etag) = resolve_object(url).await?;
+  if !same_object(redirect_path, size, etag.as_deref()) {
+    return Err(SnapshotChanged.into());
+  }
+  let saved = std::fs::read_to_string(redirect_path)?;
+  anyhow::ensure!(
+    saved != new_url.as_str(),
+    "{stale}, and {url} still points to it"
+  );
+  save_redirect(redirect_path, new_url.as_str())?;
+  status::print_line(format_args!(
+    "The snapshot moved to {new_url}, resuming from there"
+  ));
   Ok(())
 }
 
-pub(crate) fn download_with_retries<W: Write + Seek>(
+/// Downloads `url` into `file`, resuming after failures, and reports the
+/// progress to `reporter` (e.g. a callback of the code embedding it).
+pub async fn download_with_retries<W: Write + Seek>(
+  source: &dyn SnapshotSource,
   url: &str,
   file: &mut W,
   redirect_path: &Path,
-  max_retries: u32,
-  retry_delay: std::time::Duration,
+  retries: RetryBudget,
+  buffer_size: usize,
+  floor: Option<SpeedFloor>,
+  reporter: &mut Reporter,
 ) -> Result<()> {
-  let mut attempts = 0;
+  let RetryBudget {
+    max_retries, delay, ..
+  } = retries;
+  let (mut attempts, mut total) = (0, 0);
 
   loop {
-    attempts += 1;
-    match download_file(url, file, redirect_path) {
+    let before = file.seek(SeekFrom::End(0))?;
+    let result = download_file(
+      source,
+      url,
+      file,
+      redirect_path,
+      buffer_size,
+      floor,
+      reporter,
+    )
+    .await;
+    // Whatever the caller prints next goes below the progress
+    if result.is_err() {
+      status::end();
+    }
+    total += 1;
+    // A flaky connection making steady progress isn't given up
+    let progress = file.seek(SeekFrom::End(0))?.saturating_sub(before);
+    attempts = if progress >= RETRY_RESET_PROGRESS {
+      1
+    } else {
+      attempts + 1
+    };
+    // Following a rotated URL is free, failing to is a failed attempt
+    let result = match result {
+      Err(e) if e.is::<StaleUrl>() => {
+        let stale = e.downcast::<StaleUrl>()?;
+        match follow_rotation(url, redirect_path, stale).await {
+          Ok(()) => {
+            attempts -= 1;
+            total -= 1;
+            continue;
+          }
+          Err(e) => Err(e),
+        }
+      }
+      result => result,
+    };
+    match result {
       Ok(()) => return Ok(()),
-      Err(e) if attempts <= max_retries => {
-        println!("Download error: {e}. Attempt {attempts} / {max_retries}",);
-        std::thread::sleep(retry_delay);
+      Err(e)
+        if ExitError::find(&e).is_some() || e.is::<SlowDownload>() || e.is::<SnapshotChanged>() =>
+      {
+        return Err(e)
+      }
+      Err(e) if retries.allows(attempts, total) => {
+        status::print_line(format_args!(
+          "Download error: {e}. Attempt {attempts} / {max_retries}"
+        ));
+        events::emit(Event::Retry {
+          attempt: attempts,
+          max_retries,
+          delay_secs: delay.as_secs(),
+          error: format!("{e:#}"),
+        });
+        tokio::time::sleep(delay).await;
       }
       Err(e) => return Err(anyhow!(e)),
     }
@@ -146,51 +485,106 @@ mod tests {
 
   use rand::{Rng, SeedableRng};
 
+  use crate::progress::Reporter;
+
+  fn quiet() -> Reporter {
+    Reporter::new(vec![])
+  }
+
+  fn http() -> crate::source::HttpSource {
+    crate::source::HttpSource::new().unwrap()
+  }
+
   #[test]
-  fn rejects_not_206() {
-    let mut server = mockito::Server::new();
-    let mock = server.mock("GET", "/").with_status(200).create();
+  fn retry_budget() {
+    let budget = super::RetryBudget {
+      max_retries: 3,
+      max_total: Some(20),
+      delay: time::Duration::ZERO,
+    };
+    assert!(budget.allows(3, 15));
+    assert!(!budget.allows(4, 4));
+    // progress resets the attempts in a row, but not the total
+    assert!(!budget.allows(1, 21));
+    let unlimited = super::RetryBudget {
+      max_total: None,
+      ..budget
+    };
+    assert!(unlimited.allows(1, 1000));
+  }
+
+  #[tokio::test]
+  async fn rejects_not_206() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/")
+      .with_status(200)
+      .create_async()
+      .await;
 
     let tmpdir = tempfile::tempdir().unwrap();
     let redirect_path = tmpdir.path().join("redirect.txt");
     let mut file = tempfile::tempfile().unwrap();
 
-    let result = super::download_file(&server.url(), &mut file, &redirect_path);
+    let result = super::download_file(
+      &http(),
+      &server.url(),
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await;
     let err = result.unwrap_err();
     assert_eq!(
       err.to_string(),
       "expected 206 Partial Content, but got 200 OK"
     );
 
-    mock.assert();
+    mock.assert_async().await;
   }
 
-  #[test]
-  fn fails_when_server_fails() {
-    let mut server = mockito::Server::new();
-    let mock = server.mock("GET", "/").with_status(500).create();
+  #[tokio::test]
+  async fn fails_when_server_fails() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/")
+      .with_status(500)
+      .create_async()
+      .await;
 
     let tmpdir = tempfile::tempdir().unwrap();
     let redirect_path = tmpdir.path().join("redirect.txt");
     let mut file = tempfile::tempfile().unwrap();
 
-    let result = super::download_file(&server.url(), &mut file, &redirect_path);
+    let result = super::download_file(
+      &http(),
+      &server.url(),
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await;
     let err = result.unwrap_err();
     assert!(err.to_string().contains("failed to download from"));
 
-    mock.assert();
+    mock.assert_async().await;
   }
 
-  #[test]
-  fn downloads_file() {
+  #[tokio::test]
+  async fn downloads_file() {
     let binary = b"1234567890";
 
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
     let mock = server
       .mock("GET", "/file")
       .with_status(206)
       .with_body(binary)
-      .create();
+      .create_async()
+      .await;
 
     let tmpdir = tempfile::tempdir().unwrap();
     let mut file = tempfile::tempfile().unwrap();
@@ -198,7 +592,17 @@ mod tests {
 
     let url = server.url() + "/file";
 
-    super::download_file(&url, &mut file, &redirect_path).unwrap();
+    super::download_file(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap();
     file.seek(std::io::SeekFrom::Start(0)).unwrap();
     let content = file.bytes().collect::<Result<Vec<u8>, _>>().unwrap();
     assert_eq!(content, binary);
@@ -206,26 +610,28 @@ mod tests {
     let redirect_url = fs::read_to_string(redirect_path).unwrap();
     assert_eq!(redirect_url, url);
 
-    mock.assert();
+    mock.assert_async().await;
   }
 
-  #[test]
-  fn follows_redirect_and_persists_it() {
+  #[tokio::test]
+  async fn follows_redirect_and_persists_it() {
     let binary = b"1234567890";
 
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
     let redirected_url = server.url() + "/redirected";
     let mock_redirect = server
       .mock("GET", "/file")
       .with_status(301)
       .with_header("location", &redirected_url)
-      .create();
+      .create_async()
+      .await;
 
     let mock = server
       .mock("GET", "/redirected")
       .with_status(206)
       .with_body(binary)
-      .create();
+      .create_async()
+      .await;
 
     let tmpdir = tempfile::tempdir().unwrap();
     let mut file = tempfile::tempfile().unwrap();
@@ -233,7 +639,17 @@ mod tests {
 
     let url = server.url() + "/file";
 
-    super::download_file(&url, &mut file, &redirect_path).unwrap();
+    super::download_file(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap();
     file.seek(std::io::SeekFrom::Start(0)).unwrap();
     let content = file.bytes().collect::<Result<Vec<u8>, _>>().unwrap();
     assert_eq!(content, binary);
@@ -241,13 +657,334 @@ mod tests {
     let redirect_url = fs::read_to_string(redirect_path).unwrap();
     assert_eq!(redirect_url, redirected_url);
 
-    mock_redirect.assert();
-    mock.assert();
+    mock_redirect.assert_async().await;
+    mock.assert_async().await;
+  }
+
+  /// Mocks a server where the saved URL `/expired` is refused, and `/file`
+  /// has 10 bytes with the `etag`.
+  async fn rotated_server(etag: &str) -> (mockito::ServerGuard, Vec<mockito::Mock>) {
+    let mut server = mockito::Server::new_async().await;
+    let mocks = vec![
+      server
+        .mock("GET", "/expired")
+        .with_status(403)
+        .create_async()
+        .await,
+      server
+        .mock("GET", "/file")
+        .match_header("Range", "bytes=0-0")
+        .with_status(206)
+        .with_header("Content-Range", "bytes 0-0/10")
+        .with_header("ETag", etag)
+        .with_body("1")
+        .create_async()
+        .await,
+    ];
+    (server, mocks)
+  }
+
+  fn partial_download(
+    server: &mockito::ServerGuard,
+    dir: &std::path::Path,
+  ) -> (fs::File, std::path::PathBuf) {
+    let mut file = tempfile::tempfile().unwrap();
+    std::io::Write::write_all(&mut file, b"12345").unwrap();
+    let redirect_path = dir.join("redirect.txt");
+    fs::write(&redirect_path, server.url() + "/expired").unwrap();
+    fs::write(super::size_record_path(&redirect_path), "10").unwrap();
+    fs::write(super::etag_record_path(&redirect_path), "\"abc\"").unwrap();
+    (file, redirect_path)
+  }
+
+  const NO_RETRIES: super::RetryBudget = super::RetryBudget {
+    max_retries: 0,
+    max_total: None,
+    delay: time::Duration::from_millis(1),
+  };
+
+  #[tokio::test]
+  async fn resumes_after_url_rotation() {
+    let (mut server, mocks) = rotated_server("\"abc\"").await;
+    let rest = server
+      .mock("GET", "/file")
+      .match_header("Range", "bytes=5-")
+      .with_status(206)
+      .with_header("Content-Range", "bytes 5-9/10")
+      .with_body("67890")
+      .create_async()
+      .await;
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (mut file, redirect_path) = partial_download(&server, tmpdir.path());
+
+    let url = server.url() + "/file";
+    super::download_with_retries(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      NO_RETRIES,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap();
+    file.seek(std::io::SeekFrom::Start(0)).unwrap();
+    let content = file.bytes().collect::<Result<Vec<u8>, _>>().unwrap();
+    assert_eq!(content, b"1234567890");
+    assert_eq!(fs::read_to_string(&redirect_path).unwrap(), url);
+    for mock in mocks.iter().chain([&rest]) {
+      mock.assert_async().await;
+    }
+  }
+
+  #[tokio::test]
+  async fn detects_another_snapshot_after_url_rotation() {
+    let (server, _mocks) = rotated_server("\"def\"").await;
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (mut file, redirect_path) = partial_download(&server, tmpdir.path());
+
+    let url = server.url() + "/file";
+    let err = super::download_with_retries(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      NO_RETRIES,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.is::<super::SnapshotChanged>());
+  }
+
+  #[tokio::test]
+  async fn retries_failing_to_follow_url_rotation() {
+    let mut server = mockito::Server::new_async().await;
+    let expired = server
+      .mock("GET", "/expired")
+      .with_status(403)
+      .expect(3)
+      .create_async()
+      .await;
+    let resolve = server
+      .mock("GET", "/file")
+      .with_status(500)
+      .expect(3)
+      .create_async()
+      .await;
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (mut file, redirect_path) = partial_download(&server, tmpdir.path());
+
+    let url = server.url() + "/file";
+    let err = super::download_with_retries(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      super::RetryBudget {
+        max_retries: 2,
+        ..NO_RETRIES
+      },
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("failed to resolve"), "{err}");
+    expired.assert_async().await;
+    resolve.assert_async().await;
+  }
+
+  #[test]
+  fn parses_content_range() {
+    assert_eq!(
+      super::parse_content_range("bytes 100-199/1000"),
+      Some((100, Some(1000)))
+    );
+    assert_eq!(super::parse_content_range("bytes 0-9/*"), Some((0, None)));
+    assert_eq!(super::parse_content_range("items 0-9/10"), None);
+  }
+
+  #[tokio::test]
+  async fn rejects_short_body() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/file")
+      .with_status(206)
+      .with_header("Content-Range", "bytes 0-9/20")
+      .with_body(b"1234567890")
+      .create_async()
+      .await;
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let redirect_path = tmpdir.path().join("redirect.txt");
+    let mut file = tempfile::tempfile().unwrap();
+
+    let url = server.url() + "/file";
+    let err = super::download_file(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "received 10 of 20 bytes before the connection was closed"
+    );
+    let recorded = fs::read_to_string(super::size_record_path(&redirect_path)).unwrap();
+    assert_eq!(recorded, "20");
+
+    mock.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn rejects_changed_size_on_resume() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/file")
+      .match_header("Range", "bytes=4-")
+      .with_status(206)
+      .with_header("Content-Range", "bytes 4-29/30")
+      .with_body(vec![0u8; 26])
+      .create_async()
+      .await;
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let redirect_path = tmpdir.path().join("redirect.txt");
+    fs::write(super::size_record_path(&redirect_path), "20").unwrap();
+    let mut file = tempfile::tempfile().unwrap();
+    std::io::Write::write_all(&mut file, b"1234").unwrap();
+
+    let url = server.url() + "/file";
+    let err = super::download_file(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.is::<crate::exit_error::ExitError>());
+
+    mock.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn ignores_saved_url_of_another_file() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/saved")
+      .match_header("Range", "bytes=4-")
+      .with_status(206)
+      .with_header("Content-Range", "bytes 4-29/30")
+      .with_header("ETag", "\"new\"")
+      .with_body(vec![0u8; 26])
+      .create_async()
+      .await;
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let redirect_path = tmpdir.path().join("redirect.txt");
+    fs::write(&redirect_path, server.url() + "/saved").unwrap();
+    fs::write(super::size_record_path(&redirect_path), "30").unwrap();
+    fs::write(super::etag_record_path(&redirect_path), "\"old\"").unwrap();
+    let mut file = tempfile::tempfile().unwrap();
+    std::io::Write::write_all(&mut file, b"1234").unwrap();
+
+    let url = server.url() + "/file";
+    let err = super::download_file(
+      &http(),
+      &url,
+      &mut file,
+      &redirect_path,
+      1024,
+      None,
+      &mut quiet(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the saved download URL is no longer valid \
+       (the file there changed from ETag \"old\" to \"new\")"
+    );
+    // Nothing is appended
+    assert_eq!(file.seek(std::io::SeekFrom::End(0)).unwrap(), 4);
+
+    mock.assert_async().await;
   }
 
   #[test]
-  fn retries_after_failure() {
-    let mut server = mockito::Server::new();
+  fn expiring_saved_url() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let redirect_path = tmpdir.path().join("state.url");
+    let ttl = time::Duration::from_secs(3600);
+    assert!(!super::expire_redirect(&redirect_path, ttl).unwrap());
+
+    super::save_redirect(
+      &redirect_path,
+      "https://quicksync.spacemesh.network/state.zst",
+    )
+    .unwrap();
+    assert!(!super::expire_redirect(&redirect_path, ttl).unwrap());
+
+    let resolved = chrono::Utc::now() - chrono::Duration::hours(2);
+    fs::write(
+      super::resolved_record_path(&redirect_path),
+      resolved.to_rfc3339(),
+    )
+    .unwrap();
+    // Saving the same URL keeps the time it was resolved
+    super::save_redirect(
+      &redirect_path,
+      "https://quicksync.spacemesh.network/state.zst",
+    )
+    .unwrap();
+    assert!(super::expire_redirect(&redirect_path, ttl).unwrap());
+    assert!(!redirect_path.exists());
+    assert!(!super::resolved_record_path(&redirect_path).exists());
+  }
+
+  #[tokio::test]
+  async fn checks_partial_download() {
+    let binary = b"1234567890";
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/file")
+      .match_header("Range", "bytes=0-3")
+      .with_status(206)
+      .with_body(&binary[..4])
+      .expect(2)
+      .create_async()
+      .await;
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("state.download");
+    let url = server.url() + "/file";
+
+    fs::write(&path, b"1234").unwrap();
+    assert!(super::check_partial_download(&url, &path).await.unwrap());
+    fs::write(&path, b"1204").unwrap();
+    assert!(!super::check_partial_download(&url, &path).await.unwrap());
+
+    mock.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn retries_after_failure() {
+    let mut server = mockito::Server::new_async().await;
 
     let mut rng = rand::rngs::StdRng::seed_from_u64(11);
     let binary: Vec<u8> = iter::repeat_with(|| rng.gen()).take(2_000).collect();
@@ -258,7 +995,8 @@ mod tests {
       .mock("GET", "/file")
       .with_status(301)
       .with_header("location", &(server.url() + "/redirected"))
-      .create();
+      .create_async()
+      .await;
 
     let mock = server
       .mock("GET", "/redirected")
@@ -270,8 +1008,9 @@ mod tests {
 
         binary_clone[start..].to_vec()
       })
-      .create()
-      .expect(2);
+      .expect(2)
+      .create_async()
+      .await;
 
     let tmpdir = tempfile::tempdir().unwrap();
     let redirect_path = tmpdir.path().join("redirect.txt");
@@ -319,18 +1058,35 @@ mod tests {
     };
 
     let url = server.url() + "/file";
+    // Reported through a callback, as code embedding the download would
+    let reported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let callback = {
+      let reported = reported.clone();
+      move |p: &crate::progress::Progress| reported.lock().unwrap().push(p.done)
+    };
     super::download_with_retries(
+      &http(),
       &url,
       &mut file,
       &redirect_path,
-      1,
-      time::Duration::from_millis(1),
+      super::RetryBudget {
+        max_retries: 1,
+        max_total: None,
+        delay: time::Duration::from_millis(1),
+      },
+      1024,
+      None,
+      &mut Reporter::new(vec![Box::new(callback)]),
     )
+    .await
     .unwrap();
 
-    mock_redirect.assert();
-    mock.assert();
+    mock_redirect.assert_async().await;
+    mock.assert_async().await;
 
     assert_eq!(file.bytes, *binary);
+    let reported = reported.lock().unwrap();
+    assert!(!reported.is_empty());
+    assert!(reported.windows(2).all(|w| w[0] <= w[1]));
   }
 }
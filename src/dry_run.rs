@@ -0,0 +1,272 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::block_hashes;
+use crate::download;
+use crate::exit_error::ExitError;
+use crate::history::HISTORY_FILE_NAME;
+use crate::http_trace::redact_url;
+use crate::journal::{self, Step};
+use crate::leftovers;
+use crate::patch::{KEPT_ARCHIVE, KEPT_RECORD};
+use crate::preflight;
+use crate::seekable;
+use crate::sql::get_last_layer_from_db;
+use crate::sync_marker;
+use crate::utils::{backup_path_except, resolve_snapshot, Snapshot};
+use crate::variant::Variant;
+
+const MB: f64 = 1_024_000.00;
+
+/// What `download` does to a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+  /// Created while downloading, deleted once done.
+  Temporary,
+  Created,
+  /// Rewritten or appended to.
+  Updated,
+  /// Renamed to the given backup.
+  BackedUp(PathBuf),
+  Replaced,
+}
+
+/// The files in node-data and the temp dir `download` creates, backs up and
+/// replaces, in order.
+pub fn planned_changes(
+  node_data: &Path,
+  work_dir: &Path,
+  keep_archive: bool,
+) -> Vec<(PathBuf, Change)> {
+  let temp_file = leftovers::download_path(work_dir);
+  let unpacked = work_dir.join("state_downloaded.sql");
+  let redirect = node_data.join("state.url");
+  let mut changes: Vec<(PathBuf, Change)> = [
+    work_dir.join(leftovers::MANIFEST),
+    node_data.join(journal::FILE_NAME),
+    block_hashes::record_path(&temp_file),
+    temp_file,
+    work_dir.join("state.zst"),
+    seekable::progress_path(&unpacked),
+    unpacked,
+    redirect.clone(),
+  ]
+  .into_iter()
+  .chain(download::record_paths(&redirect))
+  .map(|path| (path, Change::Temporary))
+  .collect();
+
+  let state_sql = node_data.join("state.sql");
+  let mut backups = Vec::new();
+  for path in [state_sql.clone(), node_data.join("state.sql-wal")] {
+    if path.exists() {
+      let backup = backup_path_except(&path, &backups);
+      backups.push(backup.clone());
+      changes.push((path, Change::BackedUp(backup)));
+    }
+  }
+  let created_or = |path: &Path, change: Change| match path.exists() {
+    true => change,
+    false => Change::Created,
+  };
+  changes.push((state_sql.clone(), created_or(&state_sql, Change::Replaced)));
+  let mut kept = vec![sync_marker::FILE_NAME, HISTORY_FILE_NAME];
+  if keep_archive {
+    kept.extend([KEPT_ARCHIVE, KEPT_RECORD]);
+  }
+  for name in kept {
+    let path = node_data.join(name);
+    let change = created_or(&path, Change::Updated);
+    changes.push((path, change));
+  }
+  changes
+}
+
+/// Disk space `download` needs in `dir`, next to what is free there.
+struct Space<'a> {
+  dir: &'a Path,
+  needed: Option<u64>,
+  what: &'static str,
+}
+
+/// Prints what `download` would do in `node_data`, resuming after the
+/// `resume_from` step of a previous run: the snapshot, its size and layer, the
+/// disk space and the files it changes. Fails with exit code 2 if there isn't
+/// enough disk space.
+pub async fn run(
+  node_data: &Path,
+  work_dir: &Path,
+  download_url: &Url,
+  version: &str,
+  variant: Variant,
+  keep_archive: bool,
+  resume_from: Option<Step>,
+) -> Result<()> {
+  let Snapshot { url, layer } = resolve_snapshot(download_url, version, variant)
+    .await
+    .context("resolving the latest snapshot")?;
+  let (final_url, size, _) = download::resolve_object(url.as_str())
+    .await
+    .context("resolving the archive")?;
+
+  println!(
+    "Dry run for {}, nothing is downloaded or changed",
+    node_data.display()
+  );
+  println!("  go-spacemesh version: {version}");
+  println!("  Snapshot: {url}");
+  if final_url != url {
+    println!("  Downloaded from: {}", redact_url(&final_url));
+  }
+  println!("  Layer: {layer}");
+  let mb = |bytes: u64| format!("{:.2} MB", bytes as f64 / MB);
+  println!("  Archive size: {}", size.map_or("unknown".to_string(), mb));
+  let state_sql = node_data.join("state.sql");
+  let local_size = std::fs::metadata(&state_sql).map(|m| m.len()).ok();
+  if local_size.is_some() {
+    match get_last_layer_from_db(&state_sql) {
+      Ok(local) if i64::from(local) >= layer as i64 => println!(
+        "  Local database: layer {local}, not older than the snapshot. \
+         The download stops before replacing it unless --force is passed"
+      ),
+      Ok(local) => println!("  Local database: layer {local}"),
+      Err(e) => println!("  Local database: cannot read its layer: {e:#}"),
+    }
+  }
+  let mut partial = 0;
+  if let Some(step) = resume_from {
+    println!("  Resumes a previous run after its last completed step: {step}");
+  } else if let Some(metadata) =
+    leftovers::previous_download(work_dir).and_then(|path| std::fs::metadata(path).ok())
+  {
+    partial = metadata.len();
+    println!("  Resumes the partial download at {}", mb(partial));
+  }
+
+  // The archive and the unpacked database are on disk at the same time. The
+  // database is about the size of the local one, if there is one. Installed
+  // into node-data on another volume, it's copied next to the backup
+  let rest = size.map(|size| size.saturating_sub(partial));
+  let mut spaces = vec![Space {
+    dir: work_dir,
+    needed: rest.map(|rest| rest + local_size.unwrap_or(0)),
+    what: match local_size {
+      Some(_) => " (the archive and a database as big as the local one)",
+      None => " for the archive, plus the unpacked database",
+    },
+  }];
+  if !preflight::same_volume(work_dir, node_data) {
+    spaces.push(Space {
+      dir: node_data,
+      needed: local_size,
+      what: " for the installed database",
+    });
+  }
+  for space in &spaces {
+    println!(
+      "  Free space in {}: {}, needed: {}{}",
+      space.dir.display(),
+      preflight::free_space(space.dir).map_or("unknown".to_string(), mb),
+      space.needed.map_or("unknown".to_string(), mb),
+      space.what
+    );
+  }
+
+  println!("Files:");
+  for (path, change) in planned_changes(node_data, work_dir, keep_archive) {
+    let path = path.display();
+    match change {
+      Change::Temporary => println!("  create (temporary) {path}"),
+      Change::Created => println!("  create {path}"),
+      Change::Updated => println!("  update {path}"),
+      Change::BackedUp(backup) => println!("  back up {path} -> {}", backup.display()),
+      Change::Replaced => println!("  replace {path}"),
+    }
+  }
+
+  for space in spaces {
+    let free = preflight::free_space(space.dir);
+    if let (Some(free), Some(needed)) = (free, space.needed) {
+      if free < needed {
+        return Err(
+          ExitError::new(
+            2,
+            format!(
+              "Not enough disk space in {}: {} free, {} needed",
+              space.dir.display(),
+              mb(free),
+              mb(needed)
+            ),
+          )
+          .into(),
+        );
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn planning_backups() {
+    let dir = tempfile::tempdir().unwrap();
+    let node_data = dir.path();
+    for name in ["state.sql", "state.sql-wal", "state.sql.bak"] {
+      std::fs::write(node_data.join(name), "").unwrap();
+    }
+    let changes = planned_changes(node_data, node_data, false);
+    let changed = |change: Change| {
+      changes
+        .iter()
+        .filter(|(_, c)| *c == change)
+        .map(|(path, _)| path.strip_prefix(node_data).unwrap().to_path_buf())
+        .collect::<Vec<_>>()
+    };
+    let backups: Vec<_> = changes
+      .iter()
+      .filter_map(|(path, change)| match change {
+        Change::BackedUp(backup) => Some((path.clone(), backup.clone())),
+        _ => None,
+      })
+      .collect();
+    // The WAL is backed up next, to the path after the database's backup
+    assert_eq!(
+      backups,
+      [
+        (
+          node_data.join("state.sql"),
+          node_data.join("state.sql.bak.1")
+        ),
+        (
+          node_data.join("state.sql-wal"),
+          node_data.join("state.sql.bak.2")
+        ),
+      ]
+    );
+    assert_eq!(changed(Change::Replaced), [PathBuf::from("state.sql")]);
+    assert_eq!(
+      changed(Change::Created),
+      [
+        PathBuf::from(sync_marker::FILE_NAME),
+        PathBuf::from(HISTORY_FILE_NAME)
+      ]
+    );
+    assert!(changed(Change::Temporary).contains(&PathBuf::from("state.zst")));
+  }
+
+  #[test]
+  fn planning_fresh_node_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let work_dir = dir.path().join("tmp");
+    let changes = planned_changes(dir.path(), &work_dir, true);
+    assert!(!changes
+      .iter()
+      .any(|(_, change)| matches!(change, Change::BackedUp(_) | Change::Replaced)));
+    assert!(changes.contains(&(dir.path().join(KEPT_ARCHIVE), Change::Created)));
+    assert!(changes.contains(&(work_dir.join("state.zst"), Change::Temporary)));
+  }
+}
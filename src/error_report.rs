@@ -0,0 +1,333 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::{self, Stage};
+use crate::exit_error::ExitError;
+use crate::http_trace::SendTraced;
+use crate::transport;
+
+/// Endpoint collecting the failure reports sent with `--report-errors`.
+const REPORT_URL: &str = "https://quicksync-reports.spacemesh.network/v1/reports";
+/// Log with the details of failed runs, in the working directory.
+pub const ERROR_LOG: &str = "quicksync-error.log";
+
+/// Paths given on the command line, removed from the failure reports along
+/// with the home, working and temp directories.
+static ROOTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Machine-readable description of a failed run, printed with `--json`.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+  pub code: i32,
+  pub stage: Option<Stage>,
+  /// Whether running again may succeed without changing anything, unknown
+  /// for unexpected errors.
+  pub retryable: Option<bool>,
+  pub message: String,
+  pub hint: Option<&'static str>,
+}
+
+/// Whether a failure with the exit code is retryable, and what to do about it.
+fn remediation(code: i32) -> (bool, Option<&'static str>) {
+  match code {
+    1 => (
+      true,
+      Some("Check the network connection and run again, the download is resumed"),
+    ),
+    2 => (false, Some("Free up disk space in node-data and run again")),
+    3 => (
+      true,
+      Some("Run again, delete state.zst if it keeps failing"),
+    ),
+    4 | 7 => (true, Some("Run again to download the database again")),
+    5 | 8 => (true, Some("Check the network connection and run again")),
+    6 => (
+      false,
+      Some("Check the permissions of node-data and free up disk space"),
+    ),
+    9 | 10 => (false, Some("Check the output of the hook command")),
+    11 | 12 => (
+      true,
+      Some("Check the node service, it may need to be stopped or started manually"),
+    ),
+    13 => (
+      false,
+      Some("Use --force to replace the local database anyway"),
+    ),
+    14 => (true, Some("Run again to download the database again")),
+    15 => (true, None),
+    16 => (
+      false,
+      Some("Fix the permissions, free space or quota of node-data"),
+    ),
+    17 => (false, Some("Move node-data to a local disk or use --force")),
+    18 => (false, Some("Use --allow-host or --allow-insecure-url")),
+    19 => (
+      true,
+      Some("Run again to resume, or raise --stage-timeout or --overall-timeout"),
+    ),
+    20 => (
+      false,
+      Some("Run again to install the downloaded database, or pass --yes"),
+    ),
+    21 => (
+      false,
+      Some("Confirm the new key with the server operator, then remove its pinned line"),
+    ),
+    _ => (false, None),
+  }
+}
+
+/// Whether the failure is retryable and what to do about it, for the errors
+/// whose cause is known: the exit errors and the network errors.
+fn classify(err: &anyhow::Error) -> (Option<bool>, Option<&'static str>) {
+  let known = match ExitError::find(err) {
+    Some(exit) => Some(exit.code),
+    None => err.chain().any(|e| e.is::<reqwest::Error>()).then_some(1),
+  };
+  match known.map(remediation) {
+    Some((retryable, hint)) => (Some(retryable), hint),
+    None => (None, None),
+  }
+}
+
+impl ErrorReport {
+  pub fn new(err: &anyhow::Error) -> Self {
+    let code = ExitError::find(err).map_or(1, |e| e.code);
+    let (retryable, hint) = classify(err);
+    Self {
+      code,
+      stage: events::current_stage(),
+      retryable,
+      message: format!("{err:#}"),
+      hint,
+    }
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).expect("serializing error report")
+  }
+}
+
+/// Anonymized report of a failure, sent with `--report-errors`.
+#[derive(Debug, Serialize)]
+struct FailureReport {
+  quicksync_version: &'static str,
+  os: &'static str,
+  arch: &'static str,
+  code: i32,
+  stage: Option<Stage>,
+  /// The error and its causes, without file paths.
+  errors: Vec<String>,
+}
+
+/// Removes `path` given on the command line (the directory, or the one the
+/// file is in) and what's in it from the failure reports.
+pub fn add_root(path: &Path) {
+  let dir = match path.is_file() {
+    true => path.parent().unwrap_or(path),
+    false => path,
+  };
+  ROOTS.lock().unwrap().push(dir.to_path_buf());
+}
+
+/// The paths removed from the failure reports, the longest first so a
+/// directory inside another one is removed whole.
+fn roots() -> Vec<String> {
+  let mut roots: Vec<PathBuf> = ROOTS.lock().unwrap().clone();
+  roots.extend(
+    ["HOME", "USERPROFILE"]
+      .into_iter()
+      .filter_map(std::env::var_os)
+      .map(PathBuf::from),
+  );
+  roots.extend(std::env::current_dir().ok());
+  roots.push(std::env::temp_dir());
+  let mut roots: Vec<String> = roots
+    .iter()
+    // Not the root of the file system, which would remove any path
+    .filter(|root| root.parent().is_some() && root.as_os_str().len() > 1)
+    .map(|root| root.display().to_string())
+    .collect();
+  roots.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+  roots.dedup();
+  roots
+}
+
+/// Replaces the `roots` (e.g. with the user name) in the message.
+fn anonymize(message: &str, roots: &[String]) -> String {
+  roots.iter().fold(message.to_string(), |message, root| {
+    message.replace(root.as_str(), "<path>")
+  })
+}
+
+impl FailureReport {
+  fn new(err: &anyhow::Error) -> Self {
+    let roots = roots();
+    Self {
+      quicksync_version: env!("CARGO_PKG_VERSION"),
+      os: std::env::consts::OS,
+      arch: std::env::consts::ARCH,
+      code: ExitError::find(err).map_or(1, |e| e.code),
+      stage: events::current_stage(),
+      errors: err
+        .chain()
+        .map(|e| anonymize(&e.to_string(), &roots))
+        .collect(),
+    }
+  }
+}
+
+/// Appends the details of the failure to the error log at `path`.
+pub fn write_log(path: &Path, err: &anyhow::Error) -> std::io::Result<()> {
+  let report = ErrorReport::new(err);
+  let mut log = std::fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)?;
+  writeln!(
+    log,
+    "[{}] quicksync {} ({} {}) failed with code {}",
+    chrono::Utc::now().to_rfc3339(),
+    env!("CARGO_PKG_VERSION"),
+    std::env::consts::OS,
+    std::env::consts::ARCH,
+    report.code
+  )?;
+  let args: Vec<String> = std::env::args().collect();
+  writeln!(log, "Command: {}", args.join(" "))?;
+  if let Some(stage) = report.stage {
+    writeln!(
+      log,
+      "Stage: {}",
+      serde_json::to_string(&stage)?.trim_matches('"')
+    )?;
+  }
+  for (i, cause) in err.chain().enumerate() {
+    writeln!(
+      log,
+      "{}{cause}",
+      if i == 0 { "Error: " } else { "Caused by: " }
+    )?;
+  }
+  writeln!(log)
+}
+
+/// Sends the anonymized failure report to Spacemesh.
+pub async fn upload(err: &anyhow::Error) -> Result<()> {
+  let client = transport::builder()
+    .timeout(Duration::from_secs(10))
+    .build()?;
+  let response = client
+    .post(REPORT_URL)
+    .json(&FailureReport::new(err))
+    .send_traced()
+    .await?;
+  anyhow::ensure!(
+    response.status().is_success(),
+    "the server responded with {}",
+    response.status()
+  );
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{add_root, anonymize, roots, write_log, ErrorReport};
+  use crate::exit_error::ExitError;
+
+  #[test]
+  fn reporting_errors() {
+    let err = anyhow::Error::from(ExitError::new(2, "Cannot unpack archive"));
+    let report = ErrorReport::new(&err);
+    assert_eq!(report.code, 2);
+    assert_eq!(report.retryable, Some(false));
+    assert_eq!(report.message, "Cannot unpack archive");
+    assert_eq!(
+      report.hint,
+      Some("Free up disk space in node-data and run again")
+    );
+    assert!(report.to_json().starts_with(r#"{"code":2,"stage":"#));
+    let err = anyhow::anyhow!("database is locked").context("reading layer");
+    let report = ErrorReport::new(&err);
+    assert_eq!(report.code, 1);
+    assert_eq!(report.message, "reading layer: database is locked");
+    // Not a network error, so nothing is known about it
+    assert_eq!(report.retryable, None);
+    assert_eq!(report.hint, None);
+  }
+
+  #[tokio::test]
+  async fn classifying_network_errors() {
+    let err = reqwest::get("http://127.0.0.1:1/state.zst")
+      .await
+      .unwrap_err();
+    let report = ErrorReport::new(&anyhow::Error::from(err).context("downloading"));
+    assert_eq!(report.code, 1);
+    assert_eq!(report.retryable, Some(true));
+    assert!(report
+      .hint
+      .unwrap()
+      .starts_with("Check the network connection"));
+  }
+
+  #[test]
+  fn anonymizing_paths() {
+    let roots = [
+      "/home/alice/my node".to_string(),
+      "/home/alice".to_string(),
+      r"C:\Users\alice".to_string(),
+      "/data".to_string(),
+    ];
+    let anonymize = |message| anonymize(message, &roots);
+    assert_eq!(
+      anonymize("opening /home/alice/my node/state.sql: permission denied"),
+      "opening <path>/state.sql: permission denied"
+    );
+    assert_eq!(
+      anonymize("cannot find ./go-spacemesh in /home/alice/bin"),
+      "cannot find ./go-spacemesh in <path>/bin"
+    );
+    assert_eq!(
+      anonymize(r"creating temp file: C:\Users\alice\state.download"),
+      r"creating temp file: <path>\state.download"
+    );
+    assert_eq!(
+      anonymize("state file not found: \"/data/state.sql\""),
+      "state file not found: \"<path>/state.sql\""
+    );
+    assert_eq!(
+      anonymize("failed to download from https://quicksync.spacemesh.network/1/state.zst"),
+      "failed to download from https://quicksync.spacemesh.network/1/state.zst"
+    );
+  }
+
+  #[test]
+  fn redacting_the_roots_given() {
+    let dir = tempfile::tempdir().unwrap();
+    let state_sql = dir.path().join("state.sql");
+    std::fs::write(&state_sql, "").unwrap();
+    add_root(&state_sql);
+    let message = format!("opening {}-wal", state_sql.display());
+    assert_eq!(
+      anonymize(&message, &roots()),
+      format!("opening <path>{}state.sql-wal", std::path::MAIN_SEPARATOR)
+    );
+  }
+
+  #[test]
+  fn logging_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("quicksync-error.log");
+    let err = anyhow::anyhow!("database is locked").context("reading layer");
+    write_log(&path, &err).unwrap();
+    write_log(&path, &err).unwrap();
+    let log = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(log.matches("failed with code 1").count(), 2);
+    assert!(log.contains("Error: reading layer\nCaused by: database is locked\n"));
+  }
+}
@@ -0,0 +1,353 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::control::ControlState;
+
+/// Version of the event protocol. It is bumped only on breaking changes:
+/// new event types and new fields may be added without bumping it.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Prefix of the lines with events on stdout.
+const PREFIX: &str = "EVENT ";
+/// Events queued for a slow client of the events socket before it misses some.
+const SOCKET_BUFFER: usize = 1024;
+/// How long the last events may take to reach the clients of the events socket.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The stage the run, or each of its jobs, is in, tracked even if events are
+/// disabled.
+static CURRENT_STAGES: Mutex<Vec<(Option<String>, Stage)>> = Mutex::new(Vec::new());
+/// Events sent to the clients of the events socket, if it's served.
+static SUBSCRIBERS: Mutex<Option<broadcast::Sender<String>>> = Mutex::new(None);
+static FORWARDERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+  CheckUpToDate,
+  Download,
+  VerifyArchive,
+  Unpack,
+  VerifyDb,
+  Install,
+  Restore,
+  /// Waiting for new restore points with `incremental --follow`.
+  Wait,
+  Done,
+}
+
+/// Machine-readable events for frontends (e.g. Smapp), emitted to stdout
+/// as `EVENT <json>` lines next to the human-readable output.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+  /// The first event, tells the protocol version.
+  Hello {
+    protocol: u32,
+    quicksync_version: &'a str,
+  },
+  Stage {
+    stage: Stage,
+  },
+  /// Progress of the current stage. `total` is unknown for some stages.
+  Progress {
+    stage: Stage,
+    done: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_per_sec: Option<f64>,
+  },
+  /// The run was paused, resumed or cancelled through the control channel.
+  Control {
+    state: ControlState,
+  },
+  /// The certificate key of a server pinned with `--tofu` changed.
+  CertificateChanged {
+    host: &'a str,
+    pinned: &'a str,
+    presented: &'a str,
+  },
+  Retry {
+    attempt: u32,
+    max_retries: u32,
+    delay_secs: u64,
+    error: String,
+  },
+  /// The last event, tells the outcome of the run.
+  Result {
+    success: bool,
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+  },
+}
+
+fn hello() -> Event<'static> {
+  Event::Hello {
+    protocol: PROTOCOL_VERSION,
+    quicksync_version: env!("CARGO_PKG_VERSION"),
+  }
+}
+
+/// Turns on emitting events and emits the `hello` event.
+pub fn enable() {
+  ENABLED.store(true, Ordering::Relaxed);
+  emit(hello());
+}
+
+pub fn emit(event: Event) {
+  crate::healthcheck::record(&event);
+  if ENABLED.load(Ordering::Relaxed) {
+    println!("{}", format_event(&event));
+  }
+  if let Some(sender) = SUBSCRIBERS.lock().unwrap().as_ref() {
+    // Fails only if no client is connected
+    let _ = sender.send(to_json(&event));
+  }
+}
+
+tokio::task_local! {
+  /// The job of a run syncing several node-data directories at once.
+  static JOB: String;
+}
+
+/// Runs `job` of a run syncing several node-data directories at once. Its
+/// events are tagged with `name`, and its stage is tracked apart.
+pub async fn in_job<F: Future>(name: String, job: F) -> F::Output {
+  JOB.scope(name, job).await
+}
+
+/// The name of the job running, if the run has several.
+pub fn job() -> Option<String> {
+  JOB.try_with(String::clone).ok()
+}
+
+pub fn stage(stage: Stage) {
+  // The progress of the previous stage stays on its line
+  crate::status::end();
+  let job = job();
+  let mut stages = CURRENT_STAGES.lock().unwrap();
+  stages.retain(|(j, _)| *j != job);
+  stages.push((job, stage));
+  drop(stages);
+  emit(Event::Stage { stage });
+}
+
+/// The last stage the run, or the job running, has entered.
+pub fn current_stage() -> Option<Stage> {
+  let job = job();
+  let stages = CURRENT_STAGES.lock().unwrap();
+  stages
+    .iter()
+    .find(|(j, _)| *j == job)
+    .map(|&(_, stage)| stage)
+}
+
+/// The stage of the run and of each of its jobs, by job.
+pub fn current_stages() -> Vec<(Option<String>, Stage)> {
+  CURRENT_STAGES.lock().unwrap().clone()
+}
+
+fn to_json(event: &Event) -> String {
+  let mut json = serde_json::to_string(event).expect("serializing event");
+  if let Some(job) = job() {
+    // The fields of the event stay in order, the job is last
+    json.pop();
+    json.push_str(&format!(r#","job":{}}}"#, serde_json::Value::from(job)));
+  }
+  json
+}
+
+fn format_event(event: &Event) -> String {
+  format!("{PREFIX}{}", to_json(event))
+}
+
+/// Starts streaming the events as JSON lines to every client connecting to
+/// the unix socket (named pipe on Windows) at `path`, with or without
+/// `--events`. Must be called within the async runtime.
+pub fn serve(path: &str) -> Result<()> {
+  let (sender, _) = broadcast::channel(SOCKET_BUFFER);
+  *SUBSCRIBERS.lock().unwrap() = Some(sender);
+  serve_on(path)
+}
+
+#[cfg(unix)]
+fn serve_on(path: &str) -> Result<()> {
+  // A socket left over by a previous run would fail the bind
+  let _ = std::fs::remove_file(path);
+  let listener = tokio::net::UnixListener::bind(path)
+    .map_err(|e| anyhow::anyhow!("creating events socket {path}: {e}"))?;
+  tokio::spawn(async move {
+    while let Ok((stream, _)) = listener.accept().await {
+      attach(stream);
+    }
+  });
+  Ok(())
+}
+
+#[cfg(windows)]
+fn serve_on(path: &str) -> Result<()> {
+  use tokio::net::windows::named_pipe::ServerOptions;
+
+  let path = path.to_string();
+  let mut server = ServerOptions::new()
+    .first_pipe_instance(true)
+    .create(&path)
+    .map_err(|e| anyhow::anyhow!("creating events pipe {path}: {e}"))?;
+  tokio::spawn(async move {
+    while server.connect().await.is_ok() {
+      let Ok(next) = ServerOptions::new().create(&path) else {
+        break;
+      };
+      attach(std::mem::replace(&mut server, next));
+    }
+  });
+  Ok(())
+}
+
+fn attach<W: AsyncWrite + Unpin + Send + 'static>(writer: W) {
+  let Some(receiver) = SUBSCRIBERS.lock().unwrap().as_ref().map(|s| s.subscribe()) else {
+    return;
+  };
+  let forwarder = tokio::spawn(forward(writer, receiver));
+  FORWARDERS.lock().unwrap().push(forwarder);
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> std::io::Result<()> {
+  writer.write_all(line.as_bytes()).await?;
+  writer.write_all(b"\n").await?;
+  writer.flush().await
+}
+
+/// Sends the events to a client until it disconnects or the run ends, starting
+/// with the `hello` event and the current stage for clients attaching midway.
+async fn forward<W: AsyncWrite + Unpin>(mut writer: W, mut receiver: broadcast::Receiver<String>) {
+  let mut greeting = vec![to_json(&hello())];
+  if let Some(stage) = current_stage() {
+    greeting.push(to_json(&Event::Stage { stage }));
+  }
+  for line in greeting {
+    if write_line(&mut writer, &line).await.is_err() {
+      return;
+    }
+  }
+  loop {
+    match receiver.recv().await {
+      Ok(line) => {
+        if write_line(&mut writer, &line).await.is_err() {
+          return;
+        }
+      }
+      // A slow client misses some progress rather than slowing down the run
+      Err(broadcast::error::RecvError::Lagged(_)) => {}
+      Err(broadcast::error::RecvError::Closed) => break,
+    }
+  }
+  let _ = writer.shutdown().await;
+}
+
+/// Ends the streams of the events socket once the last events reached the clients.
+pub async fn close() {
+  drop(SUBSCRIBERS.lock().unwrap().take());
+  let forwarders = std::mem::take(&mut *FORWARDERS.lock().unwrap());
+  let _ = tokio::time::timeout(CLOSE_TIMEOUT, futures::future::join_all(forwarders)).await;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn event_lines_are_stable() {
+    assert_eq!(
+      format_event(&Event::Stage {
+        stage: Stage::VerifyArchive
+      }),
+      r#"EVENT {"type":"stage","stage":"verify_archive"}"#
+    );
+    assert_eq!(
+      format_event(&Event::Progress {
+        stage: Stage::Download,
+        done: 10,
+        total: Some(100),
+        bytes_per_sec: None,
+      }),
+      r#"EVENT {"type":"progress","stage":"download","done":10,"total":100}"#
+    );
+    assert_eq!(
+      format_event(&Event::Retry {
+        attempt: 1,
+        max_retries: 3,
+        delay_secs: 5,
+        error: "timeout".into(),
+      }),
+      r#"EVENT {"type":"retry","attempt":1,"max_retries":3,"delay_secs":5,"error":"timeout"}"#
+    );
+    assert_eq!(
+      format_event(&Event::Result {
+        success: false,
+        exit_code: 7,
+        error: Some("bad checksum".into()),
+      }),
+      r#"EVENT {"type":"result","success":false,"exit_code":7,"error":"bad checksum"}"#
+    );
+  }
+
+  #[tokio::test]
+  async fn tagging_events_of_jobs() {
+    let line = in_job("/data/node-1".to_string(), async {
+      stage(Stage::Unpack);
+      assert_eq!(current_stage(), Some(Stage::Unpack));
+      format_event(&Event::Stage {
+        stage: Stage::Unpack,
+      })
+    })
+    .await;
+    assert_eq!(
+      line,
+      r#"EVENT {"type":"stage","stage":"unpack","job":"/data/node-1"}"#
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn streaming_events_to_socket() {
+    use tokio::io::AsyncBufReadExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events.sock");
+    serve(path.to_str().unwrap()).unwrap();
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    // wait for the client to be attached
+    while FORWARDERS.lock().unwrap().is_empty() {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    emit(Event::Retry {
+      attempt: 2,
+      max_retries: 3,
+      delay_secs: 1,
+      error: "socket test".into(),
+    });
+    close().await;
+
+    let mut lines = tokio::io::BufReader::new(stream).lines();
+    let mut received = Vec::new();
+    while let Some(line) = lines.next_line().await.unwrap() {
+      received.push(line);
+    }
+    assert!(received[0].starts_with(r#"{"type":"hello","protocol":1"#));
+    assert!(received.contains(
+      &r#"{"type":"retry","attempt":2,"max_retries":3,"delay_secs":1,"error":"socket test"}"#
+        .to_string()
+    ));
+  }
+}
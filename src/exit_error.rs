@@ -0,0 +1,30 @@
+/// An error that terminates the process with a specific exit code.
+/// The exit codes are documented in the README.
+#[derive(Debug)]
+pub struct ExitError {
+  pub code: i32,
+  message: String,
+}
+
+impl ExitError {
+  pub fn new<M: Into<String>>(code: i32, message: M) -> Self {
+    Self {
+      code,
+      message: message.into(),
+    }
+  }
+
+  /// The exit error `err` was caused by, also when it went through another
+  /// error (e.g. refusing a redirect inside reqwest).
+  pub fn find(err: &anyhow::Error) -> Option<&ExitError> {
+    err.chain().find_map(|e| e.downcast_ref::<ExitError>())
+  }
+}
+
+impl std::fmt::Display for ExitError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for ExitError {}
@@ -0,0 +1,367 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::delta::{self, Chunk, ChunkIndex};
+use crate::service::is_db_locked;
+use crate::sql::get_last_layer_from_db;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Metadata entry describing an exported snapshot.
+#[derive(Debug, Serialize)]
+pub struct SnapshotMetadata {
+  pub layer: i32,
+  pub user_version: i64,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub db_size: u64,
+  pub db_md5: String,
+  pub db_sha256: String,
+  pub archive: String,
+  pub archive_size: u64,
+  pub archive_md5: String,
+  pub archive_sha256: String,
+  /// Chunk index of a chunked archive.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub chunk_index: Option<String>,
+}
+
+struct Hashes {
+  md5: md5::Context,
+  sha256: Sha256,
+  len: u64,
+}
+
+impl Hashes {
+  fn new() -> Self {
+    Self {
+      md5: md5::Context::new(),
+      sha256: Sha256::new(),
+      len: 0,
+    }
+  }
+
+  fn update(&mut self, data: &[u8]) {
+    self.md5.consume(data);
+    self.sha256.update(data);
+    self.len += data.len() as u64;
+  }
+
+  fn finish(self) -> (String, String, u64) {
+    (
+      format!("{:x}", self.md5.compute()),
+      hex::encode(self.sha256.finalize()),
+      self.len,
+    )
+  }
+}
+
+/// Hashes everything written through it.
+struct HashingWriter<W: Write> {
+  inner: W,
+  hashes: Hashes,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.hashes.update(&buf[..written]);
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+fn mb(bytes: u64) -> f64 {
+  bytes as f64 / 1_024_000.00
+}
+
+fn write_checksum(path: PathBuf, checksum: &str) -> Result<()> {
+  std::fs::write(&path, checksum).with_context(|| format!("writing {}", path.display()))
+}
+
+type Archive = HashingWriter<BufWriter<File>>;
+
+fn report_progress(done: u64, total: u64) {
+  println!(
+    "Compressing... {:.2}% ({:.2} MB/{:.2} MB)",
+    done as f64 / total.max(1) as f64 * 100.0,
+    mb(done),
+    mb(total)
+  );
+}
+
+/// Compresses the database as a single zstd frame.
+fn compress(
+  mut reader: BufReader<File>,
+  writer: Archive,
+  level: i32,
+  total: u64,
+) -> Result<(Archive, Hashes)> {
+  let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
+  // Unpacking allows windows up to 2^31, a long window improves the ratio a lot
+  encoder.long_distance_matching(true)?;
+  encoder.window_log(27)?;
+
+  let mut db_hashes = Hashes::new();
+  let mut last_report = Instant::now();
+  loop {
+    let chunk = reader.fill_buf()?;
+    if chunk.is_empty() {
+      break;
+    }
+    encoder.write_all(chunk)?;
+    db_hashes.update(chunk);
+    let chunk_len = chunk.len();
+    reader.consume(chunk_len);
+
+    if last_report.elapsed() >= PROGRESS_INTERVAL {
+      last_report = Instant::now();
+      report_progress(db_hashes.len, total);
+    }
+  }
+  let mut writer = encoder.finish()?;
+  writer.flush()?;
+  Ok((writer, db_hashes))
+}
+
+/// Compresses every `chunk_size` bytes of the database as a separate zstd frame.
+fn compress_chunks(
+  reader: BufReader<File>,
+  mut writer: Archive,
+  level: i32,
+  chunk_size: u64,
+  total: u64,
+) -> Result<(Archive, Hashes, Vec<Chunk>)> {
+  anyhow::ensure!(chunk_size > 0, "chunk size must not be zero");
+  anyhow::ensure!(
+    chunk_size <= delta::MAX_CHUNK_SIZE,
+    "chunk size must be at most {} bytes",
+    delta::MAX_CHUNK_SIZE
+  );
+  let mut reader = reader.take(0);
+  let mut db_hashes = Hashes::new();
+  let mut chunks = Vec::new();
+  let mut buf = Vec::with_capacity(chunk_size as usize);
+  let mut last_report = Instant::now();
+  loop {
+    buf.clear();
+    reader.set_limit(chunk_size);
+    reader.read_to_end(&mut buf)?;
+    if buf.is_empty() {
+      break;
+    }
+    let frame = zstd::bulk::compress(&buf, level)?;
+    chunks.push(Chunk {
+      offset: writer.hashes.len,
+      length: frame.len() as u64,
+      sha256: delta::sha256_hex(&buf),
+    });
+    writer.write_all(&frame)?;
+    db_hashes.update(&buf);
+
+    if last_report.elapsed() >= PROGRESS_INTERVAL {
+      last_report = Instant::now();
+      report_progress(db_hashes.len, total);
+    }
+  }
+  writer.flush()?;
+  Ok((writer, db_hashes, chunks))
+}
+
+/// Packages the database as a quicksync snapshot in `out_dir`:
+/// - `{layer}.sql.zst` - the zstd-compressed database,
+/// - `{layer}.sql.md5`, `{layer}.sql.sha256` - checksums of the database,
+/// - `{layer}.sql.zst.md5`, `{layer}.sql.zst.sha256` - checksums of the archive,
+/// - `{layer}.json` - the metadata entry.
+///
+/// With `chunk_size` the archive is made of independent frames, one per chunk
+/// of the database, described in `{layer}.sql.zst.chunks.json`, so nodes
+/// can download only the chunks they don't have.
+pub fn export(
+  db_path: &Path,
+  out_dir: &Path,
+  level: i32,
+  buffer_size: usize,
+  chunk_size: Option<u64>,
+) -> Result<SnapshotMetadata> {
+  anyhow::ensure!(
+    !is_db_locked(db_path)?,
+    "database is in use, stop the node before exporting"
+  );
+  // Fold the WAL into the database, so the file alone holds the whole state
+  let conn = Connection::open(db_path).context("opening database")?;
+  conn
+    .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+    .context("checkpointing WAL")?;
+  let user_version: i64 = conn
+    .query_row("PRAGMA user_version", [], |row| row.get(0))
+    .context("reading user_version")?;
+  conn.close().map_err(|(_, e)| e)?;
+  let layer = get_last_layer_from_db(db_path)?;
+
+  std::fs::create_dir_all(out_dir)
+    .with_context(|| format!("creating directory: {}", out_dir.display()))?;
+  let archive_name = format!("{layer}.sql.zst");
+  let archive_path = out_dir.join(&archive_name);
+  let db_file = File::open(db_path).context("opening database")?;
+  let total = db_file.metadata()?.len();
+  let archive_file = File::create(&archive_path)
+    .with_context(|| format!("creating archive at: {}", archive_path.display()))?;
+
+  println!(
+    "Exporting layer {} ({:.2} MB) into {}...",
+    layer,
+    mb(total),
+    archive_path.display()
+  );
+  let reader = BufReader::with_capacity(buffer_size, db_file);
+  let writer = HashingWriter {
+    inner: BufWriter::with_capacity(buffer_size, archive_file),
+    hashes: Hashes::new(),
+  };
+  let (writer, db_hashes, chunks) = match chunk_size {
+    Some(chunk_size) => {
+      let (writer, db_hashes, chunks) = compress_chunks(reader, writer, level, chunk_size, total)?;
+      (writer, db_hashes, Some((chunk_size, chunks)))
+    }
+    None => {
+      let (writer, db_hashes) = compress(reader, writer, level, total)?;
+      (writer, db_hashes, None)
+    }
+  };
+
+  let (db_md5, db_sha256, db_size) = db_hashes.finish();
+  let (archive_md5, archive_sha256, archive_size) = writer.hashes.finish();
+  let chunk_index = match chunks {
+    Some((chunk_size, chunks)) => {
+      let index = ChunkIndex {
+        chunk_size,
+        db_size,
+        chunks,
+      };
+      let index_name = format!("{archive_name}{}", delta::INDEX_SUFFIX);
+      let index_path = out_dir.join(&index_name);
+      std::fs::write(&index_path, serde_json::to_string(&index)?)
+        .with_context(|| format!("writing {}", index_path.display()))?;
+      Some(index_name)
+    }
+    None => None,
+  };
+  write_checksum(out_dir.join(format!("{layer}.sql.md5")), &db_md5)?;
+  write_checksum(out_dir.join(format!("{layer}.sql.sha256")), &db_sha256)?;
+  write_checksum(out_dir.join(format!("{archive_name}.md5")), &archive_md5)?;
+  write_checksum(
+    out_dir.join(format!("{archive_name}.sha256")),
+    &archive_sha256,
+  )?;
+
+  let metadata = SnapshotMetadata {
+    layer,
+    user_version,
+    created_at: chrono::Utc::now(),
+    db_size,
+    db_md5,
+    db_sha256,
+    archive: archive_name,
+    archive_size,
+    archive_md5,
+    archive_sha256,
+    chunk_index,
+  };
+  let metadata_path = out_dir.join(format!("{layer}.json"));
+  std::fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)
+    .with_context(|| format!("writing {}", metadata_path.display()))?;
+  println!(
+    "Exported {:.2} MB archive ({:.2}% of the database)",
+    mb(archive_size),
+    archive_size as f64 / db_size.max(1) as f64 * 100.0
+  );
+  Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::export;
+  use crate::checksum::calculate_checksum;
+  use crate::delta::ChunkIndex;
+  use crate::io_tuning::IoOptions;
+  use crate::unpack::unpack;
+  use rusqlite::Connection;
+
+  #[test]
+  fn exported_snapshot_unpacks_to_the_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute_batch(
+        "PRAGMA user_version = 7;
+        CREATE TABLE layers (id INTEGER PRIMARY KEY);
+        INSERT INTO layers (id) VALUES (41), (42);",
+      )
+      .unwrap();
+    drop(conn);
+
+    let out_dir = dir.path().join("out");
+    let metadata = export(&db_path, &out_dir, 3, 1024, None).unwrap();
+    assert_eq!(metadata.layer, 42);
+    assert_eq!(metadata.user_version, 7);
+    assert_eq!(metadata.archive, "42.sql.zst");
+
+    let io = IoOptions::for_tests();
+    let db_md5 = calculate_checksum(&db_path, io).unwrap();
+    assert_eq!(metadata.db_md5, db_md5);
+    let stored_md5 = std::fs::read_to_string(out_dir.join("42.sql.md5")).unwrap();
+    assert_eq!(stored_md5, db_md5);
+    let archive_md5 = calculate_checksum(&out_dir.join("42.sql.zst"), io).unwrap();
+    assert_eq!(metadata.archive_md5, archive_md5);
+    assert!(out_dir.join("42.sql.zst.sha256").exists());
+    assert!(out_dir.join("42.json").exists());
+
+    let unpacked = dir.path().join("unpacked.sql");
+    unpack(&out_dir.join("42.sql.zst"), &unpacked, io).unwrap();
+    assert_eq!(calculate_checksum(&unpacked, io).unwrap(), db_md5);
+  }
+
+  #[test]
+  fn chunked_snapshot_has_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute_batch(
+        "CREATE TABLE layers (id INTEGER PRIMARY KEY, data BLOB);
+        INSERT INTO layers (id, data) VALUES (1, zeroblob(20000)), (2, randomblob(20000));",
+      )
+      .unwrap();
+    drop(conn);
+
+    let out_dir = dir.path().join("out");
+    let metadata = export(&db_path, &out_dir, 3, 1024, Some(4096)).unwrap();
+    assert_eq!(
+      metadata.chunk_index.as_deref(),
+      Some("2.sql.zst.chunks.json")
+    );
+    let index: ChunkIndex =
+      serde_json::from_slice(&std::fs::read(out_dir.join("2.sql.zst.chunks.json")).unwrap())
+        .unwrap();
+    assert_eq!(index.db_size, metadata.db_size);
+    assert_eq!(index.chunks.len() as u64, metadata.db_size.div_ceil(4096));
+    let last = index.chunks.last().unwrap();
+    assert_eq!(last.offset + last.length, metadata.archive_size);
+
+    let archive = std::fs::read(out_dir.join("2.sql.zst")).unwrap();
+    assert_eq!(
+      zstd::decode_all(archive.as_slice()).unwrap(),
+      std::fs::read(&db_path).unwrap()
+    );
+  }
+}
@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+use crate::http_trace::SendTraced;
+use crate::transport::{self, Transport};
+use crate::url_policy;
+
+// aria2c can't be kept from following redirects, so where it was redirected
+// to is read from its log
+const ARIA2C: &str = "aria2c --continue=true --split=8 --max-connection-per-server=8 \
+  --allow-overwrite=true --auto-file-renaming=false --log={log} --log-level=info \
+  --dir {dir} --out {file} {url}";
+// `--location` makes a redirect an error with `--max-redirs 0`, instead of a
+// successful download of the redirect's body
+const CURL: &str =
+  "curl --fail --location --max-redirs 0 --continue-at - --retry 10 --output {output} {url}";
+
+/// An external tool the archive is downloaded with, e.g. a tuned aria2c.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalDownloader {
+  Aria2c,
+  Curl,
+  /// Command template with `{url}`, `{output}`, `{dir}` and `{file}` placeholders.
+  Custom(String),
+}
+
+impl FromStr for ExternalDownloader {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "aria2c" => Ok(Self::Aria2c),
+      "curl" => Ok(Self::Curl),
+      template
+        if template.contains("{url}")
+          && (template.contains("{output}") || template.contains("{file}")) =>
+      {
+        Ok(Self::Custom(template.to_string()))
+      }
+      _ => Err(
+        "expected aria2c, curl or a command with the {url} placeholder and {output} \
+         (or {dir} and {file})"
+          .to_string(),
+      ),
+    }
+  }
+}
+
+impl ExternalDownloader {
+  fn template(&self) -> &str {
+    match self {
+      Self::Aria2c => ARIA2C,
+      Self::Curl => CURL,
+      Self::Custom(template) => template,
+    }
+  }
+
+  /// The program and the arguments downloading `url` into `output` with the
+  /// `transport` options. The placeholders are replaced within the arguments,
+  /// so paths with spaces stay one argument.
+  fn command(&self, url: &Url, output: &Path, transport: &Transport) -> Result<Vec<String>> {
+    let dir = output.parent().context("getting the output directory")?;
+    let file = output.file_name().context("getting the output file name")?;
+    let log = log_path(output);
+    let mut command: Vec<String> = self
+      .template()
+      .split_whitespace()
+      .map(|arg| {
+        arg
+          .replace("{url}", url.as_str())
+          .replace("{output}", &output.to_string_lossy())
+          .replace("{dir}", &dir.to_string_lossy())
+          .replace("{file}", &file.to_string_lossy())
+          .replace("{log}", &log.to_string_lossy())
+      })
+      .collect();
+    command.splice(1..1, self.transport_args(url, transport)?);
+    Ok(command)
+  }
+
+  /// Arguments passing `--ipv4`, `--ipv6` and `--resolve` on to the tool, an
+  /// error if it has no equivalent of one given.
+  fn transport_args(&self, url: &Url, transport: &Transport) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let unsupported = |option: &str| {
+      let tool = match self {
+        Self::Custom(_) => "a custom downloader",
+        Self::Aria2c => "aria2c",
+        Self::Curl => "curl",
+      };
+      anyhow::anyhow!("{option} can't be passed on to {tool}, download without --downloader")
+    };
+    match self {
+      Self::Curl => {
+        if transport.ipv4 {
+          args.push("--ipv4".to_string());
+        }
+        if transport.ipv6 {
+          args.push("--ipv6".to_string());
+        }
+        let port = url
+          .port_or_known_default()
+          .context("getting the port of the URL")?;
+        for (host, ip) in &transport.overrides {
+          let ip = match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+          };
+          args.extend(["--resolve".to_string(), format!("{host}:{port}:{ip}")]);
+        }
+      }
+      Self::Aria2c => {
+        if transport.ipv4 {
+          args.push("--disable-ipv6=true".to_string());
+        }
+        if transport.ipv6 {
+          return Err(unsupported("--ipv6"));
+        }
+        if !transport.overrides.is_empty() {
+          return Err(unsupported("--resolve"));
+        }
+      }
+      Self::Custom(_) => {
+        if transport.ipv4 || transport.ipv6 {
+          return Err(unsupported(if transport.ipv4 {
+            "--ipv4"
+          } else {
+            "--ipv6"
+          }));
+        }
+        if !transport.overrides.is_empty() {
+          return Err(unsupported("--resolve"));
+        }
+      }
+    }
+    Ok(args)
+  }
+
+  /// Downloads `url` into `output` with the tool. The tool resumes a partial
+  /// `output` by itself. aria2c's redirects are checked against the URL policy
+  /// afterwards; custom tools must not follow redirects themselves.
+  pub async fn download(&self, url: &Url, output: &Path) -> Result<()> {
+    let command = self.command(url, output, &transport::options())?;
+    println!("Downloading with {}...", command[0]);
+    let status = tokio::process::Command::new(&command[0])
+      .args(&command[1..])
+      .status()
+      .await
+      .with_context(|| format!("running {}", command[0]));
+    if *self == Self::Aria2c {
+      let log = log_path(output);
+      let redirects = std::fs::read_to_string(&log).unwrap_or_default();
+      let _ = std::fs::remove_file(&log);
+      for to in redirect_targets(&redirects) {
+        url_policy::check(&to)?;
+      }
+    }
+    let status = status?;
+    anyhow::ensure!(status.success(), "{} failed with {status}", command[0]);
+    anyhow::ensure!(
+      output.try_exists().unwrap_or(false),
+      "{} didn't download into {}",
+      command[0],
+      output.display()
+    );
+    Ok(())
+  }
+}
+
+/// Log aria2c writes next to `output`.
+fn log_path(output: &Path) -> PathBuf {
+  let mut log = output.as_os_str().to_owned();
+  log.push(".aria2c.log");
+  PathBuf::from(log)
+}
+
+/// URLs an aria2c log says it was redirected to.
+fn redirect_targets(log: &str) -> Vec<Url> {
+  log
+    .lines()
+    .filter_map(|line| line.split_once("Redirecting to ").map(|(_, to)| to.trim()))
+    .filter_map(|to| Url::parse(to).ok())
+    .collect()
+}
+
+/// Follows the redirects of `url` to the URL of the snapshot itself, which
+/// the checksums are next to.
+pub async fn resolve(url: &str) -> Result<Url> {
+  let client = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let response = client
+    .get(url)
+    .header("Range", "bytes=0-0")
+    .send_traced()
+    .await?;
+  anyhow::ensure!(
+    response.status().is_success(),
+    "failed to resolve {url}: {}",
+    response.status()
+  );
+  let resolved = response.url().clone();
+  url_policy::check(&resolved)?;
+  Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn building_commands() {
+    let url = Url::parse("https://quicksync.spacemesh.network/v1.7.0/61579.sql.zst").unwrap();
+    let output = Path::new("/data/node data/state.download");
+    assert_eq!(
+      ExternalDownloader::Curl
+        .command(&url, output, &Transport::default())
+        .unwrap(),
+      [
+        "curl",
+        "--fail",
+        "--location",
+        "--max-redirs",
+        "0",
+        "--continue-at",
+        "-",
+        "--retry",
+        "10",
+        "--output",
+        "/data/node data/state.download",
+        url.as_str(),
+      ]
+    );
+    let aria2c = ExternalDownloader::Aria2c
+      .command(&url, output, &Transport::default())
+      .unwrap();
+    assert!(aria2c.contains(&"/data/node data".to_string()));
+    assert!(aria2c.contains(&"state.download".to_string()));
+    assert!(aria2c.contains(&"--log=/data/node data/state.download.aria2c.log".to_string()));
+
+    let custom: ExternalDownloader = "wget -c -O {output} {url}".parse().unwrap();
+    assert_eq!(
+      custom.command(&url, output, &Transport::default()).unwrap(),
+      [
+        "wget",
+        "-c",
+        "-O",
+        "/data/node data/state.download",
+        url.as_str()
+      ]
+    );
+    assert!("wget".parse::<ExternalDownloader>().is_err());
+    assert!("wget {url}".parse::<ExternalDownloader>().is_err());
+    assert!("fetch --dir {dir} --name {file} {url}"
+      .parse::<ExternalDownloader>()
+      .is_ok());
+  }
+
+  #[test]
+  fn passing_transport_options() {
+    let url = Url::parse("https://quicksync.spacemesh.network/v1.7.0/61579.sql.zst").unwrap();
+    let output = Path::new("/data/state.download");
+    let transport = Transport {
+      ipv4: true,
+      overrides: vec![
+        (
+          "quicksync.spacemesh.network".to_string(),
+          "1.2.3.4".parse().unwrap(),
+        ),
+        (
+          "quicksync.spacemesh.network".to_string(),
+          "::1".parse().unwrap(),
+        ),
+      ],
+      ..Transport::default()
+    };
+    let curl = ExternalDownloader::Curl
+      .command(&url, output, &transport)
+      .unwrap();
+    assert_eq!(
+      curl[..6],
+      [
+        "curl",
+        "--ipv4",
+        "--resolve",
+        "quicksync.spacemesh.network:443:1.2.3.4",
+        "--resolve",
+        "quicksync.spacemesh.network:443:[::1]",
+      ]
+    );
+    // aria2c has no equivalent of --resolve, nor do custom tools of any
+    assert!(ExternalDownloader::Aria2c
+      .command(&url, output, &transport)
+      .is_err());
+    let ipv4 = Transport {
+      ipv4: true,
+      ..Transport::default()
+    };
+    let aria2c = ExternalDownloader::Aria2c
+      .command(&url, output, &ipv4)
+      .unwrap();
+    assert_eq!(aria2c[1], "--disable-ipv6=true");
+    let custom: ExternalDownloader = "wget -c -O {output} {url}".parse().unwrap();
+    assert!(custom.command(&url, output, &ipv4).is_err());
+  }
+
+  #[test]
+  fn reading_redirects_from_aria2c_logs() {
+    let log = "2025-01-01 [INFO] [HttpSkipResponseCommand.cc:218] CUID#7 - Redirecting to \
+               http://10.0.0.1/61579.sql.zst\n\
+               2025-01-01 [NOTICE] Download complete: /data/state.download\n";
+    assert_eq!(
+      redirect_targets(log),
+      [Url::parse("http://10.0.0.1/61579.sql.zst").unwrap()]
+    );
+  }
+}
@@ -0,0 +1,226 @@
+use std::io;
+
+/// Points of `download` at which the process can be killed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KillPoint {
+  /// After the archive is downloaded, before it's verified.
+  Downloaded,
+  /// After the archive is verified, before it's unpacked.
+  Verified,
+  /// After the archive is unpacked, before the database is verified.
+  Unpacked,
+  /// Right before the database is installed.
+  Install,
+}
+
+#[cfg(feature = "failpoints")]
+impl KillPoint {
+  fn name(self) -> &'static str {
+    match self {
+      Self::Downloaded => "downloaded",
+      Self::Verified => "verified",
+      Self::Unpacked => "unpacked",
+      Self::Install => "install",
+    }
+  }
+}
+
+#[cfg(feature = "failpoints")]
+mod enabled {
+  use anyhow::{anyhow, Context, Result};
+  use std::io;
+  use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+  use std::sync::OnceLock;
+
+  use super::KillPoint;
+  use crate::parsers::parse_byte_size;
+
+  /// Configures the failpoints, e.g.
+  /// `network_error_after=10MiB,enospc_after=100MiB,kill_at=downloaded`.
+  const ENV_VAR: &str = "QUICKSYNC_FAILPOINTS";
+
+  static CONFIG: OnceLock<Config> = OnceLock::new();
+  static RECEIVED: AtomicU64 = AtomicU64::new(0);
+  static UNPACKED: AtomicU64 = AtomicU64::new(0);
+  static NETWORK_FIRED: AtomicBool = AtomicBool::new(false);
+  static ENOSPC_FIRED: AtomicBool = AtomicBool::new(false);
+
+  #[derive(Debug, Default, PartialEq, Eq)]
+  struct Config {
+    network_error_after: Option<u64>,
+    enospc_after: Option<u64>,
+    kill_at: Option<KillPoint>,
+  }
+
+  impl Config {
+    fn parse(v: &str) -> Result<Self> {
+      let mut config = Self::default();
+      for entry in v.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, value) = entry
+          .split_once('=')
+          .with_context(|| format!("failpoint `{entry}` has no value"))?;
+        match name.trim() {
+          "network_error_after" => config.network_error_after = Some(parse_byte_size(value)?),
+          "enospc_after" => config.enospc_after = Some(parse_byte_size(value)?),
+          "kill_at" => {
+            let point = [
+              KillPoint::Downloaded,
+              KillPoint::Verified,
+              KillPoint::Unpacked,
+              KillPoint::Install,
+            ]
+            .into_iter()
+            .find(|p| p.name() == value.trim())
+            .with_context(|| format!("unknown kill point `{value}`"))?;
+            config.kill_at = Some(point);
+          }
+          name => return Err(anyhow!("unknown failpoint `{name}`")),
+        }
+      }
+      Ok(config)
+    }
+  }
+
+  fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+  }
+
+  /// Reads the failpoints from `QUICKSYNC_FAILPOINTS`.
+  pub fn init() -> Result<()> {
+    let Ok(v) = std::env::var(ENV_VAR) else {
+      return Ok(());
+    };
+    let config = Config::parse(&v).with_context(|| format!("parsing {ENV_VAR}"))?;
+    eprintln!("Failpoints enabled: {config:?}");
+    let _ = CONFIG.set(config);
+    Ok(())
+  }
+
+  /// Fails once, when `counter` passes `limit` after adding `bytes`.
+  fn passed(limit: Option<u64>, counter: &AtomicU64, fired: &AtomicBool, bytes: u64) -> bool {
+    let Some(limit) = limit else {
+      return false;
+    };
+    counter.fetch_add(bytes, Ordering::Relaxed) + bytes >= limit
+      && !fired.swap(true, Ordering::Relaxed)
+  }
+
+  pub fn on_received(bytes: u64) -> io::Result<()> {
+    if passed(
+      config().network_error_after,
+      &RECEIVED,
+      &NETWORK_FIRED,
+      bytes,
+    ) {
+      return Err(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "failpoint: connection reset",
+      ));
+    }
+    Ok(())
+  }
+
+  pub fn on_unpacked(bytes: u64) -> io::Result<()> {
+    if passed(config().enospc_after, &UNPACKED, &ENOSPC_FIRED, bytes) {
+      // ENOSPC
+      return Err(io::Error::from_raw_os_error(28));
+    }
+    Ok(())
+  }
+
+  pub fn kill_at(point: KillPoint) {
+    if config().kill_at != Some(point) {
+      return;
+    }
+    eprintln!("Failpoint: killing the process at `{}`", point.name());
+    #[cfg(unix)]
+    unsafe {
+      libc::kill(libc::getpid(), libc::SIGKILL);
+    }
+    std::process::abort();
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_config() {
+      assert_eq!(Config::parse("").unwrap(), Config::default());
+      assert_eq!(
+        Config::parse("network_error_after=10MiB, enospc_after=100, kill_at=unpacked").unwrap(),
+        Config {
+          network_error_after: Some(10 * 1024 * 1024),
+          enospc_after: Some(100),
+          kill_at: Some(KillPoint::Unpacked),
+        }
+      );
+      assert!(Config::parse("kill_at=never").is_err());
+      assert!(Config::parse("slow_disk=1").is_err());
+      assert!(Config::parse("enospc_after").is_err());
+    }
+
+    #[test]
+    fn firing_once() {
+      let (counter, fired) = (AtomicU64::new(0), AtomicBool::new(false));
+      assert!(!passed(None, &counter, &fired, 100));
+      let (counter, fired) = (AtomicU64::new(0), AtomicBool::new(false));
+      assert!(!passed(Some(100), &counter, &fired, 60));
+      assert!(passed(Some(100), &counter, &fired, 60));
+      assert!(!passed(Some(100), &counter, &fired, 60));
+    }
+  }
+}
+
+#[cfg(feature = "failpoints")]
+pub use enabled::init;
+
+/// Reads the failpoints to inject. Without the `failpoints` feature there are
+/// none.
+#[cfg(not(feature = "failpoints"))]
+pub fn init() -> anyhow::Result<()> {
+  Ok(())
+}
+
+/// Fails with a connection reset, once, when the run received more than
+/// `network_error_after` bytes.
+#[inline]
+pub fn on_received(_bytes: u64) -> io::Result<()> {
+  #[cfg(feature = "failpoints")]
+  return enabled::on_received(_bytes);
+  #[cfg(not(feature = "failpoints"))]
+  Ok(())
+}
+
+/// Fails with ENOSPC, once, when the run unpacked more than `enospc_after`
+/// bytes.
+#[inline]
+fn on_unpacked(_bytes: u64) -> io::Result<()> {
+  #[cfg(feature = "failpoints")]
+  return enabled::on_unpacked(_bytes);
+  #[cfg(not(feature = "failpoints"))]
+  Ok(())
+}
+
+/// Writer of the unpacked database, failing with ENOSPC like a full disk
+/// would when the run unpacked more than `enospc_after` bytes.
+pub struct UnpackedWriter<W>(pub W);
+
+impl<W: io::Write> io::Write for UnpackedWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    on_unpacked(buf.len() as u64)?;
+    self.0.write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.flush()
+  }
+}
+
+/// Kills the process with SIGKILL if `kill_at` names this point, leaving
+/// whatever it wrote on disk as it is.
+#[inline]
+pub fn kill_at(_point: KillPoint) {
+  #[cfg(feature = "failpoints")]
+  enabled::kill_at(_point);
+}
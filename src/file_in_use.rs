@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Renames a file, retrying for a while if it is in use by another process.
+///
+/// On Windows a file can't be renamed while any process (usually the node,
+/// or an antivirus scanner) has it open. If the file is still in use after
+/// the retries, the error names the processes holding it.
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+  #[cfg(windows)]
+  {
+    windows::rename(from, to)
+  }
+  #[cfg(not(windows))]
+  {
+    Ok(std::fs::rename(from, to)?)
+  }
+}
+
+fn is_cross_device(error: &std::io::Error) -> bool {
+  #[cfg(unix)]
+  {
+    error.raw_os_error() == Some(libc::EXDEV)
+  }
+  #[cfg(windows)]
+  {
+    // ERROR_NOT_SAME_DEVICE
+    error.raw_os_error() == Some(17)
+  }
+  #[cfg(not(any(unix, windows)))]
+  {
+    let _ = error;
+    false
+  }
+}
+
+/// Copy of a file moved to `to` from another file system, left next to it if
+/// the move is interrupted.
+pub fn staged_path(to: &Path) -> PathBuf {
+  let mut staged = to.as_os_str().to_owned();
+  staged.push(".moving");
+  PathBuf::from(staged)
+}
+
+/// Moves a file like [`rename`], also to another file system. Then the file
+/// is copied next to `to` first ([`staged_path`]), so `to` is still replaced
+/// by a rename.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+  match std::fs::rename(from, to) {
+    Ok(()) => return Ok(()),
+    Err(e) if is_cross_device(&e) => {}
+    Err(_) => return rename(from, to),
+  }
+  let staged = staged_path(to);
+  std::fs::copy(from, &staged)
+    .with_context(|| format!("copying {} to {}", from.display(), staged.display()))?;
+  File::open(&staged)?
+    .sync_all()
+    .with_context(|| format!("syncing {}", staged.display()))?;
+  rename(&staged, to)?;
+  std::fs::remove_file(from).with_context(|| format!("removing {}", from.display()))
+}
+
+#[cfg(windows)]
+mod windows {
+  use anyhow::Result;
+  use std::os::windows::ffi::OsStrExt;
+  use std::path::Path;
+  use std::time::Duration;
+  use windows_sys::Win32::Foundation::{ERROR_MORE_DATA, ERROR_SUCCESS};
+  use windows_sys::Win32::System::RestartManager::{
+    RmEndSession, RmGetList, RmRegisterResources, RmStartSession, CCH_RM_SESSION_KEY,
+    RM_PROCESS_INFO,
+  };
+
+  const RETRIES: u32 = 10;
+  const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+  const ERROR_ACCESS_DENIED: i32 = 5;
+  const ERROR_SHARING_VIOLATION: i32 = 32;
+  const ERROR_LOCK_VIOLATION: i32 = 33;
+
+  pub(super) fn is_in_use(error: &std::io::Error) -> bool {
+    matches!(
+      error.raw_os_error(),
+      Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+    )
+  }
+
+  pub(super) fn rename(from: &Path, to: &Path) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+      let error = match std::fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if is_in_use(&e) && attempt < RETRIES => {
+          attempt += 1;
+          std::thread::sleep(RETRY_DELAY);
+          continue;
+        }
+        Err(e) => e,
+      };
+      if !is_in_use(&error) {
+        return Err(error.into());
+      }
+      let holders = [from, to]
+        .iter()
+        .flat_map(|path| processes_using(path).unwrap_or_default())
+        .collect::<Vec<_>>();
+      let guidance = if holders.is_empty() {
+        "Make sure go-spacemesh is stopped and no other program \
+         (e.g. an antivirus scanner) uses the node-data directory"
+          .to_string()
+      } else {
+        format!(
+          "The file is in use by: {}. Stop it (or exclude the node-data \
+           directory from antivirus scans) and try again",
+          holders.join(", ")
+        )
+      };
+      anyhow::bail!(
+        "Cannot rename {} to {}: {error}. {guidance}",
+        from.display(),
+        to.display()
+      );
+    }
+  }
+
+  fn wide(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+  }
+
+  /// Lists processes that have the file open, using the Restart Manager.
+  fn processes_using(path: &Path) -> Result<Vec<String>> {
+    let mut session = 0;
+    let mut key = [0u16; CCH_RM_SESSION_KEY as usize + 1];
+    let status = unsafe { RmStartSession(&mut session, 0, key.as_mut_ptr()) };
+    anyhow::ensure!(status == ERROR_SUCCESS, "RmStartSession failed: {status}");
+    let result = list_processes(session, path);
+    unsafe { RmEndSession(session) };
+    result
+  }
+
+  fn list_processes(session: u32, path: &Path) -> Result<Vec<String>> {
+    let path = wide(path);
+    let files = [path.as_ptr()];
+    let status = unsafe {
+      RmRegisterResources(
+        session,
+        1,
+        files.as_ptr(),
+        0,
+        std::ptr::null(),
+        0,
+        std::ptr::null(),
+      )
+    };
+    anyhow::ensure!(
+      status == ERROR_SUCCESS,
+      "RmRegisterResources failed: {status}"
+    );
+
+    let mut infos: Vec<RM_PROCESS_INFO> = Vec::new();
+    loop {
+      let mut needed = 0;
+      let mut count = infos.capacity() as u32;
+      let mut reasons = 0;
+      let status = unsafe {
+        RmGetList(
+          session,
+          &mut needed,
+          &mut count,
+          infos.as_mut_ptr(),
+          &mut reasons,
+        )
+      };
+      match status {
+        ERROR_SUCCESS => {
+          unsafe { infos.set_len(count as usize) };
+          break;
+        }
+        ERROR_MORE_DATA => infos.reserve_exact(needed as usize),
+        status => anyhow::bail!("RmGetList failed: {status}"),
+      }
+    }
+
+    Ok(
+      infos
+        .iter()
+        .map(|info| {
+          let name = &info.strAppName;
+          let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+          format!(
+            "{} (PID {})",
+            String::from_utf16_lossy(&name[..len]),
+            info.Process.dwProcessId
+          )
+        })
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{move_file, rename};
+
+  #[test]
+  fn renames_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let from = dir.path().join("state_downloaded.sql");
+    let to = dir.path().join("state.sql");
+    std::fs::write(&from, b"data").unwrap();
+    rename(&from, &to).unwrap();
+    assert!(!from.exists());
+    assert_eq!(std::fs::read(&to).unwrap(), b"data");
+  }
+
+  #[cfg(windows)]
+  #[test]
+  fn detects_file_in_use() {
+    use std::io::Error;
+    assert!(super::windows::is_in_use(&Error::from_raw_os_error(32)));
+    assert!(!super::windows::is_in_use(&Error::from_raw_os_error(2)));
+  }
+
+  #[test]
+  fn moves_file() {
+    let (from_dir, to_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let from = from_dir.path().join("state_downloaded.sql");
+    let to = to_dir.path().join("state.sql");
+    std::fs::write(&from, b"data").unwrap();
+    std::fs::write(&to, b"old").unwrap();
+    move_file(&from, &to).unwrap();
+    assert!(!from.exists());
+    assert_eq!(std::fs::read(&to).unwrap(), b"data");
+  }
+}
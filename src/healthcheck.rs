@@ -0,0 +1,190 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::{Event, Stage};
+
+/// How often the file is rewritten while the progress advances, so a file not
+/// modified for longer means the run doesn't progress.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The health of the run, if it's written to a file.
+static HEALTH: Mutex<Option<Health>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum State {
+  Running,
+  Succeeded,
+  Failed,
+}
+
+/// What the healthcheck file holds.
+#[derive(Debug, Serialize)]
+struct Health {
+  #[serde(skip)]
+  path: PathBuf,
+  state: State,
+  pid: u32,
+  stage: Option<Stage>,
+  done: Option<u64>,
+  total: Option<u64>,
+  bytes_per_sec: Option<f64>,
+  exit_code: Option<i32>,
+  heartbeat: DateTime<Utc>,
+  /// Whether the progress advanced since the file was written.
+  #[serde(skip)]
+  progressed: bool,
+}
+
+impl Health {
+  fn new(path: PathBuf) -> Self {
+    Self {
+      path,
+      state: State::Running,
+      pid: std::process::id(),
+      stage: None,
+      done: None,
+      total: None,
+      bytes_per_sec: None,
+      exit_code: None,
+      heartbeat: Utc::now(),
+      progressed: false,
+    }
+  }
+
+  /// Records the event. Returns whether the file is updated at once, which
+  /// it is for stages and the result, not for the progress.
+  fn record(&mut self, event: &Event) -> bool {
+    match *event {
+      Event::Stage { stage } => {
+        (self.stage, self.done, self.total) = (Some(stage), None, None);
+        self.bytes_per_sec = None;
+        true
+      }
+      Event::Progress {
+        stage,
+        done,
+        total,
+        bytes_per_sec,
+      } => {
+        self.progressed |= self.stage != Some(stage) || self.done != Some(done);
+        self.stage = Some(stage);
+        (self.done, self.total, self.bytes_per_sec) = (Some(done), total, bytes_per_sec);
+        false
+      }
+      Event::Result {
+        success, exit_code, ..
+      } => {
+        self.state = if success {
+          State::Succeeded
+        } else {
+          State::Failed
+        };
+        self.exit_code = Some(exit_code);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Replaces the file with the current health, atomically, so a probe never
+  /// reads a partly written one.
+  fn write(&mut self) -> Result<()> {
+    self.heartbeat = Utc::now();
+    self.progressed = false;
+    let mut tmp_path = self.path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(self)?)
+      .and_then(|()| std::fs::rename(&tmp_path, &self.path))
+      .with_context(|| format!("writing {}", self.path.display()))
+  }
+}
+
+fn update(f: impl FnOnce(&mut Health) -> bool) {
+  if let Some(health) = HEALTH.lock().unwrap().as_mut() {
+    if f(health) {
+      if let Err(e) = health.write() {
+        eprintln!("Cannot update the healthcheck file: {e:#}");
+      }
+    }
+  }
+}
+
+/// Starts writing the stage, the progress and a heartbeat timestamp of the
+/// run to the file at `path`.
+pub fn enable(path: PathBuf) -> Result<()> {
+  let mut health = Health::new(path);
+  health.write()?;
+  *HEALTH.lock().unwrap() = Some(health);
+  Ok(())
+}
+
+/// Records the event. Stages and the result are written at once, the
+/// progress with the next heartbeat.
+pub fn record(event: &Event) {
+  update(|health| health.record(event));
+}
+
+/// Rewrites the file every `HEARTBEAT_INTERVAL` if the progress advanced, so
+/// a hung run leaves it untouched.
+pub async fn heartbeat() {
+  let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+  loop {
+    interval.tick().await;
+    update(|health| health.progressed);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn writing_health() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("health.json");
+    let mut health = Health::new(path.clone());
+    health.write().unwrap();
+    let read =
+      || -> serde_json::Value { serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap() };
+    assert_eq!(read()["state"], "running");
+
+    assert!(health.record(&Event::Stage {
+      stage: Stage::Download,
+    }));
+    assert!(!health.record(&Event::Progress {
+      stage: Stage::Download,
+      done: 100,
+      total: Some(1000),
+      bytes_per_sec: None,
+    }));
+    assert!(health.progressed);
+    health.write().unwrap();
+    assert_eq!(read()["stage"], "download");
+    assert_eq!(read()["done"], 100);
+    // The same progress again isn't worth a heartbeat
+    health.record(&Event::Progress {
+      stage: Stage::Download,
+      done: 100,
+      total: Some(1000),
+      bytes_per_sec: Some(0.0),
+    });
+    assert!(!health.progressed);
+
+    assert!(health.record(&Event::Result {
+      success: false,
+      exit_code: 2,
+      error: None,
+    }));
+    health.write().unwrap();
+    let written = read();
+    assert_eq!(written["state"], "failed");
+    assert_eq!(written["exit_code"], 2);
+    // The temporary file is renamed over it
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+  }
+}
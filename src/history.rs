@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::exit_error::ExitError;
+use crate::sql::get_last_layer_from_db;
+
+pub const HISTORY_FILE_NAME: &str = "quicksync-history.jsonl";
+
+#[derive(Debug, Serialize)]
+struct HistoryRecord<'a> {
+  command: &'a str,
+  quicksync_version: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  node_version: Option<&'a str>,
+  started_at: DateTime<Utc>,
+  finished_at: DateTime<Utc>,
+  duration_secs: f64,
+  start_layer: Option<i32>,
+  end_layer: Option<i32>,
+  outcome: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  exit_code: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+/// Tracks a single run that modifies the database, so it can be appended
+/// to the history log next to the database once it's finished.
+pub struct SyncHistory {
+  command: &'static str,
+  db_path: PathBuf,
+  node_version: Option<String>,
+  started_at: DateTime<Utc>,
+  start: Instant,
+  start_layer: Option<i32>,
+}
+
+fn read_layer(db_path: &Path) -> Option<i32> {
+  if !db_path.try_exists().unwrap_or(false) {
+    return None;
+  }
+  get_last_layer_from_db(db_path).ok()
+}
+
+impl SyncHistory {
+  pub fn start(command: &'static str, db_path: &Path, node_version: Option<String>) -> Self {
+    Self {
+      command,
+      db_path: db_path.to_path_buf(),
+      node_version,
+      started_at: Utc::now(),
+      start: Instant::now(),
+      start_layer: read_layer(db_path),
+    }
+  }
+
+  /// Appends the record of the finished run to the history log.
+  /// Failing to write the history never fails the run itself.
+  pub fn finish(self, result: &Result<()>) {
+    if let Err(e) = self.append(result) {
+      eprintln!("Cannot write quicksync history: {e:#}");
+    }
+  }
+
+  fn append(&self, result: &Result<()>) -> Result<()> {
+    let record = HistoryRecord {
+      command: self.command,
+      quicksync_version: env!("CARGO_PKG_VERSION"),
+      node_version: self.node_version.as_deref(),
+      started_at: self.started_at,
+      finished_at: Utc::now(),
+      duration_secs: self.start.elapsed().as_secs_f64(),
+      start_layer: self.start_layer,
+      end_layer: read_layer(&self.db_path),
+      outcome: if result.is_ok() { "success" } else { "failure" },
+      exit_code: result
+        .as_ref()
+        .err()
+        .map(|e| ExitError::find(e).map_or(1, |e| e.code)),
+      error: result.as_ref().err().map(|e| format!("{e:#}")),
+    };
+
+    let dir = self.db_path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let path = dir.join(HISTORY_FILE_NAME);
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&path)
+      .with_context(|| format!("opening {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(&record)?)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn appends_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+
+    SyncHistory::start("download", &db_path, Some("v1.7.0".into())).finish(&Ok(()));
+    SyncHistory::start("incremental", &db_path, None).finish(&Err(
+      ExitError::new(7, "Archive checksum is invalid").into(),
+    ));
+
+    let log = std::fs::read_to_string(dir.path().join(HISTORY_FILE_NAME)).unwrap();
+    let records = log
+      .lines()
+      .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+
+    assert_eq!(records[0]["command"], "download");
+    assert_eq!(records[0]["node_version"], "v1.7.0");
+    assert_eq!(records[0]["outcome"], "success");
+    assert!(records[0]["start_layer"].is_null());
+    assert!(records[0].get("error").is_none());
+
+    assert_eq!(records[1]["command"], "incremental");
+    assert_eq!(records[1]["outcome"], "failure");
+    assert_eq!(records[1]["exit_code"], 7);
+    assert_eq!(records[1]["error"], "Archive checksum is invalid");
+  }
+}
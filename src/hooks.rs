@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::process::Command;
+
+use crate::exit_error::ExitError;
+use crate::service::{wait_for_db_unlock, NodeService};
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct Hooks {
+  /// Command to run before the database is replaced (e.g. "systemctl stop spacemesh").
+  /// Nothing is replaced if the command fails
+  #[clap(long)]
+  pub pre_hook: Option<String>,
+  /// Command to run after the database is replaced (e.g. "systemctl start spacemesh").
+  /// It runs even if replacing the database has failed
+  #[clap(long)]
+  pub post_hook: Option<String>,
+  /// Stop the node service before replacing the database and start it afterwards.
+  /// Format: `systemd:<unit>`, `windows:<service name>` or `launchd:<label>`
+  #[clap(long)]
+  pub manage_service: Option<NodeService>,
+}
+
+/// Hooks whose pre-hook succeeded and whose post-hook hasn't run yet, by ID,
+/// for [`finish_aborted`] to run it if the run is aborted in between.
+static PENDING: Mutex<Vec<(usize, Hooks)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the node service and runs the post-hook of the replacements the
+/// run was aborted during (interrupted or timed out), so the node isn't left
+/// stopped.
+pub async fn finish_aborted() -> Result<()> {
+  let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+  let mut result = Ok(());
+  for (_, hooks) in pending {
+    println!("The run was aborted while replacing the database, finishing it");
+    result = result.and(hooks.run_post().await);
+  }
+  result
+}
+
+fn shell_command(command: &str) -> Command {
+  #[cfg(target_os = "windows")]
+  {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+  }
+  #[cfg(not(target_os = "windows"))]
+  {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+  }
+}
+
+async fn run_hook(name: &str, command: &str) -> Result<()> {
+  println!("Running {name}: {command}");
+  let status = shell_command(command)
+    .status()
+    .await
+    .with_context(|| format!("running {name}"))?;
+  anyhow::ensure!(status.success(), "{name} failed: {status}");
+  Ok(())
+}
+
+impl Hooks {
+  /// Replaces the database at `db_path` with `replace` between the hooks.
+  /// Nothing is replaced if the pre-hook fails. Once it succeeded, the
+  /// post-hook runs whatever happens next, so the node isn't left stopped.
+  pub async fn around<T>(
+    &self,
+    db_path: &Path,
+    replace: impl Future<Output = Result<T>>,
+  ) -> Result<T> {
+    if let Some(cmd) = &self.pre_hook {
+      run_hook("pre-hook", cmd)
+        .await
+        .map_err(|e| ExitError::new(9, format!("{e:#}")))?;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    PENDING.lock().unwrap().push((id, self.clone()));
+    let replaced = match self.stop_service(db_path).await {
+      Ok(()) => replace.await,
+      Err(e) => Err(e),
+    };
+    PENDING
+      .lock()
+      .unwrap()
+      .retain(|(pending, _)| *pending != id);
+    let post_hook = self.run_post().await;
+    let value = replaced?;
+    post_hook?;
+    Ok(value)
+  }
+
+  /// Stops the node service (if managed) and waits until the database at
+  /// `db_path` is released.
+  async fn stop_service(&self, db_path: &Path) -> Result<()> {
+    if let Some(service) = &self.manage_service {
+      service
+        .stop()
+        .await
+        .map_err(|e| ExitError::new(11, format!("Cannot stop the node: {e:#}")))?;
+      wait_for_db_unlock(db_path)
+        .await
+        .map_err(|e| ExitError::new(11, format!("Cannot stop the node: {e:#}")))?;
+    }
+    Ok(())
+  }
+
+  /// Starts the node service (if managed) and runs the post-hook.
+  async fn run_post(&self) -> Result<()> {
+    if let Some(service) = &self.manage_service {
+      service
+        .start()
+        .await
+        .map_err(|e| ExitError::new(12, format!("Cannot start the node: {e:#}")))?;
+    }
+    if let Some(cmd) = &self.post_hook {
+      run_hook("post-hook", cmd)
+        .await
+        .map_err(|e| ExitError::new(10, format!("{e:#}")))?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn runs_hooks() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("marker");
+    let hooks = Hooks {
+      pre_hook: Some(format!("touch {}", marker.display())),
+      // Idempotent, the tests share the pending hooks
+      post_hook: Some(format!("rm -f {}", marker.display())),
+      manage_service: None,
+    };
+    let replaced = hooks.around(&dir.path().join("state.sql"), async {
+      assert!(marker.exists());
+      Ok(7)
+    });
+    assert_eq!(replaced.await.unwrap(), 7);
+    assert!(!marker.exists());
+  }
+
+  #[tokio::test]
+  async fn running_post_hook_after_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("marker");
+    let hooks = Hooks {
+      pre_hook: Some("true".into()),
+      post_hook: Some(format!("touch {}", marker.display())),
+      manage_service: None,
+    };
+    let replaced = hooks.around(&dir.path().join("state.sql"), async {
+      Err::<(), _>(anyhow::anyhow!("install failed"))
+    });
+    let err = replaced.await.unwrap_err();
+    assert!(format!("{err:#}").contains("install failed"));
+    assert!(marker.exists());
+  }
+
+  #[tokio::test]
+  async fn failing_pre_hook_aborts() {
+    let hooks = Hooks {
+      pre_hook: Some("exit 3".into()),
+      ..Default::default()
+    };
+    let mut replaced = false;
+    let result = hooks.around(Path::new("state.sql"), async {
+      replaced = true;
+      Ok(())
+    });
+    let err = result.await.unwrap_err();
+    assert_eq!(err.downcast_ref::<ExitError>().unwrap().code, 9);
+    assert!(!replaced);
+  }
+
+  #[tokio::test]
+  async fn finishing_aborted_replacement() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("marker");
+    let hooks = Hooks {
+      pre_hook: Some("true".into()),
+      post_hook: Some(format!("touch {}", marker.display())),
+      manage_service: None,
+    };
+    let replace = hooks.around(
+      &dir.path().join("state.sql"),
+      std::future::pending::<Result<()>>(),
+    );
+    let aborted = tokio::time::timeout(std::time::Duration::from_millis(500), replace).await;
+    assert!(aborted.is_err());
+    assert!(!marker.exists());
+    finish_aborted().await.unwrap();
+    assert!(marker.exists());
+  }
+}
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::http_trace::SendTraced;
+
+/// Directory of the cached responses, unset if caching is off.
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Default directory of the cache, private to the user: in `$XDG_CACHE_HOME`
+/// or `~/.cache`, else in the temp directory under the user ID.
+pub fn default_dir() -> PathBuf {
+  let cache_home = std::env::var_os("XDG_CACHE_HOME")
+    .map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+    .filter(|dir| dir.is_absolute());
+  match cache_home {
+    Some(dir) => dir.join("quicksync"),
+    #[cfg(unix)]
+    None => std::env::temp_dir().join(format!("quicksync-cache-{}", unsafe { libc::geteuid() })),
+    #[cfg(not(unix))]
+    None => std::env::temp_dir().join("quicksync-cache"),
+  }
+}
+
+/// Creates the cache directory readable by the user only, and checks that
+/// nobody else owns it or can write to it.
+fn open_dir(dir: &Path) -> Result<()> {
+  if let Some(parent) = dir.parent() {
+    std::fs::create_dir_all(parent)?;
+  }
+  let mut builder = std::fs::DirBuilder::new();
+  #[cfg(unix)]
+  std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+  if let Err(e) = builder.create(dir) {
+    if e.kind() != std::io::ErrorKind::AlreadyExists {
+      return Err(e.into());
+    }
+  }
+  let metadata = std::fs::symlink_metadata(dir)?;
+  anyhow::ensure!(metadata.is_dir(), "it's not a directory");
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::MetadataExt;
+    let uid = unsafe { libc::geteuid() };
+    anyhow::ensure!(
+      metadata.uid() == uid,
+      "it's owned by user {}",
+      metadata.uid()
+    );
+    anyhow::ensure!(
+      metadata.mode() & 0o077 == 0,
+      "other users can access it (mode {:o}), it must be 700",
+      metadata.mode() & 0o777
+    );
+  }
+  Ok(())
+}
+
+/// Starts caching the small files (metadata, restore scripts, checksums and
+/// manifests) in `dir`, unless other users could tamper with it.
+pub fn enable(dir: PathBuf) {
+  match open_dir(&dir) {
+    Ok(()) => {
+      let _ = CACHE_DIR.set(dir);
+    }
+    Err(e) => println!("Not caching in {}: {e:#}", dir.display()),
+  }
+}
+
+/// Validators of a cached response, to ask the server if it changed.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+  url: String,
+  etag: Option<String>,
+  last_modified: Option<String>,
+  /// SHA-256 of the body, checked before it's used.
+  sha256: String,
+}
+
+fn paths(dir: &Path, url: &str) -> (PathBuf, PathBuf) {
+  let key = hex::encode(Sha256::digest(url.as_bytes()));
+  (
+    dir.join(format!("{key}.json")),
+    dir.join(format!("{key}.body")),
+  )
+}
+
+fn load(dir: &Path, url: &str) -> Option<(Entry, String)> {
+  let (entry_path, body_path) = paths(dir, url);
+  let entry: Entry = serde_json::from_str(&std::fs::read_to_string(entry_path).ok()?).ok()?;
+  let body = std::fs::read_to_string(body_path).ok()?;
+  // The body may be a script run on the database: a torn or altered one is
+  // downloaded again
+  let intact = entry.sha256 == hex::encode(Sha256::digest(body.as_bytes()));
+  (entry.url == url && intact).then_some((entry, body))
+}
+
+/// Writes `content` to `path` atomically.
+fn write_atomically(path: &Path, content: &str) -> Result<()> {
+  let tmp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+  std::fs::write(&tmp_path, content)
+    .and_then(|()| std::fs::rename(&tmp_path, path))
+    .with_context(|| format!("writing {}", path.display()))
+}
+
+fn store(dir: &Path, url: &str, headers: &HeaderMap, body: &str) -> Result<()> {
+  let header = |name| {
+    headers
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .map(str::to_string)
+  };
+  let entry = Entry {
+    url: url.to_string(),
+    etag: header(ETAG),
+    last_modified: header(LAST_MODIFIED),
+    sha256: hex::encode(Sha256::digest(body.as_bytes())),
+  };
+  // The server can't tell if it changed without validators
+  if entry.etag.is_none() && entry.last_modified.is_none() {
+    return Ok(());
+  }
+  let (entry_path, body_path) = paths(dir, url);
+  write_atomically(&body_path, body)?;
+  write_atomically(&entry_path, &serde_json::to_string(&entry)?)
+}
+
+async fn fetch(client: &Client, url: &str, dir: Option<&Path>) -> Result<Result<String, Response>> {
+  let cached = dir.and_then(|dir| load(dir, url));
+  let mut request = client.get(url);
+  if let Some((entry, _)) = &cached {
+    if let Some(etag) = &entry.etag {
+      request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &entry.last_modified {
+      request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+  }
+  let response = request.send_traced().await?;
+  if response.status() == StatusCode::NOT_MODIFIED {
+    if let Some((_, body)) = cached {
+      return Ok(Ok(body));
+    }
+  }
+  if !response.status().is_success() {
+    return Ok(Err(response));
+  }
+  let headers = response.headers().clone();
+  let body = response.text().await?;
+  if let Some(dir) = dir {
+    if let Err(e) = store(dir, url, &headers, &body) {
+      println!("Cannot cache {url}: {e:#}");
+    }
+  }
+  Ok(Ok(body))
+}
+
+/// Downloads the small text file at `url`, or takes it from the cache if the
+/// server says it didn't change. Returns the response instead if it failed.
+pub async fn get_text(client: &Client, url: &str) -> Result<Result<String, Response>> {
+  fetch(client, url, CACHE_DIR.get().map(PathBuf::as_path)).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn revalidating_cached_files() {
+    let mut server = mockito::Server::new_async().await;
+    let url = format!("{}/1/metadata.csv", server.url());
+    let dir = tempfile::tempdir().unwrap();
+    let client = Client::new();
+
+    let fresh = server
+      .mock("GET", "/1/metadata.csv")
+      .match_header("If-None-Match", mockito::Matcher::Missing)
+      .with_header("ETag", "\"v1\"")
+      .with_body("1,10,abcd")
+      .create_async()
+      .await;
+    let body = fetch(&client, &url, Some(dir.path())).await.unwrap();
+    assert_eq!(body.unwrap(), "1,10,abcd");
+    fresh.assert_async().await;
+
+    let unchanged = server
+      .mock("GET", "/1/metadata.csv")
+      .match_header("If-None-Match", "\"v1\"")
+      .with_status(304)
+      .create_async()
+      .await;
+    let body = fetch(&client, &url, Some(dir.path())).await.unwrap();
+    assert_eq!(body.unwrap(), "1,10,abcd");
+    unchanged.assert_async().await;
+
+    // failed responses are left to the caller
+    let missing = server
+      .mock("GET", "/1/other.csv")
+      .with_status(404)
+      .create_async()
+      .await;
+    let other = format!("{}/1/other.csv", server.url());
+    let response = fetch(&client, &other, None).await.unwrap().unwrap_err();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    missing.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn refetching_altered_files() {
+    let mut server = mockito::Server::new_async().await;
+    let url = format!("{}/1/restore.sql", server.url());
+    let dir = tempfile::tempdir().unwrap();
+    let client = Client::new();
+    let fresh = server
+      .mock("GET", "/1/restore.sql")
+      .match_header("If-None-Match", mockito::Matcher::Missing)
+      .with_header("ETag", "\"v1\"")
+      .with_body("DELETE FROM blocks;")
+      .expect(2)
+      .create_async()
+      .await;
+    fetch(&client, &url, Some(dir.path()))
+      .await
+      .unwrap()
+      .unwrap();
+    let (_, body_path) = paths(dir.path(), &url);
+    std::fs::write(&body_path, "ATTACH '/etc/cron.d/x' AS x;").unwrap();
+    // The altered body isn't revalidated, it's downloaded again
+    let body = fetch(&client, &url, Some(dir.path())).await.unwrap();
+    assert_eq!(body.unwrap(), "DELETE FROM blocks;");
+    fresh.assert_async().await;
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn refusing_shared_dirs() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().unwrap();
+    let cache = dir.path().join("cache");
+    open_dir(&cache).unwrap();
+    let mode = std::fs::metadata(&cache).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    std::fs::set_permissions(&cache, std::fs::Permissions::from_mode(0o777)).unwrap();
+    assert!(open_dir(&cache).is_err());
+  }
+}
@@ -1,27 +1,170 @@
 use anyhow::{Context, Result};
-use reqwest::blocking::Client;
+use reqwest::{Client, StatusCode};
 use rusqlite::Connection;
+use std::io::Write;
 use std::{fs, io};
 use std::{
   fs::File,
   io::{BufReader, BufWriter},
-  path::Path,
+  path::{Path, PathBuf},
   str::FromStr,
-  time::Instant,
+  time::{Duration, Instant},
 };
-use zstd::stream::Decoder;
+use url::Url;
+
+use crate::checksum::{self, ChecksumOptions};
+use crate::control;
+use crate::events::{self, Event, Stage};
+use crate::exit_error::ExitError;
+use crate::file_in_use;
+use crate::http_cache;
+use crate::http_trace::{self, SendTraced};
+use crate::io_tuning::IoOptions;
+use crate::restore_filter::{self, RowCounts};
+use crate::source::{self, FileStatus, SnapshotSource};
+use crate::sql;
+use crate::transport;
+use crate::unpack;
+use crate::url_policy;
+
+pub const DEFAULT_BASE_URL: &str = "https://quicksync-partials.spacemesh.network";
+
+/// Node databases published for incremental quicksync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Database {
+  State,
+  Atx,
+}
+
+impl Database {
+  pub fn file_name(&self) -> &'static str {
+    match self {
+      Database::State => "state.sql",
+      Database::Atx => "atx.sql",
+    }
+  }
+
+  /// Path prefix of the metadata and restore points of the database on the server.
+  /// The state database lives in the root for backwards compatibility.
+  fn namespace(&self) -> &'static str {
+    match self {
+      Database::State => "",
+      Database::Atx => "atx/",
+    }
+  }
+
+  /// Path to the database, which lives next to `state.sql`.
+  pub fn path(&self, state_db_path: &Path) -> PathBuf {
+    match self {
+      Database::State => state_db_path.to_path_buf(),
+      _ => state_db_path.with_file_name(self.file_name()),
+    }
+  }
+
+  /// File next to a database without layers, e.g. `atx.sql.layer`, with the
+  /// latest layer of the restore points applied to it.
+  fn layer_record_path(&self, state_db_path: &Path) -> PathBuf {
+    state_db_path.with_file_name(format!("{}.layer", self.file_name()))
+  }
+
+  /// Latest layer the database is synced to, from its own file. `None` for a
+  /// database without layers that no restore point was applied to yet.
+  fn latest_layer(&self, state_db_path: &Path) -> Result<Option<u32>> {
+    if *self == Database::State {
+      return get_latest_from_db(&sql::open_read_only(state_db_path)?).map(Some);
+    }
+    let path = self.layer_record_path(state_db_path);
+    match fs::read_to_string(&path) {
+      Ok(layer) => Ok(Some(
+        layer
+          .trim()
+          .parse()
+          .with_context(|| format!("parsing {}", path.display()))?,
+      )),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+  }
+
+  /// Records that the restore points up to `to` (exclusive) were applied, for
+  /// a database without layers.
+  fn record_layer(&self, state_db_path: &Path, to: u32) -> Result<()> {
+    if *self == Database::State {
+      return Ok(());
+    }
+    let path = self.layer_record_path(state_db_path);
+    fs::write(&path, (to - 1).to_string()).with_context(|| format!("writing {}", path.display()))
+  }
+}
+
+/// Databases to sync, selected on the command line.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DbSelection {
+  #[default]
+  State,
+  Atx,
+  All,
+}
 
-pub(crate) const DEFAULT_BASE_URL: &str = "https://quicksync-partials.spacemesh.network";
+impl DbSelection {
+  pub fn databases(&self) -> &'static [Database] {
+    match self {
+      DbSelection::State => &[Database::State],
+      DbSelection::Atx => &[Database::Atx],
+      DbSelection::All => &[Database::State, Database::Atx],
+    }
+  }
+}
 
-#[derive(Clone, Debug, PartialEq, Eq, parse_display::Display, parse_display::FromStr)]
-#[display("{from},{to},{hash}")]
-struct RestorePoint {
-  from: u32,
-  to: u32,
-  hash: String,
+/// A line of `metadata.csv`: `{from},{to},{hash}`. Metadata v2 adds
+/// `,{size}`, the size of the restore point file in bytes. Metadata v3 adds
+/// `,{url}`, where the file is, relative to `metadata.csv` or absolute (the
+/// size may be empty then).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestorePoint {
+  pub from: u32,
+  pub to: u32,
+  pub hash: String,
+  pub size: Option<u64>,
+  /// The file is at [`file_url`] if the metadata doesn't list it.
+  pub url: Option<String>,
 }
 
-fn get_previous_hash(layer_at: u32, conn: &Connection) -> Result<String> {
+impl std::fmt::Display for RestorePoint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{},{},{}", self.from, self.to, self.hash)?;
+    match (self.size, &self.url) {
+      (Some(size), None) => write!(f, ",{size}"),
+      (Some(size), Some(url)) => write!(f, ",{size},{url}"),
+      (None, Some(url)) => write!(f, ",,{url}"),
+      (None, None) => Ok(()),
+    }
+  }
+}
+
+impl FromStr for RestorePoint {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    // The URL, last, may have commas of its own (e.g. a signed one)
+    let fields: Vec<&str> = s.splitn(5, ',').collect();
+    let (from, to, hash, size, url) = match fields[..] {
+      [from, to, hash] => (from, to, hash, None, None),
+      [from, to, hash, size] => (from, to, hash, Some(size), None),
+      [from, to, hash, size, url] if !url.is_empty() => (from, to, hash, Some(size), Some(url)),
+      _ => anyhow::bail!("expected 3 to 5 fields in restore point '{s}'"),
+    };
+    Ok(Self {
+      from: from.parse()?,
+      to: to.parse()?,
+      hash: hash.to_string(),
+      size: size.filter(|s| !s.is_empty()).map(str::parse).transpose()?,
+      url: url.map(str::to_string),
+    })
+  }
+}
+
+pub fn get_previous_hash(layer_at: u32, conn: &Connection) -> Result<String> {
   let layer_at = layer_at - 1;
   conn
     .query_row(
@@ -41,12 +184,22 @@ fn get_previous_hash(layer_at: u32, conn: &Connection) -> Result<String> {
 //
 // The `jump_back` tells how many "previous" points should be included in
 // the returned vector.
-fn find_restore_points(layer_from: u32, metadata: &str, jump_back: usize) -> Vec<RestorePoint> {
+fn find_restore_points(
+  layer_from: u32,
+  metadata: &str,
+  jump_back: usize,
+) -> Result<Vec<RestorePoint>> {
   let mut all_points = Vec::new();
   let mut target_index = None;
 
   for (index, line) in metadata.trim().lines().enumerate() {
-    let point = RestorePoint::from_str(line.trim()).expect("parsing restore point");
+    let point = RestorePoint::from_str(line.trim()).with_context(|| {
+      format!(
+        "parsing restore point on line {}: '{}'",
+        index + 1,
+        line.trim()
+      )
+    })?;
     if (point.from..point.to).contains(&layer_from) && target_index.is_none() {
       target_index = Some(index);
     }
@@ -66,10 +219,36 @@ fn find_restore_points(layer_from: u32, metadata: &str, jump_back: usize) -> Vec
     }
   };
 
-  all_points
+  Ok(all_points)
 }
 
-fn get_latest_from_db(conn: &Connection) -> Result<u32> {
+/// Number of restore points to jump back from the one with `layer_from` for
+/// the first restore point to continue the database, i.e. for the database to
+/// have the hash it starts from. Looks at most `max_depth` points back. A
+/// restore point from layer 0 continues only a database without layers.
+fn find_jump_back(
+  layer_from: u32,
+  metadata: &str,
+  max_depth: usize,
+  conn: &Connection,
+) -> Result<Option<usize>> {
+  let remaining = find_restore_points(layer_from, metadata, 0)?.len();
+  if remaining == 0 {
+    return Ok(Some(0));
+  }
+  let points = find_restore_points(layer_from, metadata, max_depth)?;
+  // Index of the restore point with `layer_from`
+  let target = points.len() - remaining;
+  Ok((0..=target).find(|&depth| {
+    let p = &points[target - depth];
+    match p.from {
+      0 => get_latest_from_db(conn).is_err(),
+      from => get_previous_hash(from, conn).is_ok_and(|hash| hash == p.hash[..4]),
+    }
+  }))
+}
+
+pub fn get_latest_from_db(conn: &Connection) -> Result<u32> {
   conn
     .query_row(
       "SELECT max(id) FROM layers WHERE applied_block IS NOT null",
@@ -79,210 +258,933 @@ fn get_latest_from_db(conn: &Connection) -> Result<u32> {
     .context("failed to get latest layer from DB")
 }
 
-fn get_user_version(conn: &Connection) -> Result<usize> {
+pub fn get_user_version(conn: &Connection) -> Result<usize> {
   conn
     .query_row("PRAGMA user_version", [], |row| row.get(0))
     .context("failed to get user version")
 }
 
-fn file_url(user_version: usize, p: &RestorePoint, suffix: Option<&str>) -> String {
+pub fn file_url(
+  db: Database,
+  user_version: usize,
+  p: &RestorePoint,
+  suffix: Option<&str>,
+) -> String {
   let suffix = suffix.unwrap_or_default();
   format!(
-    "{}/{}_{}_{}/state.sql_diff.{}_{}.sql{}",
+    "{}{}/{}_{}_{}/{}_diff.{}_{}.sql{}",
+    db.namespace(),
+    user_version,
+    p.from,
+    p.to,
+    p.hash,
+    db.file_name(),
+    p.from,
+    p.to,
+    suffix
+  )
+}
+
+/// Path of the reverse diff of the restore point of the state database on the
+/// server, which undoes the restore point.
+pub fn reverse_file_url(user_version: usize, p: &RestorePoint, suffix: &str) -> String {
+  format!(
+    "{}/{}_{}_{}/state.sql_rdiff.{}_{}.sql{}",
     user_version, p.from, p.to, p.hash, p.from, p.to, suffix
   )
 }
 
-fn download_file(
-  client: &Client,
+/// Path of the base database of `db` for `user_version` on the server,
+/// to start a node from before applying the restore points.
+pub fn base_db_url(db: Database, user_version: usize, suffix: &str) -> String {
+  format!(
+    "{}{}/base/{}{}",
+    db.namespace(),
+    user_version,
+    db.file_name(),
+    suffix
+  )
+}
+
+/// Suffixes of the diff files, tried in this order until one is found.
+const DIFF_SUFFIXES: [&str; 5] = [".zst", ".xz", ".lz4", ".gz", ""];
+
+async fn download_file(
+  source: &dyn SnapshotSource,
   base_url: &str,
+  db: Database,
   user_version: usize,
   point: &RestorePoint,
+  suffix: Option<&str>,
+  target_path: &Path,
+) -> Result<()> {
+  let file_url = file_url(db, user_version, point, suffix);
+  fetch_file(source, base_url, &file_url, target_path).await
+}
+
+async fn fetch_file(
+  source: &dyn SnapshotSource,
+  base_url: &str,
+  file_url: &str,
   target_path: &Path,
 ) -> Result<()> {
-  let suffix = target_path
-    .extension()
-    .is_some_and(|ext| ext == "zst")
-    .then_some(".zst");
   let version = env!("CARGO_PKG_VERSION");
-  let url_version = format!(
-    "{}/{}?version={}",
-    base_url,
-    file_url(user_version, point, suffix),
-    version
-  );
+  let url_version = format!("{}/{}?version={}", base_url, file_url, version);
   println!(
     "Downloading from {}",
     url_version.split('?').next().unwrap_or(&url_version)
   );
-  let mut resp = client
-    .get(&url_version)
-    .send()
-    .context("Failed to send request")?;
-  if !resp.status().is_success() {
-    anyhow::bail!(
-      "Failed to download file {}: HTTP status {}",
-      url_version,
-      resp.status()
-    );
-  }
-  let mut file = File::create(target_path).context("Failed to create file")?;
-  resp
-    .copy_to(&mut file)
-    .context("Failed to copy response to file")?;
-  Ok(())
+  source::fetch_file(source, &Url::parse(&url_version)?, target_path).await
+}
+
+/// URL of the restore point file listed in the metadata, resolved against the
+/// URL of `metadata.csv`. `None` if it isn't listed.
+fn listed_url(
+  base_url: &str,
+  db: Database,
+  user_version: usize,
+  p: &RestorePoint,
+) -> Result<Option<Url>> {
+  let Some(url) = &p.url else {
+    return Ok(None);
+  };
+  let metadata = Url::parse(&metadata_url(base_url, db, user_version))?;
+  let url = metadata
+    .join(url)
+    .with_context(|| format!("invalid URL of restore point {p}"))?;
+  Ok(Some(url))
+}
+
+/// Whether the file isn't on the server, so another name can be tried.
+fn is_not_found(e: &anyhow::Error) -> bool {
+  e.downcast_ref::<FileStatus>()
+    .is_some_and(|e| e.status == StatusCode::NOT_FOUND)
 }
 
+/// Decompresses the downloaded diff at `input_path` into `output_path`, in the
+/// format detected from its content. Uncompressed diffs are just moved.
 fn decompress_file(input_path: &Path, output_path: &Path) -> Result<()> {
+  if !unpack::is_compressed(input_path)? {
+    return fs::rename(input_path, output_path)
+      .with_context(|| format!("moving {}", input_path.display()));
+  }
   let input_file = File::open(input_path).context("Failed to open input file")?;
   let output_file = File::create(output_path).context("Failed to create output file")?;
 
-  let mut reader = BufReader::new(input_file);
+  let reader = BufReader::new(input_file);
   let mut writer = BufWriter::new(output_file);
 
-  let mut decoder = Decoder::new(&mut reader).context("Failed to create decoder")?;
-  decoder
-    .window_log_max(31)
-    .context("Failed to set window log max")?;
+  unpack::with_decoder(reader, input_path, |decoder| {
+    io::copy(decoder, &mut writer).context("Failed to decompress")?;
+    writer.flush()?;
+    Ok(())
+  })?;
+  fs::remove_file(input_path).with_context(|| format!("removing {}", input_path.display()))
+}
 
-  io::copy(&mut decoder, &mut writer).context("Failed to decompress")?;
+/// Downloads the base database of `db` published for `user_version` into place,
+/// to start a node without downloading the full snapshot. All restore points
+/// after it are applied next.
+pub async fn bootstrap(
+  source: &dyn SnapshotSource,
+  base_url: &str,
+  db: Database,
+  user_version: usize,
+  state_db_path: &Path,
+  download_path: &Path,
+) -> Result<()> {
+  let download = download_path.join("base.db.download");
+  let base_db = download_path.join("base.db");
+  println!(
+    "{} not found, downloading the base database for user_version={user_version}",
+    db.file_name()
+  );
+  let mut found = None;
+  for suffix in DIFF_SUFFIXES {
+    let file_url = base_db_url(db, user_version, suffix);
+    match fetch_file(source, base_url, &file_url, &download).await {
+      Ok(()) => {
+        found = Some(file_url);
+        break;
+      }
+      Err(e) if is_not_found(&e) => {}
+      Err(e) => return Err(e),
+    }
+  }
+  let file_url = found.context("no base database is published")?;
+  // The base database is installed as it is, unlike the diffs checked by hash
+  let md5_url = Url::parse(&format!("{base_url}/{file_url}.md5"))?;
+  let expected = checksum::download_checksum(md5_url, &ChecksumOptions::default())
+    .await
+    .context("downloading the checksum of the base database")?;
+  let path = download.clone();
+  let actual = tokio::task::spawn_blocking(move || {
+    let io = IoOptions {
+      buffer_size: 1024 * 1024,
+      no_page_cache: false,
+      hash_threads: 1,
+    };
+    checksum::calculate_checksum(&path, io)
+  })
+  .await??;
+  if actual != expected {
+    fs::remove_file(&download).with_context(|| format!("removing {}", download.display()))?;
+    anyhow::bail!("the checksum of the base database is invalid: {actual} instead of {expected}");
+  }
+  let (input, output) = (download.clone(), base_db.clone());
+  tokio::task::spawn_blocking(move || decompress_file(&input, &output)).await??;
 
-  Ok(())
+  let conn = Connection::open(&base_db)?;
+  let base_version = get_user_version(&conn)?;
+  anyhow::ensure!(
+    base_version == user_version,
+    "the base database has user_version={base_version} instead of {user_version}"
+  );
+  if db == Database::State {
+    println!(
+      "The base database has layers up to {}",
+      get_latest_from_db(&conn)?
+    );
+  }
+  conn.close().map_err(|(_, e)| e)?;
+  // All restore points are applied to a base database without layers
+  let record = db.layer_record_path(state_db_path);
+  if db != Database::State && record.try_exists()? {
+    fs::remove_file(&record).with_context(|| format!("removing {}", record.display()))?;
+  }
+  file_in_use::move_file(&base_db, &db.path(state_db_path))
 }
 
-fn get_restore_points(
+/// Finds restore points for `db` based on the latest layer it's synced to: the
+/// one in the database for `state.sql`, the one recorded next to it for the
+/// databases without layers.
+async fn get_restore_points(
   base_url: &str,
-  target_db_path: &Path,
+  db: Database,
+  state_db_path: &Path,
   untrusted_layers: u32,
   jump_back: usize,
+  auto_jump_back: Option<usize>,
 ) -> Result<(Vec<RestorePoint>, String, usize)> {
-  let client = Client::new();
-  let conn = Connection::open(target_db_path)?;
+  let client = transport::small_files_builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let conn = sql::open_read_only(&db.path(state_db_path))?;
   let user_version = get_user_version(&conn)?;
-  let response = client
-    .get(format!(
-      "{}/{}/metadata.csv?version={}",
-      base_url,
-      user_version,
-      env!("CARGO_PKG_VERSION")
-    ))
-    .send()
-    .with_context(|| {
-      format!(
-        "Failed to fetch remote metadata.csv for user_version={}",
-        user_version
-      )
-    })?;
+  let remote_metadata = fetch_metadata(&client, base_url, db, user_version).await?;
 
-  if response.status() == reqwest::StatusCode::NOT_FOUND {
-    anyhow::bail!(
-      "Remote server returned 404 for metadata.csv. User version {} might not exist.",
+  let layer_from = match db.latest_layer(state_db_path)? {
+    Some(latest_layer) => (latest_layer + 1).saturating_sub(untrusted_layers),
+    // Applying a restore point again doesn't change the database
+    None => {
+      println!(
+        "The latest layer of {} is unknown, applying all restore points",
+        db.file_name()
+      );
+      let first = remote_metadata.trim().lines().next().map(str::trim);
+      first.map_or(Ok(0), |line| RestorePoint::from_str(line).map(|p| p.from))?
+    }
+  };
+  let jump_back = match auto_jump_back {
+    Some(max_depth) if db == Database::State => {
+      let depth = find_jump_back(layer_from, &remote_metadata, max_depth, &conn)?;
+      let depth = depth.with_context(|| {
+        format!(
+          "None of the {max_depth} restore points before layer {layer_from} continues the database"
+        )
+      })?;
+      if depth > 0 {
+        println!("Jumping back {depth} restore points to where the database matches");
+      }
+      depth
+    }
+    _ => jump_back,
+  };
+  let start_points = find_restore_points(layer_from, &remote_metadata, jump_back)?;
+  anyhow::ensure!(
+    !start_points.is_empty(),
+    "No suitable restore points found, seems that {} is too old",
+    db.file_name()
+  );
+
+  Ok((start_points, remote_metadata, user_version))
+}
+
+fn metadata_url(base_url: &str, db: Database, user_version: usize) -> String {
+  format!(
+    "{}/{}{}/metadata.csv?version={}",
+    base_url,
+    db.namespace(),
+    user_version,
+    env!("CARGO_PKG_VERSION")
+  )
+}
+
+/// How far from the database's schema version other published versions are
+/// looked for.
+const MAX_VERSION_DISTANCE: usize = 5;
+
+/// Finds the published schema version nearest to `user_version`, the newer
+/// one first if two are as near.
+async fn nearest_published_version(
+  client: &Client,
+  base_url: &str,
+  db: Database,
+  user_version: usize,
+) -> Option<usize> {
+  for distance in 1..=MAX_VERSION_DISTANCE {
+    let candidates = [
+      user_version.checked_add(distance),
+      user_version.checked_sub(distance),
+    ];
+    for version in candidates.into_iter().flatten() {
+      let url = metadata_url(base_url, db, version);
+      match client.head(&url).send_traced().await {
+        Ok(response) if response.status().is_success() => return Some(version),
+        _ => {}
+      }
+    }
+  }
+  None
+}
+
+/// Tells what to do about the server not publishing restore points for the
+/// schema version of the database.
+fn describe_unpublished_version(
+  db: Database,
+  user_version: usize,
+  nearest: Option<usize>,
+) -> String {
+  let advice = match nearest {
+    Some(nearest) if nearest > user_version => format!(
+      "The server publishes user_version={nearest}: upgrade go-spacemesh to the release with \
+       that schema and start it once to migrate {}",
+      db.file_name()
+    ),
+    Some(nearest) => format!(
+      "The server publishes user_version={nearest}, so the restore points for this schema may \
+       not be published yet: wait for them, or downgrade go-spacemesh to the release with \
+       user_version={nearest}"
+    ),
+    None => format!(
+      "No schema version within {MAX_VERSION_DISTANCE} of it is published either: check \
+       --base-url or download the full snapshot with `quicksync download`"
+    ),
+  };
+  format!(
+    "The server has no restore points for {} with user_version={user_version}. {advice}",
+    db.file_name()
+  )
+}
+
+async fn fetch_metadata(
+  client: &Client,
+  base_url: &str,
+  db: Database,
+  user_version: usize,
+) -> Result<String> {
+  let url = metadata_url(base_url, db, user_version);
+  let response = match http_cache::get_text(client, &url).await.with_context(|| {
+    format!(
+      "Failed to fetch remote metadata.csv for user_version={}",
       user_version
-    );
+    )
+  })? {
+    Ok(metadata) => return Ok(metadata),
+    Err(response) => response,
+  };
+
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    let nearest = nearest_published_version(client, base_url, db, user_version).await;
+    anyhow::bail!(describe_unpublished_version(db, user_version, nearest));
   }
 
-  let remote_metadata = response.text().with_context(|| {
+  response.text().await.with_context(|| {
     format!(
       "Failed to read remote metadata.csv for user_version={}",
       user_version
     )
-  })?;
+  })
+}
 
-  let latest_layer = get_latest_from_db(&conn)?;
-  let layer_from = (latest_layer + 1).saturating_sub(untrusted_layers);
-  let start_points = find_restore_points(layer_from, &remote_metadata, jump_back);
-  anyhow::ensure!(
-    !start_points.is_empty(),
-    "No suitable restore points found, seems that state.sql is too old"
-  );
+/// How the restore points are applied by [`incremental_restore`].
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+  /// Number of the latest layers in the database synced again.
+  pub untrusted_layers: u32,
+  /// Number of restore points to start earlier.
+  pub jump_back: usize,
+  /// Instead of `jump_back`, start at the latest restore point continuing
+  /// the database, looking at most this many restore points back.
+  pub auto_jump_back: Option<usize>,
+  /// Tables the restore script doesn't write to.
+  pub skip_tables: Vec<String>,
+  /// Number of consecutive restore points applied in one transaction.
+  pub batch_size: usize,
+  /// Apply the restore points the database already has too.
+  pub reapply: bool,
+}
 
-  Ok((start_points, remote_metadata, user_version))
+/// Number of the first restore points already in the state database: they
+/// end before `trusted_to` (the untrusted layers are always restored) and the
+/// database has their last layer with the hash the next restore point starts
+/// from. The last restore point is always applied.
+fn count_applied(points: &[RestorePoint], trusted_to: u32, conn: &Connection) -> usize {
+  points
+    .windows(2)
+    .take_while(|pair| {
+      let next = &pair[1];
+      pair[0].to <= trusted_to
+        && pair[0].to == next.from
+        && get_previous_hash(next.from, conn).is_ok_and(|hash| hash == next.hash[..4])
+    })
+    .count()
 }
 
-pub fn incremental_restore(
-  base_url: &str,
-  target_db_path: &Path,
-  download_path: &Path,
-  untrusted_layers: u32,
-  jump_back: usize,
+/// Checks that the restore point continues the database. The restore points
+/// in `metadata` are looked through for the diagnostics if it doesn't, back
+/// from the one with `layer_from`, the first untrusted layer of the database.
+fn check_continuity(
+  db: Database,
+  p: &RestorePoint,
+  layer_from: u32,
+  metadata: &str,
+  conn: &Connection,
 ) -> Result<()> {
-  let (start_points, _, user_version) =
-    get_restore_points(base_url, target_db_path, untrusted_layers, jump_back)?;
-  let client = Client::new();
-
-  let restore_string = client
-    .get(format!(
-      "{}/{}/restore.sql?version={}",
-      base_url,
-      user_version,
-      env!("CARGO_PKG_VERSION")
-    ))
-    .send()?
-    .text()?;
+  // Only the state database has layer hashes to check the continuity against
+  if db == Database::State && p.from != 0 {
+    let previous_hash = get_previous_hash(p.from, conn)?;
+    anyhow::ensure!(
+      previous_hash == p.hash[..4],
+      "{}",
+      describe_divergence(p, &previous_hash, layer_from, metadata, conn)
+    );
+  }
+  Ok(())
+}
 
-  let total = start_points.len();
-  println!(
-    "Looking for restore points with untrusted_layers={untrusted_layers}, jump_back={jump_back}"
+/// Explains that the database has the `local_hash` at the layer before the
+/// restore point, and how to recover: from the nearest earlier restore point
+/// continuing the database, or with a full sync if none does. The depth is
+/// counted from the restore point with `layer_from`, as `--auto-jump-back` does.
+fn describe_divergence(
+  p: &RestorePoint,
+  local_hash: &str,
+  layer_from: u32,
+  metadata: &str,
+  conn: &Connection,
+) -> String {
+  let mut message = format!(
+    "unexpected hash: the database diverged from the restore points at layer {}\n  \
+     local hash:    {local_hash}\n  \
+     expected hash: {} (restore point {}..{})",
+    p.from - 1,
+    &p.hash[..4],
+    p.from,
+    p.to
   );
-  println!("Found {total} potential restore points");
+  // The metadata was parsed before the restore points were applied
+  let nearest = find_jump_back(layer_from, metadata, usize::MAX, conn)
+    .ok()
+    .flatten()
+    .and_then(|depth| {
+      let points = find_restore_points(layer_from, metadata, depth).ok()?;
+      points.into_iter().next().map(|nearest| (depth, nearest))
+    });
+  match nearest {
+    Some((depth, nearest)) => {
+      message.push_str(&format!(
+        "\n  nearest matching restore point: {}..{}, {depth} back\n\
+         Run `quicksync incremental` with `--auto-jump-back {depth}` to restore from it",
+        nearest.from, nearest.to
+      ));
+    }
+    None => message.push_str(
+      "\n  no earlier restore point matches the database\n\
+       Download the full snapshot with `quicksync download` instead",
+    ),
+  }
+  message
+}
 
-  let source_db_path_zst = &download_path.join("backup_source.db.zst");
-  let source_db_path = &download_path.join("backup_source.db");
+/// Downloads and applies the restore points of a database.
+struct Restorer<'a> {
+  source: &'a dyn SnapshotSource,
+  base_url: &'a str,
+  db: Database,
+  user_version: usize,
+  target_db_path: PathBuf,
+  download_path: &'a Path,
+  restore_string: String,
+  /// Whether statements were dropped from the restore script.
+  filtered: bool,
+  /// The published restore points, for the diagnostics of a divergence.
+  metadata: String,
+  untrusted_layers: u32,
+  suffixes: [&'static str; 5],
+}
+
+impl Restorer<'_> {
+  /// The first untrusted layer of the database, where the next run would
+  /// start restoring from.
+  fn layer_from(&self, conn: &Connection) -> u32 {
+    match self.db {
+      Database::State => get_latest_from_db(conn).map_or(0, |latest| {
+        (latest + 1).saturating_sub(self.untrusted_layers)
+      }),
+      _ => 0,
+    }
+  }
 
-  for (idx, p) in start_points.into_iter().enumerate() {
+  /// Downloads the diff of the restore point into `output`, decompressed.
+  async fn download(&mut self, p: &RestorePoint, output: &Path) -> Result<()> {
+    let download = self.download_path.join("backup_source.db.download");
+    match listed_url(self.base_url, self.db, self.user_version, p)? {
+      // Listed URLs may be signed, so they're used as they are
+      Some(url) => {
+        url_policy::check(&url)?;
+        println!("Downloading from {}", http_trace::redact_url(&url));
+        source::fetch_file(self.source, &url, &download).await?;
+      }
+      None => self.download_by_convention(p, &download).await?,
+    }
+    let output = output.to_path_buf();
+    tokio::task::spawn_blocking(move || decompress_file(&download, &output)).await?
+  }
+
+  /// Downloads the diff of the restore point from [`file_url`], trying the
+  /// suffixes until one is found.
+  async fn download_by_convention(&mut self, p: &RestorePoint, download: &Path) -> Result<()> {
+    for (i, suffix) in self.suffixes.iter().enumerate() {
+      let result = download_file(
+        self.source,
+        self.base_url,
+        self.db,
+        self.user_version,
+        p,
+        Some(suffix),
+        download,
+      )
+      .await;
+      match result {
+        Ok(()) => {
+          self.suffixes[..=i].rotate_right(1);
+          return Ok(());
+        }
+        // Other errors, e.g. of the network, aren't masked by the next suffix
+        Err(e) if is_not_found(&e) && i + 1 < self.suffixes.len() => {}
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+
+  fn source_db_path(&self) -> PathBuf {
+    self.download_path.join("backup_source.db")
+  }
+
+  async fn apply(&mut self, p: &RestorePoint) -> Result<RowCounts> {
     // Reopen the DB on each iteration to force flushing all operations
     // on the end of each iteration, when the connection is closed.
     //
     // Note: the restore SQL query attaches the downloaded DB, but it
     // does not DETACH it because it causes problems.
-    let conn = Connection::open(target_db_path)?;
-    if p.from != 0 {
-      let previous_hash = get_previous_hash(p.from, &conn)?;
-      anyhow::ensure!(
-        previous_hash == p.hash[..4],
-        "unexpected hash: '{previous_hash}' doesn't match restore point {p:?}",
-      );
+    control::checkpoint().await?;
+    let conn = Connection::open(&self.target_db_path)?;
+    let layer_from = self.layer_from(&conn);
+    check_continuity(self.db, p, layer_from, &self.metadata, &conn)?;
+    let source_db_path = self.source_db_path();
+    self.download(p, &source_db_path).await?;
+
+    let (restore_string, filtered) = (self.restore_string.clone(), self.filtered);
+    let counts = tokio::task::spawn_blocking(move || {
+      let counts = restore_filter::execute(&conn, &restore_string, filtered)?;
+      conn
+        .close()
+        .map_err(|(_, e)| e)
+        .context("closing DB connection")?;
+      anyhow::Ok(counts)
+    })
+    .await??;
+    fs::remove_file(&source_db_path)
+      .with_context(|| format!("removing {}", source_db_path.display()))?;
+    Ok(counts)
+  }
+
+  /// Applies consecutive restore points in one transaction. Their diffs are
+  /// downloaded first, the restore script runs for each of them in turn, and
+  /// the continuity of each of them is checked before committing.
+  async fn apply_batch(&mut self, batch: &[RestorePoint]) -> Result<RowCounts> {
+    control::checkpoint().await?;
+    let conn = Connection::open(&self.target_db_path)?;
+    // Computed before the transaction, which is rolled back on a divergence
+    let layer_from = self.layer_from(&conn);
+    check_continuity(self.db, &batch[0], layer_from, &self.metadata, &conn)?;
+    let mut diffs = Vec::new();
+    for (i, p) in batch.iter().enumerate() {
+      let diff_path = self.download_path.join(format!("backup_source.{i}.db"));
+      self.download(p, &diff_path).await?;
+      diffs.push(diff_path);
     }
 
-    if download_file(&client, base_url, user_version, &p, source_db_path_zst).is_err() {
-      download_file(&client, base_url, user_version, &p, source_db_path)?;
-    } else {
-      decompress_file(source_db_path_zst, source_db_path)?;
-      fs::remove_file(source_db_path_zst)
-        .with_context(|| format!("removing {}", source_db_path_zst.display()))?;
+    let restore_string = self.restore_string.clone();
+    let (db, points, metadata) = (self.db, batch[1..].to_vec(), self.metadata.clone());
+    let paths = diffs.clone();
+    let counts = tokio::task::spawn_blocking(move || {
+      let counts =
+        restore_filter::execute_diffs_in_transaction(&conn, &restore_string, &paths, |conn| {
+          points
+            .iter()
+            .try_for_each(|p| check_continuity(db, p, layer_from, &metadata, conn))
+        })?;
+      conn
+        .close()
+        .map_err(|(_, e)| e)
+        .context("closing DB connection")?;
+      anyhow::Ok(counts)
+    })
+    .await?;
+    for diff_path in diffs {
+      fs::remove_file(&diff_path).with_context(|| format!("removing {}", diff_path.display()))?;
     }
+    counts
+  }
+}
+
+/// Applies the restore points after the local layers, skipping the ones ending
+/// at or before `applied_to`, which were applied already. Returns the end of
+/// the last applied restore point.
+pub async fn incremental_restore(
+  source: &dyn SnapshotSource,
+  base_url: &str,
+  db: Database,
+  state_db_path: &Path,
+  download_path: &Path,
+  options: &RestoreOptions,
+  applied_to: u32,
+) -> Result<u32> {
+  let RestoreOptions {
+    untrusted_layers,
+    jump_back,
+    auto_jump_back,
+    ref skip_tables,
+    batch_size,
+    reapply,
+  } = *options;
+  let (start_points, metadata, user_version) = get_restore_points(
+    base_url,
+    db,
+    state_db_path,
+    untrusted_layers,
+    jump_back,
+    auto_jump_back,
+  )
+  .await?;
+  let mut start_points: Vec<RestorePoint> = start_points
+    .into_iter()
+    .filter(|p| p.to > applied_to)
+    .collect();
+  let Some(last) = start_points.last() else {
+    println!("No new restore points for {}", db.file_name());
+    return Ok(applied_to);
+  };
+  let last_to = last.to;
+  let target_db_path = db.path(state_db_path);
+  if db == Database::State && !reapply {
+    let conn = sql::open_read_only(&target_db_path)?;
+    let trusted_to = (get_latest_from_db(&conn)? + 1).saturating_sub(untrusted_layers);
+    let applied = count_applied(&start_points, trusted_to, &conn);
+    if applied > 0 {
+      println!("Skipping {applied} restore points already in the database");
+      start_points.drain(..applied);
+    }
+  }
+  let client = transport::small_files_builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+
+  let restore_url = format!(
+    "{}/{}{}/restore.sql?version={}",
+    base_url,
+    db.namespace(),
+    user_version,
+    env!("CARGO_PKG_VERSION")
+  );
+  let restore_string = match http_cache::get_text(&client, &restore_url).await? {
+    Ok(restore_string) => restore_string,
+    Err(response) => response.text().await?,
+  };
+  let (restore_string, skipped) = restore_filter::filter(&restore_string, skip_tables);
+  if !skipped.is_empty() {
+    println!("Not restoring the tables: {}", skipped.join(", "));
+  }
 
-    let current_idx = idx + 1;
+  let total = start_points.len();
+  println!(
+    "Looking for restore points with untrusted_layers={untrusted_layers}, jump_back={jump_back}"
+  );
+  println!("Found {total} potential restore points");
+  events::stage(Stage::Restore);
+
+  let mut restorer = Restorer {
+    source,
+    base_url,
+    db,
+    user_version,
+    target_db_path,
+    download_path,
+    restore_string,
+    filtered: !skipped.is_empty(),
+    metadata,
+    untrusted_layers,
+    // The suffix found for a point is tried first for the next ones
+    suffixes: DIFF_SUFFIXES,
+  };
+  let mut total_counts = RowCounts::new();
+  let mut done = 0;
+  let mut applied = |done: usize, p: (u32, u32), elapsed: Duration, counts: &RowCounts| {
     println!(
-      "[{current_idx}/{total}] Restoring from {} to {}...",
-      p.from, p.to
+      "[{done}/{total}] Restored {} to {} in {elapsed:?} ({})",
+      p.0,
+      p.1,
+      restore_filter::format_counts(counts)
     );
-    let start = Instant::now();
-    conn
-      .execute_batch(&restore_string)
-      .context("executing restore")?;
-    conn.close().expect("closing DB connection");
+    restore_filter::add(&mut total_counts, counts);
+    events::emit(Event::Progress {
+      stage: Stage::Restore,
+      done: done as u64,
+      total: Some(total as u64),
+      bytes_per_sec: None,
+    });
+  };
+
+  for batch in start_points.chunks(batch_size.clamp(1, restore_filter::MAX_DIFFS)) {
+    if let [first, .., last] = batch {
+      println!(
+        "[{}-{}/{total}] Restoring from {} to {} in one transaction...",
+        done + 1,
+        done + batch.len(),
+        first.from,
+        last.to
+      );
+      let start = Instant::now();
+      match restorer.apply_batch(batch).await {
+        Ok(counts) => {
+          db.record_layer(state_db_path, last.to)?;
+          done += batch.len();
+          applied(done, (first.from, last.to), start.elapsed(), &counts);
+          continue;
+        }
+        // Cancelled
+        Err(e) if ExitError::find(&e).is_some() => return Err(e),
+        Err(e) => println!("Cannot restore them in one transaction, restoring one by one: {e:#}"),
+      }
+    }
+    for p in batch {
+      println!(
+        "[{}/{total}] Restoring from {} to {}...",
+        done + 1,
+        p.from,
+        p.to
+      );
+      let start = Instant::now();
+      let counts = restorer.apply(p).await?;
+      db.record_layer(state_db_path, p.to)?;
+      done += 1;
+      applied(done, (p.from, p.to), start.elapsed(), &counts);
+    }
+  }
+  println!(
+    "Rows changed in {}: {}",
+    db.file_name(),
+    restore_filter::format_counts(&total_counts)
+  );
+  Ok(last_to)
+}
+
+/// Rewinds the state database to the end of the latest restore point ending
+/// at or before `to_layer` by applying the reverse diffs of the restore points
+/// after it, newest first. The layer hash is checked after each of them.
+/// Returns the latest layer in the database.
+pub async fn rollback(
+  source: &dyn SnapshotSource,
+  base_url: &str,
+  state_db_path: &Path,
+  download_path: &Path,
+  to_layer: u32,
+) -> Result<u32> {
+  let client = transport::small_files_builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let conn = Connection::open(state_db_path)?;
+  let user_version = get_user_version(&conn)?;
+  let latest = get_latest_from_db(&conn)?;
+  conn.close().map_err(|(_, e)| e)?;
+
+  let metadata = fetch_metadata(&client, base_url, Database::State, user_version).await?;
+  let points = metadata
+    .trim()
+    .lines()
+    .map(|line| RestorePoint::from_str(line.trim()))
+    .collect::<Result<Vec<_>>>()?;
+  // Restore points start after the layer they check the hash of
+  let target = points
+    .iter()
+    .map(|p| p.from)
+    .filter(|&from| from > 0 && from - 1 <= to_layer)
+    .max()
+    .with_context(|| format!("no restore point ends at or before layer {to_layer}"))?;
+  let reversed: Vec<&RestorePoint> = points
+    .iter()
+    .filter(|p| p.from >= target && p.from <= latest)
+    .rev()
+    .collect();
+  if reversed.is_empty() {
+    println!("The latest layer is {latest}, nothing to roll back");
+    return Ok(latest);
+  }
+
+  let rollback_url = format!(
+    "{}/{}/rollback.sql?version={}",
+    base_url,
+    user_version,
+    env!("CARGO_PKG_VERSION")
+  );
+  let rollback_string = match http_cache::get_text(&client, &rollback_url).await? {
+    Ok(rollback_string) => rollback_string,
+    Err(response) => anyhow::bail!(
+      "the server doesn't publish reverse diffs for user_version={user_version}: HTTP status {}",
+      response.status()
+    ),
+  };
+
+  let total = reversed.len();
+  println!("Rolling back from layer {latest} to {}", target - 1);
+  events::stage(Stage::Restore);
+  let source_db_download = &download_path.join("backup_source.db.download");
+  let source_db_path = &download_path.join("backup_source.db");
+  for (idx, p) in reversed.into_iter().enumerate() {
+    control::checkpoint().await?;
+    let mut result = Ok(());
+    for suffix in DIFF_SUFFIXES {
+      let file_url = reverse_file_url(user_version, p, suffix);
+      result = fetch_file(source, base_url, &file_url, source_db_download).await;
+      if !matches!(&result, Err(e) if is_not_found(e)) {
+        break;
+      }
+    }
+    result.with_context(|| format!("no reverse diff for restore point {p}"))?;
+    let (input, output) = (source_db_download.clone(), source_db_path.clone());
+    tokio::task::spawn_blocking(move || decompress_file(&input, &output)).await??;
 
-    let duration = start.elapsed();
     println!(
-      "[{current_idx}/{total}] Restored {} to {} in {:?}",
-      p.from, p.to, duration
+      "[{}/{total}] Rolling back {} to {}...",
+      idx + 1,
+      p.to,
+      p.from - 1
     );
-
+    let (db_path, rollback_string) = (state_db_path.to_path_buf(), rollback_string.clone());
+    let p = p.clone();
+    tokio::task::spawn_blocking(move || {
+      let conn = Connection::open(db_path)?;
+      // The reverse diff is undone if the database isn't as expected after it
+      restore_filter::execute_in_transaction(&conn, &rollback_string, |conn| {
+        let rewound_to = get_latest_from_db(conn)?;
+        let hash = get_previous_hash(p.from, conn)?;
+        anyhow::ensure!(
+          rewound_to == p.from - 1 && hash == p.hash[..4],
+          "unexpected state after rolling back restore point {p}: latest layer {rewound_to} with hash '{hash}'"
+        );
+        Ok(())
+      })
+      .context("executing rollback")?;
+      conn.close().map_err(|(_, e)| e)?;
+      anyhow::Ok(())
+    })
+    .await??;
+    events::emit(Event::Progress {
+      stage: Stage::Restore,
+      done: idx as u64 + 1,
+      total: Some(total as u64),
+      bytes_per_sec: None,
+    });
     fs::remove_file(source_db_path)
       .with_context(|| format!("removing {}", source_db_path.display()))?;
   }
-  Ok(())
+  Ok(target - 1)
+}
+
+/// A layer whose hash in the local database differs from the published one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+  pub layer: u32,
+  pub local_hash: String,
+  pub published_hash: String,
+}
+
+/// Compares the hashes of up to `count` latest layers the restore points are
+/// checked against with the local state database. Returns the number of layers
+/// compared and the oldest one that diverged, if any.
+pub async fn cross_check(
+  base_url: &str,
+  state_db_path: &Path,
+  count: usize,
+) -> Result<(usize, Option<Divergence>)> {
+  let client = transport::small_files_builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let conn = sql::open_read_only(state_db_path)?;
+  let user_version = get_user_version(&conn)?;
+  let latest = get_latest_from_db(&conn)?;
+  let metadata = fetch_metadata(&client, base_url, Database::State, user_version).await?;
+  let points = metadata
+    .trim()
+    .lines()
+    .map(|line| RestorePoint::from_str(line.trim()))
+    .collect::<Result<Vec<_>>>()?;
+  let checked: Vec<&RestorePoint> = points
+    .iter()
+    .filter(|p| p.from > 0 && p.from - 1 <= latest)
+    .collect();
+
+  let mut compared = 0;
+  for p in &checked[checked.len().saturating_sub(count)..] {
+    // The layer may be missing after pruning or in a database synced from peers
+    let Ok(local_hash) = get_previous_hash(p.from, &conn) else {
+      continue;
+    };
+    compared += 1;
+    if local_hash != p.hash[..4] {
+      return Ok((
+        compared,
+        Some(Divergence {
+          layer: p.from - 1,
+          local_hash,
+          published_hash: p.hash[..4].to_string(),
+        }),
+      ));
+    }
+  }
+  Ok((compared, None))
 }
 
-pub fn check_for_restore_points(
+pub async fn check_for_restore_points(
   base_url: &str,
-  target_db_path: &Path,
+  db: Database,
+  state_db_path: &Path,
   untrusted_layers: u32,
   jump_back: usize,
+  auto_jump_back: Option<usize>,
 ) -> Result<()> {
-  let (start_points, _, _) =
-    get_restore_points(base_url, target_db_path, untrusted_layers, jump_back)?;
+  let (start_points, _, _) = get_restore_points(
+    base_url,
+    db,
+    state_db_path,
+    untrusted_layers,
+    jump_back,
+    auto_jump_back,
+  )
+  .await?;
 
   anyhow::ensure!(!start_points.is_empty(), "No restore points available.");
 
@@ -293,11 +1195,39 @@ pub fn check_for_restore_points(
   Ok(())
 }
 
+/// Total size of the restore points syncing the state database at
+/// `state_db_path`, and the layer they restore up to (exclusive).
+/// The size is known only if the server publishes metadata v2.
+pub async fn restore_size(
+  base_url: &str,
+  state_db_path: &Path,
+  untrusted_layers: u32,
+) -> Result<(Option<u64>, u32)> {
+  let (points, _, _) = get_restore_points(
+    base_url,
+    Database::State,
+    state_db_path,
+    untrusted_layers,
+    0,
+    None,
+  )
+  .await?;
+  let size = points.iter().map(|p| p.size).sum();
+  let to = points.last().map_or(0, |p| p.to);
+  Ok((size, to))
+}
+
 #[cfg(test)]
 impl RestorePoint {
   fn new<H: Into<String>>(from: u32, to: u32, hash: H) -> Self {
     let hash = hash.into();
-    Self { from, to, hash }
+    Self {
+      from,
+      to,
+      hash,
+      size: None,
+      url: None,
+    }
   }
 }
 
@@ -308,6 +1238,12 @@ mod tests {
   use rusqlite::{Connection, DatabaseName};
   use tempfile::tempdir;
 
+  use crate::source::HttpSource;
+
+  fn http() -> HttpSource {
+    HttpSource::new().unwrap()
+  }
+
   fn create_test_db(path: Option<&Path>) -> Connection {
     let conn = match path {
       Some(path) => Connection::open(path).unwrap(),
@@ -329,10 +1265,64 @@ mod tests {
     200,300,ijkl
     "#;
     // 90-100 are not available for restore
-    let result = find_restore_points(90, metadata, 0);
+    let result = find_restore_points(90, metadata, 0).unwrap();
     assert!(result.is_empty());
   }
 
+  #[test]
+  fn malformed_restore_points_are_errors() {
+    let metadata = r#"
+    100,200,bbbb
+    200,three hundred,ijkl
+    "#;
+    let err = find_restore_points(90, metadata, 0).unwrap_err();
+    assert!(format!("{err}").contains("line 2: '200,three hundred,ijkl'"));
+  }
+
+  #[test]
+  fn parsing_restore_points() {
+    let v1: RestorePoint = "100,200,abcd".parse().unwrap();
+    assert_eq!(v1, RestorePoint::new(100, 200, "abcd"));
+    let v2: RestorePoint = "100,200,abcd,52428800".parse().unwrap();
+    assert_eq!(v2.size, Some(52_428_800));
+    assert_eq!(v2.to_string(), "100,200,abcd,52428800");
+    assert!("100,200".parse::<RestorePoint>().is_err());
+    assert!("100,200,abcd,big".parse::<RestorePoint>().is_err());
+
+    let v3: RestorePoint = "100,200,abcd,,points/100.sql.zst".parse().unwrap();
+    assert_eq!(v3.size, None);
+    assert_eq!(v3.url.as_deref(), Some("points/100.sql.zst"));
+    assert_eq!(v3.to_string(), "100,200,abcd,,points/100.sql.zst");
+    assert!("100,200,abcd,5,".parse::<RestorePoint>().is_err());
+
+    let signed = "https://cdn.spacemesh.network/100.sql.zst?keys=a,b&sig=abc";
+    let v3: RestorePoint = format!("100,200,abcd,5,{signed}").parse().unwrap();
+    assert_eq!(v3.url.as_deref(), Some(signed));
+  }
+
+  #[test]
+  fn resolving_listed_urls() {
+    let base = "https://quicksync-partials.spacemesh.network";
+    let mut point = RestorePoint::new(100, 200, "abcd");
+    assert_eq!(listed_url(base, Database::State, 3, &point).unwrap(), None);
+    point.url = Some("points/100.sql.zst".to_string());
+    assert_eq!(
+      listed_url(base, Database::Atx, 3, &point)
+        .unwrap()
+        .unwrap()
+        .as_str(),
+      "https://quicksync-partials.spacemesh.network/atx/3/points/100.sql.zst"
+    );
+    point.url = Some("https://cdn.spacemesh.network/100.sql.zst?sig=abc".to_string());
+    assert_eq!(
+      listed_url(base, Database::State, 3, &point)
+        .unwrap()
+        .unwrap()
+        .as_str(),
+      "https://cdn.spacemesh.network/100.sql.zst?sig=abc"
+    );
+  }
+
   #[test]
   fn finding_restore_points() {
     let points = [
@@ -346,43 +1336,75 @@ mod tests {
       .collect::<Vec<_>>()
       .join("\n");
 
-    let result = find_restore_points(99, metadata, 0);
+    let result = find_restore_points(99, metadata, 0).unwrap();
     assert_eq!(result, points);
 
-    let result = find_restore_points(100, metadata, 0);
+    let result = find_restore_points(100, metadata, 0).unwrap();
     assert_eq!(result, points[1..]);
 
-    let result = find_restore_points(101, metadata, 0);
+    let result = find_restore_points(101, metadata, 0).unwrap();
     assert_eq!(result, points[1..]);
 
-    let result = find_restore_points(101, metadata, 1);
+    let result = find_restore_points(101, metadata, 1).unwrap();
     assert_eq!(result, points);
 
-    let result = find_restore_points(150, metadata, 0);
+    let result = find_restore_points(150, metadata, 0).unwrap();
     assert_eq!(result, points[1..]);
 
-    let result = find_restore_points(150, metadata, 1);
+    let result = find_restore_points(150, metadata, 1).unwrap();
     assert_eq!(result, points);
 
     // `jump_back` over the first point
-    let result = find_restore_points(150, metadata, 5);
+    let result = find_restore_points(150, metadata, 5).unwrap();
     assert_eq!(result, points);
 
-    let result = find_restore_points(300, metadata, 0);
+    let result = find_restore_points(300, metadata, 0).unwrap();
     assert!(result.is_empty());
 
     // synced but jumping back 1
-    let result = find_restore_points(300, metadata, 1);
+    let result = find_restore_points(300, metadata, 1).unwrap();
     assert_eq!(result, points[2..]);
 
     // synced but jumping back 1
-    let result = find_restore_points(300, metadata, 2);
+    let result = find_restore_points(300, metadata, 2).unwrap();
     assert_eq!(result, points[1..]);
 
-    let result = find_restore_points(500, metadata, 1);
+    let result = find_restore_points(500, metadata, 1).unwrap();
     assert_eq!(result, points[2..]);
   }
 
+  #[test]
+  fn file_urls_are_namespaced_per_database() {
+    let point = RestorePoint::new(100, 200, "abcd");
+    assert_eq!(
+      file_url(Database::State, 3, &point, None),
+      "3/100_200_abcd/state.sql_diff.100_200.sql"
+    );
+    assert_eq!(
+      file_url(Database::Atx, 3, &point, Some(".zst")),
+      "atx/3/100_200_abcd/atx.sql_diff.100_200.sql.zst"
+    );
+    let state = Path::new("/data/state.sql");
+    assert_eq!(Database::State.path(state), state);
+    assert_eq!(Database::Atx.path(state), Path::new("/data/atx.sql"));
+  }
+
+  #[test]
+  fn recording_layers_per_database() {
+    let dir = tempdir().unwrap();
+    let state = dir.path().join("state.sql");
+    {
+      let conn = create_test_db(Some(&state));
+      insert_layer(&conn, 299, 100, &[0xBB, 0xBB]);
+    }
+    // atx.sql doesn't follow state.sql
+    assert_eq!(Database::Atx.latest_layer(&state).unwrap(), None);
+    Database::Atx.record_layer(&state, 200).unwrap();
+    assert_eq!(Database::Atx.latest_layer(&state).unwrap(), Some(199));
+    Database::State.record_layer(&state, 400).unwrap();
+    assert_eq!(Database::State.latest_layer(&state).unwrap(), Some(299));
+  }
+
   fn insert_layer(conn: &Connection, id: u32, applied_block: i64, hash: &[u8]) {
     conn
       .execute(
@@ -392,6 +1414,94 @@ mod tests {
       .unwrap();
   }
 
+  /// Answers the requests for compressed diffs with 404, as a server
+  /// publishing them uncompressed does.
+  async fn compressed_not_found(server: &mut mockito::ServerGuard) {
+    server
+      .mock("GET", Matcher::Regex(r"\.sql\.(zst|xz|lz4|gz)".to_string()))
+      .match_query(Matcher::Any)
+      .with_status(404)
+      .create_async()
+      .await;
+  }
+
+  #[test]
+  fn counting_applied_points() {
+    let points = [
+      RestorePoint::new(0, 100, "aaaa"),
+      RestorePoint::new(100, 200, "bbbb"),
+      RestorePoint::new(200, 300, "cccc"),
+      RestorePoint::new(300, 400, "dddd"),
+    ];
+    let conn = create_test_db(None);
+    insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+    insert_layer(&conn, 199, 100, &[0xCC, 0xCC]);
+    insert_layer(&conn, 299, 100, &[0xEE, 0xEE]);
+    assert_eq!(count_applied(&points, 300, &conn), 2);
+    // The last one is applied anyway
+    assert_eq!(count_applied(&points[..2], 300, &conn), 1);
+    assert_eq!(count_applied(&points[2..], 300, &conn), 0);
+    // Nor are the ones with untrusted layers
+    assert_eq!(count_applied(&points, 190, &conn), 1);
+  }
+
+  #[test]
+  fn finding_jump_back() {
+    let metadata = "0,100,aaaa\n100,200,bbbb\n200,300,cccc\n300,400,dddd";
+    let conn = create_test_db(None);
+    insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+    insert_layer(&conn, 199, 100, &[0xEE, 0xEE]);
+    insert_layer(&conn, 299, 100, &[0xEE, 0xEE]);
+    // The database diverged at layer 199
+    assert_eq!(find_jump_back(250, metadata, 5, &conn).unwrap(), Some(1));
+    assert_eq!(find_jump_back(250, metadata, 0, &conn).unwrap(), None);
+    assert_eq!(find_jump_back(150, metadata, 0, &conn).unwrap(), Some(0));
+    // The first restore point continues only an empty database
+    conn
+      .execute(
+        "UPDATE layers SET aggregated_hash = x'FFFF' WHERE id = 99",
+        [],
+      )
+      .unwrap();
+    assert_eq!(find_jump_back(250, metadata, 5, &conn).unwrap(), None);
+    let empty = create_test_db(None);
+    assert_eq!(find_jump_back(250, metadata, 5, &empty).unwrap(), Some(2));
+    // Nothing to restore
+    assert_eq!(find_jump_back(500, metadata, 5, &conn).unwrap(), Some(0));
+  }
+
+  #[test]
+  fn describing_divergence() {
+    let metadata = "0,100,aaaa\n100,200,bbbb\n200,300,cccc";
+    let conn = create_test_db(None);
+    insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+    insert_layer(&conn, 199, 100, &[0xEE, 0xEE]);
+    let p = RestorePoint::new(200, 300, "cccc");
+    let message = describe_divergence(&p, "eeee", 200, metadata, &conn);
+    assert!(message.contains("at layer 199"));
+    assert!(message.contains("local hash:    eeee"));
+    assert!(message.contains("expected hash: cccc"));
+    assert!(message.contains("nearest matching restore point: 100..200, 1 back"));
+    assert!(message.contains("--auto-jump-back 1"));
+
+    // Counted from the first untrusted layer
+    let metadata = "0,100,aaaa\n100,200,bbbb\n200,300,cccc\n300,400,dddd";
+    insert_layer(&conn, 299, 100, &[0xEE, 0xEE]);
+    let message = describe_divergence(&p, "eeee", 300, metadata, &conn);
+    assert!(message.contains("nearest matching restore point: 100..200, 2 back"));
+    assert!(message.contains("--auto-jump-back 2"));
+
+    let message = describe_divergence(&p, "eeee", 200, "200,300,cccc", &conn);
+    assert!(message.contains("no earlier restore point matches"));
+    // Nor does the first one, the database has layers
+    let metadata = "0,100,aaaa\n100,200,bbbb\n200,300,cccc";
+    let conn = create_test_db(None);
+    insert_layer(&conn, 99, 100, &[0xFF, 0xFF]);
+    let p = RestorePoint::new(100, 200, "bbbb");
+    let message = describe_divergence(&p, "ffff", 100, metadata, &conn);
+    assert!(message.contains("Download the full snapshot"));
+  }
+
   #[test]
   fn getting_previous_hash() {
     let conn = create_test_db(None);
@@ -416,15 +1526,11 @@ mod tests {
     assert_eq!(result, 42);
   }
 
-  #[test]
-  fn downloading_file() {
-    let point = RestorePoint {
-      from: 100,
-      to: 200,
-      hash: "abcd".to_string(),
-    };
-    let file_url = file_url(1, &point, Some(".zst"));
-    let mut server = mockito::Server::new();
+  #[tokio::test]
+  async fn downloading_file() {
+    let point = RestorePoint::new(100, 200, "abcd");
+    let file_url = file_url(Database::State, 1, &point, Some(".zst"));
+    let mut server = mockito::Server::new_async().await;
     let mock = server
       .mock("GET", format!("/{file_url}").as_str())
       .match_query(Matcher::UrlEncoded(
@@ -433,19 +1539,334 @@ mod tests {
       ))
       .with_status(200)
       .with_body("file contents")
-      .create();
+      .create_async()
+      .await;
 
     let dir = tempdir().unwrap();
     let dst = dir.path().join("dst.zst");
-    super::download_file(&Client::new(), &server.url(), 1, &point, &dst).unwrap();
-    mock.assert();
+    super::download_file(
+      &Client::new(),
+      &server.url(),
+      Database::State,
+      1,
+      &point,
+      Some(".zst"),
+      &dst,
+    )
+    .await
+    .unwrap();
+    mock.assert_async().await;
 
     let data = std::fs::read(&dst).unwrap();
     assert_eq!(&data, "file contents".as_bytes());
   }
 
+  #[tokio::test]
+  async fn rolling_back_layers() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("state.db");
+    {
+      let conn = create_test_db(Some(&db_path));
+      insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+      insert_layer(&conn, 199, 100, &[0xCC, 0xCC]);
+      insert_layer(&conn, 249, 100, &[0xDD, 0xDD]);
+    }
+    let points = [
+      RestorePoint::new(100, 200, "bbbb"),
+      RestorePoint::new(200, 300, "cccc"),
+    ];
+    let mut server = mockito::Server::new_async().await;
+    compressed_not_found(&mut server).await;
+    let mock_metadata = server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body(points.clone().map(|p| p.to_string()).join("\n"))
+      .create_async()
+      .await;
+    // The reverse diffs list the layers of their restore point to delete
+    let mock_query = server
+      .mock("GET", "/0/rollback.sql")
+      .match_query(Matcher::Any)
+      .with_body(format!(
+        r#"ATTACH DATABASE '{}' AS src;
+         DELETE FROM layers WHERE id IN (SELECT id FROM src.layers);"#,
+        dir.path().join("backup_source.db").display(),
+      ))
+      .create_async()
+      .await;
+    let mut data_mocks = Vec::new();
+    for (point, layers) in points.iter().zip([&[100, 199][..], &[200, 249]]) {
+      let conn = create_test_db(None);
+      for &layer in layers {
+        insert_layer(&conn, layer, 0, &[0, 0]);
+      }
+      let checkpoint = dir.path().join("checkpoint.db");
+      conn.backup(DatabaseName::Main, &checkpoint, None).unwrap();
+      let mock = server
+        .mock(
+          "GET",
+          format!("/{}", reverse_file_url(0, point, "")).as_str(),
+        )
+        .match_query(Matcher::Any)
+        .with_body(std::fs::read(&checkpoint).unwrap())
+        .create_async()
+        .await;
+      data_mocks.push(mock);
+    }
+
+    // Rewound to the end of the first restore point
+    let latest = rollback(&http(), &server.url(), &db_path, dir.path(), 150)
+      .await
+      .unwrap();
+    assert_eq!(latest, 99);
+    mock_metadata.assert_async().await;
+    mock_query.assert_async().await;
+    for mock in data_mocks {
+      mock.assert_async().await;
+    }
+    let conn = Connection::open(&db_path).unwrap();
+    assert_eq!(get_latest_from_db(&conn).unwrap(), 99);
+  }
+
+  #[tokio::test]
+  async fn undoing_failed_rollbacks() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("state.db");
+    {
+      let conn = create_test_db(Some(&db_path));
+      insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+      insert_layer(&conn, 149, 100, &[0xCC, 0xCC]);
+    }
+    // The hash published for layer 99 doesn't match the local one
+    let point = RestorePoint::new(100, 200, "ffff");
+    let mut server = mockito::Server::new_async().await;
+    compressed_not_found(&mut server).await;
+    server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body(point.to_string())
+      .create_async()
+      .await;
+    server
+      .mock("GET", "/0/rollback.sql")
+      .match_query(Matcher::Any)
+      .with_body(format!(
+        r#"ATTACH DATABASE '{}' AS src;
+         DELETE FROM layers WHERE id IN (SELECT id FROM src.layers);"#,
+        dir.path().join("backup_source.db").display(),
+      ))
+      .create_async()
+      .await;
+    let conn = create_test_db(None);
+    insert_layer(&conn, 149, 0, &[0, 0]);
+    let checkpoint = dir.path().join("checkpoint.db");
+    conn.backup(DatabaseName::Main, &checkpoint, None).unwrap();
+    server
+      .mock(
+        "GET",
+        format!("/{}", reverse_file_url(0, &point, "")).as_str(),
+      )
+      .match_query(Matcher::Any)
+      .with_body(std::fs::read(&checkpoint).unwrap())
+      .create_async()
+      .await;
+
+    let err = rollback(&http(), &server.url(), &db_path, dir.path(), 120)
+      .await
+      .unwrap_err();
+    assert!(format!("{err:#}").contains("unexpected state"));
+    // The reverse diff was undone
+    let conn = Connection::open(&db_path).unwrap();
+    assert_eq!(get_latest_from_db(&conn).unwrap(), 149);
+  }
+
+  #[tokio::test]
+  async fn cross_checking_layer_hashes() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("state.db");
+    {
+      let conn = create_test_db(Some(&db_path));
+      insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+      insert_layer(&conn, 199, 100, &[0xCC, 0xCC]);
+      insert_layer(&conn, 299, 100, &[0x0D, 0xDD]);
+      insert_layer(&conn, 310, 100, &[0xEE, 0xEE]);
+    }
+    let metadata = [
+      RestorePoint::new(100, 200, "bbbb"),
+      RestorePoint::new(200, 300, "cccc"),
+      RestorePoint::new(300, 400, "dddd"),
+      RestorePoint::new(400, 500, "eeee"),
+    ]
+    .map(|p| p.to_string())
+    .join("\n");
+    let mut server = mockito::Server::new_async().await;
+    server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body(metadata)
+      .create_async()
+      .await;
+
+    let (compared, divergence) = cross_check(&server.url(), &db_path, 3).await.unwrap();
+    assert_eq!(compared, 3);
+    assert_eq!(
+      divergence,
+      Some(Divergence {
+        layer: 299,
+        local_hash: "0ddd".to_string(),
+        published_hash: "dddd".to_string(),
+      })
+    );
+
+    // the layers without a restore point ending there aren't compared
+    Connection::open(&db_path)
+      .unwrap()
+      .execute(
+        "UPDATE layers SET aggregated_hash = x'DDDD' WHERE id = 299",
+        [],
+      )
+      .unwrap();
+    let result = cross_check(&server.url(), &db_path, 10).await.unwrap();
+    assert_eq!(result, (3, None));
+  }
+
+  #[tokio::test]
+  async fn bootstrapping_from_base_db() {
+    let dir = tempdir().unwrap();
+    let base = dir.path().join("published.db");
+    {
+      let conn = create_test_db(Some(&base));
+      insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
+      conn.pragma_update(None, "user_version", 3).unwrap();
+    }
+    let published = zstd::encode_all(&std::fs::read(&base).unwrap()[..], 3).unwrap();
+    let mut server = mockito::Server::new_async().await;
+    let md5 = format!("{:x}", md5::compute(&published));
+    let mock = server
+      .mock("GET", "/atx/3/base/atx.sql.zst")
+      .match_query(Matcher::Any)
+      .with_body(published)
+      .create_async()
+      .await;
+    server
+      .mock("GET", "/atx/3/base/atx.sql.zst.md5")
+      .with_body(&md5)
+      .create_async()
+      .await;
+
+    let state_db = dir.path().join("node-data").join("state.sql");
+    std::fs::create_dir(state_db.parent().unwrap()).unwrap();
+    bootstrap(
+      &http(),
+      &server.url(),
+      Database::Atx,
+      3,
+      &state_db,
+      dir.path(),
+    )
+    .await
+    .unwrap();
+    mock.assert_async().await;
+    let conn = Connection::open(state_db.with_file_name("atx.sql")).unwrap();
+    assert_eq!(get_latest_from_db(&conn).unwrap(), 99);
+
+    // a base database of another schema version isn't used
+    server
+      .mock("GET", Matcher::Regex(r"^/atx/4/".to_string()))
+      .with_status(404)
+      .create_async()
+      .await;
+    let err = bootstrap(
+      &http(),
+      &server.url(),
+      Database::Atx,
+      4,
+      &state_db,
+      dir.path(),
+    )
+    .await
+    .unwrap_err();
+    assert!(format!("{err:#}").contains("no base database is published"));
+
+    // nor one not matching its checksum
+    server
+      .mock("GET", "/atx/5/base/atx.sql.zst")
+      .match_query(Matcher::Any)
+      .with_body("tampered")
+      .create_async()
+      .await;
+    server
+      .mock("GET", "/atx/5/base/atx.sql.zst.md5")
+      .with_body(&md5)
+      .create_async()
+      .await;
+    let err = bootstrap(
+      &http(),
+      &server.url(),
+      Database::Atx,
+      5,
+      &state_db,
+      dir.path(),
+    )
+    .await
+    .unwrap_err();
+    assert!(format!("{err:#}").contains("checksum of the base database is invalid"));
+
+    // a server error isn't taken for a missing file
+    server
+      .mock("GET", "/atx/6/base/atx.sql.zst")
+      .match_query(Matcher::Any)
+      .with_status(503)
+      .create_async()
+      .await;
+    let err = bootstrap(
+      &http(),
+      &server.url(),
+      Database::Atx,
+      6,
+      &state_db,
+      dir.path(),
+    )
+    .await
+    .unwrap_err();
+    assert!(format!("{err:#}").contains("503"));
+  }
+
   #[test]
-  fn incremental_restore() {
+  fn decompressing_diffs() {
+    let dir = tempdir().unwrap();
+    let (input, output) = (dir.path().join("diff.download"), dir.path().join("diff.db"));
+    let mut encoder = flate2::write::GzEncoder::new(
+      File::create(&input).unwrap(),
+      flate2::Compression::default(),
+    );
+    encoder.write_all(b"SQLite format 3\0").unwrap();
+    encoder.finish().unwrap();
+    decompress_file(&input, &output).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), b"SQLite format 3\0");
+    assert!(!input.exists());
+
+    // uncompressed diffs are used as they are
+    std::fs::write(&input, b"SQLite format 3\0plain").unwrap();
+    decompress_file(&input, &output).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), b"SQLite format 3\0plain");
+  }
+
+  #[tokio::test]
+  async fn incremental_restore() {
+    restore_all_points(RestoreOptions::default()).await;
+  }
+
+  #[tokio::test]
+  async fn batched_incremental_restore() {
+    restore_all_points(RestoreOptions {
+      batch_size: 3,
+      ..Default::default()
+    })
+    .await;
+  }
+
+  async fn restore_all_points(options: RestoreOptions) {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("state.db");
     {
@@ -453,7 +1874,8 @@ mod tests {
       insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
     }
 
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
+    compressed_not_found(&mut server).await;
 
     let points = [
       ("bbbb", RestorePoint::new(0, 100, "aaaa")),
@@ -475,7 +1897,8 @@ mod tests {
         env!("CARGO_PKG_VERSION").into(),
       ))
       .with_body(metadata)
-      .create();
+      .create_async()
+      .await;
 
     // Restore SQL just copies contents of the `layers` table
     // Note: there's no detach because the real restore query also
@@ -491,39 +1914,49 @@ mod tests {
          INSERT OR IGNORE INTO layers SELECT * from src.layers;"#,
         dir.path().join("backup_source.db").display(),
       ))
-      .create();
-
-    let data_mocks = points
-      .iter()
-      .skip(1)
-      .map(|(hash, point)| {
-        // For simplicity, the database used to restore contains only
-        // the last layer of the point and its expected hash.
-        let conn = create_test_db(None);
-        let hash = hex::decode(hash).unwrap();
-        insert_layer(&conn, point.to - 1, 111, &hash);
-
-        let checkpoint = dir.path().join("checkpoint.db");
-        conn.backup(DatabaseName::Main, &checkpoint, None).unwrap();
-
-        let file_url = file_url(0, point, None);
-        server
-          .mock("GET", format!("/{file_url}").as_str())
-          .match_query(Matcher::UrlEncoded(
-            "version".into(),
-            env!("CARGO_PKG_VERSION").into(),
-          ))
-          .with_body(std::fs::read(&checkpoint).unwrap())
-          .create()
-      })
-      .collect::<Vec<_>>();
+      .create_async()
+      .await;
+
+    let mut data_mocks = Vec::new();
+    for (hash, point) in points.iter().skip(1) {
+      // For simplicity, the database used to restore contains only
+      // the last layer of the point and its expected hash.
+      let conn = create_test_db(None);
+      let hash = hex::decode(hash).unwrap();
+      insert_layer(&conn, point.to - 1, 111, &hash);
+
+      let checkpoint = dir.path().join("checkpoint.db");
+      conn.backup(DatabaseName::Main, &checkpoint, None).unwrap();
+
+      let file_url = file_url(Database::State, 0, point, None);
+      let mock = server
+        .mock("GET", format!("/{file_url}").as_str())
+        .match_query(Matcher::UrlEncoded(
+          "version".into(),
+          env!("CARGO_PKG_VERSION").into(),
+        ))
+        .with_body(std::fs::read(&checkpoint).unwrap())
+        .create_async()
+        .await;
+      data_mocks.push(mock);
+    }
 
-    super::incremental_restore(&server.url(), &db_path, dir.path(), 0, 0).unwrap();
+    super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &options,
+      0,
+    )
+    .await
+    .unwrap();
 
-    mock_metadata.assert();
-    mock_query.assert();
+    mock_metadata.assert_async().await;
+    mock_query.assert_async().await;
     for mock in data_mocks {
-      mock.assert();
+      mock.assert_async().await;
     }
 
     let conn = Connection::open(&db_path).unwrap();
@@ -534,8 +1967,8 @@ mod tests {
     assert_eq!(result, points.last().unwrap().0);
   }
 
-  #[test]
-  fn incremental_restore_with_untrusted_layers() {
+  #[tokio::test]
+  async fn incremental_restore_with_untrusted_layers() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("state.db");
     {
@@ -543,7 +1976,8 @@ mod tests {
       insert_layer(&conn, 99, 100, &[0xBB, 0xBB]);
     }
 
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
+    compressed_not_found(&mut server).await;
 
     let points = [
       ("bbbb", RestorePoint::new(0, 100, "aaaa")),
@@ -565,7 +1999,8 @@ mod tests {
         env!("CARGO_PKG_VERSION").into(),
       ))
       .with_body(metadata)
-      .create();
+      .create_async()
+      .await;
 
     // Restore SQL just copies contents of the `layers` table
     // Note: there's no detach because the real restore query also
@@ -581,39 +2016,53 @@ mod tests {
          INSERT OR IGNORE INTO layers SELECT * from src.layers;"#,
         dir.path().join("backup_source.db").display(),
       ))
-      .create();
-
-    let data_mocks = points
-      .iter()
-      .map(|(hash, point)| {
-        // For simplicity, the database used to restore contains only
-        // the last layer of the point and its expected hash.
-        let conn = create_test_db(None);
-        let hash = hex::decode(hash).unwrap();
-        insert_layer(&conn, point.to - 1, 111, &hash);
-
-        let checkpoint = dir.path().join("checkpoint.db");
-        conn.backup(DatabaseName::Main, &checkpoint, None).unwrap();
-
-        let file_url = file_url(0, point, None);
-        server
-          .mock("GET", format!("/{file_url}").as_str())
-          .match_query(Matcher::UrlEncoded(
-            "version".into(),
-            env!("CARGO_PKG_VERSION").into(),
-          ))
-          .with_body(std::fs::read(&checkpoint).unwrap())
-          .create()
-      })
-      .collect::<Vec<_>>();
+      .create_async()
+      .await;
+
+    let mut data_mocks = Vec::new();
+    for (hash, point) in points.iter() {
+      // For simplicity, the database used to restore contains only
+      // the last layer of the point and its expected hash.
+      let conn = create_test_db(None);
+      let hash = hex::decode(hash).unwrap();
+      insert_layer(&conn, point.to - 1, 111, &hash);
+
+      let checkpoint = dir.path().join("checkpoint.db");
+      conn.backup(DatabaseName::Main, &checkpoint, None).unwrap();
+
+      let file_url = file_url(Database::State, 0, point, None);
+      let mock = server
+        .mock("GET", format!("/{file_url}").as_str())
+        .match_query(Matcher::UrlEncoded(
+          "version".into(),
+          env!("CARGO_PKG_VERSION").into(),
+        ))
+        .with_body(std::fs::read(&checkpoint).unwrap())
+        .create_async()
+        .await;
+      data_mocks.push(mock);
+    }
 
     let untrusted_layers = 10;
-    super::incremental_restore(&server.url(), &db_path, dir.path(), untrusted_layers, 0).unwrap();
+    super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &RestoreOptions {
+        untrusted_layers,
+        ..Default::default()
+      },
+      0,
+    )
+    .await
+    .unwrap();
 
-    mock_metadata.assert();
-    mock_query.assert();
+    mock_metadata.assert_async().await;
+    mock_query.assert_async().await;
     for mock in data_mocks {
-      mock.assert();
+      mock.assert_async().await;
     }
 
     let conn = Connection::open(&db_path).unwrap();
@@ -624,15 +2073,48 @@ mod tests {
     assert_eq!(result, points.last().unwrap().0);
   }
 
-  #[test]
-  fn fails_on_hash_mismatch() {
+  #[tokio::test]
+  async fn finding_published_versions() {
+    let mut server = mockito::Server::new_async().await;
+    let mock_missing = server
+      .mock("GET", "/3/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_status(404)
+      .create_async()
+      .await;
+    let mock_published = server
+      .mock("HEAD", "/5/metadata.csv")
+      .match_query(Matcher::Any)
+      .create_async()
+      .await;
+    server
+      .mock("HEAD", Matcher::Any)
+      .with_status(404)
+      .create_async()
+      .await;
+    let client = Client::new();
+    let err = fetch_metadata(&client, &server.url(), Database::State, 3)
+      .await
+      .unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("no restore points for state.sql with user_version=3"));
+    assert!(message.contains("upgrade go-spacemesh"));
+    mock_missing.assert_async().await;
+    mock_published.assert_async().await;
+
+    assert!(describe_unpublished_version(Database::Atx, 5, Some(3)).contains("wait for them"));
+    assert!(describe_unpublished_version(Database::State, 5, None).contains("quicksync download"));
+  }
+
+  #[tokio::test]
+  async fn fails_on_hash_mismatch() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("state.db");
     {
       let conn = create_test_db(Some(&db_path));
       insert_layer(&conn, 99, 100, &[0xFF, 0xFF]);
     }
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
 
     let metadata = RestorePoint::new(100, 200, "aaaa".to_string()).to_string();
     let mock_metadata = server
@@ -642,7 +2124,8 @@ mod tests {
         env!("CARGO_PKG_VERSION").into(),
       ))
       .with_body(metadata)
-      .create();
+      .create_async()
+      .await;
 
     let mock_query = server
       .mock("GET", "/0/restore.sql")
@@ -651,23 +2134,167 @@ mod tests {
         env!("CARGO_PKG_VERSION").into(),
       ))
       .with_body(".import backup_source.db layers")
-      .create();
-
-    let err = super::incremental_restore(&server.url(), &db_path, dir.path(), 0, 0).unwrap_err();
+      .create_async()
+      .await;
+
+    let err = super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &RestoreOptions::default(),
+      0,
+    )
+    .await
+    .unwrap_err();
     assert!(err.to_string().contains("unexpected hash"));
-    mock_metadata.assert();
-    mock_query.assert();
+    mock_metadata.assert_async().await;
+    mock_query.assert_async().await;
   }
 
-  #[test]
-  fn no_matching_restore_points() {
+  #[tokio::test]
+  async fn sizing_restore_points() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("state.db");
+    {
+      let conn = create_test_db(Some(&db_path));
+      insert_layer(&conn, 150, 100, &[0xFF, 0xFF]);
+    }
+    let mut server = mockito::Server::new_async().await;
+    let mock_metadata = server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body("0,100,aaaa,1000\n100,200,bbbb,2000\n200,300,cccc,3000\n")
+      .create_async()
+      .await;
+    let size = restore_size(&server.url(), &db_path, 0).await.unwrap();
+    assert_eq!(size, (Some(5000), 300));
+    mock_metadata.assert_async().await;
+    mock_metadata.remove_async().await;
+
+    // restore points of metadata v1 have no size
+    let mock_metadata = server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body("100,200,bbbb,2000\n200,300,cccc\n")
+      .create_async()
+      .await;
+    let size = restore_size(&server.url(), &db_path, 0).await.unwrap();
+    assert_eq!(size, (None, 300));
+    mock_metadata.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn server_errors_arent_masked_by_other_suffixes() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("state.db");
+    {
+      let conn = create_test_db(Some(&db_path));
+      insert_layer(&conn, 99, 100, &[0xAA, 0xAA]);
+    }
+    let mut server = mockito::Server::new_async().await;
+    let point = RestorePoint::new(100, 200, "aaaa");
+    server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body(point.to_string())
+      .create_async()
+      .await;
+    server
+      .mock("GET", "/0/restore.sql")
+      .match_query(Matcher::Any)
+      .with_body("SELECT 1;")
+      .create_async()
+      .await;
+    server
+      .mock(
+        "GET",
+        format!("/{}", file_url(Database::State, 0, &point, Some(".zst"))).as_str(),
+      )
+      .match_query(Matcher::Any)
+      .with_status(503)
+      .create_async()
+      .await;
+    let other_suffixes = server
+      .mock("GET", Matcher::Regex(r"\.sql(\.(xz|lz4|gz))?$".to_string()))
+      .match_query(Matcher::Any)
+      .expect(0)
+      .create_async()
+      .await;
+
+    let err = super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &RestoreOptions::default(),
+      0,
+    )
+    .await
+    .unwrap_err();
+    assert!(format!("{err:#}").contains("503"));
+    other_suffixes.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn skipping_applied_restore_points() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("state.db");
+    {
+      let conn = create_test_db(Some(&db_path));
+      insert_layer(&conn, 299, 100, &[0xCC, 0xCC]);
+    }
+    let mut server = mockito::Server::new_async().await;
+    let metadata = [
+      RestorePoint::new(100, 200, "bbbb".to_string()),
+      RestorePoint::new(200, 300, "cccc".to_string()),
+    ]
+    .map(|p| p.to_string())
+    .join("\n");
+    let mock_metadata = server
+      .mock("GET", "/0/metadata.csv")
+      .match_query(Matcher::Any)
+      .with_body(metadata)
+      .create_async()
+      .await;
+    let mock_query = server
+      .mock("GET", "/0/restore.sql")
+      .match_query(Matcher::Any)
+      .expect(0)
+      .create_async()
+      .await;
+
+    // The untrusted layers are in the restore point applied by the previous poll
+    let applied_to = super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &RestoreOptions {
+        untrusted_layers: 10,
+        ..Default::default()
+      },
+      300,
+    )
+    .await
+    .unwrap();
+    assert_eq!(applied_to, 300);
+    mock_metadata.assert_async().await;
+    mock_query.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn no_matching_restore_points() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("state.db");
     {
       let conn = create_test_db(Some(&db_path));
       insert_layer(&conn, 80, 100, &[0xFF, 0xFF]);
     }
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
 
     let metadata = RestorePoint::new(200, 300, "aaaa".to_string()).to_string();
     let mock_metadata = server
@@ -677,24 +2304,35 @@ mod tests {
         env!("CARGO_PKG_VERSION").into(),
       ))
       .with_body(metadata)
-      .create();
-
-    let err = super::incremental_restore(&server.url(), &db_path, dir.path(), 0, 0).unwrap_err();
+      .create_async()
+      .await;
+
+    let err = super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &RestoreOptions::default(),
+      0,
+    )
+    .await
+    .unwrap_err();
     assert!(err
       .to_string()
       .contains("No suitable restore points found, seems that state.sql is too old"));
-    mock_metadata.assert();
+    mock_metadata.assert_async().await;
   }
 
-  #[test]
-  fn non_existing_user_version() {
+  #[tokio::test]
+  async fn non_existing_user_version() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("state.db");
     {
       let conn = create_test_db(Some(&db_path));
       insert_layer(&conn, 80, 100, &[0xFF, 0xFF]);
     }
-    let mut server = mockito::Server::new();
+    let mut server = mockito::Server::new_async().await;
 
     let mock_metadata = server
       .mock("GET", "/0/metadata.csv")
@@ -704,12 +2342,23 @@ mod tests {
       ))
       .with_status(404)
       .with_body("Not Found")
-      .create();
-    let err = super::incremental_restore(&server.url(), &db_path, dir.path(), 0, 0).unwrap_err();
+      .create_async()
+      .await;
+    let err = super::incremental_restore(
+      &http(),
+      &server.url(),
+      Database::State,
+      &db_path,
+      dir.path(),
+      &RestoreOptions::default(),
+      0,
+    )
+    .await
+    .unwrap_err();
     println!("{}", err);
     assert!(err
       .to_string()
-      .contains("Remote server returned 404 for metadata.csv. User version 0 might not exist."));
-    mock_metadata.assert();
+      .contains("The server has no restore points for state.sql with user_version=0"));
+    mock_metadata.assert_async().await;
   }
 }
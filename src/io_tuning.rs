@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+pub const DEFAULT_IO_BUFFER_SIZE: &str = "16MiB";
+pub const DEFAULT_HASH_THREADS: &str = "1";
+
+/// How much data is written before it's dropped from the page cache.
+const DROP_CACHE_INTERVAL: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoOptions {
+  /// Size of the buffers used for reading and writing huge files
+  pub buffer_size: usize,
+  /// Keep written data out of the OS page cache
+  pub no_page_cache: bool,
+  /// Threads reading ahead huge files while they're hashed, 1 reads them
+  /// without memory-mapping
+  pub hash_threads: usize,
+}
+
+#[cfg(test)]
+impl IoOptions {
+  /// Small buffers, so the tests go through many of them.
+  pub fn for_tests() -> Self {
+    IoOptions {
+      buffer_size: 1024,
+      no_page_cache: false,
+      hash_threads: 1,
+    }
+  }
+}
+
+/// A file that optionally keeps the written data out of the OS page cache,
+/// so writing a huge file doesn't evict everything else from memory.
+///
+/// On macOS it relies on `F_NOCACHE`, on Linux the written data is flushed
+/// and dropped with `posix_fadvise` every `DROP_CACHE_INTERVAL` bytes.
+/// On other platforms it behaves like a plain file.
+pub struct NoCacheFile {
+  file: File,
+  enabled: bool,
+  pending: u64,
+}
+
+impl NoCacheFile {
+  pub fn new(file: File, enabled: bool) -> io::Result<Self> {
+    #[cfg(target_os = "macos")]
+    if enabled {
+      use std::os::unix::io::AsRawFd;
+      // SAFETY: the descriptor is valid as long as `file` is alive
+      if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    Ok(Self {
+      file,
+      enabled,
+      pending: 0,
+    })
+  }
+
+  pub fn sync_data(&self) -> io::Result<()> {
+    self.file.sync_data()
+  }
+
+  fn drop_cache(&mut self) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+      use std::os::unix::io::AsRawFd;
+      // Dirty pages can't be dropped, so write them out first
+      self.file.sync_data()?;
+      // SAFETY: the descriptor is valid as long as `self.file` is alive
+      let ret =
+        unsafe { libc::posix_fadvise(self.file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+      if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Write for NoCacheFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.file.write(buf)?;
+    if self.enabled {
+      self.pending += written as u64;
+      if self.pending >= DROP_CACHE_INTERVAL {
+        self.drop_cache()?;
+        self.pending = 0;
+      }
+    }
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
+
+impl Seek for NoCacheFile {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.file.seek(pos)
+  }
+}
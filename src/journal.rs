@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Journal of the steps of `download` completed in node-data.
+pub const FILE_NAME: &str = "quicksync-journal.json";
+/// Bytes hashed at each end of an artifact.
+const FINGERPRINT_SPAN: u64 = 1024 * 1024;
+
+/// Steps of `download` a crashed run can be resumed after, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+  /// The archive is downloaded.
+  Downloaded,
+  /// The checksum of the archive is valid.
+  Verified,
+  /// The database is unpacked, or patched by a delta download.
+  Unpacked,
+  /// The local database is backed up, the unpacked one is left to move.
+  BackedUp,
+  /// The unpacked database replaced the local one.
+  Installed,
+}
+
+impl fmt::Display for Step {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Step::Downloaded => "downloaded",
+      Step::Verified => "verified",
+      Step::Unpacked => "unpacked",
+      Step::BackedUp => "backed up",
+      Step::Installed => "installed",
+    };
+    f.write_str(name)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+  step: Step,
+  /// File the step produced.
+  artifact: PathBuf,
+  /// Fingerprint of the artifact once the step completed.
+  hash: String,
+  completed_at: DateTime<Utc>,
+}
+
+/// The snapshot the steps are completed for: its URL and the checksum of its
+/// archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub url: String,
+  pub md5: String,
+}
+
+/// Steps completed by the runs of `download` in a node-data directory, with
+/// the hashes of their artifacts, so a run resumes exactly where a crashed
+/// one stopped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+  #[serde(skip)]
+  node_data: PathBuf,
+  /// Whether `snapshot` is the one the current run syncs.
+  #[serde(skip)]
+  keyed: bool,
+  #[serde(default)]
+  snapshot: Option<Snapshot>,
+  entries: Vec<Entry>,
+}
+
+fn path(node_data: &Path) -> PathBuf {
+  node_data.join(FILE_NAME)
+}
+
+/// BLAKE3 of the size, the first and the last MiB of a file: cheap to compute
+/// for a database of many GB, and changed by a truncated or rewritten file.
+pub fn fingerprint(path: &Path) -> Result<String> {
+  let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+  let len = file.metadata()?.len();
+  let mut hasher = blake3::Hasher::new();
+  hasher.update(&len.to_le_bytes());
+  let mut buf = Vec::new();
+  (&mut file).take(FINGERPRINT_SPAN).read_to_end(&mut buf)?;
+  if len > FINGERPRINT_SPAN {
+    file.seek(SeekFrom::Start(
+      (len - FINGERPRINT_SPAN).max(FINGERPRINT_SPAN),
+    ))?;
+    file.read_to_end(&mut buf)?;
+  }
+  hasher.update(&buf);
+  Ok(hasher.finalize().to_hex().to_string())
+}
+
+impl Journal {
+  /// The journal in node-data, empty if there is none or it's unreadable.
+  /// Its steps are resumed only once it's keyed to the same snapshot.
+  pub fn load(node_data: &Path) -> Self {
+    let journal = std::fs::read_to_string(path(node_data))
+      .ok()
+      .and_then(|content| serde_json::from_str::<Journal>(&content).ok())
+      .unwrap_or_default();
+    Journal {
+      node_data: node_data.to_path_buf(),
+      ..journal
+    }
+  }
+
+  /// Writes the journal into node-data, atomically.
+  fn save(&self) -> Result<()> {
+    let path = path(&self.node_data);
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string_pretty(self)?)
+      .and_then(|()| std::fs::rename(&tmp_path, &path))
+      .with_context(|| format!("writing {}", path.display()))
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  pub fn is_keyed(&self) -> bool {
+    self.keyed
+  }
+
+  /// Keys the journal to the `snapshot` the run syncs, forgetting the steps
+  /// completed for another one. The steps of an unknown snapshot are neither
+  /// resumed nor recorded.
+  pub fn key(&mut self, snapshot: Option<Snapshot>) {
+    if snapshot.is_none() || self.snapshot != snapshot {
+      self.entries.clear();
+    }
+    self.keyed = snapshot.is_some();
+    self.snapshot = snapshot;
+  }
+
+  /// Records that `step` completed with `artifact`, forgetting the later
+  /// steps of a previous run.
+  pub fn record(&mut self, step: Step, artifact: &Path) -> Result<()> {
+    if !self.keyed {
+      return Ok(());
+    }
+    let hash = fingerprint(artifact)?;
+    self.entries.retain(|entry| entry.step < step);
+    self.entries.push(Entry {
+      step,
+      artifact: artifact.to_path_buf(),
+      hash,
+      completed_at: Utc::now(),
+    });
+    self.save()
+  }
+
+  /// Forgets `step` and the steps after it.
+  pub fn forget(&mut self, step: Step) -> Result<()> {
+    self.entries.retain(|entry| entry.step < step);
+    self.save()
+  }
+
+  /// The last step completed whose artifact is still on disk as it was left.
+  pub fn resume_point(&self) -> Option<Step> {
+    if !self.keyed {
+      return None;
+    }
+    self
+      .entries
+      .iter()
+      .rev()
+      .find(|entry| fingerprint(&entry.artifact).is_ok_and(|hash| hash == entry.hash))
+      .map(|entry| entry.step)
+  }
+
+  /// The snapshot a previous run downloaded the `archive` of, if the archive
+  /// is still the one it recorded, keyed or not.
+  pub fn snapshot_of(&self, archive: &Path) -> Option<&Snapshot> {
+    let downloaded = self.entries.iter().any(|entry| {
+      entry.step == Step::Downloaded
+        && entry.artifact == archive
+        && fingerprint(archive).is_ok_and(|hash| hash == entry.hash)
+    });
+    self.snapshot.as_ref().filter(|_| downloaded)
+  }
+
+  /// Removes the journal once the run is done.
+  pub fn clear(&mut self) -> Result<()> {
+    self.entries.clear();
+    let path = path(&self.node_data);
+    if path.try_exists().unwrap_or(false) {
+      std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fingerprinting_ends() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.zst");
+    let mut content = vec![7u8; 3 * FINGERPRINT_SPAN as usize];
+    std::fs::write(&path, &content).unwrap();
+    let hash = fingerprint(&path).unwrap();
+    // The middle isn't hashed
+    content[FINGERPRINT_SPAN as usize + 1] = 0;
+    std::fs::write(&path, &content).unwrap();
+    assert_eq!(fingerprint(&path).unwrap(), hash);
+    content[content.len() - 1] = 0;
+    std::fs::write(&path, &content).unwrap();
+    assert_ne!(fingerprint(&path).unwrap(), hash);
+    content.truncate(FINGERPRINT_SPAN as usize + 10);
+    std::fs::write(&path, &content).unwrap();
+    assert_ne!(fingerprint(&path).unwrap(), hash);
+  }
+
+  #[test]
+  fn resuming_after_last_intact_step() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("state.zst");
+    let unpacked = dir.path().join("state_downloaded.sql");
+    std::fs::write(&archive, "archive").unwrap();
+    std::fs::write(&unpacked, "database").unwrap();
+
+    let load = || {
+      let mut journal = Journal::load(dir.path());
+      journal.key(Some(snapshot("abc")));
+      journal
+    };
+    let mut journal = load();
+    assert_eq!(journal.resume_point(), None);
+    journal.record(Step::Downloaded, &archive).unwrap();
+    journal.record(Step::Verified, &archive).unwrap();
+    journal.record(Step::Unpacked, &unpacked).unwrap();
+    assert_eq!(load().resume_point(), Some(Step::Unpacked));
+
+    // A crash while the unpacked database was rewritten
+    std::fs::write(&unpacked, "datab").unwrap();
+    assert_eq!(load().resume_point(), Some(Step::Verified));
+    std::fs::remove_file(&archive).unwrap();
+    assert_eq!(load().resume_point(), None);
+
+    // A new download forgets the later steps
+    std::fs::write(&archive, "another archive").unwrap();
+    journal.record(Step::Downloaded, &archive).unwrap();
+    std::fs::write(&unpacked, "database").unwrap();
+    assert_eq!(load().resume_point(), Some(Step::Downloaded));
+
+    journal.clear().unwrap();
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+  }
+
+  fn snapshot(md5: &str) -> Snapshot {
+    Snapshot {
+      url: "https://quicksync.spacemesh.network/1/100.sql.zst".to_string(),
+      md5: md5.to_string(),
+    }
+  }
+
+  #[test]
+  fn ignoring_steps_of_other_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("state.zst");
+    std::fs::write(&archive, "archive").unwrap();
+    let mut journal = Journal::load(dir.path());
+    journal.key(Some(snapshot("abc")));
+    journal.record(Step::Downloaded, &archive).unwrap();
+    journal.record(Step::Verified, &archive).unwrap();
+
+    // Not trusted until it's keyed, but the archive is known to be of the snapshot
+    let mut journal = Journal::load(dir.path());
+    assert_eq!(journal.resume_point(), None);
+    assert_eq!(journal.snapshot_of(&archive), Some(&snapshot("abc")));
+    journal.key(None);
+    assert_eq!(journal.resume_point(), None);
+    journal.record(Step::Downloaded, &archive).unwrap();
+
+    let mut journal = Journal::load(dir.path());
+    journal.key(Some(snapshot("def")));
+    assert_eq!(journal.resume_point(), None);
+    assert!(journal.is_empty());
+
+    let mut journal = Journal::load(dir.path());
+    journal.key(Some(snapshot("abc")));
+    assert_eq!(journal.resume_point(), Some(Step::Verified));
+    journal.forget(Step::Verified).unwrap();
+    assert_eq!(journal.resume_point(), Some(Step::Downloaded));
+  }
+}
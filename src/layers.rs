@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::parsers::parse_duration;
+
+/// Parameters of the network the layers are counted in. Mainnet by default.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Network {
+  /// Genesis time in ISO format
+  #[clap(short = 't', long, default_value = "2023-07-14T08:00:00Z")]
+  pub genesis_time: DateTime<Utc>,
+  /// Layer duration
+  #[clap(short = 'l', long, default_value = "5m", value_parser = parse_duration)]
+  pub layer_duration: Duration,
+  /// Number of layers in an epoch
+  #[clap(long, default_value_t = 4032, value_parser = clap::value_parser!(u32).range(1..))]
+  pub layers_per_epoch: u32,
+}
+
+impl Network {
+  pub fn clock(&self) -> Result<LayerClock> {
+    LayerClock::new(
+      self.genesis_time,
+      self.layer_duration,
+      self.layers_per_epoch,
+    )
+  }
+}
+
+/// Converts between layers, epochs and time in a network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerClock {
+  genesis_time: DateTime<Utc>,
+  layer_duration: Duration,
+  layers_per_epoch: u32,
+}
+
+/// A layer with its epoch and time span, as printed by `layer`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct LayerInfo {
+  pub layer: i64,
+  pub epoch: i64,
+  pub start: DateTime<Utc>,
+  pub end: DateTime<Utc>,
+  pub effective_genesis: i64,
+}
+
+impl LayerClock {
+  /// The clock of mainnet, the network of the default `--genesis-time`,
+  /// `--layer-duration` and `--layers-per-epoch`.
+  pub fn mainnet() -> Self {
+    Self {
+      genesis_time: Utc.with_ymd_and_hms(2023, 7, 14, 8, 0, 0).unwrap(),
+      layer_duration: Duration::minutes(5),
+      layers_per_epoch: 4032,
+    }
+  }
+
+  pub fn new(
+    genesis_time: DateTime<Utc>,
+    layer_duration: Duration,
+    layers_per_epoch: u32,
+  ) -> Result<Self> {
+    anyhow::ensure!(
+      layer_duration.num_milliseconds() > 0,
+      "layer duration must be positive"
+    );
+    anyhow::ensure!(layers_per_epoch > 0, "layers per epoch must be positive");
+    Ok(Self {
+      genesis_time,
+      layer_duration,
+      layers_per_epoch,
+    })
+  }
+
+  /// Layer at `time`, 0 before genesis.
+  pub fn layer_at(&self, time: DateTime<Utc>) -> i64 {
+    let elapsed = (time - self.genesis_time).num_milliseconds().max(0);
+    elapsed / self.layer_duration.num_milliseconds()
+  }
+
+  /// Layer of the network now, by the local clock.
+  pub fn current_layer(&self) -> i64 {
+    self.layer_at(Utc::now())
+  }
+
+  /// Time the layer starts at, an error if it's out of the range of time.
+  pub fn layer_start(&self, layer: i64) -> Result<DateTime<Utc>> {
+    self
+      .layer_duration
+      .num_milliseconds()
+      .checked_mul(layer)
+      .and_then(Duration::try_milliseconds)
+      .and_then(|elapsed| self.genesis_time.checked_add_signed(elapsed))
+      .with_context(|| format!("layer {layer} is out of the range of time"))
+  }
+
+  pub fn epoch(&self, layer: i64) -> i64 {
+    layer / i64::from(self.layers_per_epoch)
+  }
+
+  pub fn first_layer(&self, epoch: i64) -> i64 {
+    epoch * i64::from(self.layers_per_epoch)
+  }
+
+  /// Last layer of the genesis epochs (0 and 1), which have no blocks. The
+  /// network data starts after it.
+  pub fn effective_genesis(&self) -> i64 {
+    self.first_layer(2) - 1
+  }
+
+  pub fn info(&self, layer: i64) -> Result<LayerInfo> {
+    let next = layer
+      .checked_add(1)
+      .context("no layer after the last one")?;
+    Ok(LayerInfo {
+      layer,
+      epoch: self.epoch(layer),
+      start: self.layer_start(layer)?,
+      end: self.layer_start(next)?,
+      effective_genesis: self.effective_genesis(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn time(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  #[test]
+  fn converting_layers_and_time() {
+    let clock = LayerClock::new(time("2023-07-14T08:00:00Z"), Duration::minutes(5), 4032).unwrap();
+    assert_eq!(clock.layer_at(time("2023-07-14T08:00:00Z")), 0);
+    assert_eq!(clock.layer_at(time("2023-07-14T08:09:59Z")), 1);
+    assert_eq!(clock.layer_at(time("2023-07-28T08:00:00Z")), 4032);
+    assert_eq!(
+      clock.layer_start(4032).unwrap(),
+      time("2023-07-28T08:00:00Z")
+    );
+    assert_eq!(clock, LayerClock::mainnet());
+    assert_eq!(clock.epoch(4031), 0);
+    assert_eq!(clock.epoch(4032), 1);
+    assert_eq!(clock.effective_genesis(), 8063);
+    // Before genesis
+    assert_eq!(clock.layer_at(time("2023-07-14T07:59:00Z")), 0);
+  }
+
+  #[test]
+  fn custom_networks() {
+    let clock = LayerClock::new(time("2024-01-01T00:00:00Z"), Duration::seconds(30), 10).unwrap();
+    let info = clock.info(25).unwrap();
+    assert_eq!(info.epoch, 2);
+    assert_eq!(info.start, time("2024-01-01T00:12:30Z"));
+    assert_eq!(info.end, time("2024-01-01T00:13:00Z"));
+    assert_eq!(info.effective_genesis, 19);
+    assert!(LayerClock::new(time("2024-01-01T00:00:00Z"), Duration::zero(), 10).is_err());
+  }
+
+  #[test]
+  fn layers_out_of_range() {
+    let clock = LayerClock::mainnet();
+    assert!(clock.layer_start(i64::MAX / 1000).is_err());
+    assert!(clock.layer_start(1 << 40).is_err());
+    assert!(clock.info(i64::MAX).is_err());
+    assert!(clock.info(1_000_000).is_ok());
+  }
+}
@@ -0,0 +1,240 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::utils;
+
+/// Record of the run the temp files in the work dir belong to.
+pub const MANIFEST: &str = "quicksync-run.json";
+/// Temp files left longer ago are from an abandoned run.
+const STALE_AFTER_DAYS: i64 = 7;
+
+/// What to do with temp files left by a previous run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+  /// Resume recent ones, delete stale ones.
+  Auto,
+  /// Ask on the terminal.
+  Ask,
+  Resume,
+  Fresh,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+  pid: u32,
+  started: DateTime<Utc>,
+  files: Vec<PathBuf>,
+}
+
+fn manifest_path(work_dir: &Path) -> PathBuf {
+  work_dir.join(MANIFEST)
+}
+
+fn load(work_dir: &Path) -> Option<Manifest> {
+  let content = std::fs::read_to_string(manifest_path(work_dir)).ok()?;
+  serde_json::from_str(&content).ok()
+}
+
+fn is_download(path: &Path) -> bool {
+  path.extension().is_some_and(|ext| ext == "download")
+    && path
+      .file_name()
+      .is_some_and(|name| name.to_string_lossy().starts_with("state."))
+}
+
+/// Name of the archive downloaded by a run starting now, unique to it.
+pub fn download_name() -> String {
+  format!(
+    "state.{}-{}.download",
+    std::process::id(),
+    Utc::now().format("%Y%m%d%H%M%S")
+  )
+}
+
+/// The archive a previous run was downloading into the work dir: the one it
+/// recorded, or else the last modified `state.*.download` there.
+pub fn previous_download(work_dir: &Path) -> Option<PathBuf> {
+  if let Some(manifest) = load(work_dir) {
+    let recorded = manifest.files.into_iter().find(|f| is_download(f));
+    if let Some(path) = recorded.filter(|f| f.try_exists().unwrap_or(false)) {
+      return Some(path);
+    }
+  }
+  std::fs::read_dir(work_dir)
+    .ok()?
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| is_download(path))
+    .max_by_key(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+}
+
+/// Where the archive is downloaded into the work dir: where a previous run
+/// left it, or a new name for this run.
+pub fn download_path(work_dir: &Path) -> PathBuf {
+  previous_download(work_dir).unwrap_or_else(|| work_dir.join(download_name()))
+}
+
+/// Records that this run uses the temp `files` in the work dir. A run resuming
+/// from the files of the recorded one keeps the time it started.
+pub fn record(work_dir: &Path, files: &[PathBuf]) -> Result<()> {
+  let started = load(work_dir).map_or_else(Utc::now, |manifest| manifest.started);
+  let manifest = Manifest {
+    pid: std::process::id(),
+    started,
+    files: files.to_vec(),
+  };
+  let path = manifest_path(work_dir);
+  std::fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+    .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Removes the record once the temp files are gone.
+pub fn finish(work_dir: &Path) -> Result<()> {
+  let path = manifest_path(work_dir);
+  if path.try_exists().unwrap_or(false) {
+    std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+  }
+  Ok(())
+}
+
+/// When the leftover files were written: when their run started, or their
+/// last modification if the run isn't recorded.
+fn left_at(manifest: Option<&Manifest>, leftovers: &[PathBuf]) -> Option<DateTime<Utc>> {
+  if let Some(manifest) = manifest {
+    return Some(manifest.started);
+  }
+  leftovers
+    .iter()
+    .filter_map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+    .max()
+    .map(DateTime::<Utc>::from)
+}
+
+/// Decides whether to resume from the temp files left by a previous run.
+fn should_resume(
+  policy: Policy,
+  left_at: Option<DateTime<Utc>>,
+  now: DateTime<Utc>,
+  description: &str,
+) -> Result<bool> {
+  let stale = left_at.is_some_and(|at| now - at > chrono::Duration::days(STALE_AFTER_DAYS));
+  match policy {
+    Policy::Resume => Ok(true),
+    Policy::Fresh => Ok(false),
+    Policy::Ask => utils::ask(&format!("Found {description}. Resume from them?"), true),
+    Policy::Auto if stale => {
+      println!("Found {description}, starting over. Pass --resume to resume from them");
+      Ok(false)
+    }
+    Policy::Auto => Ok(true),
+  }
+}
+
+/// Finds the `files` left by a previous run in the work dir and deletes them,
+/// with its record, unless they're resumed from. Returns whether they are, or
+/// there are none.
+pub fn check(work_dir: &Path, files: &[PathBuf], policy: Policy) -> Result<bool> {
+  let leftovers: Vec<PathBuf> = files
+    .iter()
+    .filter(|f| f.try_exists().unwrap_or(false))
+    .cloned()
+    .collect();
+  if leftovers.is_empty() {
+    // Nothing to resume from, the run isn't the recorded one's
+    finish(work_dir)?;
+  } else {
+    let manifest = load(work_dir);
+    let left_at = left_at(manifest.as_ref(), &leftovers);
+    let mut description = format!("{} temp files of a previous run", leftovers.len());
+    if let Some(manifest) = &manifest {
+      description.push_str(&format!(" (pid {})", manifest.pid));
+    }
+    if let Some(at) = left_at {
+      description.push_str(&format!(" from {}", at.format("%Y-%m-%d %H:%M UTC")));
+    }
+    if !should_resume(policy, left_at, Utc::now(), &description)? {
+      for file in &leftovers {
+        std::fs::remove_file(file).with_context(|| format!("removing {}", file.display()))?;
+      }
+      finish(work_dir)?;
+      return Ok(false);
+    }
+  }
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resuming_recent_leftovers() {
+    let now = Utc::now();
+    let recent = Some(now - chrono::Duration::days(1));
+    let stale = Some(now - chrono::Duration::days(30));
+    let resume = |policy, at| should_resume(policy, at, now, "files").unwrap();
+    assert!(resume(Policy::Auto, recent));
+    assert!(resume(Policy::Auto, None));
+    assert!(!resume(Policy::Auto, stale));
+    assert!(resume(Policy::Resume, stale));
+    assert!(!resume(Policy::Fresh, recent));
+  }
+
+  #[test]
+  fn deleting_stale_leftovers() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = vec![dir.path().join("state.zst"), dir.path().join("state.url")];
+    std::fs::write(&files[0], b"old archive").unwrap();
+    let manifest = Manifest {
+      pid: 1,
+      started: Utc::now() - chrono::Duration::days(90),
+      files: files.clone(),
+    };
+    std::fs::write(
+      manifest_path(dir.path()),
+      serde_json::to_string(&manifest).unwrap(),
+    )
+    .unwrap();
+
+    assert!(!check(dir.path(), &files, Policy::Auto).unwrap());
+    assert!(!files[0].exists());
+    record(dir.path(), &files).unwrap();
+    let recorded = load(dir.path()).unwrap();
+    assert_eq!(recorded.pid, std::process::id());
+
+    // the files of the recorded run are resumed from, since it started
+    std::fs::write(&files[0], b"archive").unwrap();
+    assert!(check(dir.path(), &files, Policy::Auto).unwrap());
+    assert!(files[0].exists());
+    record(dir.path(), &files).unwrap();
+    assert_eq!(load(dir.path()).unwrap().started, recorded.started);
+
+    finish(dir.path()).unwrap();
+    assert!(load(dir.path()).is_none());
+  }
+
+  #[test]
+  fn naming_downloads_after_the_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let name = download_name();
+    assert!(name.starts_with(&format!("state.{}-", std::process::id())));
+    assert!(previous_download(dir.path()).is_none());
+    assert_eq!(download_path(dir.path()).parent(), Some(dir.path()));
+
+    // unrecorded, e.g. by an older version
+    let legacy = dir.path().join("state.download");
+    std::fs::write(&legacy, b"archive").unwrap();
+    assert_eq!(previous_download(dir.path()), Some(legacy.clone()));
+
+    let recorded = dir.path().join("state.1-20250101000000.download");
+    std::fs::write(&recorded, b"archive").unwrap();
+    record(
+      dir.path(),
+      &[recorded.clone(), dir.path().join("state.zst")],
+    )
+    .unwrap();
+    assert_eq!(download_path(dir.path()), recorded);
+  }
+}
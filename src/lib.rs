@@ -0,0 +1,135 @@
+//! Downloading, verifying and installing the state database of a Spacemesh
+//! node, behind the `quicksync` command.
+//!
+//! Code embedding the download calls [`download::download_with_retries`] with a
+//! [`source::SnapshotSource`], [`source::HttpSource`] or a transport of its
+//! own, and gets the progress through [`progress::ProgressSink`].
+//! [`layers::LayerClock`] converts between layers, epochs and time.
+
+pub mod download;
+pub mod exit_error;
+pub mod layers;
+pub mod progress;
+pub mod source;
+
+pub use events::Stage;
+
+// Shared with the `quicksync` binary, which sets up the state they keep (the
+// status line, the events, the URL policy, the pinned keys...) for the modules
+// above too. Not part of the API.
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod block_hashes;
+#[doc(hidden)]
+pub mod cert_pin;
+#[doc(hidden)]
+pub mod check;
+#[doc(hidden)]
+pub mod checksum;
+#[doc(hidden)]
+pub mod clock;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod delta;
+#[doc(hidden)]
+pub mod diff;
+#[doc(hidden)]
+pub mod discovery;
+#[doc(hidden)]
+pub mod dry_run;
+#[doc(hidden)]
+pub mod error_report;
+#[doc(hidden)]
+pub mod eta;
+#[doc(hidden)]
+pub mod events;
+#[doc(hidden)]
+pub mod export;
+#[doc(hidden)]
+pub mod external_downloader;
+#[doc(hidden)]
+pub mod failpoints;
+#[doc(hidden)]
+pub mod file_in_use;
+#[doc(hidden)]
+pub mod go_spacemesh;
+#[doc(hidden)]
+pub mod healthcheck;
+#[doc(hidden)]
+pub mod history;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod http_cache;
+#[doc(hidden)]
+pub mod http_trace;
+#[doc(hidden)]
+pub mod incremental_quicksync;
+#[doc(hidden)]
+pub mod io_tuning;
+#[doc(hidden)]
+pub mod journal;
+#[doc(hidden)]
+pub mod leftovers;
+#[doc(hidden)]
+pub mod long_path;
+#[doc(hidden)]
+pub mod mirrors;
+#[doc(hidden)]
+pub mod netfs;
+#[doc(hidden)]
+pub mod parsers;
+#[doc(hidden)]
+pub mod parts;
+#[doc(hidden)]
+pub mod patch;
+#[doc(hidden)]
+pub mod pipeline;
+#[doc(hidden)]
+pub mod preflight;
+#[doc(hidden)]
+pub mod prune;
+#[doc(hidden)]
+pub mod read_error_response;
+#[doc(hidden)]
+pub mod reader_with_bytes;
+#[doc(hidden)]
+pub mod regions;
+#[doc(hidden)]
+pub mod restore_filter;
+#[doc(hidden)]
+pub mod sanity;
+#[doc(hidden)]
+pub mod seed;
+#[doc(hidden)]
+pub mod seekable;
+#[doc(hidden)]
+pub mod selftest;
+#[doc(hidden)]
+pub mod service;
+#[doc(hidden)]
+pub mod speed_meter;
+#[doc(hidden)]
+pub mod sql;
+#[doc(hidden)]
+pub mod status;
+#[doc(hidden)]
+pub mod sync_marker;
+#[doc(hidden)]
+pub mod timeouts;
+#[doc(hidden)]
+pub mod transport;
+#[doc(hidden)]
+pub mod unpack;
+#[doc(hidden)]
+pub mod url_policy;
+#[doc(hidden)]
+pub mod user_agent;
+#[doc(hidden)]
+pub mod utils;
+#[doc(hidden)]
+pub mod vacuum;
+#[doc(hidden)]
+pub mod variant;
@@ -0,0 +1,62 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Makes the path absolute.
+///
+/// On Windows the path also gets the extended-length prefix (`\\?\`, or
+/// `\\?\UNC\` for network shares), which lifts the limit of 260 characters
+/// on paths, so node-data directories nested deep in user profiles work.
+pub fn absolute(path: &Path) -> io::Result<PathBuf> {
+  let absolute = std::path::absolute(path)?;
+  #[cfg(windows)]
+  {
+    Ok(match absolute.to_str() {
+      Some(s) => PathBuf::from(extended(s)),
+      None => absolute,
+    })
+  }
+  #[cfg(not(windows))]
+  {
+    Ok(absolute)
+  }
+}
+
+/// Adds the extended-length prefix to an absolute Windows path.
+/// Such paths are taken literally, so they must not contain `.` or `..`
+/// components, which `std::path::absolute` takes care of.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn extended(path: &str) -> String {
+  if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+    path.to_string()
+  } else if let Some(unc) = path.strip_prefix(r"\\") {
+    format!(r"\\?\UNC\{unc}")
+  } else {
+    format!(r"\\?\{path}")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{absolute, extended};
+  use std::path::Path;
+
+  #[test]
+  fn extending_windows_paths() {
+    assert_eq!(
+      extended(r"C:\Users\me\node-data"),
+      r"\\?\C:\Users\me\node-data"
+    );
+    assert_eq!(
+      extended(r"\\nas\share\node-data"),
+      r"\\?\UNC\nas\share\node-data"
+    );
+    assert_eq!(extended(r"\\?\C:\node-data"), r"\\?\C:\node-data");
+  }
+
+  #[test]
+  fn makes_paths_absolute() {
+    let path = absolute(Path::new("node-data")).unwrap();
+    assert!(path.is_absolute());
+    assert!(path.ends_with("node-data"));
+  }
+}
@@ -1,39 +1,117 @@
 use chrono::Duration;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use futures::StreamExt;
 use std::fs::OpenOptions;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process;
-use std::{env, path::PathBuf};
 use url::Url;
 
-mod checksum;
-mod download;
-mod eta;
-mod go_spacemesh;
-mod incremental_quicksync;
-mod parsers;
-mod read_error_response;
-mod reader_with_bytes;
-mod sql;
-mod unpack;
-mod user_agent;
-mod utils;
+use quicksync::{
+  bench, block_hashes, cert_pin, check, checksum, clock, control, delta, diff, discovery, download,
+  dry_run, error_report, events, exit_error, export, external_downloader, failpoints, file_in_use,
+  go_spacemesh, healthcheck, history, hooks, http_cache, http_trace, incremental_quicksync,
+  io_tuning, journal, layers, leftovers, long_path, mirrors, netfs, parsers, parts, patch,
+  pipeline, preflight, progress, prune, regions, restore_filter, sanity, seed, seekable, selftest,
+  source, sql, status, sync_marker, timeouts, transport, unpack, url_policy, utils, vacuum,
+  variant,
+};
 
 use anyhow::{anyhow, Context};
+use block_hashes::BlockHashWriter;
 use checksum::*;
-use download::download_with_retries;
+use download::{
+  check_partial_download, download_with_retries, SlowDownload, SnapshotChanged, SpeedFloor,
+};
+use exit_error::ExitError;
+use failpoints::KillPoint;
 use go_spacemesh::get_version;
-use incremental_quicksync::{check_for_restore_points, incremental_restore};
+use history::SyncHistory;
+use hooks::Hooks;
+use incremental_quicksync::{check_for_restore_points, incremental_restore, Database, DbSelection};
+use io_tuning::{IoOptions, NoCacheFile, DEFAULT_HASH_THREADS, DEFAULT_IO_BUFFER_SIZE};
+use journal::{Journal, Step};
 use parsers::*;
-use sql::get_last_layer_from_db;
+use parts::PartWriter;
+use pipeline::{verify_and_unpack, PipelineWriter, Pipelined};
+use sql::{get_db_status, get_last_layer_from_db, wal_size};
+use sync_marker::SyncMarker;
+use url_policy::UrlPolicy;
 use utils::*;
+use variant::Variant;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
   #[clap(subcommand)]
   command: Commands,
+  /// Print machine-readable `EVENT <json>` lines for frontends
+  #[clap(long, global = true)]
+  events: bool,
+  /// Print a line per progress update even in a terminal, instead of updating
+  /// a single status line and the terminal title
+  #[clap(long, global = true)]
+  no_status_line: bool,
+  /// Log every HTTP request and response to stderr: the request and status
+  /// lines, the headers (credentials redacted), the redirects and the timing
+  #[clap(long, global = true)]
+  trace_http: bool,
+  /// Pin the public key of the certificate of each snapshot server in the
+  /// given file on first use, and warn loudly if it changes later
+  #[clap(long, global = true, value_name = "PINS_FILE")]
+  tofu: Option<PathBuf>,
+  /// Fail with exit code 21 instead of warning when a key pinned with --tofu
+  /// changed
+  #[clap(long, global = true, requires = "tofu")]
+  tofu_strict: bool,
+  /// How often to report the progress of the download and unpacking, as a
+  /// duration (e.g. 30s) or a percentage of the total (e.g. 1%), in the
+  /// output and the events
+  #[clap(long, global = true, default_value = "0.1%", value_parser = parse_progress_interval)]
+  progress_interval: progress::Interval,
+  /// Directory to cache the small files (metadata, restore scripts, checksums,
+  /// manifests) in, revalidated with the server on each use (defaults to
+  /// quicksync in $XDG_CACHE_HOME or ~/.cache, it must be private to the user)
+  #[clap(long, global = true)]
+  cache_dir: Option<PathBuf>,
+  /// Always download the small files again instead of using the cache
+  #[clap(long, global = true, conflicts_with = "cache_dir")]
+  no_cache: bool,
+  /// Abort a stage (e.g. the download or unpacking) that takes longer than the
+  /// given duration (e.g. 2h), keeping what's done so the next run resumes it
+  #[clap(long, global = true, value_parser = parse_duration)]
+  stage_timeout: Option<Duration>,
+  /// Abort the run if it takes longer than the given duration (e.g. 6h),
+  /// keeping what's done so the next run resumes it
+  #[clap(long, global = true, value_parser = parse_duration)]
+  overall_timeout: Option<Duration>,
+  /// Accept `pause`, `resume` and `cancel` commands from `stdin`
+  /// or a unix socket (named pipe on Windows) at the given path
+  #[clap(long, global = true)]
+  control: Option<String>,
+  /// Stream the events as JSON lines to every client connecting to a unix
+  /// socket (named pipe on Windows) at the given path
+  #[clap(long, global = true)]
+  events_socket: Option<String>,
+  /// On failure, print a JSON object with the exit code, the stage, whether
+  /// it's worth retrying, the message and a hint to stderr
+  #[clap(long, global = true)]
+  json: bool,
+  /// On failure, send an anonymized report (stage, errors, OS and versions,
+  /// no file paths) to Spacemesh. Details are always logged to quicksync-error.log
+  #[clap(long, global = true)]
+  report_errors: bool,
+  /// Keep a JSON file at the given path with the state, stage, progress and a
+  /// heartbeat timestamp of the run, rewritten every 10 seconds while it
+  /// progresses, for container healthchecks. Implies --no-status-line
+  #[clap(long, global = true)]
+  healthcheck_file: Option<PathBuf>,
+  #[clap(flatten)]
+  url_policy: UrlPolicy,
+  #[clap(flatten)]
+  transport: transport::Transport,
 }
 
 const DEFAULT_DOWNLOAD_URL: &str = "https://quicksync.spacemesh.network/";
@@ -45,12 +123,8 @@ enum Commands {
     /// Path to the node-data directory
     #[clap(short = 'd', long)]
     node_data: PathBuf,
-    /// Genesis time in ISO format
-    #[clap(short = 't', long, default_value = "2023-07-14T08:00:00Z")]
-    genesis_time: chrono::DateTime<chrono::Utc>,
-    /// Layer duration
-    #[clap(short = 'l', long, default_value = "5m", value_parser = parse_duration)]
-    layer_duration: Duration,
+    #[clap(flatten)]
+    network: layers::Network,
     /// Path to go-spacemesh binary
     #[clap(short = 'g', long, default_value = go_spacemesh_default_path())]
     go_spacemesh_path: PathBuf,
@@ -61,12 +135,44 @@ enum Commands {
       default_value = DEFAULT_DOWNLOAD_URL
     )]
     download_url: Url,
+    /// Region of the snapshot server (e.g. eu) to use instead of the download URL.
+    /// Regions are listed in `regions.json` at the download URL
+    #[clap(long)]
+    region: Option<String>,
+    /// Snapshot variant to check
+    #[clap(long, value_enum, default_value_t)]
+    variant: Variant,
+    /// Number of layers present in the DB that are not trusted to be fully synced.
+    /// The node syncs them again, so they count as layers behind. By default,
+    /// the layers the node hasn't verified plus 10
+    #[clap(long)]
+    untrusted_layers: Option<u32>,
+    /// Average time the node takes to sync a layer from peers, used to estimate
+    /// the catch-up time of normal sync
+    #[clap(long, default_value = "2s", value_parser = parse_duration)]
+    sync_time_per_layer: Duration,
+    /// URL of incremental quicksync restore points, used to estimate partial restore
+    #[clap(long, default_value = incremental_quicksync::DEFAULT_BASE_URL)]
+    base_url: String,
+    /// Compare the hashes of the given number of recent layers in the database with
+    /// the ones published with the restore points, to detect a forked or corrupted
+    /// database
+    #[clap(long, num_args = 0..=1, default_missing_value = "5")]
+    cross_check: Option<usize>,
+    /// Only compare the database with the network layer computed from the genesis
+    /// time, without running go-spacemesh or contacting any server
+    #[clap(long, conflicts_with_all = ["region", "cross_check"])]
+    offline: bool,
   },
   /// Downloads latest db from official website
   Download {
-    /// Path to the node-data directory
-    #[clap(short = 'd', long)]
-    node_data: PathBuf,
+    /// Path to the node-data directory. Repeat it to sync several nodes on the
+    /// machine in parallel
+    #[clap(short = 'd', long, required = true)]
+    node_data: Vec<PathBuf>,
+    /// Number of node-data directories synced at the same time
+    #[clap(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
     /// Path to go-spacemesh binary
     #[clap(short = 'g', long, default_value = go_spacemesh_default_path())]
     go_spacemesh_path: PathBuf,
@@ -77,9 +183,146 @@ enum Commands {
       default_value = DEFAULT_DOWNLOAD_URL
     )]
     download_url: Url,
-    /// Maximum retries amount for downloading (or resuming download) if something went wrong
+    /// Region of the snapshot server (e.g. eu) to use instead of the download URL.
+    /// Regions are listed in `regions.json` at the download URL
+    #[clap(long)]
+    region: Option<String>,
+    /// Another server with the same snapshots (can be repeated). The fastest
+    /// of the download URL and the mirrors is used
+    #[clap(long = "mirror")]
+    mirrors: Vec<Url>,
+    /// Look for machines running `quicksync serve` on the LAN and download from
+    /// one of them if its snapshot is fresh enough. They serve plain HTTP, so
+    /// --allow-insecure-url is needed
+    #[clap(long)]
+    lan: bool,
+    /// How long to look for snapshot sources on the LAN
+    #[clap(long, default_value = "3s", value_parser = parse_duration)]
+    lan_wait: Duration,
+    /// How many layers the snapshot on the LAN may be behind the one on the
+    /// download server
+    #[clap(long, default_value_t = 0)]
+    lan_max_lag: u64,
+    /// Snapshot variant to download
+    #[clap(long, value_enum, default_value_t)]
+    variant: Variant,
+    /// Maximum retries amount for downloading (or resuming download) if something went wrong.
+    /// Also used for downloading checksums
     #[clap(short = 'r', long, default_value = "10")]
     max_retries: u32,
+    /// Download the archive with an external tool: `aria2c`, `curl` or a command
+    /// with `{url}` and `{output}` placeholders, e.g. "wget -c -O {output} {url}"
+    #[clap(long)]
+    downloader: Option<external_downloader::ExternalDownloader>,
+    /// Reconnect if the download stays slower than the given speed (e.g. 1MiB/s)
+    /// for --min-speed-grace, switching to the fastest mirror if there are any
+    #[clap(long, value_parser = parse_speed)]
+    min_speed: Option<u64>,
+    /// How long the download may stay below the minimum speed
+    #[clap(long, default_value = "60s", value_parser = parse_duration)]
+    min_speed_grace: Duration,
+    /// Resolve the download URL again instead of resuming from the URL saved in
+    /// `state.url` once it's older than this, as signed URLs expire
+    #[clap(long, default_value = "24h", value_parser = parse_duration)]
+    url_file_ttl: Duration,
+    /// Maximum retries of the download in total. The --max-retries count starts
+    /// over whenever an attempt downloaded some data, so without it a download
+    /// making progress is retried indefinitely
+    #[clap(long)]
+    max_total_retries: Option<u32>,
+    /// Timeout of each attempt to download a checksum
+    #[clap(long, default_value = "30s", value_parser = parse_duration)]
+    checksum_timeout: Duration,
+    /// URL of the MD5 checksum of the archive, for mirrors not publishing it
+    /// next to the archive as `{layer}.sql.zst.md5`
+    #[clap(long)]
+    archive_checksum_url: Option<Url>,
+    /// URL of the MD5 checksum of the database, for mirrors not publishing it
+    /// next to the archive as `{layer}.sql.md5`
+    #[clap(long)]
+    db_checksum_url: Option<Url>,
+    /// Size of the buffers used to write, hash and unpack the database (e.g. 16MiB)
+    #[clap(long, default_value = DEFAULT_IO_BUFFER_SIZE, value_parser = parse_byte_size)]
+    io_buffer_size: u64,
+    /// Keep the huge downloaded and unpacked files out of the OS page cache
+    #[clap(long)]
+    no_page_cache: bool,
+    /// Directory for the downloaded archive and the unpacked database, e.g. on
+    /// a bigger disk than node-data (defaults to node-data)
+    #[clap(long)]
+    temp_dir: Option<PathBuf>,
+    /// Memory-map the downloaded archive and database while their checksums are
+    /// computed, with this many threads reading them ahead (1 reads them without
+    /// memory-mapping)
+    #[clap(long, default_value = DEFAULT_HASH_THREADS, value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: u16,
+    /// Download and replace the local database even if it is up to date,
+    /// newer than the downloaded one or on a network file system
+    #[clap(long)]
+    force: bool,
+    /// Keep the verified archive in node-data after installing it (as
+    /// `snapshot.zst`), so the next download can be a patch to it
+    #[clap(long)]
+    keep_archive: bool,
+    /// Always download the full archive, even if only the changes since the kept
+    /// archive or the local database could be downloaded
+    #[clap(long)]
+    no_delta: bool,
+    /// After the download, serve the kept archive to other nodes for the given
+    /// duration (e.g. 2h). They download it by passing this node as --mirror
+    #[clap(
+      long,
+      value_parser = parse_duration,
+      requires_all = ["keep_archive", "seed_listen"],
+      conflicts_with_all = ["download_only", "verify_only"]
+    )]
+    seed_for: Option<Duration>,
+    /// After the download, serve the kept archive to other nodes until the
+    /// given multiple of its size was served (e.g. 2.0)
+    #[clap(
+      long,
+      requires_all = ["keep_archive", "seed_listen"],
+      conflicts_with_all = ["download_only", "verify_only"]
+    )]
+    seed_ratio: Option<f64>,
+    /// Address to serve the kept archive at with --seed-for or --seed-ratio
+    /// (e.g. 0.0.0.0:7513 for the other machines to reach it)
+    #[clap(long)]
+    seed_listen: Option<SocketAddr>,
+    /// Resume from the temp files of a previous run, however old they are
+    #[clap(long, conflicts_with = "fresh")]
+    resume: bool,
+    /// Make reruns safe, e.g. of a crash-looping init container: resume the temp
+    /// files of a previous run, and exit with 0 without downloading if the latest
+    /// snapshot was installed already or the local database is past it
+    #[clap(long)]
+    idempotent: bool,
+    /// Delete the temp files of a previous run and start over
+    #[clap(long)]
+    fresh: bool,
+    /// Only download the archive, e.g. overnight, and stop before verifying it
+    #[clap(long, conflicts_with_all = ["verify_only", "install_only"])]
+    download_only: bool,
+    /// Only verify the archive downloaded with --download-only
+    #[clap(long, conflicts_with_all = ["install_only", "fresh"])]
+    verify_only: bool,
+    /// Only unpack and install the archive downloaded with --download-only,
+    /// without verifying it again if --verify-only did
+    #[clap(long, conflicts_with = "fresh")]
+    install_only: bool,
+    /// Replace the local database without asking, when run in a terminal
+    #[clap(short = 'y', long)]
+    yes: bool,
+    /// Print the snapshot, its size and layer, the disk space and which files
+    /// would be created, backed up and replaced, without downloading the archive
+    #[clap(long, conflicts_with_all = ["download_only", "verify_only", "install_only"])]
+    dry_run: bool,
+    /// Wait a random time up to the given duration (e.g. 10m) before contacting
+    /// the server, so that many nodes started at once don't hit it at the same time
+    #[clap(long, value_parser = parse_duration)]
+    start_delay_jitter: Option<Duration>,
+    #[clap(flatten)]
+    hooks: Hooks,
   },
   /// Uses incremental recovery quicksync method
   Incremental {
@@ -87,15 +330,214 @@ enum Commands {
     #[clap(short = 's', long)]
     state_sql: PathBuf,
     /// Number of layers present in the DB that are not trusted to be fully synced.
-    /// These layers will also be synced.
-    #[clap(long, default_value_t = 10)]
-    untrusted_layers: u32,
+    /// These layers will also be synced. By default, the layers the node hasn't
+    /// verified plus 10
+    #[clap(long)]
+    untrusted_layers: Option<u32>,
     /// Jump-back to recover earlier than latest layer. It will jump back one row in recovery metadata
     #[clap(short = 'j', long, default_value_t = 0)]
     jump_back: usize,
+    /// Jump back automatically to the latest restore point continuing the
+    /// database (whose starting hash it has), looking at most MAX_DEPTH
+    /// restore points back
+    #[clap(
+      long,
+      value_name = "MAX_DEPTH",
+      num_args = 0..=1,
+      default_missing_value = "10",
+      conflicts_with = "jump_back"
+    )]
+    auto_jump_back: Option<usize>,
     /// URL to download parts from
     #[clap(short = 'u', long, default_value = incremental_quicksync::DEFAULT_BASE_URL)]
     base_url: String,
+    /// Databases to sync. Other databases are expected next to state.sql
+    #[clap(long = "db", value_enum, default_value_t)]
+    db: DbSelection,
+    /// If the databases don't exist, download the base databases published for
+    /// the given schema version (`PRAGMA user_version`) and apply all restore
+    /// points on top of them, instead of downloading the full snapshot
+    #[clap(long, value_name = "USER_VERSION")]
+    bootstrap: Option<usize>,
+    /// Tables to restore: `pruned` skips the historical data `prune` deletes
+    #[clap(long, value_enum, default_value_t)]
+    profile: restore_filter::RestoreProfile,
+    /// Table not to restore, in addition to the profile (can be repeated)
+    #[clap(long = "skip-table")]
+    skip_tables: Vec<String>,
+    /// Number of consecutive restore points (at most 10) downloaded first and
+    /// applied in one transaction, which is faster for nodes far behind. A batch
+    /// that fails is applied again point by point
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=10))]
+    batch_size: u16,
+    /// Apply the restore points (e.g. jumped back to) that the database already
+    /// has the last layer of with the published hash too
+    #[clap(long)]
+    reapply: bool,
+    /// Keep polling for new restore points and apply them as they are published,
+    /// e.g. to keep a standby node in sync
+    #[clap(long)]
+    follow: bool,
+    /// Time between the polls for new restore points with --follow
+    #[clap(long, default_value = "10m", value_parser = parse_duration)]
+    poll_interval: Duration,
+    /// Wait a random time up to the given duration (e.g. 10m) before contacting
+    /// the server, so that many nodes started at once don't hit it at the same time
+    #[clap(long, value_parser = parse_duration)]
+    start_delay_jitter: Option<Duration>,
+    #[clap(flatten)]
+    hooks: Hooks,
+  },
+  /// Rewinds the database to an earlier restore point with the reverse diffs
+  /// published by the incremental quicksync server
+  Rollback {
+    /// Path to the node state.sql
+    #[clap(short = 's', long)]
+    state_sql: PathBuf,
+    /// Number of layers to roll back, rounded up to a restore point
+    #[clap(
+      long,
+      required_unless_present = "to_layer",
+      conflicts_with = "to_layer"
+    )]
+    layers: Option<u32>,
+    /// Layer to roll back to, rounded down to a restore point
+    #[clap(long)]
+    to_layer: Option<u32>,
+    /// URL to download reverse diffs from
+    #[clap(short = 'u', long, default_value = incremental_quicksync::DEFAULT_BASE_URL)]
+    base_url: String,
+    #[clap(flatten)]
+    hooks: Hooks,
+  },
+  /// Deletes historical data the node doesn't need from the database
+  Prune {
+    /// Path to the node state.sql
+    #[clap(short = 's', long)]
+    state_sql: PathBuf,
+    /// Number of the latest layers to keep the historical data for
+    #[clap(long, default_value_t = 8064)]
+    keep_layers: u32,
+    /// Number of layers in an epoch
+    #[clap(long, default_value_t = 4032, value_parser = clap::value_parser!(u32).range(1..))]
+    layers_per_epoch: u32,
+    /// Run VACUUM after pruning to shrink the database file
+    #[clap(long)]
+    vacuum: bool,
+  },
+  /// Rebuilds the database to reclaim unused space
+  Vacuum {
+    /// Path to the node state.sql
+    #[clap(short = 's', long)]
+    state_sql: PathBuf,
+    /// Vacuum the database in place instead of into a new file.
+    /// Needs less free disk space, but doesn't report progress
+    #[clap(long)]
+    in_place: bool,
+  },
+  /// Packages the local database as a quicksync snapshot with checksums and metadata
+  Export {
+    /// Path to the node state.sql
+    #[clap(short = 's', long)]
+    state_sql: PathBuf,
+    /// Directory to write the snapshot into
+    #[clap(short = 'o', long, default_value = ".")]
+    output_dir: PathBuf,
+    /// Zstd compression level
+    #[clap(long, default_value_t = 19, value_parser = clap::value_parser!(i32).range(1..=22))]
+    level: i32,
+    /// Size of the read and write buffers, e.g. 16MiB
+    #[clap(long, value_parser = parse_byte_size, default_value = DEFAULT_IO_BUFFER_SIZE)]
+    io_buffer_size: u64,
+    /// Compress the database in chunks of this size (e.g. 16MiB) and publish
+    /// a chunk index, so nodes download only the chunks that changed
+    #[clap(long, value_parser = parse_byte_size)]
+    chunk_size: Option<u64>,
+  },
+  /// Generates an incremental quicksync restore point from the local database
+  Diff {
+    /// Path to the node state.sql to take the data from
+    #[clap(short = 's', long)]
+    state_sql: PathBuf,
+    /// Older copy of the database to diff against
+    #[clap(
+      long,
+      conflicts_with = "from_layer",
+      required_unless_present = "from_layer"
+    )]
+    base_sql: Option<PathBuf>,
+    /// First layer of the restore point (instead of --base-sql)
+    #[clap(long)]
+    from_layer: Option<u32>,
+    /// Directory to put the restore point and metadata.csv into
+    #[clap(short = 'o', long, default_value = ".")]
+    output_dir: PathBuf,
+    /// Compress the restore point with zstd
+    #[clap(long)]
+    compress: bool,
+  },
+  /// Serves the archive kept with `download --keep-archive` to other machines
+  /// and advertises it on the LAN, where `download --lan` finds it
+  Serve {
+    /// Path to the node-data directory
+    #[clap(short = 'd', long)]
+    node_data: PathBuf,
+    /// Address to serve the kept archive at (e.g. 0.0.0.0:7513 for the other
+    /// machines to reach it)
+    #[clap(long)]
+    listen: SocketAddr,
+    /// Don't advertise the archive on the LAN over mDNS
+    #[clap(long)]
+    no_advertise: bool,
+  },
+  /// Runs the whole download pipeline against a local server with a tiny
+  /// synthetic snapshot to check an integration without real downloads
+  #[clap(hide = true)]
+  Selftest {
+    /// Keep the temporary directory with the downloaded database
+    #[clap(long)]
+    keep: bool,
+  },
+  /// Prints the completion script for the given shell
+  Completions {
+    #[clap(value_enum)]
+    shell: clap_complete::Shell,
+  },
+  /// Prints the manual page in roff format
+  Manpage,
+  /// Measures the disk, unpacking, hashing and download speed, and estimates
+  /// how long `download` takes
+  Bench {
+    /// Path to the node-data directory, whose disk is measured
+    #[clap(short = 'd', long)]
+    node_data: PathBuf,
+    /// Path to go-spacemesh binary
+    #[clap(short = 'g', long, default_value = go_spacemesh_default_path())]
+    go_spacemesh_path: PathBuf,
+    /// URL to download database from. Node version will be appended at the end
+    #[clap(short = 'u', long, default_value = DEFAULT_DOWNLOAD_URL)]
+    download_url: Url,
+    /// Snapshot variant to download
+    #[clap(long, value_enum, default_value_t)]
+    variant: Variant,
+    /// Amount of data written, unpacked and hashed (e.g. 256MiB)
+    #[clap(long, default_value = "256MiB", value_parser = parse_byte_size)]
+    sample_size: u64,
+    /// Don't measure the download speed
+    #[clap(long)]
+    offline: bool,
+  },
+  /// Prints a layer of the network with its epoch and time span as JSON,
+  /// by default the current one
+  Layer {
+    #[clap(flatten)]
+    network: layers::Network,
+    /// Layer to print
+    #[clap(long, conflicts_with = "time")]
+    layer: Option<i64>,
+    /// Print the layer at the given time in ISO format instead
+    #[clap(long)]
+    time: Option<chrono::DateTime<chrono::Utc>>,
   },
   /// Incremental check availability
   IncrementalCheck {
@@ -103,18 +545,63 @@ enum Commands {
     #[clap(short = 's', long)]
     state_sql: PathBuf,
     /// Number of layers present in the DB that are not trusted to be fully synced.
-    /// These layers will also be synced.
-    #[clap(long, default_value_t = 10)]
-    untrusted_layers: u32,
+    /// These layers will also be synced. By default, the layers the node hasn't
+    /// verified plus 10
+    #[clap(long)]
+    untrusted_layers: Option<u32>,
     /// Jump-back to recover earlier than latest layer. It will jump back one row in recovery metadata
     #[clap(short = 'j', long, default_value_t = 0)]
     jump_back: usize,
+    /// Jump back automatically to the latest restore point continuing the
+    /// database (whose starting hash it has), looking at most MAX_DEPTH
+    /// restore points back
+    #[clap(
+      long,
+      value_name = "MAX_DEPTH",
+      num_args = 0..=1,
+      default_missing_value = "10",
+      conflicts_with = "jump_back"
+    )]
+    auto_jump_back: Option<usize>,
     /// URL to download parts from
     #[clap(short = 'u', long, default_value = incremental_quicksync::DEFAULT_BASE_URL)]
     base_url: String,
+    /// Databases to sync. Other databases are expected next to state.sql
+    #[clap(long = "db", value_enum, default_value_t)]
+    db: DbSelection,
   },
 }
 
+impl Commands {
+  /// URLs given to the command, whose hosts the URL policy allows.
+  fn policy_urls(&self) -> anyhow::Result<Vec<Url>> {
+    Ok(match self {
+      Commands::Check {
+        download_url,
+        base_url,
+        ..
+      } => vec![download_url.clone(), Url::parse(base_url)?],
+      Commands::Download {
+        download_url,
+        mirrors,
+        archive_checksum_url,
+        db_checksum_url,
+        ..
+      } => std::iter::once(download_url)
+        .chain(mirrors)
+        .chain(archive_checksum_url)
+        .chain(db_checksum_url)
+        .cloned()
+        .collect(),
+      Commands::Incremental { base_url, .. }
+      | Commands::Rollback { base_url, .. }
+      | Commands::IncrementalCheck { base_url, .. } => vec![Url::parse(base_url)?],
+      Commands::Bench { download_url, .. } => vec![download_url.clone()],
+      _ => Vec::new(),
+    })
+  }
+}
+
 fn go_spacemesh_default_path() -> &'static str {
   #[cfg(target_os = "windows")]
   {
@@ -126,7 +613,7 @@ fn go_spacemesh_default_path() -> &'static str {
   }
 }
 
-fn backup_or_fail(file_path: PathBuf) {
+fn backup_or_fail(file_path: PathBuf) -> anyhow::Result<()> {
   match file_path.try_exists() {
     Ok(true) => {
       println!(
@@ -139,8 +626,7 @@ fn backup_or_fail(file_path: PathBuf) {
           println!("File backed up to: {}", backup_name);
         }
         Err(e) => {
-          eprintln!("Cannot create a backup file: {}", e);
-          process::exit(6);
+          return Err(ExitError::new(6, format!("Cannot create a backup file: {}", e)).into());
         }
       }
     }
@@ -151,207 +637,2219 @@ fn backup_or_fail(file_path: PathBuf) {
       );
     }
     Err(e) => {
-      eprintln!("Cannot create a backup file: {}", e);
-      process::exit(6);
+      return Err(ExitError::new(6, format!("Cannot create a backup file: {}", e)).into());
     }
   }
+  Ok(())
 }
 
-fn resolve_path(relative_path: &Path) -> anyhow::Result<PathBuf> {
-  let current_dir = env::current_dir()?;
-  Ok(current_dir.join(relative_path))
+/// The untrusted layers given with `--untrusted-layers`, or else the ones
+/// derived from the state database.
+fn untrusted_layers(flag: Option<u32>, state_sql: &Path) -> anyhow::Result<u32> {
+  if let Some(layers) = flag {
+    return Ok(layers);
+  }
+  let layers = get_db_status(state_sql)?.untrusted_layers();
+  println!("Untrusted layers: {layers} (derived from the database)");
+  Ok(layers)
 }
 
-fn main() -> anyhow::Result<()> {
-  let cli = Cli::parse();
+/// Lists the selected databases present next to `state.sql`.
+/// Missing databases are skipped unless they were requested explicitly.
+fn selected_databases(
+  selection: DbSelection,
+  state_sql_path: &Path,
+) -> anyhow::Result<Vec<Database>> {
+  let mut databases = Vec::new();
+  for &db in selection.databases() {
+    let path = db.path(state_sql_path);
+    if path.try_exists().context("checking if database exists")? {
+      databases.push(db);
+    } else if selection == DbSelection::All {
+      println!("Skipping {}: file not found", db.file_name());
+    } else {
+      return Err(anyhow!("database not found: {:?}", path));
+    }
+  }
+  Ok(databases)
+}
 
-  match cli.command {
-    Commands::Check {
-      node_data,
-      genesis_time,
-      layer_duration,
-      go_spacemesh_path,
-      download_url,
-    } => {
-      let result = {
-        let dir_path = node_data.clone();
-        let db_file_path = dir_path.join("state.sql");
-        let db_file_str = db_file_path.to_str().expect("Cannot compose path");
-        println!("Checking database: {}", db_file_str);
-        let db_layer = if db_file_path.try_exists().unwrap_or(false) {
-          i64::from(get_last_layer_from_db(&db_file_path).or_else(|err| {
-            eprintln!("{}", err);
-            println!("Cannot read database, trating it as empty database");
-            Ok::<i32, anyhow::Error>(0)
-          })?)
-        } else {
-          println!("Database file is not found");
-          0
-        };
-        println!("Latest layer in db: {}", db_layer);
+/// Replaces the download URL with the endpoint of the region, if one is given.
+async fn region_url(download_url: Url, region: Option<String>) -> anyhow::Result<Url> {
+  match region {
+    Some(region) => {
+      let url = regions::resolve(&download_url, &region).await?;
+      url_policy::check(&url)?;
+      Ok(url)
+    }
+    None => Ok(download_url),
+  }
+}
 
-        let time_layer = calculate_latest_layer(genesis_time, layer_duration)?;
-        println!("Current network layer: {}", time_layer);
+/// Probes the download speed and sizes the snapshot and the restore points
+/// of the database at `db_path`, to estimate each way of catching up.
+async fn estimate_catch_up(
+  status: &check::SyncStatus,
+  time_per_layer: Duration,
+  download_url: &Url,
+  go_version: &str,
+  variant: Variant,
+  base_url: &str,
+  db_path: &Path,
+) -> check::Estimates {
+  println!("Measuring download speed...");
+  let probe = match mirrors::probe(download_url.clone(), go_version.to_string(), variant).await {
+    Ok(probe) => {
+      println!(
+        "Download speed: {:.2} MB/s",
+        probe.bytes_per_sec / 1_024_000.00
+      );
+      Some(probe)
+    }
+    Err(e) => {
+      println!("Cannot measure download speed: {e:#}");
+      None
+    }
+  };
+  let snapshot = probe.as_ref().and_then(|p| {
+    Some(check::Download {
+      bytes: p.size?,
+      layer: i64::try_from(p.layer).ok()?,
+    })
+  });
 
-        let go_path = resolve_path(&go_spacemesh_path).unwrap();
-        let go_version = get_version(&go_path)?;
-        let quicksync_layer = fetch_latest_available_layer(&download_url, &go_version)?;
-        println!("Latest layer in cloud: {}", quicksync_layer);
-        Ok(())
-      };
-      if result.is_err() {
-        process::exit(1);
+  let restore = if status.applied_layer > 0 {
+    match incremental_quicksync::restore_size(base_url, db_path, status.untrusted_layers).await {
+      Ok((Some(bytes), to)) => Some(check::Download {
+        bytes,
+        layer: i64::from(to) - 1,
+      }),
+      Ok((None, _)) => {
+        println!("Restore points don't list their sizes, cannot estimate partial restore");
+        None
+      }
+      Err(e) => {
+        println!("Cannot estimate partial restore: {e:#}");
+        None
       }
-      result
     }
-    Commands::Download {
-      node_data,
-      go_spacemesh_path,
-      mut download_url,
-      max_retries,
-    } => {
-      let dir_path = node_data;
-      let redirect_file_path = dir_path.join("state.url");
-      let archive_file_path = dir_path.join("state.zst");
-      let unpacked_file_path = dir_path.join("state_downloaded.sql");
-      let final_file_path = dir_path.join("state.sql");
-      let wal_file_path = dir_path.join("state.sql-wal");
-
-      // Download archive if needed
-      if !archive_file_path.try_exists().unwrap_or(false) {
-        println!("Downloading the latest database...");
-        let url = if redirect_file_path.try_exists().unwrap_or(false) {
-          std::fs::read_to_string(&redirect_file_path)?
-        } else {
-          let go_path = resolve_path(&go_spacemesh_path).context("checking node version")?;
-          let version = get_version(&go_path)?;
-          download_url
-            .path_segments_mut()
-            .map_err(|e| anyhow::anyhow!("parsing download url: {e:?}"))?
-            .extend(&[&version, "state.zst"]);
-          download_url.to_string()
-        };
+  } else {
+    None
+  };
 
-        let temp_file_path = dir_path.join("state.download");
-        if let Some(dir) = temp_file_path.parent() {
-          std::fs::create_dir_all(dir)?;
-        }
+  status.estimate(
+    time_per_layer,
+    probe.map(|p| p.bytes_per_sec),
+    snapshot,
+    restore,
+  )
+}
 
-        let mut file = OpenOptions::new()
-          .create(true)
-          .read(true)
-          .append(true)
-          .open(&temp_file_path)
-          .with_context(|| format!("creating temp file: {}", temp_file_path.display()))?;
+/// Sleeps a random time up to `max` to spread requests of many nodes over time.
+async fn start_delay_jitter(max: Option<Duration>) -> anyhow::Result<()> {
+  let Some(max) = max else {
+    return Ok(());
+  };
+  let delay = random_delay(max.to_std()?);
+  println!("Waiting {} seconds before starting...", delay.as_secs());
+  tokio::time::sleep(delay).await;
+  Ok(())
+}
 
-        if let Err(e) = download_with_retries(
-          &url,
-          &mut file,
-          &redirect_file_path,
-          max_retries,
-          std::time::Duration::from_secs(5),
-        ) {
-          eprintln!("Failed to download a file after {max_retries} attempts: {e}",);
-          file.flush()?;
-          process::exit(1);
-        }
-        drop(file);
+fn resolve_path(relative_path: &Path) -> anyhow::Result<PathBuf> {
+  let path = long_path::absolute(relative_path)?;
+  error_report::add_root(&path);
+  Ok(path)
+}
+
+/// Refuses to replace the local database with an older one.
+fn check_downgrade(local_db: &Path, downloaded_db: &Path) -> anyhow::Result<()> {
+  if !local_db.try_exists().unwrap_or(false) {
+    return Ok(());
+  }
+  let local_layer = match get_last_layer_from_db(local_db) {
+    Ok(layer) => layer,
+    Err(e) => {
+      println!("Cannot read the local database, skipping downgrade check: {e}");
+      return Ok(());
+    }
+  };
+  let downloaded_layer =
+    get_last_layer_from_db(downloaded_db).context("reading latest layer of downloaded db")?;
+  println!("Latest layer in local db: {local_layer}, in downloaded db: {downloaded_layer}");
+  if downloaded_layer < local_layer {
+    return Err(
+      ExitError::new(
+        13,
+        format!(
+          "Downloaded database (layer {downloaded_layer}) is older than the local one (layer {local_layer}). Use --force to replace it anyway"
+        ),
+      )
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+/// Why a rerun of `download` with `--idempotent` has nothing to do, if it
+/// doesn't: the latest snapshot is the one installed last, or the local
+/// database has its layer already.
+async fn nothing_to_do(
+  node_data: &Path,
+  go_spacemesh_path: &Path,
+  download_url: &Url,
+  variant: Variant,
+) -> anyhow::Result<Option<String>> {
+  let marker =
+    SyncMarker::load(node_data).filter(|marker| marker.matches(node_data, download_url, variant));
+  let go_path = resolve_path(go_spacemesh_path).context("checking node version")?;
+  let version = get_version(&go_path)?;
+  let latest = match resolve_snapshot(download_url, &version, variant).await {
+    Ok(snapshot) => snapshot,
+    // A completed sync stays complete while the server can't be reached
+    Err(e) => {
+      return Ok(marker.map(|marker| {
+        format!(
+          "the snapshot of layer {} was installed at {} (cannot check for a newer one: {e:#})",
+          marker.layer, marker.completed_at
+        )
+      }))
+    }
+  };
+  if let Some(marker) = marker.filter(|marker| marker.layer >= latest.layer) {
+    return Ok(Some(format!(
+      "the latest snapshot (layer {}) was installed at {}",
+      latest.layer, marker.completed_at
+    )));
+  }
+  let local_layer = get_last_layer_from_db(&node_data.join("state.sql")).unwrap_or(0);
+  Ok(
+    (u64::try_from(local_layer).unwrap_or(0) >= latest.layer).then(|| {
+      format!(
+        "the local database (layer {local_layer}) is past the latest snapshot (layer {})",
+        latest.layer
+      )
+    }),
+  )
+}
 
-        // Rename `state.download` -> `state.zst`
-        std::fs::rename(&temp_file_path, &archive_file_path)?;
-        println!("Archive downloaded!");
+/// Records the snapshot at the URL in the `redirect_file_path`, looked up at
+/// `download_url`, as installed.
+fn save_sync_marker(
+  node_data: &Path,
+  redirect_file_path: &Path,
+  download_url: &Url,
+  variant: Variant,
+  db_md5: Option<String>,
+) -> anyhow::Result<()> {
+  let url = Url::parse(std::fs::read_to_string(redirect_file_path)?.trim())?;
+  let (db_size, db_modified) = sync_marker::db_state(node_data)?;
+  SyncMarker {
+    download_url: download_url.to_string(),
+    variant,
+    layer: extract_number_from_url(&url)?,
+    snapshot_url: url.into(),
+    db_md5,
+    db_size,
+    db_modified,
+    completed_at: chrono::Utc::now(),
+  }
+  .save(node_data)
+}
+
+/// Shows what installing the downloaded database changes and asks to go on.
+fn confirm_install(local_db: &Path, downloaded_db: &Path) -> anyhow::Result<bool> {
+  let mb = |path: &Path| std::fs::metadata(path).map_or(0.0, |m| m.len() as f64 / 1_024_000.00);
+  let layer = |path: &Path| {
+    get_last_layer_from_db(path).map_or_else(|_| "unknown".to_string(), |l| l.to_string())
+  };
+  let (local_mb, downloaded_mb) = (mb(local_db), mb(downloaded_db));
+  println!(
+    "About to replace the local database {}:",
+    local_db.display()
+  );
+  if local_db.try_exists().unwrap_or(false) {
+    println!("  layer: {} -> {}", layer(local_db), layer(downloaded_db));
+    println!(
+      "  size: {local_mb:.2} MB -> {downloaded_mb:.2} MB ({:+.2} MB)",
+      downloaded_mb - local_mb
+    );
+    println!(
+      "  backup: {} (keeps {local_mb:.2} MB until deleted)",
+      backup_path(local_db).display()
+    );
+  } else {
+    println!("  layer: none -> {}", layer(downloaded_db));
+    println!("  size: {downloaded_mb:.2} MB");
+  }
+  ask("Replace it?", false)
+}
+
+/// Backs up the local database, unless a crashed run did it already, and
+/// moves the unpacked one in its place.
+fn install_db(
+  unpacked: &Path,
+  final_path: &Path,
+  wal_path: PathBuf,
+  network_fs: bool,
+  journal: &mut Journal,
+  backed_up: bool,
+) -> anyhow::Result<()> {
+  if !backed_up {
+    backup_or_fail(final_path.to_path_buf())?;
+    backup_or_fail(wal_path)?;
+    journal.record(Step::BackedUp, unpacked)?;
+  }
+
+  if network_fs {
+    netfs::copy_replace(unpacked, final_path)
+      .context("Cannot copy downloaded file into state.sql")?;
+  } else {
+    file_in_use::move_file(unpacked, final_path)
+      .context("Cannot rename downloaded file into state.sql")?;
+  }
+  journal.record(Step::Installed, final_path)
+}
+
+/// Checks if the local database is at least as recent as the latest snapshot,
+/// comparing checksums if both are at the same layer.
+async fn is_up_to_date(
+  db_path: &Path,
+  download_url: &Url,
+  version: &str,
+  variant: Variant,
+  io: IoOptions,
+  checksum: &ChecksumOptions,
+) -> anyhow::Result<bool> {
+  let local_layer = u64::try_from(get_last_layer_from_db(db_path)?)?;
+  let Snapshot {
+    url: snapshot_url,
+    layer: snapshot_layer,
+  } = resolve_snapshot(download_url, version, variant).await?;
+  println!("Latest layer in local db: {local_layer}, in snapshot: {snapshot_layer}");
+  if local_layer != snapshot_layer {
+    return Ok(local_layer > snapshot_layer);
+  }
+  println!("Comparing the local database with the snapshot, it may take some time...");
+  db_matches_snapshot(&snapshot_url, db_path, io, checksum).await
+}
+
+/// Why `download` stops before downloading anything into `node_data`, if it
+/// does: the local database matches the latest snapshot, or with
+/// `--idempotent`, the run has nothing to do.
+async fn up_to_date(
+  node_data: &Path,
+  go_spacemesh_path: &Path,
+  download_url: &Url,
+  variant: Variant,
+  idempotent: bool,
+  io: IoOptions,
+  checksum: &ChecksumOptions,
+) -> anyhow::Result<Option<String>> {
+  let db_path = node_data.join("state.sql");
+  if !db_path.try_exists().unwrap_or(false) {
+    return Ok(None);
+  }
+  events::stage(events::Stage::CheckUpToDate);
+  if idempotent {
+    if let Some(reason) = nothing_to_do(node_data, go_spacemesh_path, download_url, variant).await?
+    {
+      return Ok(Some(format!("Nothing to do: {reason}")));
+    }
+  }
+  let go_path = resolve_path(go_spacemesh_path).context("checking node version")?;
+  let version = get_version(&go_path)?;
+  match is_up_to_date(&db_path, download_url, &version, variant, io, checksum).await {
+    Ok(true) => Ok(Some(
+      "Already up to date: the local database matches the latest snapshot".to_string(),
+    )),
+    Ok(false) => Ok(None),
+    Err(e) => {
+      println!("Cannot check if the local database is up to date: {e:#}");
+      Ok(None)
+    }
+  }
+}
+
+/// Builds the latest archive by patching the archive kept from the previous
+/// snapshot, if the server publishes a patch for it.
+/// Returns false if the full archive has to be downloaded instead.
+async fn patch_download(
+  kept: patch::KeptArchive,
+  node_data: &Path,
+  redirect_file_path: &Path,
+  archive_file_path: &Path,
+  snapshot: &Snapshot,
+  io: IoOptions,
+  checksum: &ChecksumOptions,
+) -> anyhow::Result<bool> {
+  if snapshot.layer <= kept.layer {
+    return Ok(false);
+  }
+  let patch_url = patch::patch_url(&snapshot.url, kept.layer)?;
+  let Some(patch_data) = patch::fetch_patch(&patch_url).await? else {
+    return Ok(false);
+  };
+  events::stage(events::Stage::Download);
+  println!(
+    "Patching the archive of layer {} ({:.2} MB patch)...",
+    kept.layer,
+    patch_data.len() as f64 / 1_024_000.00
+  );
+  let patched = node_data.join("state.patched");
+  let source = patch::KeptArchive::archive_path(node_data);
+  let applied = {
+    let patched = patched.clone();
+    tokio::task::spawn_blocking(move || patch::apply(&source, &kept.md5, &patch_data, &patched))
+      .await?
+  };
+  if let Err(e) = applied {
+    let _ = std::fs::remove_file(&patched);
+    return Err(e);
+  }
+
+  // The patched archive is verified here, so it's not hashed again afterwards
+  download::save_redirect(redirect_file_path, snapshot.url.as_str())?;
+  let md5_url = checksum
+    .archive_md5_url(redirect_file_path)?
+    .context("the archive checksum URL is unknown")?;
+  if verify_archive(&md5_url, &patched, io, checksum).await? {
+    file_in_use::move_file(&patched, archive_file_path)?;
+    Ok(true)
+  } else {
+    std::fs::remove_file(&patched)?;
+    std::fs::remove_file(redirect_file_path)?;
+    anyhow::bail!("the patched archive doesn't match the snapshot")
+  }
+}
+
+/// Checksums of the parts of the snapshot downloaded from `url`, or resumed
+/// from the URL in the redirect file, if the server publishes them.
+async fn fetch_part_list(redirect_file_path: &Path, url: &str) -> Option<parts::PartList> {
+  let list = async {
+    let snapshot_url = match std::fs::read_to_string(redirect_file_path) {
+      Ok(saved) => Url::parse(saved.trim())?,
+      Err(_) => download::resolve_object(url).await?.0,
+    };
+    parts::fetch(&snapshot_url).await
+  };
+  list.await.unwrap_or_else(|e| {
+    println!("Cannot verify the parts of the archive: {e:#}");
+    None
+  })
+}
+
+/// Saves the URL of the snapshot the archive was downloaded from in the
+/// missing redirect file (e.g. the archive was downloaded by an earlier run),
+/// so that its checksums are looked up next to it: the `archive_snapshot`
+/// recorded in the journal, or else the latest snapshot at `download_url` if
+/// the archive has its size. Fails with exit code 8 if it's unknown.
+async fn recover_snapshot_url(
+  redirect_file_path: &Path,
+  archive_file_path: &Path,
+  archive_snapshot: Option<&journal::Snapshot>,
+  download_url: &Url,
+  go_spacemesh_path: &Path,
+  variant: Variant,
+) -> anyhow::Result<()> {
+  if let Some(snapshot) = archive_snapshot {
+    println!(
+      "Verifying against the snapshot the archive was downloaded from: {}",
+      snapshot.url
+    );
+    download::save_redirect(redirect_file_path, &snapshot.url)?;
+    return Ok(());
+  }
+  let go_path = resolve_path(go_spacemesh_path).context("checking node version")?;
+  let version = get_version(&go_path)?;
+  let snapshot = resolve_snapshot(download_url, &version, variant).await?;
+  let (_, size, _) = download::resolve_object(snapshot.url.as_str()).await?;
+  let archive_len = std::fs::metadata(archive_file_path)?.len();
+  if let Some(size) = size.filter(|&size| size != archive_len) {
+    return Err(
+      ExitError::new(
+        8,
+        format!(
+          "Cannot validate archive checksum: the archive has {archive_len} bytes, the latest \
+           snapshot {size} bytes, and the snapshot it was downloaded from is unknown. Delete {} \
+           to download the latest snapshot",
+          archive_file_path.display()
+        ),
+      )
+      .into(),
+    );
+  }
+  println!("Verifying against the latest snapshot: {}", snapshot.url);
+  download::save_redirect(redirect_file_path, snapshot.url.as_str())?;
+  Ok(())
+}
+
+/// Keys the journal to the snapshot in the redirect file and the checksum of
+/// its archive, unless it's keyed already. Its steps aren't resumed nor
+/// recorded if they're unknown.
+async fn key_journal(journal: &mut Journal, redirect_file_path: &Path, checksum: &ChecksumOptions) {
+  if journal.is_keyed() {
+    return;
+  }
+  let snapshot = async {
+    let url = std::fs::read_to_string(redirect_file_path)?;
+    let md5_url = checksum
+      .archive_md5_url(redirect_file_path)?
+      .context("the archive checksum URL is unknown")?;
+    let md5 = download_checksum(md5_url, checksum).await?;
+    anyhow::Ok(journal::Snapshot {
+      url: url.trim().to_string(),
+      md5,
+    })
+  };
+  journal.key(snapshot.await.ok());
+}
+
+/// Whether the local database is still the snapshot a crashed run installed.
+async fn installed_snapshot(
+  final_file_path: &Path,
+  redirect_file_path: &Path,
+  io: IoOptions,
+  checksum: &ChecksumOptions,
+) -> bool {
+  let Ok(Some(md5_url)) = checksum.db_md5_url(redirect_file_path) else {
+    return false;
+  };
+  println!("Checking the database installed by the previous run...");
+  // The node may be writing to it, so it's read without mapping
+  let io = IoOptions {
+    hash_threads: 1,
+    ..io
+  };
+  matches!(
+    verify_db(&md5_url, final_file_path, io, checksum).await,
+    Ok(Some(_))
+  )
+}
+
+/// Rebuilds the snapshot from the chunks of the local database and the changed
+/// chunks downloaded from the server, if the snapshot is chunked.
+/// Returns false if the full archive has to be downloaded instead.
+async fn delta_download(
+  local_db: &Path,
+  unpacked: &Path,
+  redirect_file_path: &Path,
+  snapshot_url: &Url,
+  max_retries: u32,
+) -> anyhow::Result<bool> {
+  let index = match delta::fetch_index(snapshot_url).await {
+    Ok(Some(index)) => index,
+    Ok(None) => return Ok(false),
+    Err(e) => {
+      println!("Cannot use the chunk index: {e:#}");
+      return Ok(false);
+    }
+  };
+
+  println!("The snapshot is chunked, downloading only the chunks that changed...");
+  events::stage(events::Stage::Download);
+  match delta::download(
+    &source::HttpSource::new()?,
+    snapshot_url,
+    &index,
+    local_db,
+    unpacked,
+    max_retries,
+  )
+  .await
+  {
+    Ok(stats) => {
+      println!(
+        "Reused {} chunks of the local database, downloaded {} chunks ({:.2} MB)",
+        stats.reused,
+        stats.fetched,
+        stats.fetched_bytes as f64 / 1_024_000.00
+      );
+      // Checksums of the database are found next to the snapshot
+      download::save_redirect(redirect_file_path, snapshot_url.as_str())?;
+      Ok(true)
+    }
+    // Keep the exit code of a cancelled download
+    Err(e) if ExitError::find(&e).is_some() => Err(e),
+    Err(e) => {
+      println!("Cannot download the changed chunks: {e:#}. Downloading the full archive");
+      if unpacked.try_exists().unwrap_or(false) {
+        std::fs::remove_file(unpacked)?;
       }
+      Ok(false)
+    }
+  }
+}
 
-      if redirect_file_path.try_exists().unwrap_or(false) {
-        println!("Verifying the checksum, it may take some time...");
-        // Verify downloaded archive
-        match verify_archive(&redirect_file_path, &archive_file_path) {
-          Ok(true) => {
-            println!("Archive checksm validated");
-          }
-          Ok(false) => {
-            eprintln!("Archive checksum is invalid. Deleting archive");
-            std::fs::remove_file(&archive_file_path)?;
-            process::exit(7);
-          }
-          Err(e) => {
-            eprintln!("Cannot validate archive checksum: {}", e);
-            process::exit(8);
-          }
-        }
-      } else {
-        println!("Download URL is not found: skip archive checksum verification");
+/// Keeps the installed archive for patching it into the next snapshot.
+async fn keep_archive_file(
+  node_data: &Path,
+  archive_file_path: &Path,
+  redirect_file_path: &Path,
+  checksum: &ChecksumOptions,
+) -> anyhow::Result<()> {
+  anyhow::ensure!(
+    redirect_file_path.try_exists().unwrap_or(false),
+    "the snapshot URL is unknown"
+  );
+  let url = Url::parse(&std::fs::read_to_string(redirect_file_path)?)?;
+  // The archive was verified against this checksum
+  let md5 = archive_checksum(&url, checksum).await?;
+  let kept = patch::keep(node_data, archive_file_path, url, md5)?;
+  println!(
+    "Archive of layer {} is kept in {}",
+    kept.layer,
+    patch::KeptArchive::archive_path(node_data).display()
+  );
+  Ok(())
+}
+
+/// Stages of the `download` command to run, all of them by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stages {
+  All,
+  /// Download the archive and stop.
+  DownloadOnly,
+  /// Verify the downloaded archive and stop.
+  VerifyOnly,
+  /// Unpack and install the downloaded archive, without verifying it again
+  /// if it was verified.
+  InstallOnly,
+}
+
+/// Prints the outcome of the download into each node-data directory.
+/// Fails with the exit code of the first failed one.
+fn fleet_report(results: Vec<(PathBuf, anyhow::Result<()>)>) -> anyhow::Result<()> {
+  println!("Results:");
+  let mut exit_code = None;
+  let total = results.len();
+  let mut failed = 0;
+  for (node_data, result) in results {
+    match result {
+      Ok(()) => println!("  {}: done", node_data.display()),
+      Err(e) => {
+        let code = ExitError::find(&e).map_or(1, |e| e.code);
+        println!(
+          "  {}: failed (exit code {code}): {e:#}",
+          node_data.display()
+        );
+        exit_code.get_or_insert(code);
+        failed += 1;
       }
+    }
+  }
+  match exit_code {
+    Some(code) => Err(
+      ExitError::new(
+        code,
+        format!("{failed} of {total} node-data directories failed"),
+      )
+      .into(),
+    ),
+    None => Ok(()),
+  }
+}
 
-      match unpack::unpack(&archive_file_path, &unpacked_file_path) {
-        Ok(_) => {
-          println!("Archive unpacked successfully");
-        }
-        Err(e) => {
-          if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
-            // FIXME: use ErrorKind::StorageFull once it's stabilized (https://github.com/rust-lang/rust/issues/86442)
-            if io_err.raw_os_error() == Some(28) {
-              eprintln!("Cannot unpack archive: not enough disk space");
-              std::fs::remove_file(&unpacked_file_path)?;
-              process::exit(2);
-            }
-          }
-          eprintln!("Cannot unpack archive: {}", e);
-          std::fs::remove_file(&unpacked_file_path)?;
-          process::exit(3);
-        }
+/// Archive of a fleet, downloaded on the first use into `dir` and then linked
+/// (or copied) into the directory of each node-data.
+struct SharedArchive<'a> {
+  dir: PathBuf,
+  options: DownloadOptions<'a>,
+  /// Exit code and message of a failed download, shared by all the jobs.
+  downloaded: tokio::sync::OnceCell<Result<(), (i32, String)>>,
+}
+
+impl SharedArchive<'_> {
+  /// Puts the shared archive at `archive`, downloading it if no job did yet.
+  async fn place(&self, archive: &Path, redirect: &Path) -> anyhow::Result<()> {
+    let downloaded = self
+      .downloaded
+      .get_or_init(|| async {
+        let download: futures::future::LocalBoxFuture<'_, anyhow::Result<()>> =
+          Box::pin(download(self.dir.clone(), self.options.clone()));
+        events::in_job("shared".to_string(), download)
+          .await
+          .map_err(|e| (ExitError::find(&e).map_or(1, |e| e.code), format!("{e:#}")))
+      })
+      .await;
+    if let Err((code, message)) = downloaded {
+      return Err(
+        ExitError::new(
+          *code,
+          format!("Cannot download the shared archive: {message}"),
+        )
+        .into(),
+      );
+    }
+    let shared_archive = self.dir.join("state.zst");
+    if std::fs::hard_link(&shared_archive, archive).is_err() {
+      std::fs::copy(&shared_archive, archive)
+        .with_context(|| format!("copying the shared archive to {}", archive.display()))?;
+    }
+    let shared_redirect = self.dir.join("state.url");
+    let records = std::iter::once((shared_redirect.clone(), redirect.to_path_buf())).chain(
+      download::record_paths(&shared_redirect)
+        .into_iter()
+        .zip(download::record_paths(redirect)),
+    );
+    for (from, to) in records {
+      if from.try_exists().unwrap_or(false) {
+        std::fs::copy(&from, &to).with_context(|| format!("copying {}", from.display()))?;
       }
+    }
+    Ok(())
+  }
+}
 
-      // Verify checksum
-      if redirect_file_path.try_exists().unwrap_or(false) {
-        println!("Verifying MD5 checksum...");
-        match verify_db(&redirect_file_path, &unpacked_file_path) {
-          Ok(true) => {
-            println!("Checksum is valid");
-          }
-          Ok(false) => {
-            eprintln!("MD5 checksums are not equal. Deleting archive and unpacked state.sql");
-            std::fs::remove_file(&unpacked_file_path)?;
-            std::fs::remove_file(&archive_file_path)?;
-            std::fs::remove_file(&redirect_file_path)?;
-            process::exit(4);
-          }
-          Err(e) => {
-            eprintln!("Cannot verify checksum: {}", e);
-            process::exit(5);
-          }
-        }
-      } else {
-        println!("Download URL is not found: skip DB checksum verification");
+/// Serves the archive kept in `node_data` at `listen` until the `limits` are
+/// reached, advertising it on the LAN if `advertise` is set.
+async fn serve_kept_archive(
+  node_data: &Path,
+  listen: SocketAddr,
+  limits: seed::Limits,
+  advertise: bool,
+) -> anyhow::Result<()> {
+  let listener = tokio::net::TcpListener::bind(listen)
+    .await
+    .with_context(|| format!("listening at {listen} to seed"))?;
+  let kept = patch::KeptArchive::load(node_data)?.context("there is no kept archive to serve")?;
+  // The advertisement lasts as long as the daemon is kept
+  let _advertised = match advertise {
+    true => match discovery::advertise(listener.local_addr()?.port(), kept.layer) {
+      Ok(daemon) => Some(daemon),
+      Err(e) => {
+        println!("Cannot advertise the archive on the LAN: {e:#}");
+        None
       }
+    },
+    false => None,
+  };
+  seed::seed(listener, node_data, limits).await?;
+  Ok(())
+}
 
-      backup_or_fail(final_file_path.clone());
-      backup_or_fail(wal_file_path);
+/// Settings of the `download` command.
+#[derive(Clone)]
+struct DownloadOptions<'a> {
+  go_spacemesh_path: &'a Path,
+  download_url: Url,
+  /// Other servers with the same snapshots, the fastest server is used.
+  mirrors: Vec<Url>,
+  /// Snapshot sources found on the LAN, preferred if they're fresh enough.
+  lan: Vec<discovery::Source>,
+  /// Layers the snapshot on the LAN may be behind the download server.
+  lan_max_lag: u64,
+  variant: Variant,
+  max_retries: u32,
+  /// Retries of the download in total, however much they downloaded.
+  max_total_retries: Option<u32>,
+  /// External tool the archive is downloaded with instead.
+  downloader: Option<external_downloader::ExternalDownloader>,
+  /// Bytes per second the download is reconnected (or moved to another
+  /// mirror) below.
+  min_speed: Option<f64>,
+  min_speed_grace: std::time::Duration,
+  /// Age of the URL saved in `state.url` it's resolved again after.
+  url_file_ttl: std::time::Duration,
+  io: IoOptions,
+  checksum: ChecksumOptions,
+  hooks: &'a Hooks,
+  force: bool,
+  /// Download only the changes if there is a patch or the snapshot is chunked.
+  delta: bool,
+  keep_archive: bool,
+  /// Directory of the archive and the unpacked database, node-data if `None`.
+  temp_dir: Option<PathBuf>,
+  leftovers: leftovers::Policy,
+  stages: Stages,
+  /// Ask before replacing the local database.
+  confirm: bool,
+  /// Succeed without downloading if there's nothing to do.
+  idempotent: bool,
+  /// Archive downloaded once for all the node-data directories of a fleet.
+  shared: Option<&'a SharedArchive<'a>>,
+}
 
-      std::fs::rename(&unpacked_file_path, &final_file_path)
-        .expect("Cannot rename downloaded file into state.sql");
+async fn download(node_data: PathBuf, options: DownloadOptions<'_>) -> anyhow::Result<()> {
+  let DownloadOptions {
+    go_spacemesh_path,
+    download_url,
+    mirrors,
+    lan,
+    lan_max_lag,
+    variant,
+    max_retries,
+    max_total_retries,
+    downloader,
+    min_speed,
+    min_speed_grace,
+    url_file_ttl,
+    io,
+    mut checksum,
+    hooks,
+    force,
+    delta,
+    keep_archive,
+    temp_dir,
+    leftovers,
+    stages,
+    confirm,
+    idempotent,
+    shared,
+  } = options;
+  let dir_path = node_data;
+  let work_dir = temp_dir.unwrap_or_else(|| dir_path.clone());
+  let redirect_file_path = dir_path.join("state.url");
+  let archive_file_path = work_dir.join("state.zst");
+  let unpacked_file_path = work_dir.join("state_downloaded.sql");
+  // Records the size of the archive verified by `--verify-only`
+  let verified_file_path = work_dir.join("state.verified");
+  let final_file_path = dir_path.join("state.sql");
+  let wal_file_path = dir_path.join("state.sql-wal");
 
-      if archive_file_path.try_exists().unwrap_or(false) {
-        println!("Archive file is deleted.");
-        std::fs::remove_file(&archive_file_path)?;
+  preflight::check_writable(&dir_path).map_err(|e| ExitError::new(16, format!("{e:#}")))?;
+  if work_dir != dir_path {
+    std::fs::create_dir_all(&work_dir)
+      .with_context(|| format!("creating temp dir: {}", work_dir.display()))?;
+    preflight::check_writable(&work_dir).map_err(|e| ExitError::new(16, format!("{e:#}")))?;
+  }
+  // Named after the run that started downloading it
+  let previous_download = leftovers::previous_download(&work_dir);
+  let mut temp_file_path = previous_download
+    .clone()
+    .unwrap_or_else(|| work_dir.join(leftovers::download_name()));
+  let temp_files_of = |temp_file_path: &Path| {
+    [
+      temp_file_path.to_path_buf(),
+      block_hashes::record_path(temp_file_path),
+      archive_file_path.clone(),
+      unpacked_file_path.clone(),
+      seekable::progress_path(&unpacked_file_path),
+      redirect_file_path.clone(),
+      verified_file_path.clone(),
+      // Copies into node-data from another file system
+      file_in_use::staged_path(&final_file_path),
+      file_in_use::staged_path(&archive_file_path),
+    ]
+    .into_iter()
+    .chain(download::record_paths(&redirect_file_path))
+    .collect::<Vec<_>>()
+  };
+  if !leftovers::check(&work_dir, &temp_files_of(&temp_file_path), leftovers)?
+    && previous_download.is_some()
+  {
+    temp_file_path = work_dir.join(leftovers::download_name());
+  }
+  leftovers::record(&work_dir, &temp_files_of(&temp_file_path))?;
+  let block_record_path = block_hashes::record_path(&temp_file_path);
+  // The steps a crashed run completed for the same snapshot, with their files
+  // still intact
+  let mut journal = Journal::load(&dir_path);
+  // Known before keying the journal, which forgets it for another snapshot
+  let archive_snapshot = journal.snapshot_of(&archive_file_path).cloned();
+  if !journal.is_empty() {
+    key_journal(&mut journal, &redirect_file_path, &checksum).await;
+  }
+  let mut resume_from = journal.resume_point();
+  // The node may have changed the database since, without changing its fingerprint
+  if resume_from == Some(Step::Installed)
+    && !installed_snapshot(&final_file_path, &redirect_file_path, io, &checksum).await
+  {
+    journal.forget(Step::BackedUp)?;
+    resume_from = journal.resume_point();
+  }
+  if let Some(step) = resume_from {
+    println!("Resuming a previous run after its last completed step: {step}");
+  }
+  if matches!(stages, Stages::VerifyOnly | Stages::InstallOnly)
+    && !archive_file_path.try_exists().unwrap_or(false)
+  {
+    anyhow::bail!(
+      "There is no downloaded archive at {}, download it with --download-only first",
+      archive_file_path.display()
+    );
+  }
+  let network_fs = netfs::detect(&dir_path);
+  if let Some(fs) = &network_fs {
+    println!(
+      "Warning: {} is on a network file system ({fs}). SQLite locking and WAL don't work reliably there, \
+       which may corrupt the database. Consider keeping node-data on a local disk",
+      dir_path.display()
+    );
+    if !force {
+      return Err(
+        ExitError::new(
+          17,
+          "Refusing to sync onto a network file system, use --force to do it anyway",
+        )
+        .into(),
+      );
+    }
+  }
+
+  let resuming = resume_from.is_some()
+    || archive_file_path.try_exists().unwrap_or(false)
+    || redirect_file_path.try_exists().unwrap_or(false);
+  if !force && !resuming {
+    let up_to_date = up_to_date(
+      &dir_path,
+      go_spacemesh_path,
+      &download_url,
+      variant,
+      idempotent,
+      io,
+      &checksum,
+    );
+    if let Some(reason) = up_to_date.await? {
+      println!("{reason}");
+      journal.clear()?;
+      leftovers::finish(&work_dir)?;
+      return Ok(());
+    }
+  }
+
+  let mut delta_done = false;
+  let snapshot = if delta && !resuming && stages == Stages::All {
+    let go_path = resolve_path(go_spacemesh_path).context("checking node version")?;
+    let version = get_version(&go_path)?;
+    resolve_snapshot(&download_url, &version, variant)
+      .await
+      .map_err(|e| println!("Cannot resolve the snapshot URL: {e:#}"))
+      .ok()
+  } else {
+    None
+  };
+  if let Some(snapshot) = snapshot {
+    let patched = match patch::KeptArchive::load(&dir_path) {
+      Ok(Some(kept)) => {
+        let result = patch_download(
+          kept,
+          &dir_path,
+          &redirect_file_path,
+          &archive_file_path,
+          &snapshot,
+          io,
+          &checksum,
+        )
+        .await;
+        result.unwrap_or_else(|e| {
+          println!("Cannot patch the kept archive: {e:#}. Downloading the full archive");
+          false
+        })
       }
-      if redirect_file_path.try_exists().unwrap_or(false) {
-        println!("URL file is deleted.");
-        std::fs::remove_file(&redirect_file_path)?;
+      Ok(None) => false,
+      Err(e) => {
+        println!("Cannot use the kept archive: {e:#}");
+        false
       }
+    };
+    if patched {
+      key_journal(&mut journal, &redirect_file_path, &checksum).await;
+      journal.record(Step::Downloaded, &archive_file_path)?;
+      journal.record(Step::Verified, &archive_file_path)?;
+      resume_from = Some(Step::Verified);
+    }
+    // A patched archive is unpacked as a downloaded one
+    if !patched && final_file_path.try_exists().unwrap_or(false) {
+      delta_done = delta_download(
+        &final_file_path,
+        &unpacked_file_path,
+        &redirect_file_path,
+        &snapshot.url,
+        max_retries,
+      )
+      .await?;
+    }
+  }
 
-      println!("Done!");
-      println!("Now you can run go-spacemesh as usually.");
+  if delta_done {
+    key_journal(&mut journal, &redirect_file_path, &checksum).await;
+    journal.record(Step::Unpacked, &unpacked_file_path)?;
+  }
+  let unpacked_before =
+    resume_from >= Some(Step::Unpacked) && matches!(stages, Stages::All | Stages::InstallOnly);
 
-      Ok(())
-    }
+  // Checksums of the archive and the database unpacked while it was downloaded
+  let mut pipelined: Option<Pipelined> = None;
+  if !delta_done && !unpacked_before {
+    if let Some(shared) = shared {
+      if !archive_file_path.try_exists().unwrap_or(false) {
+        shared
+          .place(&archive_file_path, &redirect_file_path)
+          .await?;
+        key_journal(&mut journal, &redirect_file_path, &checksum).await;
+        journal.record(Step::Downloaded, &archive_file_path)?;
+      }
+    }
+    // Download archive if needed
+    if !archive_file_path.try_exists().unwrap_or(false) {
+      println!("Downloading the latest database...");
+      events::stage(events::Stage::Download);
+      let candidates: Vec<Url> = std::iter::once(download_url.clone())
+        .chain(mirrors.iter().cloned())
+        .collect();
+      // Picking mirrors again is possible only if one was picked at the start
+      let mut mirror: Option<(String, mirrors::Probe)> = None;
+      if download::expire_redirect(&redirect_file_path, url_file_ttl)? {
+        println!("The saved download URL is older than --url-file-ttl, resolving it again");
+      }
+      let saved_url = match redirect_file_path.try_exists().unwrap_or(false) {
+        true => Some(std::fs::read_to_string(&redirect_file_path)?),
+        false => None,
+      };
+      let mut url = if let Some(saved_url) = &saved_url {
+        // The download resumes from the saved URL, the snapshot URL is
+        // resolved again if the saved one is refused
+        let version = resolve_path(go_spacemesh_path).and_then(|path| get_version(&path));
+        match version {
+          Ok(version) => {
+            let mut url = download_url.clone();
+            url
+              .path_segments_mut()
+              .map_err(|e| anyhow::anyhow!("parsing download url: {e:?}"))?
+              .extend(&[&version, variant.file_name()]);
+            url.to_string()
+          }
+          Err(_) => saved_url.clone(),
+        }
+      } else {
+        let go_path = resolve_path(go_spacemesh_path).context("checking node version")?;
+        let version = get_version(&go_path)?;
+        let lan_url = match lan.is_empty() {
+          true => None,
+          false => {
+            let server_url = download_url.clone();
+            discovery::pick(
+              &lan,
+              lan_max_lag,
+              server_url,
+              &version,
+              variant,
+              &mut checksum,
+            )
+            .await
+          }
+        };
+        if let Some(url) = lan_url {
+          url.to_string()
+        } else if mirrors.is_empty() {
+          let mut url = download_url.clone();
+          url
+            .path_segments_mut()
+            .map_err(|e| anyhow::anyhow!("parsing download url: {e:?}"))?
+            .extend(&[&version, variant.file_name()]);
+          url.to_string()
+        } else {
+          let best = mirrors::pick_fastest(&candidates, &version, variant)
+            .await
+            .map_err(|e| ExitError::new(1, format!("Cannot pick a mirror: {e:#}")))?;
+          let url = best.url.to_string();
+          mirror = Some((version, best));
+          url
+        }
+      };
+      // The floor given by the user, or a share of the speed of the picked mirror
+      let user_floor = min_speed.map(|bytes_per_sec| SpeedFloor {
+        bytes_per_sec,
+        grace: min_speed_grace,
+      });
+      let mirror_floor = |best: &mirrors::Probe| {
+        user_floor.or(Some(SpeedFloor {
+          bytes_per_sec: best.bytes_per_sec * mirrors::SLOWDOWN_FACTOR,
+          grace: min_speed_grace,
+        }))
+      };
+      let mut floor = match &mirror {
+        Some((_, best)) => mirror_floor(best),
+        None => user_floor,
+      };
+
+      if let Some(dir) = temp_file_path.parent() {
+        std::fs::create_dir_all(dir)?;
+      }
+      let source = source::HttpSource::new()?;
+      let mut parts_to_verify = None;
+      if let Some(downloader) = &downloader {
+        let resolved = external_downloader::resolve(&url)
+          .await
+          .map_err(|e| ExitError::new(1, format!("Cannot find the snapshot: {e:#}")))?;
+        download::save_redirect(&redirect_file_path, resolved.as_str())?;
+        downloader
+          .download(&resolved, &temp_file_path)
+          .await
+          .map_err(|e| match ExitError::find(&e) {
+            // e.g. a redirect refused by the URL policy
+            Some(_) => e,
+            None => ExitError::new(1, format!("Failed to download a file: {e:#}")).into(),
+          })?;
+        // The archive is verified and unpacked after the download
+      } else {
+        if temp_file_path.try_exists().unwrap_or(false) {
+          let path = temp_file_path.clone();
+          let verified = tokio::task::spawn_blocking(move || {
+            block_hashes::verify(&path, block_hashes::BLOCK_SIZE)
+          })
+          .await?;
+          match verified {
+            Ok(None) => {}
+            Ok(Some(kept)) => println!(
+              "The partially downloaded file is corrupted, resuming from {:.2} MB",
+              kept as f64 / 1_024_000.00
+            ),
+            Err(e) => {
+              println!("Cannot verify the partially downloaded file, starting over: {e:#}");
+              std::fs::remove_file(&temp_file_path)?;
+              let _ = std::fs::remove_file(&block_record_path);
+            }
+          }
+        }
+        if temp_file_path.try_exists().unwrap_or(false) {
+          let check_url = saved_url.as_ref().unwrap_or(&url);
+          match check_partial_download(&source, check_url, &temp_file_path).await {
+            Ok(true) => {}
+            Ok(false) => {
+              println!("The partially downloaded file doesn't match the snapshot, starting over");
+              std::fs::remove_file(&temp_file_path)?;
+              let _ = std::fs::remove_file(&block_record_path);
+            }
+            Err(e) => println!("Cannot check the partially downloaded file: {e:#}"),
+          }
+        }
+
+        // The download starts over, as another retry, if the snapshot changed
+        // while it was resumed
+        let mut restarts = 0;
+        let (result, mut file) = loop {
+          let part_list = fetch_part_list(&redirect_file_path, &url).await;
+          let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&temp_file_path)
+            .with_context(|| format!("creating temp file: {}", temp_file_path.display()))?;
+          let file = NoCacheFile::new(file, io.no_page_cache)?;
+          // Hashes the data downloaded before again, which takes a while
+          let file = {
+            let path = temp_file_path.clone();
+            tokio::task::spawn_blocking(move || {
+              BlockHashWriter::new(file, &path, block_hashes::BLOCK_SIZE)
+            })
+            .await??
+          };
+          // Verify and unpack the archive while it's downloaded
+          let file = {
+            let path = temp_file_path.clone();
+            let unpacked = (stages == Stages::All).then(|| unpacked_file_path.clone());
+            tokio::task::spawn_blocking(move || PipelineWriter::new(file, &path, unpacked, io))
+              .await??
+          };
+          // and each part as soon as it's complete
+          let mut file = {
+            let path = temp_file_path.clone();
+            tokio::task::spawn_blocking(move || PartWriter::new(file, &path, part_list)).await??
+          };
+
+          let mut reevaluations = 0;
+          let result = loop {
+            let result = download_with_retries(
+              &source,
+              &url,
+              &mut file,
+              &redirect_file_path,
+              download::RetryBudget {
+                max_retries,
+                max_total: max_total_retries,
+                delay: std::time::Duration::from_secs(5),
+              },
+              io.buffer_size,
+              floor,
+              &mut progress::Reporter::standard(),
+            )
+            .await;
+            if !matches!(&result, Err(e) if e.is::<SlowDownload>()) {
+              break result;
+            }
+            // Keep downloading at any speed if reconnecting didn't help
+            if reevaluations == mirrors::MAX_REEVALUATIONS {
+              floor = None;
+              continue;
+            }
+            reevaluations += 1;
+            let Some((version, current)) = mirror.as_mut() else {
+              status::print_line("The download is slower than --min-speed, reconnecting...");
+              continue;
+            };
+            // Keep downloading from the current mirror if there is no better one
+            floor = user_floor;
+            status::print_line("The download slowed down, looking for a faster mirror...");
+            match mirrors::pick_fastest(&candidates, version, variant).await {
+              // The partially downloaded file is valid only for the same snapshot
+              Ok(best) if best.layer == current.layer => {
+                floor = mirror_floor(&best);
+                url = best.url.to_string();
+                // The download continues from the URL in `state.url`
+                download::save_redirect(&redirect_file_path, &url)?;
+                *current = best;
+              }
+              Ok(_) => status::print_line(
+                "Mirrors have a different snapshot now, staying with the current one",
+              ),
+              Err(e) => status::print_line(format_args!("Cannot pick another mirror: {e:#}")),
+            }
+          };
+          let e = match result {
+            Err(e) if e.is::<SnapshotChanged>() => e,
+            result => break (result, file),
+          };
+          // The partially downloaded file belongs to another snapshot
+          drop(file);
+          let records = download::record_paths(&redirect_file_path);
+          for path in [
+            &temp_file_path,
+            &block_record_path,
+            &unpacked_file_path,
+            &redirect_file_path,
+          ]
+          .into_iter()
+          .chain(&records)
+          {
+            let _ = std::fs::remove_file(path);
+          }
+          if restarts == max_retries {
+            return Err(
+              ExitError::new(
+                1,
+                format!("{e}: the partially downloaded file is deleted, run again to start over"),
+              )
+              .into(),
+            );
+          }
+          restarts += 1;
+          println!("The snapshot changed since the download started, starting over");
+        };
+        if let Err(e) = result {
+          file.flush()?;
+          // Keep the exit code of a cancelled download
+          if ExitError::find(&e).is_some() {
+            return Err(e);
+          }
+          return Err(
+            ExitError::new(1, format!("Failed to download a file after retrying: {e}")).into(),
+          );
+        }
+        file.flush()?;
+        let (file, checked) = file.into_inner();
+        parts_to_verify = checked;
+        pipelined = tokio::task::spawn_blocking(move || file.finish()).await?;
+      }
+
+      // Re-download only the corrupted parts if the server publishes their checksums
+      let url = Url::parse(&std::fs::read_to_string(&redirect_file_path)?)?;
+      let parts_to_verify = match parts_to_verify {
+        Some((list, only)) => Some((list, Some(only))),
+        // An external downloader downloaded the archive, all parts are verified
+        None if downloader.is_some() => match parts::fetch(&url).await {
+          Ok(list) => list.map(|list| (list, None)),
+          Err(e) => {
+            println!("Cannot verify the parts of the archive: {e:#}");
+            None
+          }
+        },
+        None => None,
+      };
+      if let Some((list, only)) = parts_to_verify {
+        let repaired =
+          parts::verify_and_repair(&source, &url, &temp_file_path, &list, only, max_retries)
+            .await
+            .map_err(|e| ExitError::new(7, format!("Archive is corrupted: {e:#}")))?;
+        if repaired > 0 {
+          println!("Repaired {repaired} parts of the archive");
+          // The repaired parts weren't unpacked
+          pipelined = None;
+        }
+      }
+
+      // Rename `state.{pid}-{time}.download` -> `state.zst`
+      file_in_use::rename(&temp_file_path, &archive_file_path)?;
+      if block_record_path.try_exists().unwrap_or(false) {
+        std::fs::remove_file(&block_record_path)?;
+      }
+      println!("Archive downloaded!");
+      key_journal(&mut journal, &redirect_file_path, &checksum).await;
+      journal.record(Step::Downloaded, &archive_file_path)?;
+      failpoints::kill_at(KillPoint::Downloaded);
+    }
+    if stages == Stages::DownloadOnly {
+      println!("Run with --verify-only or --install-only to continue");
+      return Ok(());
+    }
+
+    if !redirect_file_path.try_exists().unwrap_or(false) {
+      let recovered = recover_snapshot_url(
+        &redirect_file_path,
+        &archive_file_path,
+        archive_snapshot.as_ref(),
+        &download_url,
+        go_spacemesh_path,
+        variant,
+      );
+      match recovered.await {
+        Ok(()) => {}
+        Err(e) if ExitError::find(&e).is_some() => return Err(e),
+        Err(e) => println!("Cannot find the URL of the latest snapshot: {e:#}"),
+      }
+    }
+    key_journal(&mut journal, &redirect_file_path, &checksum).await;
+    let md5_url = checksum
+      .archive_md5_url(&redirect_file_path)
+      .map_err(|e| ExitError::new(8, format!("Cannot validate archive checksum: {e:#}")))?;
+    let archive_len = std::fs::metadata(&archive_file_path)?.len();
+    let verified_before = resume_from >= Some(Step::Verified)
+      || (stages == Stages::InstallOnly
+        && std::fs::read_to_string(&verified_file_path)
+          .is_ok_and(|len| len.trim() == archive_len.to_string()));
+    if verified_before {
+      println!("Archive was verified before");
+    } else if let Some(md5_url) = md5_url {
+      println!("Verifying the checksum, it may take some time...");
+      events::stage(events::Stage::VerifyArchive);
+      // Unpack the archive while it's hashed, instead of reading it twice
+      if pipelined.is_none() && stages != Stages::VerifyOnly {
+        let (archive, unpacked) = (archive_file_path.clone(), unpacked_file_path.clone());
+        pipelined =
+          tokio::task::spawn_blocking(move || verify_and_unpack(&archive, unpacked, io)).await??;
+      }
+      // Verify downloaded archive
+      let verified = match &pipelined {
+        Some(pipelined) => download_checksum(md5_url, &checksum)
+          .await
+          .map(|expected| expected == pipelined.archive_md5),
+        None => verify_archive(&md5_url, &archive_file_path, io, &checksum).await,
+      };
+      match verified {
+        Ok(true) => {
+          println!("Archive checksm validated");
+          journal.record(Step::Verified, &archive_file_path)?;
+          failpoints::kill_at(KillPoint::Verified);
+          if stages == Stages::VerifyOnly {
+            std::fs::write(&verified_file_path, archive_len.to_string())?;
+          }
+        }
+        Ok(false) => {
+          std::fs::remove_file(&archive_file_path)?;
+          if unpacked_file_path.try_exists().unwrap_or(false) {
+            std::fs::remove_file(&unpacked_file_path)?;
+          }
+          return Err(ExitError::new(7, "Archive checksum is invalid. Deleting archive").into());
+        }
+        Err(e) => {
+          // The archive was unpacked before it could be verified
+          if unpacked_file_path.try_exists().unwrap_or(false) {
+            std::fs::remove_file(&unpacked_file_path)?;
+          }
+          return Err(ExitError::new(8, format!("Cannot validate archive checksum: {}", e)).into());
+        }
+      }
+    } else {
+      println!("Download URL is not found: skip archive checksum verification");
+    }
+    if stages == Stages::VerifyOnly {
+      println!("Run with --install-only to install it");
+      return Ok(());
+    }
+
+    if pipelined.is_some() {
+      println!("Archive unpacked while downloading or verifying it");
+      journal.record(Step::Unpacked, &unpacked_file_path)?;
+      failpoints::kill_at(KillPoint::Unpacked);
+    } else {
+      events::stage(events::Stage::Unpack);
+      let unpack_result = {
+        let (archive, unpacked) = (archive_file_path.clone(), unpacked_file_path.clone());
+        tokio::task::spawn_blocking(move || unpack::unpack(&archive, &unpacked, io)).await?
+      };
+      match unpack_result {
+        Ok(_) => {
+          println!("Archive unpacked successfully");
+          journal.record(Step::Unpacked, &unpacked_file_path)?;
+          failpoints::kill_at(KillPoint::Unpacked);
+        }
+        Err(e) => {
+          if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+            // FIXME: use ErrorKind::StorageFull once it's stabilized (https://github.com/rust-lang/rust/issues/86442)
+            if io_err.raw_os_error() == Some(28) {
+              std::fs::remove_file(&unpacked_file_path)?;
+              return Err(ExitError::new(2, "Cannot unpack archive: not enough disk space").into());
+            }
+          }
+          std::fs::remove_file(&unpacked_file_path)?;
+          return Err(ExitError::new(3, format!("Cannot unpack archive: {}", e)).into());
+        }
+      }
+    }
+  }
+
+  let mut db_md5 = None;
+  if resume_from == Some(Step::Installed) {
+    println!("The snapshot was installed by the previous run, cleaning up after it");
+  } else {
+    // Verify checksum
+    events::stage(events::Stage::VerifyDb);
+    let md5_url = checksum
+      .db_md5_url(&redirect_file_path)
+      .map_err(|e| ExitError::new(5, format!("Cannot verify checksum: {e:#}")))?;
+    if let Some(md5_url) = md5_url {
+      println!("Verifying MD5 checksum...");
+      let verified = match &pipelined {
+        Some(pipelined) => download_checksum(md5_url, &checksum)
+          .await
+          .map(|expected| (expected == pipelined.db_md5).then_some(expected)),
+        None => verify_db(&md5_url, &unpacked_file_path, io, &checksum).await,
+      };
+      match verified {
+        Ok(Some(md5)) => {
+          println!("Checksum is valid");
+          db_md5 = Some(md5);
+        }
+        Ok(None) => {
+          std::fs::remove_file(&unpacked_file_path)?;
+          // There is no archive after a delta download
+          if archive_file_path.try_exists().unwrap_or(false) {
+            std::fs::remove_file(&archive_file_path)?;
+          }
+          std::fs::remove_file(&redirect_file_path)?;
+          return Err(
+            ExitError::new(
+              4,
+              "MD5 checksums are not equal. Deleting archive and unpacked state.sql",
+            )
+            .into(),
+          );
+        }
+        Err(e) => {
+          return Err(ExitError::new(5, format!("Cannot verify checksum: {}", e)).into());
+        }
+      }
+    } else {
+      println!("Download URL is not found: skip DB checksum verification");
+    }
+
+    println!("Checking the downloaded database...");
+    if let Err(e) = sanity::check_db(&unpacked_file_path) {
+      std::fs::remove_file(&unpacked_file_path)?;
+      if archive_file_path.try_exists().unwrap_or(false) {
+        std::fs::remove_file(&archive_file_path)?;
+      }
+      if redirect_file_path.try_exists().unwrap_or(false) {
+        std::fs::remove_file(&redirect_file_path)?;
+      }
+      return Err(
+        ExitError::new(
+          14,
+          format!("Downloaded database is broken: {e:#}. Deleting archive and unpacked state.sql"),
+        )
+        .into(),
+      );
+    }
+
+    if !force {
+      match check_downgrade(&final_file_path, &unpacked_file_path) {
+        Err(e) if idempotent && ExitError::find(&e).is_some_and(|e| e.code == 13) => {
+          println!("Nothing to do: {e}. Deleting the downloaded files");
+          for path in [&unpacked_file_path, &archive_file_path, &redirect_file_path] {
+            if path.try_exists().unwrap_or(false) {
+              std::fs::remove_file(path)?;
+            }
+          }
+          journal.clear()?;
+          leftovers::finish(&work_dir)?;
+          return Ok(());
+        }
+        result => result?,
+      }
+    }
+
+    // The verified database is kept for the next run to install
+    if confirm && !confirm_install(&final_file_path, &unpacked_file_path)? {
+      return Err(ExitError::new(20, "Declined replacing the local database").into());
+    }
+
+    events::stage(events::Stage::Install);
+    let backed_up = resume_from >= Some(Step::BackedUp);
+    let install = async {
+      failpoints::kill_at(KillPoint::Install);
+      install_db(
+        &unpacked_file_path,
+        &final_file_path,
+        wal_file_path,
+        network_fs.is_some(),
+        &mut journal,
+        backed_up,
+      )
+    };
+    let installed = hooks.around(&final_file_path, install).await;
+    if installed.is_err() {
+      journal.clear()?;
+    }
+    installed?;
+  }
+
+  if let Err(e) = save_sync_marker(
+    &dir_path,
+    &redirect_file_path,
+    &download_url,
+    variant,
+    db_md5,
+  ) {
+    println!("Cannot record the installed snapshot: {e:#}");
+  }
+
+  if archive_file_path.try_exists().unwrap_or(false) {
+    let kept = if keep_archive {
+      keep_archive_file(
+        &dir_path,
+        &archive_file_path,
+        &redirect_file_path,
+        &checksum,
+      )
+      .await
+      .map_err(|e| println!("Cannot keep the archive: {e:#}"))
+      .is_ok()
+    } else {
+      false
+    };
+    if !kept {
+      println!("Archive file is deleted.");
+      std::fs::remove_file(&archive_file_path)?;
+    }
+  } else if keep_archive {
+    println!("There is no archive to keep: only the changes were downloaded");
+  }
+  if redirect_file_path.try_exists().unwrap_or(false) {
+    println!("URL file is deleted.");
+    std::fs::remove_file(&redirect_file_path)?;
+  }
+  for record_path in download::record_paths(&redirect_file_path) {
+    if record_path.try_exists().unwrap_or(false) {
+      std::fs::remove_file(&record_path)?;
+    }
+  }
+  if verified_file_path.try_exists().unwrap_or(false) {
+    std::fs::remove_file(&verified_file_path)?;
+  }
+  journal.clear()?;
+  leftovers::finish(&work_dir)?;
+
+  println!("Done!");
+  println!("Now you can run go-spacemesh as usually.");
+  events::stage(events::Stage::Done);
+
+  Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+  let cli = Cli::parse();
+  failpoints::init()?;
+  progress::set_interval(cli.progress_interval);
+  if cli.trace_http {
+    http_trace::enable();
+  }
+  if let Some(path) = &cli.tofu {
+    cert_pin::enable(path.clone(), cli.tofu_strict);
+  }
+  if let Some(path) = &cli.healthcheck_file {
+    healthcheck::enable(path.clone())?;
+  }
+  if cli.events {
+    events::enable();
+  } else if !cli.no_status_line && cli.healthcheck_file.is_none() && std::io::stdout().is_terminal()
+  {
+    status::enable();
+  }
+  let json = cli.json;
+  let report_errors = cli.report_errors;
+  if !cli.no_cache {
+    http_cache::enable(
+      cli
+        .cache_dir
+        .clone()
+        .unwrap_or_else(http_cache::default_dir),
+    );
+  }
+  cli.transport.apply()?;
+  cli.url_policy.apply_max_redirects();
+  let timeouts = timeouts::Timeouts {
+    stage: cli.stage_timeout.map(|d| d.to_std()).transpose()?,
+    overall: cli.overall_timeout.map(|d| d.to_std()).transpose()?,
+  };
+
+  let runtime = tokio::runtime::Runtime::new().context("starting async runtime")?;
+  let result = runtime.block_on(async {
+    if let Some(source) = &cli.control {
+      control::listen(source)?;
+    }
+    if let Some(path) = &cli.events_socket {
+      events::serve(path)?;
+    }
+    if cli.healthcheck_file.is_some() {
+      tokio::spawn(healthcheck::heartbeat());
+    }
+    tokio::select! {
+      result = run(cli) => result,
+      _ = tokio::signal::ctrl_c() => Err(anyhow!("interrupted")),
+      e = timeouts.expired() => Err(e),
+    }
+  });
+  if let Err(e) = runtime.block_on(hooks::finish_aborted()) {
+    eprintln!("{e:#}");
+  }
+  status::reset();
+  if let Err(e) = &result {
+    let log_path = Path::new(error_report::ERROR_LOG);
+    if let Err(log_err) = error_report::write_log(log_path, e) {
+      eprintln!("Cannot write {}: {log_err}", log_path.display());
+    }
+    if report_errors {
+      match runtime.block_on(error_report::upload(e)) {
+        Ok(()) => eprintln!("The failure has been reported, thank you"),
+        Err(upload_err) => eprintln!("Cannot report the failure: {upload_err:#}"),
+      }
+    }
+  }
+  let exit_error = result.as_ref().err().and_then(ExitError::find);
+  events::emit(events::Event::Result {
+    success: result.is_ok(),
+    exit_code: match (&result, exit_error) {
+      (Ok(()), _) => 0,
+      (Err(_), Some(exit)) => exit.code,
+      (Err(_), None) => 1,
+    },
+    error: result.as_ref().err().map(|e| format!("{e:#}")),
+  });
+  runtime.block_on(events::close());
+  // Don't wait for blocking tasks (unpacking, hashing) of an interrupted run
+  runtime.shutdown_background();
+  if let Err(e) = &result {
+    if json {
+      let report = error_report::ErrorReport::new(e);
+      eprintln!("{}", report.to_json());
+      process::exit(report.code);
+    }
+  }
+  if let Some(exit) = exit_error {
+    eprintln!("{exit}");
+    process::exit(exit.code);
+  }
+  result
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+  cli.url_policy.enforce(&cli.command.policy_urls()?)?;
+  match cli.command {
+    Commands::Check {
+      node_data,
+      network,
+      go_spacemesh_path,
+      download_url,
+      region,
+      variant,
+      untrusted_layers,
+      sync_time_per_layer,
+      base_url,
+      cross_check,
+      offline,
+    } => {
+      let download_url = region_url(download_url, region).await?;
+      let result = {
+        let dir_path = node_data.clone();
+        let db_file_path = dir_path.join("state.sql");
+        let db_file_str = db_file_path.to_str().expect("Cannot compose path");
+        println!("Checking database: {}", db_file_str);
+        let mut derived_untrusted_layers = sql::UNTRUSTED_LAYERS_MARGIN;
+        let db_layer = if db_file_path.try_exists().unwrap_or(false) {
+          let wal_size = wal_size(&db_file_path);
+          if wal_size > 0 {
+            println!(
+              "Including {:.2} MB of state.sql-wal not checkpointed yet",
+              wal_size as f64 / 1_024_000.00
+            );
+          }
+          match get_db_status(&db_file_path) {
+            Ok(status) => {
+              println!(
+                "Database: {:.2} MB, schema version {}",
+                status.db_size as f64 / 1_024_000.00,
+                status.user_version
+              );
+              println!("Latest layer in db: {}", status.latest_layer);
+              if let Some(verified) = status.latest_verified_layer {
+                println!("Latest verified layer in db: {verified}");
+              }
+              derived_untrusted_layers = status.untrusted_layers();
+              i64::from(status.latest_applied_layer)
+            }
+            Err(err) => {
+              eprintln!("{}", err);
+              println!("Cannot read database, trating it as empty database");
+              0
+            }
+          }
+        } else {
+          println!("Database file is not found");
+          0
+        };
+        println!("Latest applied layer in db: {}", db_layer);
+        let untrusted_layers = untrusted_layers.unwrap_or(derived_untrusted_layers);
+
+        let clock = network.clock()?;
+        let time_layer = clock.current_layer();
+        println!(
+          "Current network layer: {} (epoch {})",
+          time_layer,
+          clock.epoch(time_layer)
+        );
+        // The current layer is only as right as the local clock
+        if !offline {
+          match clock::measure_skew(&download_url).await {
+            Ok(skew) => {
+              if let Some(description) = clock::describe_significant(skew) {
+                let server_layer = clock.layer_at(chrono::Utc::now() - skew);
+                println!(
+                  "Warning: the local clock is {description} the snapshot server's, so the current \
+                   network layer may be wrong (it's {server_layer} by the server's clock). \
+                   Fix the system time"
+                );
+              }
+            }
+            Err(e) => println!("Cannot check the local clock: {e:#}"),
+          }
+        }
+
+        let go_version = if offline {
+          println!("Offline: the snapshot and the download estimates are skipped");
+          None
+        } else {
+          let go_path = resolve_path(&go_spacemesh_path).unwrap();
+          Some(get_version(&go_path)?)
+        };
+        let snapshot = match &go_version {
+          Some(go_version) => {
+            let snapshot = fetch_snapshot_info(&download_url, go_version, variant).await?;
+            println!("Latest layer in cloud: {}", snapshot.layer);
+            match snapshot.size {
+              Some(size) => println!(
+                "Snapshot: {} ({:.2} MB)",
+                snapshot.url,
+                size as f64 / 1_024_000.00
+              ),
+              None => println!("Snapshot: {} (size unknown)", snapshot.url),
+            }
+            Some(snapshot)
+          }
+          None => None,
+        };
+
+        let status = check::SyncStatus {
+          applied_layer: db_layer,
+          untrusted_layers,
+          network_layer: time_layer,
+          snapshot_layer: snapshot
+            .map(|snapshot| i64::try_from(snapshot.layer))
+            .transpose()?,
+        };
+        println!(
+          "Layers behind: {} (including {} untrusted layers)",
+          status.layers_behind(),
+          untrusted_layers
+        );
+        println!(
+          "Estimated normal-sync catch-up time: {}",
+          check::format_duration(status.catch_up_time(sync_time_per_layer))
+        );
+
+        let Some(go_version) = go_version else {
+          // Normal sync is the only way to catch up that is known offline
+          let estimates = status.estimate(sync_time_per_layer, None, None, None);
+          println!("Recommendation: {}", status.recommend(&estimates));
+          return Ok(());
+        };
+        let estimates = estimate_catch_up(
+          &status,
+          sync_time_per_layer,
+          &download_url,
+          &go_version,
+          variant,
+          &base_url,
+          &db_file_path,
+        )
+        .await;
+        let format =
+          |time: Option<Duration>| time.map_or("unknown".to_string(), check::format_duration);
+        println!(
+          "Estimated time of full download: {}",
+          format(estimates.full_download)
+        );
+        println!(
+          "Estimated time of partial restore: {}",
+          format(estimates.partial_restore)
+        );
+        let mut recommendation = status.recommend(&estimates);
+        if let Some(count) = cross_check.filter(|_| db_layer > 0) {
+          match incremental_quicksync::cross_check(&base_url, &db_file_path, count).await {
+            Ok((compared, None)) => {
+              println!("Cross-check: {compared} recent layer hashes match the network")
+            }
+            Ok((_, Some(divergence))) => {
+              println!(
+                "Warning: the database diverged from the network at layer {}: its hash is '{}', the published one is '{}'. \
+                 It is forked or corrupted, replace it with `download --force`",
+                divergence.layer, divergence.local_hash, divergence.published_hash
+              );
+              recommendation = check::Recommendation::Quicksync;
+            }
+            Err(e) => println!("Cannot cross-check the database: {e:#}"),
+          }
+        }
+        println!("Recommendation: {recommendation}");
+        Ok(())
+      };
+      // Failures go through the error reporting of main
+      result
+    }
+    Commands::Download {
+      node_data,
+      jobs,
+      go_spacemesh_path,
+      download_url,
+      region,
+      mirrors,
+      lan,
+      lan_wait,
+      lan_max_lag,
+      variant,
+      max_retries,
+      downloader,
+      max_total_retries,
+      min_speed,
+      min_speed_grace,
+      url_file_ttl,
+      checksum_timeout,
+      archive_checksum_url,
+      db_checksum_url,
+      io_buffer_size,
+      no_page_cache,
+      temp_dir,
+      hash_threads,
+      force,
+      keep_archive,
+      no_delta,
+      seed_for,
+      seed_ratio,
+      seed_listen,
+      resume,
+      idempotent,
+      fresh,
+      download_only,
+      verify_only,
+      install_only,
+      yes,
+      dry_run,
+      start_delay_jitter: jitter,
+      hooks,
+    } => {
+      let io = IoOptions {
+        buffer_size: io_buffer_size as usize,
+        no_page_cache,
+        hash_threads: hash_threads.into(),
+      };
+      let targets = node_data
+        .iter()
+        .map(|dir| resolve_path(dir).context("resolving node-data path"))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+      let temp_dir = temp_dir
+        .map(|dir| resolve_path(&dir).context("resolving temp dir path"))
+        .transpose()?;
+      let checksum = ChecksumOptions {
+        max_retries,
+        timeout: checksum_timeout.to_std()?,
+        archive_url: archive_checksum_url,
+        db_url: db_checksum_url,
+        ..Default::default()
+      };
+      let min_speed_grace = min_speed_grace.to_std()?;
+      let url_file_ttl = url_file_ttl.to_std()?;
+      let download_url = region_url(download_url, region).await?;
+      let node_version = resolve_path(&go_spacemesh_path)
+        .and_then(|path| get_version(&path))
+        .ok();
+      let fleet = targets.len() > 1;
+      if dry_run {
+        let go_path = resolve_path(&go_spacemesh_path).context("checking node version")?;
+        let version = get_version(&go_path)?;
+        for (i, node_data) in targets.iter().enumerate() {
+          // The temp files of each node-data directory are kept apart
+          let work_dir = match &temp_dir {
+            Some(dir) if fleet => dir.join(i.to_string()),
+            Some(dir) => dir.clone(),
+            None => node_data.clone(),
+          };
+          // A real run would stop here, unless it resumes a previous one
+          let mut journal = Journal::load(node_data);
+          if !journal.is_empty() {
+            key_journal(&mut journal, &node_data.join("state.url"), &checksum).await;
+          }
+          let resume_from = journal.resume_point();
+          let resuming = resume_from.is_some()
+            || work_dir.join("state.zst").try_exists().unwrap_or(false)
+            || node_data.join("state.url").try_exists().unwrap_or(false);
+          if !force && !resuming {
+            let up_to_date = up_to_date(
+              node_data,
+              &go_spacemesh_path,
+              &download_url,
+              variant,
+              idempotent,
+              io,
+              &checksum,
+            );
+            if let Some(reason) = up_to_date.await? {
+              println!(
+                "Dry run for {}, nothing is downloaded or changed. {reason}",
+                node_data.display()
+              );
+              continue;
+            }
+          }
+          let planned = dry_run::run(
+            node_data,
+            &work_dir,
+            &download_url,
+            &version,
+            variant,
+            keep_archive,
+            resume_from,
+          );
+          planned.await?;
+        }
+        return Ok(());
+      }
+      start_delay_jitter(jitter).await?;
+      let lan = match lan {
+        true => {
+          let sources = discovery::discover(lan_wait.to_std()?).await?;
+          println!("Found {} snapshot sources on the LAN", sources.len());
+          sources
+        }
+        false => Vec::new(),
+      };
+      let interactive = std::io::stdin().is_terminal() && cli.control.as_deref() != Some("stdin");
+      if fleet && interactive && !yes {
+        return Err(anyhow!(
+          "Pass --yes to replace the databases in several node-data directories"
+        ));
+      }
+      let seeding = (seed_for.is_some() || seed_ratio.is_some()).then(|| seed::Limits {
+        duration: seed_for.and_then(|d| d.to_std().ok()),
+        ratio: seed_ratio,
+      });
+      if fleet && seeding.is_some() {
+        return Err(anyhow!(
+          "Seeding is only possible when syncing one node-data directory"
+        ));
+      }
+      let options = |target: usize| DownloadOptions {
+        go_spacemesh_path: &go_spacemesh_path,
+        download_url: download_url.clone(),
+        mirrors: mirrors.clone(),
+        lan: lan.clone(),
+        lan_max_lag,
+        variant,
+        max_retries,
+        max_total_retries,
+        downloader: downloader.clone(),
+        min_speed: min_speed.map(|speed| speed as f64),
+        min_speed_grace,
+        url_file_ttl,
+        io,
+        checksum: checksum.clone(),
+        hooks: &hooks,
+        force,
+        delta: !no_delta,
+        keep_archive,
+        // The temp files of each node-data directory are kept apart
+        temp_dir: temp_dir.as_ref().map(|dir| {
+          if fleet {
+            dir.join(target.to_string())
+          } else {
+            dir.clone()
+          }
+        }),
+        // The archive of an earlier stage is used however old it is
+        leftovers: if resume || verify_only || install_only {
+          leftovers::Policy::Resume
+        } else if fresh {
+          leftovers::Policy::Fresh
+        } else if idempotent {
+          leftovers::Policy::Resume
+        } else if interactive && !fleet {
+          leftovers::Policy::Ask
+        } else {
+          leftovers::Policy::Auto
+        },
+        idempotent,
+        stages: if download_only {
+          Stages::DownloadOnly
+        } else if verify_only {
+          Stages::VerifyOnly
+        } else if install_only {
+          Stages::InstallOnly
+        } else {
+          Stages::All
+        },
+        confirm: interactive && !yes,
+        shared: None,
+      };
+      // The archive of a fleet is downloaded once and linked into each
+      // node-data directory
+      let shared = (fleet && !verify_only && !install_only).then(|| SharedArchive {
+        dir: match &temp_dir {
+          Some(dir) => dir.join("shared"),
+          None => targets[0].join("quicksync-shared"),
+        },
+        options: DownloadOptions {
+          stages: Stages::DownloadOnly,
+          delta: false,
+          keep_archive: false,
+          temp_dir: None,
+          idempotent: false,
+          confirm: false,
+          ..options(0)
+        },
+        downloaded: tokio::sync::OnceCell::new(),
+      });
+      if let Some(shared) = &shared {
+        std::fs::create_dir_all(&shared.dir)
+          .with_context(|| format!("creating {}", shared.dir.display()))?;
+      }
+      let sync_target = |(i, node_data): (usize, PathBuf)| {
+        let history = SyncHistory::start(
+          "download",
+          &node_data.join("state.sql"),
+          node_version.clone(),
+        );
+        let options = DownloadOptions {
+          shared: shared.as_ref(),
+          ..options(i)
+        };
+        async move {
+          let job = download(node_data.clone(), options);
+          let result = if fleet {
+            events::in_job(node_data.display().to_string(), job).await
+          } else {
+            job.await
+          };
+          history.finish(&result);
+          (node_data, result)
+        }
+      };
+      if !fleet {
+        let (node_data, result) = sync_target((0, targets[0].clone())).await;
+        if let (Ok(()), Some(limits), Some(listen)) = (&result, seeding, seed_listen) {
+          serve_kept_archive(&node_data, listen, limits, true).await?;
+        }
+        return result;
+      }
+      let results: Vec<(PathBuf, anyhow::Result<()>)> =
+        futures::stream::iter(targets.into_iter().enumerate())
+          .map(sync_target)
+          .buffer_unordered(jobs.into())
+          .collect()
+          .await;
+      // Kept after a failure for the next run to resume
+      if let Some(shared) = &shared {
+        if results.iter().all(|(_, result)| result.is_ok()) && shared.dir.exists() {
+          std::fs::remove_dir_all(&shared.dir)
+            .with_context(|| format!("removing {}", shared.dir.display()))?;
+        }
+      }
+      fleet_report(results)
+    }
+    Commands::Serve {
+      node_data,
+      listen,
+      no_advertise,
+    } => {
+      let node_data = resolve_path(&node_data).context("resolving node-data path")?;
+      serve_kept_archive(&node_data, listen, seed::Limits::default(), !no_advertise).await
+    }
+    Commands::Selftest { keep } => {
+      let fixture = tokio::task::spawn_blocking(selftest::Fixture::create).await??;
+      let url = selftest::serve(&fixture)?;
+      url_policy::allow_lan_source(&url);
+      println!("Serving a synthetic snapshot at {url}");
+      let io = IoOptions {
+        buffer_size: 1024 * 1024,
+        no_page_cache: false,
+        hash_threads: 1,
+      };
+      let hooks = Hooks::default();
+      let options = DownloadOptions {
+        go_spacemesh_path: &fixture.go_spacemesh,
+        download_url: url,
+        mirrors: Vec::new(),
+        lan: Vec::new(),
+        lan_max_lag: 0,
+        variant: Variant::default(),
+        max_retries: 1,
+        max_total_retries: None,
+        downloader: None,
+        min_speed: None,
+        min_speed_grace: download::DEFAULT_SLOWDOWN_GRACE,
+        url_file_ttl: download::DEFAULT_URL_FILE_TTL,
+        io,
+        checksum: ChecksumOptions::default(),
+        hooks: &hooks,
+        force: false,
+        delta: true,
+        keep_archive: false,
+        temp_dir: None,
+        leftovers: leftovers::Policy::Auto,
+        stages: Stages::All,
+        confirm: false,
+        idempotent: false,
+        shared: None,
+      };
+      download(fixture.node_data.clone(), options).await?;
+      fixture.verify()?;
+      println!("Selftest passed");
+      if keep {
+        println!("Files kept in {}", fixture.dir.display());
+        Ok(())
+      } else {
+        fixture.cleanup()
+      }
+    }
     Commands::Incremental {
       state_sql,
       untrusted_layers,
       jump_back,
+      auto_jump_back,
       base_url,
+      db,
+      bootstrap,
+      profile,
+      skip_tables,
+      batch_size,
+      reapply,
+      follow,
+      poll_interval,
+      start_delay_jitter: jitter,
+      hooks,
     } => {
       println!("Warning: incremental quicksync is considered to be beta feature for now");
+      let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
+      if bootstrap.is_some() {
+        let dir = state_sql_path.parent().unwrap();
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+      } else if !state_sql_path
+        .try_exists()
+        .context("checking if state file exists")?
+      {
+        return Err(anyhow!(
+          "state file not found: {:?}, pass --bootstrap to start from a base database",
+          state_sql_path
+        ));
+      }
+      let download_path = resolve_path(Path::new(".")).unwrap();
+      for dir in [download_path.as_path(), state_sql_path.parent().unwrap()] {
+        preflight::check_writable(dir).map_err(|e| ExitError::new(16, format!("{e:#}")))?;
+      }
+      start_delay_jitter(jitter).await?;
+      let source = source::HttpSource::new()?;
+      if let Some(user_version) = bootstrap {
+        for &db in db.databases() {
+          if !db.path(&state_sql_path).try_exists().unwrap_or(false) {
+            incremental_quicksync::bootstrap(
+              &source,
+              &base_url,
+              db,
+              user_version,
+              &state_sql_path,
+              &download_path,
+            )
+            .await
+            .with_context(|| format!("bootstrapping {}", db.file_name()))?;
+          }
+        }
+      }
+      let untrusted_layers = untrusted_layers(untrusted_layers, &state_sql_path)?;
+      let databases = selected_databases(db, &state_sql_path)?;
+      // The end of the restore points applied by the previous polls, per database
+      let mut applied_to = vec![0; databases.len()];
+      let mut options = incremental_quicksync::RestoreOptions {
+        untrusted_layers,
+        jump_back,
+        auto_jump_back,
+        skip_tables: profile
+          .skipped_tables()
+          .into_iter()
+          .chain(skip_tables)
+          .collect(),
+        batch_size: usize::from(batch_size),
+        reapply,
+      };
+      loop {
+        let history = SyncHistory::start("incremental", &state_sql_path, None);
+        let restore = async {
+          for (db, applied_to) in databases.iter().zip(&mut applied_to) {
+            *applied_to = incremental_restore(
+              &source,
+              &base_url,
+              *db,
+              &state_sql_path,
+              &download_path,
+              &options,
+              *applied_to,
+            )
+            .await?;
+          }
+          Ok(())
+        };
+        let result = hooks.around(&state_sql_path, restore).await;
+        history.finish(&result);
+        if !follow {
+          break result;
+        }
+        match result {
+          // Cancelled, or a hook or the node service failed
+          Err(e) if ExitError::find(&e).is_some() => break Err(e),
+          Err(e) => println!("Cannot apply new restore points: {e:#}"),
+          Ok(()) => {}
+        }
+        // Jumping back is only for the first poll
+        options.jump_back = 0;
+        options.auto_jump_back = None;
+        events::stage(events::Stage::Wait);
+        println!(
+          "Polling for new restore points in {}",
+          check::format_duration(poll_interval)
+        );
+        let poll = tokio::time::sleep(poll_interval.to_std()?);
+        tokio::select! {
+          () = poll => {}
+          () = control::cancelled() => {}
+          () = control::terminated() => break Err(anyhow!("terminated")),
+        }
+        control::checkpoint().await?;
+      }
+    }
+    Commands::Rollback {
+      state_sql,
+      layers,
+      to_layer,
+      base_url,
+      hooks,
+    } => {
       let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
       if !state_sql_path
         .try_exists()
@@ -360,19 +2858,215 @@ fn main() -> anyhow::Result<()> {
         return Err(anyhow!("state file not found: {:?}", state_sql_path));
       }
       let download_path = resolve_path(Path::new(".")).unwrap();
-      incremental_restore(
+      for dir in [download_path.as_path(), state_sql_path.parent().unwrap()] {
+        preflight::check_writable(dir).map_err(|e| ExitError::new(16, format!("{e:#}")))?;
+      }
+      let to_layer = match (to_layer, layers) {
+        (Some(layer), _) => layer,
+        (None, layers) => {
+          let latest = u32::try_from(get_last_layer_from_db(&state_sql_path)?)?;
+          latest.saturating_sub(layers.unwrap_or_default())
+        }
+      };
+      let history = SyncHistory::start("rollback", &state_sql_path, None);
+      let source = source::HttpSource::new()?;
+      let rollback = incremental_quicksync::rollback(
+        &source,
         &base_url,
         &state_sql_path,
         &download_path,
-        untrusted_layers,
-        jump_back,
-      )
+        to_layer,
+      );
+      let result = hooks
+        .around(&state_sql_path, rollback)
+        .await
+        .map(|latest| println!("Rolled back to layer {latest}"));
+      history.finish(&result);
+      result
+    }
+    Commands::Prune {
+      state_sql,
+      keep_layers,
+      layers_per_epoch,
+      vacuum,
+    } => {
+      let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
+      if !state_sql_path
+        .try_exists()
+        .context("checking if state file exists")?
+      {
+        return Err(anyhow!("state file not found: {:?}", state_sql_path));
+      }
+      tokio::task::spawn_blocking(move || {
+        prune::prune(&state_sql_path, keep_layers, layers_per_epoch)?;
+        if vacuum {
+          vacuum::vacuum(&state_sql_path, false)?;
+        }
+        anyhow::Ok(())
+      })
+      .await?
+    }
+    Commands::Vacuum {
+      state_sql,
+      in_place,
+    } => {
+      let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
+      if !state_sql_path
+        .try_exists()
+        .context("checking if state file exists")?
+      {
+        return Err(anyhow!("state file not found: {:?}", state_sql_path));
+      }
+      tokio::task::spawn_blocking(move || vacuum::vacuum(&state_sql_path, in_place)).await?
+    }
+    Commands::Diff {
+      state_sql,
+      base_sql,
+      from_layer,
+      output_dir,
+      compress,
+    } => {
+      let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
+      if !state_sql_path
+        .try_exists()
+        .context("checking if state file exists")?
+      {
+        return Err(anyhow!("state file not found: {:?}", state_sql_path));
+      }
+      let base_sql_path = base_sql
+        .map(|p| resolve_path(&p))
+        .transpose()
+        .context("resolving base database path")?;
+      let output_dir = resolve_path(&output_dir).context("resolving output path")?;
+      tokio::task::spawn_blocking(move || {
+        let base = match (&base_sql_path, from_layer) {
+          (Some(path), _) => diff::DiffBase::Database(path),
+          (None, layer) => diff::DiffBase::Layer(layer.unwrap_or_default()),
+        };
+        diff::generate_diff(&state_sql_path, base, &output_dir, compress)?;
+        anyhow::Ok(())
+      })
+      .await?
+    }
+    Commands::Export {
+      state_sql,
+      output_dir,
+      level,
+      io_buffer_size,
+      chunk_size,
+    } => {
+      let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
+      if !state_sql_path
+        .try_exists()
+        .context("checking if state file exists")?
+      {
+        return Err(anyhow!("state file not found: {:?}", state_sql_path));
+      }
+      let output_dir = resolve_path(&output_dir).context("resolving output path")?;
+      tokio::task::spawn_blocking(move || {
+        export::export(
+          &state_sql_path,
+          &output_dir,
+          level,
+          io_buffer_size as usize,
+          chunk_size,
+        )?;
+        anyhow::Ok(())
+      })
+      .await?
+    }
+    Commands::Completions { shell } => {
+      clap_complete::generate(
+        shell,
+        &mut Cli::command(),
+        env!("CARGO_PKG_NAME"),
+        &mut std::io::stdout(),
+      );
+      Ok(())
+    }
+    Commands::Manpage => {
+      clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout())?;
+      Ok(())
+    }
+    Commands::Bench {
+      node_data,
+      go_spacemesh_path,
+      download_url,
+      variant,
+      sample_size,
+      offline,
+    } => {
+      let node_data = resolve_path(&node_data).context("resolving node-data path")?;
+      let io = IoOptions {
+        buffer_size: 16 * 1024 * 1024,
+        no_page_cache: false,
+        hash_threads: 1,
+      };
+      let sample_size = usize::try_from(sample_size)?;
+      println!("Measuring with {} MB of data...", sample_size / 1_024_000);
+      let mut speeds =
+        tokio::task::spawn_blocking(move || bench::measure_local(&node_data, sample_size, io))
+          .await??;
+      let mb_per_sec = |bytes_per_sec: f64| bytes_per_sec / 1_024_000.00;
+      println!("Disk write: {:.2} MB/s", mb_per_sec(speeds.disk_write));
+      println!("Unpacking: {:.2} MB/s", mb_per_sec(speeds.decompression));
+      println!("Hashing: {:.2} MB/s", mb_per_sec(speeds.hashing));
+      if offline {
+        return Ok(());
+      }
+      let go_path = resolve_path(&go_spacemesh_path).context("resolving go-spacemesh path")?;
+      let go_version = get_version(&go_path)?;
+      let probe = mirrors::probe(download_url, go_version, variant)
+        .await
+        .context("measuring download speed")?;
+      let (downloaded, download_speed) =
+        bench::measure_download(&probe.url, sample_size as u64, bench::DOWNLOAD_TIME)
+          .await
+          .context("measuring download speed")?;
+      speeds.download = Some(download_speed);
+      println!(
+        "Download: {:.2} MB/s (over {:.2} MB)",
+        mb_per_sec(download_speed),
+        downloaded as f64 / 1_024_000.00
+      );
+      match probe
+        .size
+        .and_then(|size| Some((size, speeds.full_sync_time(size)?)))
+      {
+        Some((size, time)) => println!(
+          "Estimated download of the {:.2} MB snapshot: {}, then the node syncs the layers after \
+           layer {}",
+          size as f64 / 1_024_000.00,
+          check::format_duration(time),
+          probe.layer
+        ),
+        None => {
+          println!("The server doesn't report the snapshot size, cannot estimate the download")
+        }
+      }
+      Ok(())
+    }
+    Commands::Layer {
+      network,
+      layer,
+      time,
+    } => {
+      let clock = network.clock()?;
+      let layer = match (layer, time) {
+        (Some(layer), _) => layer,
+        (None, Some(time)) => clock.layer_at(time),
+        (None, None) => clock.current_layer(),
+      };
+      println!("{}", serde_json::to_string(&clock.info(layer)?)?);
+      Ok(())
     }
     Commands::IncrementalCheck {
       state_sql,
       base_url,
       untrusted_layers,
       jump_back,
+      auto_jump_back,
+      db,
     } => {
       let state_sql_path = resolve_path(&state_sql).context("resolving state.sql path")?;
       if !state_sql_path
@@ -381,7 +3075,19 @@ fn main() -> anyhow::Result<()> {
       {
         return Err(anyhow!("state file not found: {:?}", state_sql_path));
       }
-      check_for_restore_points(&base_url, &state_sql_path, untrusted_layers, jump_back)
+      let untrusted_layers = untrusted_layers(untrusted_layers, &state_sql_path)?;
+      for db in selected_databases(db, &state_sql_path)? {
+        check_for_restore_points(
+          &base_url,
+          db,
+          &state_sql_path,
+          untrusted_layers,
+          jump_back,
+          auto_jump_back,
+        )
+        .await?;
+      }
+      Ok(())
     }
   }
 }
@@ -0,0 +1,188 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::download::parse_content_range;
+use crate::http_trace::SendTraced;
+use crate::status;
+use crate::transport;
+use crate::url_policy;
+use crate::utils::extract_number_from_url;
+use crate::variant::Variant;
+
+/// Size of the ranged request used to measure the throughput of a mirror.
+const PROBE_SIZE: u64 = 4 * 1024 * 1024;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A download slower than this share of the speed measured when picking
+/// the mirror is a reason to look for a faster one.
+pub const SLOWDOWN_FACTOR: f64 = 0.25;
+/// How many times a slowed down download may switch mirrors.
+pub const MAX_REEVALUATIONS: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct Probe {
+  /// URL of the snapshot on the mirror (after redirects).
+  pub url: Url,
+  pub layer: u64,
+  pub latency: Duration,
+  pub bytes_per_sec: f64,
+  /// Size of the snapshot, if the mirror reports it.
+  pub size: Option<u64>,
+}
+
+/// Downloads the beginning of the snapshot from the mirror at `base`
+/// to measure its latency and throughput.
+pub async fn probe(base: Url, version: String, variant: Variant) -> Result<Probe> {
+  let mut url = base;
+  url
+    .path_segments_mut()
+    .map_err(|e| anyhow::anyhow!("parsing mirror url: {e:?}"))?
+    .extend(&[&version, variant.file_name()]);
+
+  let client = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .timeout(PROBE_TIMEOUT)
+    .build()?;
+  let start = Instant::now();
+  let response = client
+    .get(url)
+    .header("Range", format!("bytes=0-{}", PROBE_SIZE - 1))
+    .send_traced()
+    .await?;
+  let latency = start.elapsed();
+  anyhow::ensure!(
+    response.status() == StatusCode::PARTIAL_CONTENT,
+    "expected {}, but got {}",
+    StatusCode::PARTIAL_CONTENT,
+    response.status()
+  );
+  let url = response.url().clone();
+  let layer = extract_number_from_url(&url)?;
+  let size = response
+    .headers()
+    .get(reqwest::header::CONTENT_RANGE)
+    .and_then(|v| v.to_str().ok())
+    .and_then(parse_content_range)
+    .and_then(|(_, total)| total);
+  let body = response.bytes().await?;
+  let transfer = start.elapsed().saturating_sub(latency);
+  let bytes_per_sec = body.len() as f64 / transfer.as_secs_f64().max(0.001);
+
+  Ok(Probe {
+    url,
+    layer,
+    latency,
+    bytes_per_sec,
+    size,
+  })
+}
+
+/// Picks the mirror with the latest snapshot, and the fastest among those.
+fn choose(probes: Vec<Probe>) -> Option<Probe> {
+  let latest = probes.iter().map(|p| p.layer).max()?;
+  probes
+    .into_iter()
+    .filter(|p| p.layer == latest)
+    .max_by(|a, b| a.bytes_per_sec.total_cmp(&b.bytes_per_sec))
+}
+
+/// Probes all mirrors at once and picks the fastest one.
+pub async fn pick_fastest(mirrors: &[Url], version: &str, variant: Variant) -> Result<Probe> {
+  status::print_line(format_args!("Checking {} mirrors...", mirrors.len()));
+  let mut probes = JoinSet::new();
+  for mirror in mirrors {
+    let mirror = mirror.clone();
+    let version = version.to_string();
+    probes.spawn(async move { (mirror.clone(), probe(mirror, version, variant).await) });
+  }
+
+  let mut available = Vec::new();
+  while let Some(result) = probes.join_next().await {
+    let (mirror, result) = result.context("probing mirror")?;
+    match result {
+      Ok(p) => {
+        status::print_line(format_args!(
+          "Mirror {}: layer {}, latency {} ms, speed {:.2} MB/s",
+          mirror,
+          p.layer,
+          p.latency.as_millis(),
+          p.bytes_per_sec / 1_024_000.00
+        ));
+        available.push(p);
+      }
+      Err(e) => status::print_line(format_args!("Mirror {mirror} is unavailable: {e:#}")),
+    }
+  }
+
+  let best = choose(available).context("no mirror is available")?;
+  status::print_line(format_args!("Using {}", best.url));
+  Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn probe_result(url: &str, layer: u64, bytes_per_sec: f64) -> Probe {
+    Probe {
+      url: Url::parse(url).unwrap(),
+      layer,
+      latency: Duration::from_millis(10),
+      bytes_per_sec,
+      size: None,
+    }
+  }
+
+  #[test]
+  fn choosing_fastest_mirror_with_latest_snapshot() {
+    let probes = vec![
+      probe_result("https://a/1/100.sql.zst", 100, 5.0),
+      probe_result("https://b/1/100.sql.zst", 100, 10.0),
+      probe_result("https://c/1/90.sql.zst", 90, 50.0),
+    ];
+    assert_eq!(choose(probes).unwrap().url.host_str(), Some("b"));
+    assert!(choose(Vec::new()).is_none());
+  }
+
+  #[tokio::test]
+  async fn probing_mirrors() {
+    let mut fast = mockito::Server::new_async().await;
+    let redirect = fast
+      .mock("GET", "/v1.0/state.zst")
+      .with_status(302)
+      .with_header("Location", &format!("{}/1/123.sql.zst", fast.url()))
+      .create_async()
+      .await;
+    let data = fast
+      .mock("GET", "/1/123.sql.zst")
+      .match_header("Range", format!("bytes=0-{}", PROBE_SIZE - 1).as_str())
+      .with_status(206)
+      .with_header("Content-Range", "bytes 0-1023/1048576000")
+      .with_body(vec![0u8; 1024])
+      .create_async()
+      .await;
+    let mut broken = mockito::Server::new_async().await;
+    let missing = broken
+      .mock("GET", "/v1.0/state.zst")
+      .with_status(404)
+      .create_async()
+      .await;
+
+    let mirrors = [
+      Url::parse(&fast.url()).unwrap(),
+      Url::parse(&broken.url()).unwrap(),
+    ];
+    let best = pick_fastest(&mirrors, "v1.0", Variant::Archival)
+      .await
+      .unwrap();
+    assert_eq!(best.layer, 123);
+    assert_eq!(best.size, Some(1_048_576_000));
+    assert!(best.url.as_str().starts_with(&fast.url()));
+    redirect.assert_async().await;
+    data.assert_async().await;
+    missing.assert_async().await;
+  }
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::Path;
+
+/// Returns the name of the file system holding `dir` if it is a network
+/// (NFS, SMB, ...) or FUSE file system.
+///
+/// SQLite relies on file locks and shared memory (for the WAL) that don't work
+/// reliably on such file systems, and renames may not be atomic there.
+pub fn detect(dir: &Path) -> Option<String> {
+  imp::detect(dir)
+}
+
+/// Moves `from` to `to` by copying, for file systems where replacing a file
+/// by renaming isn't reliable.
+pub fn copy_replace(from: &Path, to: &Path) -> Result<()> {
+  std::fs::copy(from, to)
+    .with_context(|| format!("copying {} to {}", from.display(), to.display()))?;
+  File::open(to)?
+    .sync_all()
+    .with_context(|| format!("syncing {}", to.display()))?;
+  std::fs::remove_file(from).with_context(|| format!("removing {}", from.display()))
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::Path;
+
+  /// File system magic numbers, see `man 2 statfs`.
+  const NETWORK_FS: &[(i64, &str)] = &[
+    (0x6969, "nfs"),
+    (0x517B, "smb"),
+    (0xFF534D42, "cifs"),
+    (0xFE534D42, "smb2"),
+    (0x65735546, "fuse"),
+    (0x00C36400, "ceph"),
+    (0x5346414F, "afs"),
+    (0x01021997, "9p"),
+  ];
+
+  pub(super) fn detect(dir: &Path) -> Option<String> {
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+      return None;
+    }
+    // `f_type` differs in width and signedness between architectures
+    #[allow(clippy::unnecessary_cast)]
+    let fs_type = stat.f_type as i64 & 0xFFFF_FFFF;
+    NETWORK_FS
+      .iter()
+      .find(|(magic, _)| *magic == fs_type)
+      .map(|(_, name)| name.to_string())
+  }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+  use std::ffi::CStr;
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::Path;
+
+  const NETWORK_FS: &[&str] = &["nfs", "smbfs", "afpfs", "webdav", "cifs"];
+
+  pub(super) fn detect(dir: &Path) -> Option<String> {
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+      return None;
+    }
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) }
+      .to_string_lossy()
+      .into_owned();
+    (NETWORK_FS.contains(&name.as_str()) || name.contains("fuse")).then_some(name)
+  }
+}
+
+#[cfg(windows)]
+mod imp {
+  use std::os::windows::ffi::OsStrExt;
+  use std::path::{Component, Path, Prefix};
+  use windows_sys::Win32::Storage::FileSystem::GetDriveTypeW;
+
+  const DRIVE_REMOTE: u32 = 4;
+
+  pub(super) fn detect(dir: &Path) -> Option<String> {
+    let root = match dir.components().next()? {
+      Component::Prefix(prefix) => match prefix.kind() {
+        Prefix::UNC(..) | Prefix::VerbatimUNC(..) => return Some("smb".to_string()),
+        Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => format!("{}:\\", letter as char),
+        _ => return None,
+      },
+      _ => return None,
+    };
+    let root: Vec<u16> = std::ffi::OsStr::new(&root)
+      .encode_wide()
+      .chain(Some(0))
+      .collect();
+    let drive_type = unsafe { GetDriveTypeW(root.as_ptr()) };
+    (drive_type == DRIVE_REMOTE).then(|| "network drive".to_string())
+  }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod imp {
+  use std::path::Path;
+
+  pub(super) fn detect(_dir: &Path) -> Option<String> {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{copy_replace, detect};
+
+  #[test]
+  fn temp_dir_is_local() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(detect(dir.path()), None);
+  }
+
+  #[test]
+  fn copy_replaces_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let from = dir.path().join("state_downloaded.sql");
+    let to = dir.path().join("state.sql");
+    std::fs::write(&from, b"new").unwrap();
+    std::fs::write(&to, b"old").unwrap();
+    copy_replace(&from, &to).unwrap();
+    assert!(!from.exists());
+    assert_eq!(std::fs::read(&to).unwrap(), b"new");
+  }
+}
@@ -1,4 +1,7 @@
 use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+
+use crate::progress::Interval;
 
 pub fn parse_duration(v: &str) -> Result<chrono::Duration, Error> {
   let ds = v
@@ -9,3 +12,132 @@ pub fn parse_duration(v: &str) -> Result<chrono::Duration, Error> {
 
   Ok(res)
 }
+
+/// Parses sizes like `16MiB`, `512k` or `1048576` into a number of bytes.
+pub fn parse_byte_size(v: &str) -> Result<u64, Error> {
+  let v = v.trim();
+  let (num, unit) = v.split_at(v.find(|c: char| !c.is_ascii_digit()).unwrap_or(v.len()));
+  let num = num
+    .parse::<u64>()
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+  let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+    "" | "b" => 1,
+    "k" | "kb" | "kib" => 1024,
+    "m" | "mb" | "mib" => 1024 * 1024,
+    "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+    other => {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("unknown size unit: {other}"),
+      ))
+    }
+  };
+  match num.checked_mul(multiplier) {
+    Some(0) => Err(Error::new(
+      ErrorKind::InvalidInput,
+      "size must be greater than zero",
+    )),
+    Some(size) => Ok(size),
+    None => Err(Error::new(ErrorKind::InvalidInput, "size is too big")),
+  }
+}
+
+/// Parses speeds like `1MiB/s` or `512k` into a number of bytes per second.
+pub fn parse_speed(v: &str) -> Result<u64, Error> {
+  let v = v.trim();
+  parse_byte_size(v.strip_suffix("/s").unwrap_or(v))
+}
+
+/// Parses `host:ip` overrides of the address of a host, with IPv6 addresses
+/// optionally in brackets (e.g. `example.com:[2001:db8::1]`).
+pub fn parse_resolve(v: &str) -> Result<(String, IpAddr), Error> {
+  let (host, ip) = v
+    .split_once(':')
+    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "expected host:ip"))?;
+  let ip = ip.trim_start_matches('[').trim_end_matches(']');
+  let ip = ip
+    .parse::<IpAddr>()
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{ip}: {e}")))?;
+  if host.is_empty() {
+    return Err(Error::new(ErrorKind::InvalidInput, "host is empty"));
+  }
+  Ok((host.to_lowercase(), ip))
+}
+
+/// Parses progress intervals, a duration like `30s` or a percentage like `1%`.
+pub fn parse_progress_interval(v: &str) -> Result<Interval, Error> {
+  let v = v.trim();
+  let Some(percent) = v.strip_suffix('%') else {
+    let interval = parse_duration(v)?
+      .to_std()
+      .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    return Ok(Interval::Time(interval));
+  };
+  match percent.trim().parse::<f64>() {
+    Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(Interval::Percent(percent)),
+    _ => Err(Error::new(
+      ErrorKind::InvalidInput,
+      format!("invalid percentage: {v}, expected more than 0% and at most 100%"),
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_byte_sizes() {
+    assert_eq!(parse_byte_size("1048576").unwrap(), 1024 * 1024);
+    assert_eq!(parse_byte_size("512k").unwrap(), 512 * 1024);
+    assert_eq!(parse_byte_size("16MiB").unwrap(), 16 * 1024 * 1024);
+    assert_eq!(parse_byte_size("2 GB").unwrap(), 2 * 1024 * 1024 * 1024);
+  }
+
+  #[test]
+  fn parses_speeds() {
+    assert_eq!(parse_speed("1MiB/s").unwrap(), 1024 * 1024);
+    assert_eq!(parse_speed("512k").unwrap(), 512 * 1024);
+    assert!(parse_speed("/s").is_err());
+  }
+
+  #[test]
+  fn parses_resolve_overrides() {
+    assert_eq!(
+      parse_resolve("Quicksync.spacemesh.network:10.0.0.1").unwrap(),
+      (
+        "quicksync.spacemesh.network".to_string(),
+        "10.0.0.1".parse().unwrap()
+      )
+    );
+    assert_eq!(
+      parse_resolve("example.com:[2001:db8::1]").unwrap().1,
+      "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+    assert!(parse_resolve("example.com").is_err());
+    assert!(parse_resolve(":10.0.0.1").is_err());
+    assert!(parse_resolve("example.com:10.0.0").is_err());
+  }
+
+  #[test]
+  fn parses_progress_intervals() {
+    assert_eq!(
+      parse_progress_interval("30s").unwrap(),
+      Interval::Time(std::time::Duration::from_secs(30))
+    );
+    assert_eq!(
+      parse_progress_interval("2.5%").unwrap(),
+      Interval::Percent(2.5)
+    );
+    assert!(parse_progress_interval("0%").is_err());
+    assert!(parse_progress_interval("150%").is_err());
+    assert!(parse_progress_interval("often").is_err());
+  }
+
+  #[test]
+  fn rejects_invalid_byte_sizes() {
+    assert!(parse_byte_size("").is_err());
+    assert!(parse_byte_size("0").is_err());
+    assert!(parse_byte_size("10 parsecs").is_err());
+  }
+}
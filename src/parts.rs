@@ -0,0 +1,361 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+use crate::http_trace::SendTraced;
+use crate::read_error_response::read_error_response;
+use crate::source::SnapshotSource;
+use crate::transport;
+use crate::url_policy;
+
+/// Suffix of the list of part checksums published next to a snapshot.
+pub const PARTS_SUFFIX: &str = ".parts.json";
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// MD5 checksums of consecutive parts of the archive, the same ones S3
+/// computes the ETag of a multipart upload from.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PartList {
+  /// Size of the parts, only the last one may be smaller.
+  pub part_size: u64,
+  pub parts: Vec<String>,
+}
+
+impl PartList {
+  fn range(&self, i: usize, len: u64) -> (u64, u64) {
+    let start = i as u64 * self.part_size;
+    (start, self.part_size.min(len - start))
+  }
+}
+
+/// Verifies the parts of the download written through it against their
+/// checksums, each one as soon as it's complete.
+pub struct PartWriter<W> {
+  inner: W,
+  list: Option<PartList>,
+  /// Parts downloaded by an earlier run, before the current one.
+  resumed: usize,
+  part: usize,
+  hashed: u64,
+  md5: md5::Context,
+  bad: Vec<usize>,
+}
+
+impl<W: Write + Seek> PartWriter<W> {
+  /// Wraps `inner`, the partial download at `path` opened for appending,
+  /// verifying its parts against `list` if the server publishes it.
+  /// The data after the last complete part is hashed again.
+  pub fn new(inner: W, path: &Path, list: Option<PartList>) -> Result<Self> {
+    let mut writer = Self {
+      inner,
+      list,
+      resumed: 0,
+      part: 0,
+      hashed: 0,
+      md5: md5::Context::new(),
+      bad: Vec::new(),
+    };
+    let Some(part_size) = writer.list.as_ref().map(|list| list.part_size) else {
+      return Ok(writer);
+    };
+    let len = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+    writer.resumed = (len / part_size) as usize;
+    writer.part = writer.resumed;
+    if len % part_size > 0 {
+      let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+      file.seek(SeekFrom::Start(writer.resumed as u64 * part_size))?;
+      let mut buf = vec![0; 1024 * 1024];
+      loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+          break;
+        }
+        writer.hash(&buf[..read]);
+      }
+    }
+    Ok(writer)
+  }
+
+  fn hash(&mut self, mut data: &[u8]) {
+    let Some(part_size) = self.list.as_ref().map(|list| list.part_size) else {
+      return;
+    };
+    while !data.is_empty() {
+      let take = data.len().min((part_size - self.hashed) as usize);
+      self.md5.consume(&data[..take]);
+      self.hashed += take as u64;
+      data = &data[take..];
+      if self.hashed == part_size {
+        self.check_part();
+      }
+    }
+  }
+
+  fn check_part(&mut self) {
+    let md5 = std::mem::replace(&mut self.md5, md5::Context::new()).compute();
+    let expected = self
+      .list
+      .as_ref()
+      .and_then(|list| list.parts.get(self.part));
+    if !expected.is_some_and(|expected| format!("{md5:x}") == expected.to_lowercase()) {
+      self.bad.push(self.part);
+    }
+    self.part += 1;
+    self.hashed = 0;
+  }
+
+  /// The wrapped writer, and the parts to verify again once the download is
+  /// complete, if the server publishes their checksums: the corrupted ones and
+  /// the ones downloaded by an earlier run.
+  pub fn into_inner(mut self) -> (W, Option<(PartList, Vec<usize>)>) {
+    // The last part is shorter
+    if self.hashed > 0 {
+      self.check_part();
+    }
+    let suspect = (0..self.resumed).chain(self.bad).collect();
+    (self.inner, self.list.map(|list| (list, suspect)))
+  }
+}
+
+impl<W: Write + Seek> Write for PartWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.hash(&buf[..written]);
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<W: Write + Seek> Seek for PartWriter<W> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.inner.seek(pos)
+  }
+}
+
+fn client() -> Result<Client> {
+  Ok(
+    transport::builder()
+      .redirect(url_policy::redirect_policy())
+      .timeout(Duration::from_secs(600))
+      .build()?,
+  )
+}
+
+/// Fetches the part checksums of the snapshot at `snapshot_url`.
+/// Returns `None` if the server doesn't publish them.
+pub async fn fetch(snapshot_url: &Url) -> Result<Option<PartList>> {
+  let url =
+    Url::parse(&format!("{snapshot_url}{PARTS_SUFFIX}")).context("composing part list URL")?;
+  let response = client()?.get(url.clone()).send_traced().await?;
+  let status = response.status();
+  if status == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  if !status.is_success() {
+    let err = read_error_response(response).await;
+    anyhow::bail!("Cannot download part checksums from {url}: {status} {err}");
+  }
+  let list: PartList = response
+    .json()
+    .await
+    .with_context(|| format!("parsing part checksums from {url}"))?;
+  anyhow::ensure!(list.part_size > 0, "part size is zero");
+  Ok(Some(list))
+}
+
+/// Returns the indexes of the parts of the file not matching their checksums.
+fn bad_parts(path: &Path, list: &PartList, only: Option<&[usize]>) -> Result<Vec<usize>> {
+  let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+  let len = file.metadata()?.len();
+  anyhow::ensure!(
+    list.parts.len() as u64 == len.div_ceil(list.part_size),
+    "{} parts of {} bytes don't add up to {len} bytes",
+    list.parts.len(),
+    list.part_size
+  );
+  let mut reader = BufReader::new(file);
+  let mut buf = vec![0; 1024 * 1024];
+  let mut bad = Vec::new();
+  for (i, expected) in list.parts.iter().enumerate() {
+    if only.is_some_and(|only| !only.contains(&i)) {
+      continue;
+    }
+    let (start, part_len) = list.range(i, len);
+    reader.seek(SeekFrom::Start(start))?;
+    let mut part = (&mut reader).take(part_len);
+    let mut md5 = md5::Context::new();
+    loop {
+      let read = part.read(&mut buf)?;
+      if read == 0 {
+        break;
+      }
+      md5.consume(&buf[..read]);
+    }
+    if format!("{:x}", md5.compute()) != expected.to_lowercase() {
+      bad.push(i);
+    }
+  }
+  Ok(bad)
+}
+
+async fn bad_parts_blocking(
+  path: &Path,
+  list: &PartList,
+  only: Option<Vec<usize>>,
+) -> Result<Vec<usize>> {
+  let (path, list) = (path.to_path_buf(), list.clone());
+  tokio::task::spawn_blocking(move || bad_parts(&path, &list, only.as_deref())).await?
+}
+
+/// Verifies the parts of the downloaded file at `path`, all of them or `only`
+/// the given ones (e.g. the ones not verified while downloading), and
+/// downloads again only the parts that don't match from `source`. Returns the
+/// number of repaired parts.
+pub async fn verify_and_repair(
+  source: &dyn SnapshotSource,
+  url: &Url,
+  path: &Path,
+  list: &PartList,
+  only: Option<Vec<usize>>,
+  max_retries: u32,
+) -> Result<usize> {
+  let count = only.as_ref().map_or(list.parts.len(), Vec::len);
+  if count > 0 {
+    println!("Verifying {count} parts of the archive...");
+  }
+  let mut bad = bad_parts_blocking(path, list, only).await?;
+  if bad.is_empty() {
+    return Ok(0);
+  }
+  println!(
+    "{} parts of the archive are corrupted, downloading them again...",
+    bad.len()
+  );
+  let repaired = bad.len();
+  let len = std::fs::metadata(path)?.len();
+  let mut attempt = 0;
+  while !bad.is_empty() {
+    anyhow::ensure!(
+      attempt <= max_retries,
+      "{} parts are still corrupted after {max_retries} attempts",
+      bad.len()
+    );
+    if attempt > 0 {
+      tokio::time::sleep(RETRY_DELAY).await;
+    }
+    attempt += 1;
+    let mut file = OpenOptions::new()
+      .write(true)
+      .open(path)
+      .with_context(|| format!("opening {}", path.display()))?;
+    for &i in &bad {
+      let (start, part_len) = list.range(i, len);
+      match source.fetch_range(url, start, part_len).await {
+        Ok(data) => {
+          file.seek(SeekFrom::Start(start))?;
+          file.write_all(&data)?;
+        }
+        Err(e) => println!("Cannot download part {i}: {e:#}"),
+      }
+    }
+    file.sync_all()?;
+    bad = bad_parts_blocking(path, list, Some(bad)).await?;
+  }
+  Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::source::HttpSource;
+
+  #[tokio::test]
+  async fn repairing_corrupted_parts() {
+    let data: Vec<u8> = (0..2_500u32).map(|i| (i % 241) as u8).collect();
+    let list = PartList {
+      part_size: 1000,
+      parts: data
+        .chunks(1000)
+        .map(|part| format!("{:x}", md5::compute(part)))
+        .collect(),
+    };
+
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/1/100.sql.zst")
+      .match_header("Range", "bytes=1000-1999")
+      .with_status(206)
+      .with_body(&data[1000..2000])
+      .create_async()
+      .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.download");
+    let mut corrupted = data.clone();
+    corrupted[1500] ^= 0xFF;
+    std::fs::write(&path, &corrupted).unwrap();
+
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    let source = HttpSource::new().unwrap();
+    assert_eq!(
+      verify_and_repair(&source, &url, &path, &list, None, 0)
+        .await
+        .unwrap(),
+      1
+    );
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+    assert_eq!(
+      verify_and_repair(&source, &url, &path, &list, None, 0)
+        .await
+        .unwrap(),
+      0
+    );
+    mock.assert_async().await;
+  }
+
+  #[test]
+  fn verifying_parts_while_downloading() {
+    let data: Vec<u8> = (0..3_500u32).map(|i| (i % 241) as u8).collect();
+    let list = PartList {
+      part_size: 1000,
+      parts: data
+        .chunks(1000)
+        .map(|part| format!("{:x}", md5::compute(part)))
+        .collect(),
+    };
+    let mut corrupted = data.clone();
+    corrupted[300] ^= 0xFF;
+    corrupted[2500] ^= 0xFF;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.download");
+    let open = || {
+      OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .unwrap()
+    };
+    // the first run is interrupted after a part and a half
+    let mut writer = PartWriter::new(open(), &path, Some(list.clone())).unwrap();
+    writer.write_all(&corrupted[..1500]).unwrap();
+    drop(writer);
+    // the resumed run hashes the half part again, the part before is left to
+    // verify once the download is complete
+    let mut writer = PartWriter::new(open(), &path, Some(list)).unwrap();
+    for chunk in corrupted[1500..].chunks(700) {
+      writer.write_all(chunk).unwrap();
+    }
+    let (_, checked) = writer.into_inner();
+    assert_eq!(checked.unwrap().1, [0, 2]);
+  }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::file_in_use;
+use crate::http_trace::SendTraced;
+use crate::read_error_response::read_error_response;
+use crate::transport;
+use crate::url_policy;
+use crate::utils::extract_number_from_url;
+
+/// Archive of the installed snapshot kept in node-data.
+pub const KEPT_ARCHIVE: &str = "snapshot.zst";
+/// Record of the kept archive: the snapshot it came from and its checksum.
+pub const KEPT_RECORD: &str = "snapshot.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeptArchive {
+  /// URL of the snapshot the archive was downloaded from.
+  pub url: Url,
+  pub layer: u64,
+  pub size: u64,
+  pub md5: String,
+}
+
+impl KeptArchive {
+  pub fn archive_path(node_data: &Path) -> PathBuf {
+    node_data.join(KEPT_ARCHIVE)
+  }
+
+  /// Reads the record of the archive kept in `node_data`.
+  /// Returns `None` if there is no kept archive or it doesn't have the
+  /// recorded size. Its checksum is checked when it's patched.
+  pub fn load(node_data: &Path) -> Result<Option<Self>> {
+    let record_path = node_data.join(KEPT_RECORD);
+    if !record_path.try_exists().unwrap_or(false) {
+      return Ok(None);
+    }
+    let record =
+      std::fs::read(&record_path).with_context(|| format!("reading {}", record_path.display()))?;
+    let kept: Self = serde_json::from_slice(&record)
+      .with_context(|| format!("parsing {}", record_path.display()))?;
+    match std::fs::metadata(Self::archive_path(node_data)) {
+      Ok(metadata) if metadata.len() == kept.size => Ok(Some(kept)),
+      _ => Ok(None),
+    }
+  }
+}
+
+/// Keeps the verified `archive` of the snapshot at `url` in `node_data`,
+/// replacing the previously kept one.
+pub fn keep(node_data: &Path, archive: &Path, url: Url, md5: String) -> Result<KeptArchive> {
+  let record_path = node_data.join(KEPT_RECORD);
+  if record_path.try_exists().unwrap_or(false) {
+    std::fs::remove_file(&record_path)
+      .with_context(|| format!("removing {}", record_path.display()))?;
+  }
+  let kept = KeptArchive {
+    layer: extract_number_from_url(&url)?,
+    size: std::fs::metadata(archive)?.len(),
+    url,
+    md5,
+  };
+  file_in_use::move_file(archive, &KeptArchive::archive_path(node_data))?;
+  std::fs::write(&record_path, serde_json::to_string_pretty(&kept)?)
+    .with_context(|| format!("writing {}", record_path.display()))?;
+  Ok(kept)
+}
+
+/// URL of the patch turning the archive of layer `from_layer` into the
+/// snapshot at `snapshot_url`.
+pub fn patch_url(snapshot_url: &Url, from_layer: u64) -> Result<Url> {
+  Url::parse(&format!("{snapshot_url}.from-{from_layer}.patch")).context("composing patch URL")
+}
+
+/// Downloads the patch. Returns `None` if the server doesn't publish it.
+pub async fn fetch_patch(url: &Url) -> Result<Option<Vec<u8>>> {
+  let client = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .build()?;
+  let response = client.get(url.clone()).send_traced().await?;
+  let status = response.status();
+  if status == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  if !status.is_success() {
+    let err = read_error_response(response).await;
+    anyhow::bail!("Cannot download patch from {url}: {status} {err}");
+  }
+  Ok(Some(response.bytes().await?.to_vec()))
+}
+
+/// Applies the bsdiff `patch` to the `source` archive, writing the result to `target`.
+/// Fails if the source doesn't have the `md5` checksum it was kept with.
+pub fn apply(source: &Path, md5: &str, patch: &[u8], target: &Path) -> Result<()> {
+  let source_file = File::open(source).with_context(|| format!("opening {}", source.display()))?;
+  // SAFETY: the kept archive is only touched by quicksync itself
+  let source_data = unsafe { memmap2::Mmap::map(&source_file) }
+    .with_context(|| format!("mapping {}", source.display()))?;
+  let source_md5 = format!("{:x}", md5::compute(&source_data[..]));
+  anyhow::ensure!(
+    source_md5.eq_ignore_ascii_case(md5),
+    "{} doesn't match its checksum, it was changed since it was kept",
+    source.display()
+  );
+  let target_file =
+    File::create(target).with_context(|| format!("creating {}", target.display()))?;
+  let mut writer = BufWriter::new(target_file);
+  qbsdiff::Bspatch::new(patch)
+    .context("parsing patch")?
+    .apply(&source_data, &mut writer)
+    .context("applying patch")?;
+  writer
+    .into_inner()
+    .map_err(|e| e.into_error())?
+    .sync_all()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn applying_patch() {
+    let dir = tempfile::tempdir().unwrap();
+    let old: Vec<u8> = (0..50_000u32).map(|i| (i % 253) as u8).collect();
+    let mut new = old.clone();
+    new[100..200].fill(0);
+    new.extend_from_slice(b"next layer");
+    let mut patch = Vec::new();
+    qbsdiff::Bsdiff::new(&old, &new)
+      .compare(&mut patch)
+      .unwrap();
+
+    let source = dir.path().join(KEPT_ARCHIVE);
+    let target = dir.path().join("state.download");
+    std::fs::write(&source, &old).unwrap();
+    let md5 = format!("{:x}", md5::compute(&old));
+    apply(&source, &md5, &patch, &target).unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), new);
+    // a kept archive changed since, even of the same size, isn't patched
+    let mut changed = old.clone();
+    changed[0] ^= 1;
+    std::fs::write(&source, &changed).unwrap();
+    assert!(apply(&source, &md5, &patch, &target).is_err());
+  }
+
+  #[test]
+  fn keeping_archives() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(KeptArchive::load(dir.path()).unwrap().is_none());
+
+    let kept = KeptArchive {
+      url: Url::parse("https://quicksync.spacemesh.network/1/100.sql.zst").unwrap(),
+      layer: 100,
+      size: 3,
+      md5: "abc".to_string(),
+    };
+    std::fs::write(
+      dir.path().join(KEPT_RECORD),
+      serde_json::to_string(&kept).unwrap(),
+    )
+    .unwrap();
+    // the archive is missing
+    assert!(KeptArchive::load(dir.path()).unwrap().is_none());
+
+    std::fs::write(KeptArchive::archive_path(dir.path()), b"zst").unwrap();
+    assert_eq!(KeptArchive::load(dir.path()).unwrap().unwrap().layer, 100);
+
+    let archive = dir.path().join("state.zst");
+    std::fs::write(&archive, b"newer").unwrap();
+    let url = Url::parse("https://quicksync.spacemesh.network/1/110.sql.zst").unwrap();
+    keep(dir.path(), &archive, url, "def".to_string()).unwrap();
+    assert!(!archive.exists());
+    let kept = KeptArchive::load(dir.path()).unwrap().unwrap();
+    assert_eq!((kept.layer, kept.size, kept.md5.as_str()), (110, 5, "def"));
+    // the next snapshot is patched from the layer of the kept one
+    let next = Url::parse("https://quicksync.spacemesh.network/1/120.sql.zst").unwrap();
+    assert_eq!(
+      patch_url(&next, kept.layer).unwrap().as_str(),
+      "https://quicksync.spacemesh.network/1/120.sql.zst.from-110.patch"
+    );
+  }
+}
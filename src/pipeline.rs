@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use tokio::runtime::RuntimeFlavor;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use zstd::stream::read::Decoder;
+
+use crate::failpoints;
+use crate::io_tuning::{IoOptions, NoCacheFile};
+use crate::reader_with_bytes::ReaderWithBytes;
+
+/// Buffers of downloaded data waiting to be unpacked, before the download
+/// waits for the unpacking to catch up.
+const QUEUED_BUFFERS: usize = 4;
+
+/// First bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Checksums computed while the archive was downloaded and unpacked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipelined {
+  pub archive_md5: String,
+  pub db_md5: String,
+}
+
+/// Reads the data sent to the channel until the sender is dropped.
+struct ChannelReader<'a> {
+  rx: &'a mut Receiver<Vec<u8>>,
+  buf: Vec<u8>,
+  pos: usize,
+}
+
+impl Read for ChannelReader<'_> {
+  fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+    while self.pos == self.buf.len() {
+      match self.rx.blocking_recv() {
+        Some(buf) => (self.buf, self.pos) = (buf, 0),
+        None => return Ok(0),
+      }
+    }
+    let read = out.len().min(self.buf.len() - self.pos);
+    out[..read].copy_from_slice(&self.buf[self.pos..self.pos + read]);
+    self.pos += read;
+    Ok(read)
+  }
+}
+
+/// Writer computing the MD5 of what's written through it.
+struct Md5Writer<W> {
+  inner: W,
+  md5: md5::Context,
+}
+
+impl<W: Write> Write for Md5Writer<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.md5.consume(&buf[..written]);
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Unpacks the zstd archive received from `rx` into `outpath` and returns
+/// the MD5 of the unpacked database.
+fn unpack(rx: &mut Receiver<Vec<u8>>, outpath: &Path, io: IoOptions) -> Result<String> {
+  let reader = ChannelReader {
+    rx,
+    buf: Vec::new(),
+    pos: 0,
+  };
+  let mut decoder = Decoder::new(reader)?;
+  decoder.window_log_max(31)?;
+  let outfile = File::create(outpath)
+    .with_context(|| format!("creating file to unpack into at: {}", outpath.display()))?;
+  let outfile = NoCacheFile::new(outfile, io.no_page_cache)?;
+  let mut writer = Md5Writer {
+    inner: BufWriter::with_capacity(io.buffer_size, outfile),
+    md5: md5::Context::new(),
+  };
+  io::copy(
+    &mut ReaderWithBytes::new(decoder),
+    &mut failpoints::UnpackedWriter(&mut writer),
+  )?;
+  writer.flush()?;
+  Ok(format!("{:x}", writer.md5.compute()))
+}
+
+/// Computes the checksum of the downloaded archive at `path` and unpacks it
+/// into `outpath` in one read of it, unpacking on another thread. Returns
+/// `None` if it couldn't be unpacked like that, e.g. if it isn't zstd.
+pub fn verify_and_unpack(
+  path: &Path,
+  outpath: PathBuf,
+  io: IoOptions,
+) -> Result<Option<Pipelined>> {
+  let mut magic = [0; 4];
+  let read = File::open(path).and_then(|mut file| file.read_exact(&mut magic));
+  if read.is_err() || magic != ZSTD_MAGIC {
+    return Ok(None);
+  }
+  Ok(PipelineWriter::new(io::sink(), path, Some(outpath), io)?.finish())
+}
+
+/// Sends `buf` to the unpacker, waiting while the queue is full. A tokio
+/// worker hands its other tasks over to another thread while it waits.
+/// Returns `false` if the unpacker is gone.
+fn send(tx: &Sender<Vec<u8>>, buf: Vec<u8>) -> bool {
+  let buf = match tx.try_send(buf) {
+    Ok(()) => return true,
+    Err(TrySendError::Closed(_)) => return false,
+    Err(TrySendError::Full(buf)) => buf,
+  };
+  let wait = || futures::executor::block_on(tx.send(buf)).is_ok();
+  match tokio::runtime::Handle::try_current() {
+    Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+      tokio::task::block_in_place(wait)
+    }
+    _ => wait(),
+  }
+}
+
+/// Writer of the downloaded archive that computes its checksum and unpacks it
+/// on another thread as it's downloaded, instead of reading it twice more
+/// after the download. The download waits if unpacking falls behind.
+pub struct PipelineWriter<W> {
+  inner: W,
+  md5: md5::Context,
+  tx: Option<Sender<Vec<u8>>>,
+  unpacker: Option<JoinHandle<Result<String>>>,
+  /// The unpacked database, deleted if unpacking fails.
+  outpath: Option<PathBuf>,
+}
+
+impl<W> PipelineWriter<W> {
+  /// Wraps `inner`, the archive at `path` opened for appending, and starts
+  /// unpacking it into `outpath`, beginning with what's downloaded already.
+  /// Without `outpath` the data is only written to `inner`.
+  pub fn new(inner: W, path: &Path, outpath: Option<PathBuf>, io: IoOptions) -> Result<Self> {
+    let Some(outpath) = outpath else {
+      return Ok(Self {
+        inner,
+        md5: md5::Context::new(),
+        tx: None,
+        unpacker: None,
+        outpath: None,
+      });
+    };
+    let (tx, mut rx) = channel(QUEUED_BUFFERS);
+    let unpacked = outpath.clone();
+    let unpacker = std::thread::spawn(move || {
+      let result = unpack(&mut rx, &unpacked, io);
+      // Keep receiving, so the download isn't blocked if unpacking failed
+      while rx.blocking_recv().is_some() {}
+      result
+    });
+    let mut writer = Self {
+      inner,
+      md5: md5::Context::new(),
+      tx: Some(tx),
+      unpacker: Some(unpacker),
+      outpath: Some(outpath),
+    };
+    match writer.pass_file(path, io) {
+      Ok(()) => Ok(writer),
+      Err(e) => {
+        writer.join();
+        writer.remove_unpacked();
+        Err(e)
+      }
+    }
+  }
+
+  /// Passes what's downloaded of the archive at `path` already.
+  fn pass_file(&mut self, path: &Path, io: IoOptions) -> Result<()> {
+    let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    file.seek(SeekFrom::Start(0))?;
+    loop {
+      let mut buf = vec![0; io.buffer_size];
+      let read = file.read(&mut buf)?;
+      if read == 0 {
+        return Ok(());
+      }
+      buf.truncate(read);
+      self.pass(buf);
+    }
+  }
+
+  fn pass(&mut self, buf: Vec<u8>) {
+    self.md5.consume(&buf);
+    if let Some(tx) = &self.tx {
+      if !send(tx, buf) {
+        self.tx = None;
+      }
+    }
+  }
+
+  /// Waits for the unpacking to finish. Returns `None` if it failed or
+  /// wasn't started, then the archive has to be verified and unpacked again.
+  /// What was unpacked before a failure is deleted.
+  pub fn finish(mut self) -> Option<Pipelined> {
+    match self.join()? {
+      Ok(db_md5) => Some(Pipelined {
+        archive_md5: format!("{:x}", self.md5.compute()),
+        db_md5,
+      }),
+      Err(e) => {
+        println!("Cannot unpack the archive while hashing it, unpacking it separately: {e:#}");
+        self.remove_unpacked();
+        None
+      }
+    }
+  }
+
+  /// Waits for the unpacking thread, if it was started.
+  fn join(&mut self) -> Option<Result<String>> {
+    drop(self.tx.take());
+    let unpacker = self.unpacker.take()?;
+    Some(unpacker.join().expect("unpacking thread panicked"))
+  }
+
+  fn remove_unpacked(&self) {
+    if let Some(outpath) = &self.outpath {
+      let _ = std::fs::remove_file(outpath);
+    }
+  }
+}
+
+impl<W: Write + Seek> Write for PipelineWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.pass(buf[..written].to_vec());
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<W: Write + Seek> Seek for PipelineWriter<W> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.inner.seek(pos)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unpacking_while_downloading() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, outpath) = (
+      dir.path().join("state.download"),
+      dir.path().join("state.sql"),
+    );
+    let db: Vec<u8> = (0..50_000u32).map(|i| (i % 7) as u8).collect();
+    let archive = zstd::encode_all(&db[..], 3).unwrap();
+
+    // resumed after the first part was downloaded
+    std::fs::write(&path, &archive[..100]).unwrap();
+    let file = std::fs::OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap();
+    let mut writer =
+      PipelineWriter::new(file, &path, Some(outpath.clone()), IoOptions::for_tests()).unwrap();
+    for chunk in archive[100..].chunks(300) {
+      writer.write_all(chunk).unwrap();
+    }
+    assert_eq!(
+      writer.finish(),
+      Some(Pipelined {
+        archive_md5: format!("{:x}", md5::compute(&archive)),
+        db_md5: format!("{:x}", md5::compute(&db)),
+      })
+    );
+    assert_eq!(std::fs::read(&path).unwrap(), archive);
+    assert_eq!(std::fs::read(&outpath).unwrap(), db);
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+  async fn unpacking_on_the_runtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, outpath) = (
+      dir.path().join("state.download"),
+      dir.path().join("state.sql"),
+    );
+    // Incompressible, so the download waits for the unpacking to catch up
+    let db: Vec<u8> = (0..1_000_000).map(|_| rand::random()).collect();
+    let archive = zstd::encode_all(&db[..], 1).unwrap();
+    let file = std::fs::File::create(&path).unwrap();
+    let mut writer =
+      PipelineWriter::new(file, &path, Some(outpath.clone()), IoOptions::for_tests()).unwrap();
+    let ticks = tokio::spawn(async {
+      let mut ticks = 0;
+      while ticks < 10 {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        ticks += 1;
+      }
+      ticks
+    });
+    for chunk in archive.chunks(1024) {
+      writer.write_all(chunk).unwrap();
+    }
+    assert!(writer.finish().is_some());
+    assert_eq!(std::fs::read(&outpath).unwrap(), db);
+    assert_eq!(ticks.await.unwrap(), 10);
+  }
+
+  #[test]
+  fn downloading_other_formats() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, outpath) = (
+      dir.path().join("state.download"),
+      dir.path().join("state.sql"),
+    );
+    std::fs::write(&path, b"").unwrap();
+    let file = std::fs::OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap();
+    let mut writer =
+      PipelineWriter::new(file, &path, Some(outpath.clone()), IoOptions::for_tests()).unwrap();
+    // not zstd, so it's unpacked after the download
+    writer.write_all(&[b'P', b'K', 3, 4, 0, 0]).unwrap();
+    assert_eq!(writer.finish(), None);
+    assert_eq!(std::fs::read(&path).unwrap(), [b'P', b'K', 3, 4, 0, 0]);
+    assert!(!outpath.exists());
+  }
+
+  #[test]
+  fn verifying_while_unpacking() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, outpath) = (dir.path().join("state.zst"), dir.path().join("state.sql"));
+    let db: Vec<u8> = (0..50_000u32).map(|i| (i % 11) as u8).collect();
+    let archive = zstd::encode_all(&db[..], 3).unwrap();
+    std::fs::write(&path, &archive).unwrap();
+    assert_eq!(
+      verify_and_unpack(&path, outpath.clone(), IoOptions::for_tests()).unwrap(),
+      Some(Pipelined {
+        archive_md5: format!("{:x}", md5::compute(&archive)),
+        db_md5: format!("{:x}", md5::compute(&db)),
+      })
+    );
+    assert_eq!(std::fs::read(&outpath).unwrap(), db);
+
+    // A truncated archive leaves nothing unpacked behind
+    std::fs::write(&path, &archive[..archive.len() / 2]).unwrap();
+    assert_eq!(
+      verify_and_unpack(&path, outpath.clone(), IoOptions::for_tests()).unwrap(),
+      None
+    );
+    assert!(!outpath.exists());
+
+    std::fs::write(&path, b"PK\x03\x04").unwrap();
+    assert_eq!(
+      verify_and_unpack(&path, outpath, IoOptions::for_tests()).unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn downloading_without_unpacking() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.download");
+    std::fs::write(&path, b"").unwrap();
+    let file = std::fs::OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap();
+    let mut writer = PipelineWriter::new(file, &path, None, IoOptions::for_tests()).unwrap();
+    let archive = zstd::encode_all(&b"database"[..], 3).unwrap();
+    writer.write_all(&archive).unwrap();
+    assert_eq!(writer.finish(), None);
+    assert_eq!(std::fs::read(&path).unwrap(), archive);
+    assert!(!dir.path().join("state.sql").exists());
+  }
+}
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// Size of the probe file, enough to hit a nearly exhausted quota.
+const PROBE_SIZE: usize = 1024 * 1024;
+/// Files created in the directory while syncing: the download, the archive,
+/// the unpacked database, backups and the history.
+#[cfg(unix)]
+const MIN_FREE_INODES: u64 = 16;
+
+/// Checks that files can be created in `dir`, so a download doesn't go to waste
+/// because of wrong permissions, a read-only mount or an exhausted quota.
+pub fn check_writable(dir: &Path) -> Result<()> {
+  std::fs::create_dir_all(dir).with_context(|| format!("creating directory {}", dir.display()))?;
+
+  let probe = dir.join(format!(".quicksync-probe-{}", std::process::id()));
+  let result = write_probe(&probe);
+  let _ = std::fs::remove_file(&probe);
+  result.map_err(|e| anyhow::anyhow!("Cannot write into {}: {}", dir.display(), reason(&e)))?;
+
+  #[cfg(unix)]
+  check_inodes(dir)?;
+
+  let state_sql = dir.join("state.sql");
+  if let Ok(metadata) = std::fs::metadata(&state_sql) {
+    anyhow::ensure!(
+      !metadata.permissions().readonly(),
+      "{} is read-only and cannot be replaced",
+      state_sql.display()
+    );
+  }
+  Ok(())
+}
+
+/// Why writing failed. The error kinds of a read-only file system, a full disk
+/// and an exceeded quota aren't stable on the supported toolchain, so they're
+/// told apart by the OS error.
+fn reason(e: &std::io::Error) -> String {
+  if e.kind() == ErrorKind::PermissionDenied {
+    return "permission denied".to_string();
+  }
+  #[cfg(unix)]
+  match e.raw_os_error() {
+    Some(libc::EROFS) => return "the file system is read-only".to_string(),
+    Some(libc::ENOSPC) => return "no space left on the device".to_string(),
+    Some(libc::EDQUOT) => return "disk quota exceeded".to_string(),
+    _ => {}
+  }
+  e.to_string()
+}
+
+fn write_probe(path: &Path) -> std::io::Result<()> {
+  let mut file = File::create_new(path)?;
+  file.write_all(&vec![0u8; PROBE_SIZE])?;
+  file.sync_all()
+}
+
+/// The directory itself, or the closest one above it that exists yet.
+fn existing_ancestor(dir: &Path) -> &Path {
+  dir.ancestors().find(|dir| dir.exists()).unwrap_or(dir)
+}
+
+/// Statistics of the file system of `dir` (or of the directory above it that
+/// exists), if known.
+#[cfg(unix)]
+fn statvfs(dir: &Path) -> Option<libc::statvfs> {
+  use std::os::unix::ffi::OsStrExt;
+
+  let path = std::ffi::CString::new(existing_ancestor(dir).as_os_str().as_bytes()).ok()?;
+  let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+  (unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == 0).then_some(stat)
+}
+
+#[cfg(unix)]
+fn check_inodes(dir: &Path) -> Result<()> {
+  // Not knowing is no reason to fail
+  let Some(stat) = statvfs(dir) else {
+    return Ok(());
+  };
+  // Some file systems (e.g. btrfs) create inodes dynamically and report none
+  if stat.f_files == 0 {
+    return Ok(());
+  }
+  // `fsfilcnt_t` is narrower than u64 on some platforms
+  #[allow(clippy::unnecessary_cast)]
+  let free = stat.f_favail as u64;
+  anyhow::ensure!(
+    free >= MIN_FREE_INODES,
+    "Cannot write into {}: only {free} free inodes left",
+    dir.display()
+  );
+  Ok(())
+}
+
+/// Bytes available to the user on the file system of `dir`, if known.
+#[cfg(unix)]
+pub fn free_space(dir: &Path) -> Option<u64> {
+  let stat = statvfs(dir)?;
+  // The fields are narrower than u64 on some platforms
+  #[allow(clippy::unnecessary_cast)]
+  Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes available to the user on the volume of `dir`, if known.
+#[cfg(windows)]
+pub fn free_space(dir: &Path) -> Option<u64> {
+  use std::os::windows::ffi::OsStrExt;
+  use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+  let path: Vec<u16> = existing_ancestor(dir)
+    .as_os_str()
+    .encode_wide()
+    .chain(Some(0))
+    .collect();
+  let mut available = 0u64;
+  let ok = unsafe {
+    GetDiskFreeSpaceExW(
+      path.as_ptr(),
+      &mut available,
+      std::ptr::null_mut(),
+      std::ptr::null_mut(),
+    )
+  };
+  (ok != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_dir: &Path) -> Option<u64> {
+  None
+}
+
+/// Whether `a` and `b` (or the directories above them that exist) are on the
+/// same file system.
+#[cfg(unix)]
+pub fn same_volume(a: &Path, b: &Path) -> bool {
+  use std::os::unix::fs::MetadataExt;
+
+  let dev = |dir: &Path| std::fs::metadata(existing_ancestor(dir)).map(|m| m.dev());
+  match (dev(a), dev(b)) {
+    (Ok(a), Ok(b)) => a == b,
+    _ => a == b,
+  }
+}
+
+/// Whether `a` and `b` are on the same volume, by their drive.
+#[cfg(not(unix))]
+pub fn same_volume(a: &Path, b: &Path) -> bool {
+  a.components().next() == b.components().next()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{check_writable, free_space, reason, same_volume};
+
+  #[test]
+  fn writable_directory_passes() {
+    let dir = tempfile::tempdir().unwrap();
+    let node_data = dir.path().join("node-data");
+    check_writable(&node_data).unwrap();
+    // the probe is cleaned up
+    assert_eq!(std::fs::read_dir(&node_data).unwrap().count(), 0);
+  }
+
+  #[test]
+  fn file_in_place_of_directory_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let node_data = dir.path().join("node-data");
+    std::fs::write(&node_data, b"").unwrap();
+    assert!(check_writable(&node_data).is_err());
+  }
+
+  #[test]
+  fn directories_not_created_yet() {
+    let dir = tempfile::tempdir().unwrap();
+    let temp_dir = dir.path().join("tmp/0");
+    assert_eq!(
+      free_space(&temp_dir).is_some(),
+      free_space(dir.path()).is_some()
+    );
+    assert!(same_volume(&temp_dir, dir.path()));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn telling_write_errors_apart() {
+    let error = |code| std::io::Error::from_raw_os_error(code);
+    assert_eq!(reason(&error(libc::EROFS)), "the file system is read-only");
+    assert_eq!(reason(&error(libc::ENOSPC)), "no space left on the device");
+    assert_eq!(reason(&error(libc::EDQUOT)), "disk quota exceeded");
+    assert_eq!(reason(&error(libc::EACCES)), "permission denied");
+  }
+}
@@ -0,0 +1,245 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::eta::Eta;
+use crate::events::{self, Event, Stage};
+use crate::status;
+
+const MB: f64 = 1_024_000.00;
+/// How often the progress is reported by percentage when the total is unknown.
+const UNKNOWN_TOTAL_STEP: u64 = 1000 * 1024 * 1024;
+
+/// The interval set with `--progress-interval`.
+static INTERVAL: OnceLock<Interval> = OnceLock::new();
+
+/// How often the progress is reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interval {
+  Time(Duration),
+  /// Percentage of the total, or every 1000 MB if it's unknown.
+  Percent(f64),
+}
+
+impl Default for Interval {
+  fn default() -> Self {
+    Interval::Percent(0.1)
+  }
+}
+
+/// Sets how often every transfer reports its progress.
+pub fn set_interval(interval: Interval) {
+  let _ = INTERVAL.set(interval);
+}
+
+/// Progress of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+  pub stage: Stage,
+  pub done: u64,
+  /// Unknown for some stages.
+  pub total: Option<u64>,
+  pub bytes_per_sec: Option<f64>,
+}
+
+impl Progress {
+  pub fn eta(&self) -> Eta {
+    match (self.total, self.bytes_per_sec) {
+      (Some(total), Some(speed)) if speed > 1.0 => {
+        Eta::Seconds((total as f64 - self.done as f64) / speed)
+      }
+      _ => Eta::Unknown,
+    }
+  }
+
+  /// The line describing the progress.
+  fn line(&self) -> String {
+    match (self.stage, self.total) {
+      (Stage::Download, Some(total)) => format!(
+        "Downloading... {:.2}% ({:.2} MB/{:.2} MB) Speed: {:.2} MB/s ETA: {}",
+        self.done as f64 / total.max(1) as f64 * 100.0,
+        self.done as f64 / MB,
+        total as f64 / MB,
+        self.bytes_per_sec.unwrap_or(0.0) / MB,
+        self.eta()
+      ),
+      (Stage::Unpack, _) => format!("Unpacking... {} MB extracted", self.done / (1024 * 1024)),
+      (stage, total) => format!(
+        "{stage:?}... {} of {} bytes",
+        self.done,
+        total.map_or("?".to_string(), |t| t.to_string())
+      ),
+    }
+  }
+}
+
+/// Output the progress of a transfer is reported to.
+pub trait ProgressSink: Send {
+  fn report(&mut self, progress: &Progress);
+  /// Called once the transfer is over.
+  fn finish(&mut self) {}
+}
+
+/// The progress as a line of text, kept on one line in a terminal.
+pub struct StatusLine;
+
+impl ProgressSink for StatusLine {
+  fn report(&mut self, progress: &Progress) {
+    status::progress(
+      progress.stage,
+      progress.done,
+      progress.total,
+      &progress.line(),
+    );
+  }
+
+  fn finish(&mut self) {
+    status::end();
+  }
+}
+
+/// The progress as `progress` events, for the JSON output, the event socket
+/// and the healthcheck file.
+pub struct Events;
+
+impl ProgressSink for Events {
+  fn report(&mut self, progress: &Progress) {
+    events::emit(Event::Progress {
+      stage: progress.stage,
+      done: progress.done,
+      total: progress.total,
+      bytes_per_sec: progress.bytes_per_sec,
+    });
+  }
+}
+
+/// A callback, for code embedding the transfer.
+impl<F: FnMut(&Progress) + Send> ProgressSink for F {
+  fn report(&mut self, progress: &Progress) {
+    self(progress)
+  }
+}
+
+/// Reports the progress of a transfer to its sinks at the set interval.
+pub struct Reporter {
+  sinks: Vec<Box<dyn ProgressSink>>,
+  interval: Interval,
+  last_reported: Option<(u64, Instant)>,
+}
+
+impl Reporter {
+  pub fn new(sinks: Vec<Box<dyn ProgressSink>>) -> Self {
+    Reporter {
+      sinks,
+      interval: INTERVAL.get().copied().unwrap_or_default(),
+      last_reported: None,
+    }
+  }
+
+  /// Reports to the status line and as events.
+  pub fn standard() -> Self {
+    Self::new(vec![Box::new(StatusLine), Box::new(Events)])
+  }
+
+  /// Whether the progress is due to be reported.
+  fn due(&self, progress: &Progress, now: Instant) -> bool {
+    let Some((last_done, last_time)) = self.last_reported else {
+      return true;
+    };
+    match self.interval {
+      Interval::Time(interval) => now.duration_since(last_time) >= interval,
+      Interval::Percent(percent) => {
+        let step = progress.total.map_or(UNKNOWN_TOTAL_STEP, |total| {
+          (total as f64 * percent / 100.0) as u64
+        });
+        progress.done > last_done + step
+      }
+    }
+  }
+
+  pub fn update(&mut self, progress: Progress) {
+    let now = Instant::now();
+    if !self.due(&progress, now) {
+      return;
+    }
+    for sink in &mut self.sinks {
+      sink.report(&progress);
+    }
+    self.last_reported = Some((progress.done, now));
+  }
+
+  pub fn finish(&mut self) {
+    for sink in &mut self.sinks {
+      sink.finish();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn reporting_every_step() {
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let sink = {
+      let reported = reported.clone();
+      move |p: &Progress| reported.lock().unwrap().push(p.done)
+    };
+    let mut reporter = Reporter::new(vec![Box::new(sink)]);
+    for done in (0..=10_000).step_by(3) {
+      reporter.update(Progress {
+        stage: Stage::Download,
+        done,
+        total: Some(10_000),
+        bytes_per_sec: None,
+      });
+    }
+    let reported = reported.lock().unwrap();
+    assert_eq!(reported[..3], [0, 12, 24]);
+    assert_eq!(reported.len(), 834);
+  }
+
+  #[test]
+  fn reporting_every_interval() {
+    let mut reporter = Reporter::new(vec![]);
+    let start = Instant::now();
+    let progress = |done| Progress {
+      stage: Stage::Unpack,
+      done,
+      total: None,
+      bytes_per_sec: None,
+    };
+    assert!(reporter.due(&progress(0), start));
+    reporter.last_reported = Some((0, start));
+    // By percentage, every 1000 MB if the total is unknown
+    reporter.interval = Interval::Percent(5.0);
+    assert!(!reporter.due(&progress(UNKNOWN_TOTAL_STEP), start));
+    assert!(reporter.due(&progress(UNKNOWN_TOTAL_STEP + 1), start));
+
+    reporter.interval = Interval::Time(Duration::from_secs(30));
+    assert!(!reporter.due(&progress(u64::MAX / 2), start + Duration::from_secs(29)));
+    assert!(reporter.due(&progress(1), start + Duration::from_secs(30)));
+  }
+
+  #[test]
+  fn describing_progress() {
+    let progress = Progress {
+      stage: Stage::Download,
+      done: 1_024_000,
+      total: Some(4_096_000),
+      bytes_per_sec: Some(1_024_000.0),
+    };
+    assert_eq!(
+      progress.line(),
+      "Downloading... 25.00% (1.00 MB/4.00 MB) Speed: 1.00 MB/s ETA: 3 sec"
+    );
+    let progress = Progress {
+      stage: Stage::Unpack,
+      done: 2048 * 1024 * 1024,
+      total: None,
+      bytes_per_sec: None,
+    };
+    assert_eq!(progress.line(), "Unpacking... 2048 MB extracted");
+  }
+}
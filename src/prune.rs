@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::service::is_db_locked;
+
+/// Historical data that the node doesn't need anymore,
+/// following what the go-spacemesh pruner removes.
+struct PruneRule {
+  /// Table that must exist for the rule to apply
+  table: &'static str,
+  description: &'static str,
+  /// Condition selecting the rows to prune, `?1` is the layer cutoff and `?2` is the epoch cutoff
+  condition: &'static str,
+  /// Statement pruning the rows matching `condition`
+  statement: &'static str,
+}
+
+const RULES: &[PruneRule] = &[
+  PruneRule {
+    table: "proposals",
+    description: "old proposals",
+    condition: "layer < ?1",
+    statement: "DELETE FROM proposals",
+  },
+  PruneRule {
+    table: "certificates",
+    description: "old certificates",
+    condition: "layer < ?1",
+    statement: "DELETE FROM certificates",
+  },
+  PruneRule {
+    table: "activesets",
+    description: "old active sets",
+    condition: "epoch < ?2",
+    statement: "DELETE FROM activesets",
+  },
+  PruneRule {
+    table: "transactions",
+    description: "old transaction results",
+    condition: "layer < ?1 AND result IS NOT NULL",
+    statement: "UPDATE transactions SET result = NULL",
+  },
+];
+
+/// Tables whose old rows are deleted, rather than trimmed.
+pub fn deleted_tables() -> impl Iterator<Item = &'static str> {
+  RULES
+    .iter()
+    .filter(|rule| rule.statement.starts_with("DELETE"))
+    .map(|rule| rule.table)
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+  conn
+    .query_row(
+      "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+      [table],
+      |row| row.get::<_, u32>(0),
+    )
+    .map(|count| count > 0)
+    .with_context(|| format!("checking if table {table} exists"))
+}
+
+fn latest_layer(conn: &Connection) -> Result<u32> {
+  conn
+    .query_row("SELECT coalesce(max(id), 0) FROM layers", [], |row| {
+      row.get(0)
+    })
+    .context("getting latest layer")
+}
+
+/// Deletes historical data older than `keep_layers` before the latest layer in the DB.
+/// Returns the total number of pruned rows.
+pub fn prune(db_path: &Path, keep_layers: u32, layers_per_epoch: u32) -> Result<usize> {
+  anyhow::ensure!(
+    !is_db_locked(db_path)?,
+    "database is in use, stop the node before pruning"
+  );
+  let conn = Connection::open(db_path).context("opening database")?;
+
+  let latest = latest_layer(&conn)?;
+  let layer_cutoff = latest.saturating_sub(keep_layers);
+  let epoch_cutoff = layer_cutoff / layers_per_epoch;
+  println!(
+    "Latest layer in db: {latest}, pruning data before layer {layer_cutoff} (epoch {epoch_cutoff})"
+  );
+
+  let mut total = 0;
+  for rule in RULES {
+    if !table_exists(&conn, rule.table)? {
+      println!(
+        "Skipping {}: table {} not found",
+        rule.description, rule.table
+      );
+      continue;
+    }
+    let count: usize = conn
+      .query_row(
+        &format!(
+          "SELECT count(*) FROM {} WHERE {}",
+          rule.table, rule.condition
+        ),
+        params![layer_cutoff, epoch_cutoff],
+        |row| row.get(0),
+      )
+      .with_context(|| format!("counting {}", rule.description))?;
+    if count == 0 {
+      println!("Nothing to prune: {}", rule.description);
+      continue;
+    }
+
+    println!("Pruning {count} rows: {}...", rule.description);
+    let start = Instant::now();
+    let pruned = conn
+      .execute(
+        &format!("{} WHERE {}", rule.statement, rule.condition),
+        params![layer_cutoff, epoch_cutoff],
+      )
+      .with_context(|| format!("pruning {}", rule.description))?;
+    println!("Pruned {pruned} rows in {:?}", start.elapsed());
+    total += pruned;
+  }
+  println!("Pruned {total} rows in total");
+  Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn prunes_old_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute_batch(
+        r#"
+        CREATE TABLE layers (id INTEGER);
+        CREATE TABLE proposals (id INTEGER, layer INTEGER);
+        CREATE TABLE activesets (id INTEGER, epoch INTEGER);
+        CREATE TABLE transactions (id INTEGER, layer INTEGER, result BLOB);
+        INSERT INTO layers VALUES (100);
+        INSERT INTO proposals VALUES (1, 10), (2, 50), (3, 95);
+        INSERT INTO activesets VALUES (1, 0), (2, 4), (3, 9);
+        INSERT INTO transactions VALUES (1, 10, x'aa'), (2, 95, x'bb');
+        "#,
+      )
+      .unwrap();
+
+    // keeps layers 90..=100 (epochs >= 9 with 10 layers per epoch)
+    let pruned = prune(&db_path, 10, 10).unwrap();
+    assert_eq!(pruned, 5);
+
+    let count = |sql: &str| conn.query_row(sql, [], |row| row.get::<_, u32>(0)).unwrap();
+    assert_eq!(count("SELECT count(*) FROM proposals"), 1);
+    assert_eq!(count("SELECT count(*) FROM activesets"), 1);
+    assert_eq!(count("SELECT count(*) FROM transactions"), 2);
+    assert_eq!(
+      count("SELECT count(*) FROM transactions WHERE result IS NULL"),
+      1
+    );
+
+    // pruning again is a no-op
+    assert_eq!(prune(&db_path, 10, 10).unwrap(), 0);
+  }
+}
@@ -1,14 +1,61 @@
+use regex::Regex;
+use reqwest::Response;
 use serde::{Deserialize, Serialize};
 
+/// Longest part of an unrecognized body included in the error.
+const SNIPPET_LEN: usize = 200;
+
 #[derive(Deserialize, Serialize)]
 struct ErrorResponse {
   msg: String,
 }
 
-pub fn read_error_response(body: String) -> String {
-  match serde_json::from_str::<ErrorResponse>(body.as_str()) {
-    Ok(j) => j.msg,
-    Err(_) => String::from("Unknown error"),
+/// Text of the first `tag` element in an XML or HTML document.
+fn element(body: &str, tag: &str) -> Option<String> {
+  let re = Regex::new(&format!(r"(?is)<{tag}(?:\s[^>]*)?>(.*?)</{tag}>")).ok()?;
+  let text = re.captures(body)?.get(1)?.as_str().trim();
+  (!text.is_empty()).then(|| text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Extracts the message from an error body: our JSON errors, S3/GCS XML error
+/// documents and HTML pages. Otherwise describes what was received.
+fn describe_error(content_type: Option<&str>, body: &str) -> String {
+  if let Ok(j) = serde_json::from_str::<ErrorResponse>(body) {
+    return j.msg;
+  }
+  // <Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>
+  if let Some(code) = element(body, "Code") {
+    return match element(body, "Message") {
+      Some(message) => format!("{code}: {message}"),
+      None => code,
+    };
+  }
+  if let Some(title) = element(body, "title") {
+    return title;
+  }
+
+  let content_type = content_type.unwrap_or("unknown content type");
+  let body = body.trim();
+  if body.is_empty() {
+    return format!("Unknown error ({content_type}, empty body)");
+  }
+  let mut snippet: String = body.chars().take(SNIPPET_LEN).collect();
+  if snippet.len() < body.len() {
+    snippet.push_str("...");
+  }
+  format!("Unknown error ({content_type}): {snippet}")
+}
+
+/// Reads the body of an error response and extracts a readable message from it.
+pub async fn read_error_response(response: Response) -> String {
+  let content_type = response
+    .headers()
+    .get(reqwest::header::CONTENT_TYPE)
+    .and_then(|ct| ct.to_str().ok())
+    .map(str::to_string);
+  match response.text().await {
+    Ok(body) => describe_error(content_type.as_deref(), &body),
+    Err(e) => format!("Cannot read the error response: {e}"),
   }
 }
 
@@ -18,13 +65,45 @@ mod tests {
 
   #[test]
   fn test_returns_expected_message() {
-    let body = String::from("{ \"msg\": \"Expected error message\" }");
-    assert_eq!(read_error_response(body), "Expected error message");
+    let body = "{ \"msg\": \"Expected error message\" }";
+    assert_eq!(describe_error(None, body), "Expected error message");
   }
 
   #[test]
   fn test_returns_unknown_error_on_failure() {
-    let body = String::from("<html></html>");
-    assert_eq!(read_error_response(body), "Unknown error");
+    assert_eq!(
+      describe_error(Some("text/plain"), "  something broke\n"),
+      "Unknown error (text/plain): something broke"
+    );
+    assert_eq!(
+      describe_error(None, ""),
+      "Unknown error (unknown content type, empty body)"
+    );
+    let long = "x".repeat(500);
+    assert_eq!(
+      describe_error(None, &long),
+      format!(
+        "Unknown error (unknown content type): {}...",
+        "x".repeat(200)
+      )
+    );
+  }
+
+  #[test]
+  fn test_extracts_storage_errors() {
+    let s3 = r#"<?xml version="1.0" encoding="UTF-8"?>
+      <Error><Code>AccessDenied</Code><Message>Access Denied</Message><RequestId>1</RequestId></Error>"#;
+    assert_eq!(
+      describe_error(Some("application/xml"), s3),
+      "AccessDenied: Access Denied"
+    );
+    let gcs =
+      "<Error><Code>NoSuchKey</Code><Message>The specified key\n does not exist.</Message></Error>";
+    assert_eq!(
+      describe_error(None, gcs),
+      "NoSuchKey: The specified key does not exist."
+    );
+    let html = "<html><head><title lang=\"en\">502 Bad Gateway</title></head></html>";
+    assert_eq!(describe_error(Some("text/html"), html), "502 Bad Gateway");
   }
 }
@@ -1,11 +1,12 @@
 use std::io::{self, Read};
 
-const MB: usize = 1024 * 1024;
+use crate::events::Stage;
+use crate::progress::{Progress, Reporter};
 
 pub struct ReaderWithBytes<R: Read> {
   reader: R,
   bytes_read: usize,
-  last_reported: usize,
+  reporter: Reporter,
 }
 
 impl<R: Read> ReaderWithBytes<R> {
@@ -13,7 +14,7 @@ impl<R: Read> ReaderWithBytes<R> {
     ReaderWithBytes {
       reader,
       bytes_read: 0,
-      last_reported: 0,
+      reporter: Reporter::standard(),
     }
   }
 }
@@ -23,10 +24,12 @@ impl<R: Read> Read for ReaderWithBytes<R> {
     let bytes_read = self.reader.read(buf)?;
     self.bytes_read += bytes_read;
 
-    if self.bytes_read > self.last_reported + 1000 * MB {
-      println!("Unpacking... {} MB extracted", self.bytes_read / MB);
-      self.last_reported = self.bytes_read;
-    }
+    self.reporter.update(Progress {
+      stage: Stage::Unpack,
+      done: self.bytes_read as u64,
+      total: None,
+      bytes_per_sec: None,
+    });
 
     Ok(bytes_read)
   }
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use url::Url;
+
+use crate::http_cache;
+use crate::read_error_response::read_error_response;
+use crate::transport;
+use crate::url_policy;
+
+/// Name of the manifest of regional endpoints, published next to the snapshots.
+const MANIFEST_FILE: &str = "regions.json";
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+  regions: Vec<Region>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Region {
+  name: String,
+  url: Url,
+  #[serde(default)]
+  description: String,
+}
+
+/// Looks up the endpoint of `region` in the manifest published at `download_url`.
+pub async fn resolve(download_url: &Url, region: &str) -> Result<Url> {
+  let manifest_url = download_url
+    .join(MANIFEST_FILE)
+    .context("composing regions manifest URL")?;
+  let client = transport::small_files_builder()
+    .redirect(url_policy::redirect_policy())
+    .timeout(std::time::Duration::from_secs(30))
+    .build()?;
+  let manifest = match http_cache::get_text(&client, manifest_url.as_str()).await? {
+    Ok(manifest) => manifest,
+    Err(response) => {
+      let status = response.status();
+      let err = read_error_response(response).await;
+      anyhow::bail!("Cannot download regions from {manifest_url}: {status} {err}");
+    }
+  };
+  let manifest: Manifest = serde_json::from_str(&manifest)
+    .with_context(|| format!("parsing regions from {manifest_url}"))?;
+
+  match manifest
+    .regions
+    .iter()
+    .find(|r| r.name.eq_ignore_ascii_case(region))
+  {
+    Some(r) => {
+      println!("Using region {} ({})", r.name, r.url);
+      Ok(r.url.clone())
+    }
+    None => {
+      let available = manifest
+        .regions
+        .iter()
+        .map(|r| match r.description.as_str() {
+          "" => r.name.clone(),
+          description => format!("{} ({description})", r.name),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+      anyhow::bail!("Unknown region '{region}', available regions: {available}")
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::resolve;
+  use url::Url;
+
+  #[tokio::test]
+  async fn resolving_regions() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/regions.json")
+      .with_body(
+        r#"{"regions": [
+          {"name": "eu", "url": "https://eu.example.com/", "description": "Europe"},
+          {"name": "us", "url": "https://us.example.com/"}
+        ]}"#,
+      )
+      .expect(2)
+      .create_async()
+      .await;
+
+    let base = Url::parse(&format!("{}/", server.url())).unwrap();
+    let url = resolve(&base, "EU").await.unwrap();
+    assert_eq!(url.as_str(), "https://eu.example.com/");
+
+    let err = resolve(&base, "asia").await.unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Unknown region 'asia', available regions: eu (Europe), us"
+    );
+    mock.assert_async().await;
+  }
+}
@@ -0,0 +1,480 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::prune;
+
+/// Set of tables restored by `incremental`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestoreProfile {
+  /// All tables
+  #[default]
+  Full,
+  /// Without the historical data `prune` deletes (proposals, certificates and
+  /// active sets), for pruned nodes
+  Pruned,
+}
+
+impl RestoreProfile {
+  /// Tables the profile doesn't restore.
+  pub fn skipped_tables(self) -> Vec<String> {
+    match self {
+      Self::Full => Vec::new(),
+      Self::Pruned => prune::deleted_tables().map(str::to_string).collect(),
+    }
+  }
+}
+
+/// Splits a SQL script into its statements at the semicolons outside of
+/// quotes and comments.
+fn split_statements(script: &str) -> Vec<&str> {
+  let mut statements = Vec::new();
+  let (mut start, mut quote, mut line_comment, mut block_comment) = (0, None, false, false);
+  let mut chars = script.char_indices().peekable();
+  while let Some((i, c)) = chars.next() {
+    let next = chars.peek().map(|&(_, c)| c);
+    match (quote, c) {
+      _ if line_comment => line_comment = c != '\n',
+      _ if block_comment => {
+        if c == '*' && next == Some('/') {
+          chars.next();
+          block_comment = false;
+        }
+      }
+      (Some(q), _) => {
+        if c == q {
+          quote = None;
+        }
+      }
+      (None, '\'' | '"' | '`') => quote = Some(c),
+      (None, '[') => quote = Some(']'),
+      (None, '-') if next == Some('-') => line_comment = true,
+      (None, '/') if next == Some('*') => block_comment = true,
+      (None, ';') => {
+        statements.push(&script[start..i]);
+        start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  statements.push(&script[start..]);
+  statements.retain(|s| !s.trim().is_empty());
+  statements
+}
+
+/// Table the statement writes to, if it's an insert, update or delete.
+fn target_table(statement: &str) -> Option<String> {
+  static WRITE: OnceLock<Regex> = OnceLock::new();
+  let re = WRITE.get_or_init(|| {
+    Regex::new(concat!(
+      // Leading whitespace and comments
+      r"(?is)^(?:\s|--[^\n]*\n|/\*.*?\*/)*",
+      r"(?:(?:INSERT(?:\s+OR\s+\w+)?|REPLACE)\s+INTO|UPDATE(?:\s+OR\s+\w+)?|DELETE\s+FROM)",
+      r#"\s+(?:main\s*\.\s*)?["`\[]?(\w+)"#,
+    ))
+    .expect("valid regex")
+  });
+  re.captures(statement).map(|caps| caps[1].to_lowercase())
+}
+
+/// Drops the statements of the restore script writing to the `skipped`
+/// tables. Returns the filtered script and the skipped tables it wrote to.
+pub fn filter(script: &str, skipped: &[String]) -> (String, Vec<String>) {
+  if skipped.is_empty() {
+    return (script.to_string(), Vec::new());
+  }
+  let mut dropped = Vec::new();
+  let kept: Vec<&str> = split_statements(script)
+    .into_iter()
+    .filter(|statement| match target_table(statement) {
+      Some(table) if skipped.iter().any(|s| s.eq_ignore_ascii_case(&table)) => {
+        if !dropped.contains(&table) {
+          dropped.push(table);
+        }
+        false
+      }
+      _ => true,
+    })
+    .collect();
+  (format!("{};", kept.join(";")), dropped)
+}
+
+/// Whether the script creates a trigger, whose body has semicolons of its own
+/// the script can't be split into statements at.
+fn creates_trigger(script: &str) -> bool {
+  static TRIGGER: OnceLock<Regex> = OnceLock::new();
+  let re = TRIGGER.get_or_init(|| {
+    Regex::new(concat!(
+      // Leading whitespace and comments
+      r"(?is)^(?:\s|--[^\n]*\n|/\*.*?\*/)*",
+      r"CREATE\s+(?:TEMP(?:ORARY)?\s+)?TRIGGER\b",
+    ))
+    .expect("valid regex")
+  });
+  split_statements(script).iter().any(|s| re.is_match(s))
+}
+
+/// Schema the attachment of the restore script attaches the diff as.
+fn attached_schema(statement: &str) -> Option<String> {
+  static ATTACH: OnceLock<Regex> = OnceLock::new();
+  let re = ATTACH.get_or_init(|| {
+    Regex::new(r#"(?is)^\s*ATTACH\s.*\sAS\s+["`\[]?(\w+)["`\]]?\s*$"#).expect("valid regex")
+  });
+  re.captures(statement).map(|caps| caps[1].to_string())
+}
+
+/// Rows changed by a restore script, per table.
+pub type RowCounts = BTreeMap<String, u64>;
+
+/// Key of the rows counted for the whole script, when it isn't executed
+/// statement by statement.
+const ALL_TABLES: &str = "all tables";
+
+/// Diffs [`execute_diffs_in_transaction`] applies at most, the databases
+/// SQLite can attach at once.
+pub const MAX_DIFFS: usize = 10;
+
+/// Executes the restore script. A `filtered` one is executed statement by
+/// statement, counting the rows the statements of each table changed, others
+/// as a whole, counting the rows of all tables together.
+pub fn execute(conn: &Connection, script: &str, filtered: bool) -> Result<RowCounts> {
+  let mut counts = RowCounts::new();
+  if !filtered || creates_trigger(script) {
+    let total_changes = || conn.query_row("SELECT total_changes()", [], |row| row.get::<_, u64>(0));
+    let before = total_changes()?;
+    conn.execute_batch(script).context("executing restore")?;
+    counts.insert(ALL_TABLES.to_string(), total_changes()? - before);
+    return Ok(counts);
+  }
+  for statement in split_statements(script) {
+    conn
+      .execute_batch(statement)
+      .with_context(|| format!("executing restore: {}", statement.trim()))?;
+    if let Some(table) = target_table(statement) {
+      *counts.entry(table).or_default() += conn.changes();
+    }
+  }
+  Ok(counts)
+}
+
+/// Executes the restore script in one transaction, which `verify` checks the
+/// database in before committing. Only the attachments of the script, which
+/// SQLite doesn't allow in a transaction, are executed before it.
+pub fn execute_in_transaction(
+  conn: &Connection,
+  script: &str,
+  verify: impl FnOnce(&Connection) -> Result<()>,
+) -> Result<RowCounts> {
+  let (attachments, statements) = split_attachments(script)?;
+  for statement in attachments {
+    conn.execute_batch(statement).context("executing restore")?;
+  }
+  in_transaction(conn, verify, |counts| {
+    statements
+      .iter()
+      .try_for_each(|statement| execute_counted(conn, statement, counts))
+  })
+}
+
+/// Executes the restore script once for each of the `diffs`, in order, in one
+/// transaction, which `verify` checks the database in before committing. The
+/// same as applying the diffs one by one: each is attached in turn under its
+/// own schema, which the statements of the script are pointed at.
+pub fn execute_diffs_in_transaction(
+  conn: &Connection,
+  script: &str,
+  diffs: &[PathBuf],
+  verify: impl FnOnce(&Connection) -> Result<()>,
+) -> Result<RowCounts> {
+  anyhow::ensure!(
+    diffs.len() <= MAX_DIFFS,
+    "at most {MAX_DIFFS} diffs can be applied at once"
+  );
+  let (attachments, statements) = split_attachments(script)?;
+  let [attachment] = attachments[..] else {
+    anyhow::bail!("the restore script doesn't attach a single diff");
+  };
+  let schema = attached_schema(attachment).context("finding the schema of the diff")?;
+  let qualified = Regex::new(&format!(
+    r#"(?i)["`\[]?\b{}\b["`\]]?\s*\."#,
+    regex::escape(&schema)
+  ))?;
+  in_transaction(conn, verify, |counts| {
+    for (i, diff) in diffs.iter().enumerate() {
+      // SQLite can attach, but not detach, in a transaction
+      let diff_schema = format!("{schema}_{i}");
+      conn
+        .execute(
+          &format!(r#"ATTACH DATABASE ?1 AS "{diff_schema}""#),
+          [diff.to_string_lossy()],
+        )
+        .with_context(|| format!("attaching {}", diff.display()))?;
+      for statement in &statements {
+        let statement = qualified.replace_all(statement, format!(r#""{diff_schema}"."#));
+        execute_counted(conn, &statement, counts)?;
+      }
+    }
+    Ok(())
+  })
+}
+
+/// Splits the restore script into its leading attachments, which SQLite
+/// doesn't allow in a transaction, and the other statements.
+fn split_attachments(script: &str) -> Result<(Vec<&str>, Vec<&str>)> {
+  anyhow::ensure!(
+    !creates_trigger(script),
+    "the restore script can't be split into statements"
+  );
+  let mut statements = split_statements(script);
+  let attachments = statements
+    .iter()
+    .take_while(|s| s.trim_start().to_lowercase().starts_with("attach"))
+    .count();
+  let rest = statements.split_off(attachments);
+  Ok((statements, rest))
+}
+
+/// Executes the statement, adding the rows it changed to `counts`.
+fn execute_counted(conn: &Connection, statement: &str, counts: &mut RowCounts) -> Result<()> {
+  conn
+    .execute_batch(statement)
+    .with_context(|| format!("executing restore: {}", statement.trim()))?;
+  if let Some(table) = target_table(statement) {
+    *counts.entry(table).or_default() += conn.changes();
+  }
+  Ok(())
+}
+
+/// Runs `execute` in a transaction committed if `verify` passes, rolled back
+/// otherwise.
+fn in_transaction(
+  conn: &Connection,
+  verify: impl FnOnce(&Connection) -> Result<()>,
+  execute: impl FnOnce(&mut RowCounts) -> Result<()>,
+) -> Result<RowCounts> {
+  conn.execute_batch("BEGIN")?;
+  let mut counts = RowCounts::new();
+  let result = execute(&mut counts).and_then(|()| verify(conn));
+  match result {
+    Ok(()) => conn.execute_batch("COMMIT")?,
+    Err(e) => {
+      conn.execute_batch("ROLLBACK")?;
+      return Err(e);
+    }
+  }
+  Ok(counts)
+}
+
+/// Adds the counts of `other` to `total`.
+pub fn add(total: &mut RowCounts, other: &RowCounts) {
+  for (table, count) in other {
+    *total.entry(table.clone()).or_default() += count;
+  }
+}
+
+/// Formats the counts as e.g. `layers: 100, proposals: 2500`.
+pub fn format_counts(counts: &RowCounts) -> String {
+  if counts.is_empty() {
+    return "no rows".to_string();
+  }
+  counts
+    .iter()
+    .map(|(table, count)| format!("{table}: {count}"))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splitting_statements() {
+    let script = "ATTACH DATABASE 'a;b.db' AS src;\n\
+                  -- copy; the layers\n\
+                  INSERT INTO layers SELECT * FROM src.layers;\n\
+                  /* ; */ INSERT INTO \"proposals\" SELECT * FROM src.proposals;\n";
+    assert_eq!(split_statements(script).len(), 3);
+  }
+
+  #[test]
+  fn finding_target_tables() {
+    assert_eq!(
+      target_table("INSERT OR IGNORE INTO layers SELECT * FROM src.layers").as_deref(),
+      Some("layers")
+    );
+    assert_eq!(
+      target_table("\n -- proposals\n REPLACE INTO main.\"Proposals\" SELECT 1").as_deref(),
+      Some("proposals")
+    );
+    assert_eq!(
+      target_table("UPDATE OR REPLACE transactions SET result = NULL").as_deref(),
+      Some("transactions")
+    );
+    assert_eq!(
+      target_table("DELETE FROM certificates WHERE layer < 10").as_deref(),
+      Some("certificates")
+    );
+    assert_eq!(target_table("ATTACH DATABASE 'src.db' AS src"), None);
+  }
+
+  #[test]
+  fn filtering_scripts() {
+    let script = "ATTACH DATABASE 'src.db' AS src;
+      INSERT OR IGNORE INTO layers SELECT * FROM src.layers;
+      INSERT OR IGNORE INTO proposals SELECT * FROM src.proposals;
+      INSERT OR IGNORE INTO certificates SELECT * FROM src.certificates;";
+    let (filtered, dropped) = filter(script, &RestoreProfile::Pruned.skipped_tables());
+    assert!(filtered.contains("INTO layers"));
+    assert!(!filtered.contains("proposals"));
+    assert!(!filtered.contains("certificates"));
+    assert_eq!(dropped, ["proposals", "certificates"]);
+
+    let (unchanged, dropped) = filter(script, &[]);
+    assert_eq!(unchanged, script);
+    assert!(dropped.is_empty());
+  }
+
+  #[test]
+  fn counting_rows() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn
+      .execute_batch(
+        "CREATE TABLE layers (id INTEGER PRIMARY KEY);
+         CREATE TABLE proposals (id INTEGER PRIMARY KEY, layer INTEGER);
+         INSERT INTO layers VALUES (1);",
+      )
+      .unwrap();
+    let counts = execute(
+      &conn,
+      "INSERT OR IGNORE INTO layers VALUES (1), (2), (3);
+       INSERT INTO proposals VALUES (1, 2), (2, 3);
+       DELETE FROM proposals WHERE layer = 3;
+       SELECT 1;",
+      true,
+    )
+    .unwrap();
+    assert_eq!(format_counts(&counts), "layers: 2, proposals: 3");
+
+    // Unfiltered scripts are executed as a whole
+    let counts = execute(&conn, "INSERT INTO layers VALUES (4), (5);", false).unwrap();
+    assert_eq!(format_counts(&counts), "all tables: 2");
+
+    let mut total = counts.clone();
+    add(&mut total, &counts);
+    assert_eq!(total["layers"], 4);
+  }
+
+  #[test]
+  fn detecting_triggers() {
+    assert!(creates_trigger(
+      "ATTACH DATABASE 'src.db' AS src;
+       /* keep the layers */ CREATE TEMP TRIGGER keep AFTER DELETE ON layers
+       BEGIN INSERT INTO layers SELECT OLD.id; END;"
+    ));
+    // Mentioned only in comments, strings and names
+    assert!(!creates_trigger(
+      "-- no trigger here
+       INSERT INTO triggers SELECT 'CREATE TRIGGER' FROM src.layers;"
+    ));
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn
+      .execute_batch("CREATE TABLE layers (id INTEGER PRIMARY KEY); CREATE TABLE log (id);")
+      .unwrap();
+    let script = "CREATE TRIGGER logged AFTER INSERT ON layers
+                  BEGIN INSERT INTO log VALUES (NEW.id); END;
+                  INSERT INTO layers VALUES (1);";
+    // Executed as a whole even when filtered
+    let counts = execute(&conn, script, true).unwrap();
+    assert!(counts.contains_key(ALL_TABLES));
+    let logged: u64 = conn
+      .query_row("SELECT count(*) FROM log", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(logged, 1);
+  }
+
+  #[test]
+  fn executing_in_transaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src.db");
+    Connection::open(&src)
+      .unwrap()
+      .execute_batch(
+        "CREATE TABLE layers (id INTEGER PRIMARY KEY); INSERT INTO layers VALUES (1), (2);",
+      )
+      .unwrap();
+    let conn = Connection::open(dir.path().join("state.sql")).unwrap();
+    conn
+      .execute_batch("CREATE TABLE layers (id INTEGER PRIMARY KEY);")
+      .unwrap();
+    let script = format!(
+      "ATTACH DATABASE '{}' AS src; INSERT INTO layers SELECT * FROM src.layers;",
+      src.display()
+    );
+
+    let err = execute_in_transaction(&conn, &script, |_| anyhow::bail!("unexpected hash"));
+    assert!(err.is_err());
+    let count = |conn: &Connection| {
+      conn
+        .query_row("SELECT count(*) FROM layers", [], |row| {
+          row.get::<_, u64>(0)
+        })
+        .unwrap()
+    };
+    assert_eq!(count(&conn), 0);
+
+    conn.execute_batch("DETACH DATABASE src").unwrap();
+    let counts = execute_in_transaction(&conn, &script, |_| Ok(())).unwrap();
+    assert_eq!(counts["layers"], 2);
+    assert_eq!(count(&conn), 2);
+  }
+
+  #[test]
+  fn executing_diffs_in_turn() {
+    let dir = tempfile::tempdir().unwrap();
+    let diffs: Vec<PathBuf> = ["first", "second"]
+      .iter()
+      .map(|value| {
+        let path = dir.path().join(format!("{value}.db"));
+        Connection::open(&path)
+          .unwrap()
+          .execute_batch(&format!(
+            "CREATE TABLE accounts (id INTEGER PRIMARY KEY, value TEXT);
+             INSERT INTO accounts VALUES (1, '{value}');
+             CREATE TABLE rewards (layer INTEGER); INSERT INTO rewards VALUES (7);"
+          ))
+          .unwrap();
+        path
+      })
+      .collect();
+    let conn = Connection::open(dir.path().join("state.sql")).unwrap();
+    conn
+      .execute_batch(
+        "CREATE TABLE accounts (id INTEGER PRIMARY KEY, value TEXT);
+         CREATE TABLE rewards (layer INTEGER);",
+      )
+      .unwrap();
+    let script = "ATTACH DATABASE 'backup_source.db' AS src;
+      INSERT OR IGNORE INTO accounts SELECT * FROM src.accounts;
+      INSERT INTO rewards SELECT * FROM src.rewards
+        WHERE layer NOT IN (SELECT layer FROM rewards);";
+
+    let counts = execute_diffs_in_transaction(&conn, script, &diffs, |_| Ok(())).unwrap();
+    // As if applied one by one: the first row is kept, no duplicates
+    let value: String = conn
+      .query_row("SELECT value FROM accounts WHERE id = 1", [], |row| {
+        row.get(0)
+      })
+      .unwrap();
+    assert_eq!(value, "first");
+    assert_eq!(counts["rewards"], 1);
+    let rewards: u64 = conn
+      .query_row("SELECT count(*) FROM rewards", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(rewards, 1);
+  }
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const HEADER_SIZE: usize = 100;
+
+/// Tables that every node database has.
+const EXPECTED_TABLES: &[&str] = &["layers", "atxs", "ballots", "blocks", "transactions"];
+
+/// Checks that the file at `db_path` looks like a complete node database:
+/// it has a valid SQLite header, isn't truncated and has the expected schema.
+pub fn check_db(db_path: &Path) -> Result<()> {
+  let mut file = File::open(db_path).with_context(|| format!("opening {}", db_path.display()))?;
+  let file_size = file.metadata()?.len();
+  let mut header = [0u8; HEADER_SIZE];
+  file
+    .read_exact(&mut header)
+    .context("file is too small to be a database")?;
+  drop(file);
+
+  anyhow::ensure!(&header[..16] == SQLITE_MAGIC, "not an SQLite database");
+
+  let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+    1 => 65536,
+    size => u64::from(size),
+  };
+  anyhow::ensure!(
+    page_size.is_power_of_two() && (512..=65536).contains(&page_size),
+    "invalid page size: {page_size}"
+  );
+  anyhow::ensure!(
+    file_size % page_size == 0,
+    "file size {file_size} is not a multiple of the page size {page_size}"
+  );
+
+  // The page count in the header is valid only if the change counter
+  // matches the "version-valid-for" number.
+  let change_counter = &header[24..28];
+  let valid_for = &header[92..96];
+  if change_counter == valid_for {
+    let page_count = u32::from_be_bytes([header[28], header[29], header[30], header[31]]);
+    let expected_size = u64::from(page_count) * page_size;
+    anyhow::ensure!(
+      file_size >= expected_size,
+      "database is truncated: {file_size} bytes, expected {expected_size} bytes"
+    );
+  }
+
+  let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    .context("opening database")?;
+  let user_version: u32 = conn
+    .query_row("PRAGMA user_version", [], |row| row.get(0))
+    .context("reading user_version")?;
+  anyhow::ensure!(user_version > 0, "database has no schema version");
+
+  for table in EXPECTED_TABLES {
+    let exists: bool = conn
+      .query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [table],
+        |row| row.get(0),
+      )
+      .with_context(|| format!("checking table {table}"))?;
+    anyhow::ensure!(exists, "table {table} is missing");
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn create_db(path: &Path) {
+    let conn = Connection::open(path).unwrap();
+    for table in EXPECTED_TABLES {
+      conn
+        .execute(&format!("CREATE TABLE {table} (id INTEGER)"), [])
+        .unwrap();
+    }
+    conn.execute("PRAGMA user_version = 7", []).unwrap();
+  }
+
+  #[test]
+  fn accepts_valid_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    create_db(&db_path);
+    check_db(&db_path).unwrap();
+  }
+
+  #[test]
+  fn rejects_not_a_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    std::fs::write(&db_path, [b'x'; 4096]).unwrap();
+    let err = check_db(&db_path).unwrap_err();
+    assert_eq!(err.to_string(), "not an SQLite database");
+  }
+
+  #[test]
+  fn rejects_truncated_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    create_db(&db_path);
+    let data = std::fs::read(&db_path).unwrap();
+    std::fs::write(&db_path, &data[..data.len() - 100]).unwrap();
+    assert!(check_db(&db_path).is_err());
+  }
+
+  #[test]
+  fn rejects_missing_tables() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute("CREATE TABLE layers (id INTEGER)", [])
+      .unwrap();
+    conn.execute("PRAGMA user_version = 7", []).unwrap();
+    let err = check_db(&db_path).unwrap_err();
+    assert_eq!(err.to_string(), "table atxs is missing");
+  }
+}
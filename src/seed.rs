@@ -0,0 +1,342 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use url::Url;
+
+use crate::patch::KeptArchive;
+
+/// Longest line of the request head.
+const MAX_LINE: u64 = 8 * 1024;
+/// Most header lines of a request.
+const MAX_HEADERS: usize = 64;
+/// Time for the client to send the request head.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most connections served at once, others are dropped.
+const MAX_CONNECTIONS: usize = 32;
+
+/// When to stop seeding. Without limits it doesn't stop.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+  pub duration: Option<Duration>,
+  /// Bytes served relative to the size of the archive.
+  pub ratio: Option<f64>,
+}
+
+/// The kept archive served in the layout of the download server, so other
+/// nodes can download it by passing the seed as `--mirror`.
+struct Seeded {
+  archive: PathBuf,
+  layer: u64,
+  size: u64,
+  md5: String,
+  /// Path of the archive on the original server, e.g. `/v1.7.0/61579.sql.zst`.
+  path: String,
+  /// Path the download of the variant for the node version redirects from.
+  variant_path: String,
+  /// Path of the checksum of the database, and where it is on the original server.
+  db_md5: Option<(String, Url)>,
+}
+
+impl Seeded {
+  fn new(node_data: &Path, kept: KeptArchive) -> Result<Self> {
+    let path = kept.url.path().to_string();
+    let (dir, name) = path.rsplit_once('/').context("parsing kept archive URL")?;
+    let variant = match name.contains("_pruned") {
+      true => "state_pruned.zst",
+      false => "state.zst",
+    };
+    let db_md5 = match path.strip_suffix(".sql.zst") {
+      Some(base) => Some((
+        format!("{base}.sql.md5"),
+        kept.url.join(&format!("{base}.sql.md5"))?,
+      )),
+      None => None,
+    };
+    Ok(Self {
+      archive: KeptArchive::archive_path(node_data),
+      layer: kept.layer,
+      size: kept.size,
+      md5: kept.md5,
+      variant_path: format!("{dir}/{variant}"),
+      path,
+      db_md5,
+    })
+  }
+}
+
+/// Parses the start and the inclusive end of a `Range: bytes=start-[end]` header.
+fn parse_range(value: &str, len: u64) -> Option<(u64, u64)> {
+  let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+  let start: u64 = start.parse().ok()?;
+  let end = match end {
+    "" => len.checked_sub(1)?,
+    end => end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+  };
+  (start <= end).then_some((start, end))
+}
+
+/// Reads a line of at most `MAX_LINE` bytes.
+async fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String> {
+  let mut line = String::new();
+  (&mut *reader).take(MAX_LINE).read_line(&mut line).await?;
+  anyhow::ensure!(line.ends_with('\n'), "request line too long or cut off");
+  Ok(line)
+}
+
+struct Request {
+  method: String,
+  path: String,
+  /// Value of the `Range` header.
+  range: Option<String>,
+}
+
+async fn read_request(reader: &mut BufReader<TcpStream>) -> Result<Request> {
+  let request_line = read_line(reader).await?;
+  let mut parts = request_line.split_whitespace();
+  let method = parts.next().unwrap_or_default().to_string();
+  let target = parts.next().unwrap_or_default();
+  let path = target.split('?').next().unwrap_or_default().to_string();
+  let mut range = None;
+  for _ in 0..MAX_HEADERS {
+    let line = read_line(reader).await?;
+    if line.trim().is_empty() {
+      return Ok(Request {
+        method,
+        path,
+        range,
+      });
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("range") {
+        range = Some(value.to_string());
+      }
+    }
+  }
+  anyhow::bail!("too many request headers")
+}
+
+async fn handle(stream: TcpStream, seeded: &Seeded, served: &AtomicU64) -> Result<()> {
+  let mut reader = BufReader::new(stream);
+  let Request {
+    method,
+    path,
+    range,
+  } = tokio::time::timeout(READ_TIMEOUT, read_request(&mut reader))
+    .await
+    .context("timed out reading the request")??;
+  let mut stream = reader.into_inner();
+
+  let head = |status: &str, headers: &str| {
+    format!("HTTP/1.1 {status}\r\n{headers}Connection: close\r\n\r\n")
+  };
+  if path == seeded.variant_path {
+    let headers = format!("Location: {}\r\nContent-Length: 0\r\n", seeded.path);
+    stream
+      .write_all(head("302 Found", &headers).as_bytes())
+      .await?;
+  } else if path == format!("{}.md5", seeded.path) {
+    let headers = format!("Content-Length: {}\r\n", seeded.md5.len());
+    stream
+      .write_all(head("200 OK", &headers).as_bytes())
+      .await?;
+    stream.write_all(seeded.md5.as_bytes()).await?;
+  } else if let Some((_, url)) = seeded.db_md5.as_ref().filter(|(p, _)| *p == path) {
+    // The checksum of the database is only on the original server
+    let headers = format!("Location: {url}\r\nContent-Length: 0\r\n");
+    stream
+      .write_all(head("302 Found", &headers).as_bytes())
+      .await?;
+  } else if path == seeded.path {
+    let (status, start, len, headers) = match range.map(|r| parse_range(&r, seeded.size)) {
+      Some(Some((start, end))) => (
+        "206 Partial Content",
+        start,
+        end + 1 - start,
+        format!("Content-Range: bytes {start}-{end}/{}\r\n", seeded.size),
+      ),
+      Some(None) => {
+        let headers = format!(
+          "Content-Range: bytes */{}\r\nContent-Length: 0\r\n",
+          seeded.size
+        );
+        stream
+          .write_all(head("416 Range Not Satisfiable", &headers).as_bytes())
+          .await?;
+        stream.flush().await?;
+        return Ok(());
+      }
+      None => ("200 OK", 0, seeded.size, String::new()),
+    };
+    let headers = format!("{headers}Content-Length: {len}\r\nAccept-Ranges: bytes\r\n");
+    stream.write_all(head(status, &headers).as_bytes()).await?;
+    if method != "HEAD" {
+      let mut file = File::open(&seeded.archive).await?;
+      file.seek(std::io::SeekFrom::Start(start)).await?;
+      let copied = tokio::io::copy(&mut file.take(len), &mut stream).await?;
+      served.fetch_add(copied, Ordering::Relaxed);
+    }
+  } else {
+    let headers = "Content-Length: 0\r\n";
+    stream
+      .write_all(head("404 Not Found", headers).as_bytes())
+      .await?;
+  }
+  stream.flush().await?;
+  Ok(())
+}
+
+/// Serves the archive kept in `node_data` to other nodes until the `limits`
+/// are reached. Returns the number of bytes served.
+pub async fn seed(listener: TcpListener, node_data: &Path, limits: Limits) -> Result<u64> {
+  let kept = KeptArchive::load(node_data)?.context("there is no kept archive to seed")?;
+  let seeded = Arc::new(Seeded::new(node_data, kept)?);
+  let served = Arc::new(AtomicU64::new(0));
+  let target = limits
+    .ratio
+    .map(|ratio| (ratio * seeded.size as f64) as u64);
+  let deadline = limits.duration.map(|d| tokio::time::Instant::now() + d);
+  println!(
+    "Seeding the archive of layer {} at http://{}, pass it to other nodes as --mirror",
+    seeded.layer,
+    listener.local_addr()?
+  );
+  let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+  let mut check = tokio::time::interval(Duration::from_secs(1));
+  loop {
+    let served_bytes = served.load(Ordering::Relaxed);
+    if target.is_some_and(|target| served_bytes >= target) {
+      println!("Seeding ratio reached");
+      break;
+    }
+    if deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline) {
+      println!("Seeding time is over");
+      break;
+    }
+    tokio::select! {
+      accepted = listener.accept() => {
+        let (stream, _) = accepted.context("accepting seed connection")?;
+        // Dropping the connection closes it
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+          continue;
+        };
+        let (seeded, served) = (seeded.clone(), served.clone());
+        tokio::spawn(async move {
+          let _permit = permit;
+          if let Err(e) = handle(stream, &seeded, &served).await {
+            println!("Seeding error: {e:#}");
+          }
+        });
+      }
+      _ = check.tick() => {}
+    }
+  }
+  let served_bytes = served.load(Ordering::Relaxed);
+  println!(
+    "Seeded {:.2} MB ({:.2}x the archive)",
+    served_bytes as f64 / 1_024_000.00,
+    served_bytes as f64 / seeded.size.max(1) as f64
+  );
+  Ok(served_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parsing_ranges() {
+    assert_eq!(parse_range("bytes=0-", 100), Some((0, 99)));
+    assert_eq!(parse_range(" bytes=10-19", 100), Some((10, 19)));
+    assert_eq!(parse_range("bytes=0-4194303", 100), Some((0, 99)));
+    assert_eq!(parse_range("bytes=100-", 100), None);
+    assert_eq!(parse_range("items=0-1", 100), None);
+  }
+
+  #[tokio::test]
+  async fn seeding_kept_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("download.zst");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 13) as u8).collect();
+    std::fs::write(&archive, &data).unwrap();
+    let url = Url::parse("https://example.com/v1.7.0/61579_pruned.sql.zst").unwrap();
+    crate::patch::keep(dir.path(), &archive, url, "abcd".to_string()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let limits = Limits {
+      duration: Some(Duration::from_secs(30)),
+      ratio: Some(1.0),
+    };
+    let node_data = dir.path().to_path_buf();
+    let seeding = tokio::spawn(async move { seed(listener, &node_data, limits).await });
+
+    let client = reqwest::Client::new();
+    let response = client
+      .get(format!("{base}/v1.7.0/state_pruned.zst"))
+      .header("Range", "bytes=0-999")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.url().path(), "/v1.7.0/61579_pruned.sql.zst");
+    assert_eq!(response.bytes().await.unwrap(), data[..1000]);
+    let md5 = reqwest::get(format!("{base}/v1.7.0/61579_pruned.sql.zst.md5"))
+      .await
+      .unwrap();
+    assert_eq!(md5.text().await.unwrap(), "abcd");
+    let db_md5 = reqwest::Client::builder()
+      .redirect(reqwest::redirect::Policy::none())
+      .build()
+      .unwrap()
+      .get(format!("{base}/v1.7.0/61579_pruned.sql.md5"))
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(
+      db_md5.headers()["location"],
+      "https://example.com/v1.7.0/61579_pruned.sql.md5"
+    );
+    let missing = reqwest::get(format!("{base}/v1.7.0/state.zst"))
+      .await
+      .unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+    let unsatisfiable = client
+      .get(format!("{base}/v1.7.0/61579_pruned.sql.zst"))
+      .header("Range", "bytes=10000-")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(
+      unsatisfiable.status(),
+      reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+    );
+    assert_eq!(unsatisfiable.headers()["content-range"], "bytes */10000");
+
+    // an endless request line is cut off without an answer
+    let mut stream = TcpStream::connect(base.trim_start_matches("http://"))
+      .await
+      .unwrap();
+    let line = format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_LINE as usize));
+    // The seed may close the connection before all of it is written
+    let _ = stream.write_all(line.as_bytes()).await;
+    let mut answer = Vec::new();
+    let _ = stream.read_to_end(&mut answer).await;
+    assert!(answer.is_empty());
+
+    // seeding stops once the whole archive was served
+    let rest = client
+      .get(format!("{base}/v1.7.0/61579_pruned.sql.zst"))
+      .header("Range", "bytes=1000-")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(rest.bytes().await.unwrap(), data[1000..]);
+    assert_eq!(seeding.await.unwrap().unwrap(), 10_000);
+  }
+}
@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use zstd::stream::read::Decoder;
+
+use crate::failpoints;
+use crate::io_tuning::{IoOptions, NoCacheFile};
+use crate::reader_with_bytes::ReaderWithBytes;
+
+/// Magic number of the skippable frame holding the seek table.
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+/// Magic number at the very end of an archive in the seekable zstd format.
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const FOOTER_SIZE: u64 = 9;
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+/// Decompressed data written between the records of the unpacking progress.
+const RECORD_INTERVAL: u64 = 256 * 1024 * 1024;
+
+/// A zstd frame of an archive in the seekable format, which can be
+/// decompressed on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekFrame {
+  pub compressed_offset: u64,
+  pub decompressed_offset: u64,
+  pub decompressed_size: u64,
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+  u32::from_le_bytes(buf[at..at + 4].try_into().expect("4 bytes"))
+}
+
+/// Reads the seek table at the end of a zstd archive, as written by the
+/// zstd seekable format (`contrib/seekable_format`).
+/// Returns `None` if the archive isn't in the seekable format.
+pub fn read_seek_table(file: &mut File) -> Result<Option<Vec<SeekFrame>>> {
+  let len = file.metadata()?.len();
+  if len < FOOTER_SIZE + SKIPPABLE_HEADER_SIZE {
+    return Ok(None);
+  }
+  let mut footer = [0; FOOTER_SIZE as usize];
+  file.seek(SeekFrom::Start(len - FOOTER_SIZE))?;
+  file.read_exact(&mut footer)?;
+  if u32_at(&footer, 5) != SEEKABLE_MAGIC {
+    return Ok(None);
+  }
+  let frames = u64::from(u32_at(&footer, 0));
+  let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+  let table_size = frames * entry_size;
+  let skippable_size = SKIPPABLE_HEADER_SIZE + table_size + FOOTER_SIZE;
+  anyhow::ensure!(
+    skippable_size <= len,
+    "the seek table of {frames} frames is larger than the archive"
+  );
+
+  let mut table = vec![0; (SKIPPABLE_HEADER_SIZE + table_size) as usize];
+  file.seek(SeekFrom::Start(len - skippable_size))?;
+  file.read_exact(&mut table)?;
+  anyhow::ensure!(
+    u32_at(&table, 0) == SKIPPABLE_MAGIC
+      && u64::from(u32_at(&table, 4)) == table_size + FOOTER_SIZE,
+    "invalid seek table header"
+  );
+
+  let mut result = Vec::with_capacity(frames as usize);
+  let (mut compressed_offset, mut decompressed_offset) = (0, 0);
+  for entry in table[SKIPPABLE_HEADER_SIZE as usize..].chunks_exact(entry_size as usize) {
+    let decompressed_size = u64::from(u32_at(entry, 4));
+    result.push(SeekFrame {
+      compressed_offset,
+      decompressed_offset,
+      decompressed_size,
+    });
+    compressed_offset += u64::from(u32_at(entry, 0));
+    decompressed_offset += decompressed_size;
+  }
+  anyhow::ensure!(
+    compressed_offset == len - skippable_size,
+    "the frames in the seek table don't add up to the archive size"
+  );
+  Ok(Some(result))
+}
+
+/// File next to the unpacked database recording how much of it is flushed
+/// to disk, and the size of the archive it's unpacked from.
+pub fn progress_path(outpath: &Path) -> PathBuf {
+  outpath.with_extension("progress")
+}
+
+/// Decompressed offset an interrupted unpacking of the same archive reached.
+fn read_progress(outpath: &Path, archive_len: u64) -> Option<u64> {
+  let content = std::fs::read_to_string(progress_path(outpath)).ok()?;
+  let (offset, len) = content.trim().split_once(' ')?;
+  if len.parse::<u64>().ok()? != archive_len {
+    return None;
+  }
+  let offset = offset.parse().ok()?;
+  let unpacked = std::fs::metadata(outpath).ok()?.len();
+  (unpacked >= offset).then_some(offset)
+}
+
+/// Unpacks the seekable zstd `archive` with the `frames` into `outpath`.
+/// If a previous unpacking was interrupted, it's resumed from the frame
+/// after the last recorded one instead of starting over.
+pub fn unpack(
+  mut archive: File,
+  frames: &[SeekFrame],
+  outpath: &Path,
+  io: IoOptions,
+) -> Result<()> {
+  let archive_len = archive.metadata()?.len();
+  let resume_from = read_progress(outpath, archive_len).unwrap_or(0);
+  let start = frames
+    .iter()
+    .rposition(|f| f.decompressed_offset <= resume_from)
+    .unwrap_or(0);
+  let (compressed_offset, mut written) = frames
+    .get(start)
+    .map_or((0, 0), |f| (f.compressed_offset, f.decompressed_offset));
+  if written > 0 {
+    println!(
+      "Resuming unpacking from {:.2} MB",
+      written as f64 / 1_024_000.00
+    );
+  }
+
+  archive.seek(SeekFrom::Start(compressed_offset))?;
+  let mut decoder = Decoder::with_buffer(BufReader::with_capacity(io.buffer_size, archive))?;
+  decoder.window_log_max(31)?;
+  let mut reader = ReaderWithBytes::new(decoder);
+
+  let outfile = OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(false)
+    .open(outpath)
+    .with_context(|| format!("creating file to unpack into at: {}", outpath.display()))?;
+  outfile.set_len(written)?;
+  let mut outfile = NoCacheFile::new(outfile, io.no_page_cache)?;
+  outfile.seek(SeekFrom::Start(written))?;
+  let mut writer = BufWriter::with_capacity(io.buffer_size, outfile);
+
+  let mut recorded = written;
+  for frame in &frames[start..] {
+    let copied = std::io::copy(
+      &mut (&mut reader).take(frame.decompressed_size),
+      &mut failpoints::UnpackedWriter(&mut writer),
+    )?;
+    anyhow::ensure!(
+      copied == frame.decompressed_size,
+      "the archive is truncated at {written} bytes"
+    );
+    written += copied;
+    if written - recorded >= RECORD_INTERVAL {
+      writer.flush()?;
+      writer.get_ref().sync_data()?;
+      std::fs::write(progress_path(outpath), format!("{written} {archive_len}"))?;
+      recorded = written;
+    }
+  }
+  // Only the seek table is left
+  anyhow::ensure!(
+    std::io::copy(&mut reader, &mut writer)? == 0,
+    "the archive has more data than its seek table"
+  );
+  writer.flush()?;
+
+  let progress = progress_path(outpath);
+  if progress.try_exists().unwrap_or(false) {
+    std::fs::remove_file(&progress)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Compresses each chunk into its own frame and appends the seek table.
+  fn seekable_archive(chunks: &[&[u8]]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut entries = Vec::new();
+    for chunk in chunks {
+      let frame = zstd::bulk::compress(chunk, 3).unwrap();
+      entries.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+      entries.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+      archive.extend_from_slice(&frame);
+    }
+    archive.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    archive.extend_from_slice(&(entries.len() as u32 + FOOTER_SIZE as u32).to_le_bytes());
+    archive.extend_from_slice(&entries);
+    archive.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+    archive.push(0);
+    archive.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    archive
+  }
+
+  #[test]
+  fn reading_seek_table() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.zst");
+    let chunks: [&[u8]; 3] = [b"first frame", b"second", b"third"];
+    let archive = seekable_archive(&chunks);
+    std::fs::write(&path, &archive).unwrap();
+
+    let frames = read_seek_table(&mut File::open(&path).unwrap())
+      .unwrap()
+      .unwrap();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[1].decompressed_offset, 11);
+    assert_eq!(frames[2].decompressed_offset, 17);
+    assert_eq!(frames[2].decompressed_size, 5);
+    let second = zstd::bulk::compress(b"first frame", 3).unwrap().len() as u64;
+    assert_eq!(frames[1].compressed_offset, second);
+
+    // a plain zstd archive has no seek table
+    std::fs::write(&path, zstd::encode_all(&b"plain"[..], 3).unwrap()).unwrap();
+    assert_eq!(
+      read_seek_table(&mut File::open(&path).unwrap()).unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn resuming_unpacking() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.zst");
+    let chunks: [&[u8]; 3] = [b"first frame,", b"second frame,", b"third frame"];
+    let archive = seekable_archive(&chunks);
+    std::fs::write(&path, &archive).unwrap();
+    let frames = read_seek_table(&mut File::open(&path).unwrap())
+      .unwrap()
+      .unwrap();
+    let io = IoOptions::for_tests();
+    let expected = chunks.concat();
+
+    // interrupted after the first frame was recorded, and more was written
+    let outpath = dir.path().join("state_downloaded.sql");
+    std::fs::write(&outpath, b"FIRST FRAME,second fr").unwrap();
+    let progress = progress_path(&outpath);
+    std::fs::write(&progress, format!("12 {}", archive.len())).unwrap();
+    unpack(File::open(&path).unwrap(), &frames, &outpath, io).unwrap();
+    // only the frames after the recorded one are unpacked again
+    assert_eq!(
+      std::fs::read(&outpath).unwrap(),
+      b"FIRST FRAME,second frame,third frame"
+    );
+    assert!(!progress.exists());
+
+    // the progress of another archive is ignored
+    std::fs::write(&progress, "12 1").unwrap();
+    unpack(File::open(&path).unwrap(), &frames, &outpath, io).unwrap();
+    assert_eq!(std::fs::read(&outpath).unwrap(), expected);
+  }
+}
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use url::Url;
+
+use crate::checksum::calculate_checksum;
+use crate::export::{export, SnapshotMetadata};
+use crate::io_tuning::IoOptions;
+
+const NODE_VERSION: &str = "v1.0.0";
+const LAYERS: u32 = 1000;
+
+/// A temporary node-data directory with a fake node binary and
+/// a tiny synthetic snapshot to serve.
+pub struct Fixture {
+  pub dir: PathBuf,
+  pub node_data: PathBuf,
+  pub go_spacemesh: PathBuf,
+  served: PathBuf,
+  snapshot: SnapshotMetadata,
+}
+
+impl Fixture {
+  pub fn create() -> Result<Self> {
+    let dir = std::env::temp_dir().join(format!("quicksync-selftest-{}", std::process::id()));
+    if dir.try_exists()? {
+      std::fs::remove_dir_all(&dir)?;
+    }
+    let source = dir.join("source");
+    let served = dir.join("served");
+    let node_data = dir.join("node-data");
+    for d in [&source, &served, &node_data] {
+      std::fs::create_dir_all(d).with_context(|| format!("creating {}", d.display()))?;
+    }
+
+    let db_path = source.join("state.sql");
+    create_db(&db_path)?;
+    let snapshot = export(&db_path, &served, 3, 1024 * 1024, None)?;
+    let go_spacemesh = create_fake_node(&dir)?;
+    Ok(Self {
+      dir,
+      node_data,
+      go_spacemesh,
+      served,
+      snapshot,
+    })
+  }
+
+  /// Checks that the installed database is the one from the snapshot.
+  pub fn verify(&self) -> Result<()> {
+    let installed = self.node_data.join("state.sql");
+    let io = IoOptions {
+      buffer_size: 1024 * 1024,
+      no_page_cache: false,
+      hash_threads: 2,
+    };
+    let md5 = calculate_checksum(&installed, io)?;
+    anyhow::ensure!(
+      md5 == self.snapshot.db_md5,
+      "installed database doesn't match the snapshot: {md5} != {}",
+      self.snapshot.db_md5
+    );
+    Ok(())
+  }
+
+  pub fn cleanup(&self) -> Result<()> {
+    std::fs::remove_dir_all(&self.dir).with_context(|| format!("removing {}", self.dir.display()))
+  }
+}
+
+fn create_db(path: &Path) -> Result<()> {
+  let conn = Connection::open(path)?;
+  conn.execute_batch(
+    "PRAGMA user_version = 1;
+    CREATE TABLE layers (id INTEGER PRIMARY KEY, applied_block INTEGER, aggregated_hash BLOB);
+    CREATE TABLE atxs (id BLOB PRIMARY KEY, epoch INTEGER);
+    CREATE TABLE ballots (id BLOB PRIMARY KEY, layer INTEGER);
+    CREATE TABLE blocks (id BLOB PRIMARY KEY, layer INTEGER);
+    CREATE TABLE transactions (id BLOB PRIMARY KEY, layer INTEGER);",
+  )?;
+  let tx = conn.unchecked_transaction()?;
+  for layer in 0..LAYERS {
+    tx.execute(
+      "INSERT INTO layers VALUES (?1, ?1, ?2)",
+      rusqlite::params![layer, layer.to_be_bytes().repeat(8)],
+    )?;
+    tx.execute(
+      "INSERT INTO ballots VALUES (?1, ?2)",
+      rusqlite::params![layer.to_le_bytes().to_vec(), layer],
+    )?;
+  }
+  tx.commit()?;
+  Ok(())
+}
+
+/// Creates a script answering `version` like the node binary does.
+fn create_fake_node(dir: &Path) -> Result<PathBuf> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let path = dir.join("go-spacemesh");
+    std::fs::write(
+      &path,
+      format!("#!/bin/sh\nprintf '{NODE_VERSION}+selftest'\n"),
+    )?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(path)
+  }
+  #[cfg(not(unix))]
+  {
+    let path = dir.join("go-spacemesh.cmd");
+    std::fs::write(&path, format!("@echo {NODE_VERSION}+selftest\r\n"))?;
+    Ok(path)
+  }
+}
+
+/// Starts an HTTP server on a random local port mimicking the quicksync
+/// download server: `/{version}/state.zst` redirects to the snapshot and
+/// other paths serve files from the fixture (with `Range` support).
+/// Returns the URL to download from.
+pub fn serve(fixture: &Fixture) -> Result<Url> {
+  let listener = TcpListener::bind("127.0.0.1:0").context("starting selftest server")?;
+  let base = format!("http://{}", listener.local_addr()?);
+  let root = fixture.served.clone();
+  let snapshot = format!("{base}/{}", fixture.snapshot.archive);
+  std::thread::spawn(move || {
+    for stream in listener.incoming().flatten() {
+      let (root, snapshot) = (root.clone(), snapshot.clone());
+      std::thread::spawn(move || {
+        if let Err(e) = handle(stream, &root, &snapshot) {
+          println!("Selftest server error: {e:#}");
+        }
+      });
+    }
+  });
+  Ok(Url::parse(&base)?)
+}
+
+fn handle(stream: TcpStream, root: &Path, snapshot: &str) -> Result<()> {
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line)?;
+  let mut parts = request_line.split_whitespace();
+  let method = parts.next().unwrap_or_default().to_string();
+  let path = parts.next().unwrap_or_default().to_string();
+
+  let mut offset = 0;
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("range") {
+        offset = value
+          .trim()
+          .trim_start_matches("bytes=")
+          .trim_end_matches('-')
+          .parse()
+          .unwrap_or(0);
+      }
+    }
+  }
+
+  let mut stream = stream;
+  let path = path.split('?').next().unwrap_or_default();
+  if path.ends_with("/state.zst") {
+    write!(
+      stream,
+      "HTTP/1.1 302 Found\r\nLocation: {snapshot}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )?;
+    return Ok(());
+  }
+
+  let relative = Path::new(path.trim_start_matches('/'));
+  let file_path = root.join(relative);
+  let is_safe = relative
+    .components()
+    .all(|c| matches!(c, Component::Normal(_)));
+  let file = match is_safe {
+    true => File::open(&file_path).ok(),
+    false => None,
+  };
+  let Some(mut file) = file else {
+    write!(
+      stream,
+      "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )?;
+    return Ok(());
+  };
+
+  let len = file.metadata()?.len();
+  let offset = offset.min(len);
+  let status = match offset {
+    0 if method == "HEAD" => "200 OK",
+    _ => "206 Partial Content",
+  };
+  write!(
+    stream,
+    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nContent-Range: bytes {offset}-{}/{len}\r\nConnection: close\r\n\r\n",
+    len - offset,
+    len.saturating_sub(1)
+  )?;
+  if method != "HEAD" {
+    file.seek(SeekFrom::Start(offset))?;
+    std::io::copy(&mut file.take(len - offset), &mut stream)?;
+  }
+  stream.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{serve, Fixture};
+
+  #[tokio::test]
+  async fn serves_snapshot_and_checksums() {
+    let fixture = tokio::task::spawn_blocking(Fixture::create)
+      .await
+      .unwrap()
+      .unwrap();
+    let url = serve(&fixture).unwrap();
+
+    let response = reqwest::Client::new()
+      .get(url.join("v1.0.0/state.zst").unwrap())
+      .header("Range", "bytes=0-")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    assert!(response.url().path().ends_with(".sql.zst"));
+    let md5_url = format!("{}.md5", response.url());
+    let archive = response.bytes().await.unwrap();
+    assert_eq!(archive.len() as u64, fixture.snapshot.archive_size);
+
+    let md5 = reqwest::get(md5_url).await.unwrap().text().await.unwrap();
+    assert_eq!(md5, fixture.snapshot.archive_md5);
+
+    let missing = reqwest::get(url.join("missing.sql.zst").unwrap())
+      .await
+      .unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+    fixture.cleanup().unwrap();
+  }
+}
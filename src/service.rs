@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, ErrorCode};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+/// How long to wait for the node to release the database after stopping it.
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A service manager running the node, in form `<manager>:<name>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeService {
+  /// `systemd:<unit>`
+  Systemd(String),
+  /// `windows:<service name>`
+  Windows(String),
+  /// `launchd:<label>`
+  Launchd(String),
+}
+
+impl FromStr for NodeService {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let (manager, name) = s
+      .split_once(':')
+      .filter(|(_, name)| !name.is_empty())
+      .ok_or_else(|| format!("expected <manager>:<name>, got '{s}'"))?;
+    match manager {
+      "systemd" => Ok(NodeService::Systemd(name.to_string())),
+      "windows" => Ok(NodeService::Windows(name.to_string())),
+      "launchd" => Ok(NodeService::Launchd(name.to_string())),
+      other => Err(format!(
+        "unknown service manager '{other}', expected one of: systemd, windows, launchd"
+      )),
+    }
+  }
+}
+
+impl NodeService {
+  fn program(&self) -> (&'static str, &str) {
+    match self {
+      NodeService::Systemd(unit) => ("systemctl", unit),
+      NodeService::Windows(name) => ("net", name),
+      NodeService::Launchd(label) => ("launchctl", label),
+    }
+  }
+
+  /// The command running `action` on the service, as printed.
+  fn command_line(&self, action: &str) -> String {
+    let (program, name) = self.program();
+    format!("{program} {action} {name}")
+  }
+
+  async fn run(&self, action: &str) -> Result<()> {
+    let (program, name) = self.program();
+    let command_line = self.command_line(action);
+    let status = Command::new(program)
+      .arg(action)
+      .arg(name)
+      .status()
+      .await
+      .with_context(|| format!("running {command_line}"))?;
+    anyhow::ensure!(status.success(), "{command_line} failed: {status}");
+    Ok(())
+  }
+
+  pub async fn stop(&self) -> Result<()> {
+    println!("Stopping node service: {}", self.command_line("stop"));
+    self.run("stop").await
+  }
+
+  pub async fn start(&self) -> Result<()> {
+    println!("Starting node service: {}", self.command_line("start"));
+    self.run("start").await
+  }
+}
+
+/// Checks if some other process holds the database open with a lock.
+pub fn is_db_locked(db_path: &Path) -> Result<bool> {
+  let conn = Connection::open(db_path).context("opening database")?;
+  conn
+    .query_row("PRAGMA locking_mode = EXCLUSIVE", [], |_| Ok(()))
+    .context("setting locking mode")?;
+  let result = conn.execute_batch("BEGIN EXCLUSIVE; COMMIT;");
+  match result {
+    Ok(()) => Ok(false),
+    Err(rusqlite::Error::SqliteFailure(e, _))
+      if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+    {
+      Ok(true)
+    }
+    Err(e) => Err(e).context("locking database"),
+  }
+}
+
+/// Waits until the database is not used by any other process (i.e. the node has stopped).
+pub async fn wait_for_db_unlock(db_path: &Path) -> Result<()> {
+  if !db_path.try_exists().unwrap_or(false) {
+    return Ok(());
+  }
+  let start = Instant::now();
+  while is_db_locked(db_path)? {
+    anyhow::ensure!(
+      start.elapsed() < UNLOCK_TIMEOUT,
+      "database {} is still locked after {} sec",
+      db_path.display(),
+      UNLOCK_TIMEOUT.as_secs()
+    );
+    println!("Waiting for the node to release the database...");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_services() {
+    assert_eq!(
+      "systemd:spacemesh.service".parse::<NodeService>().unwrap(),
+      NodeService::Systemd("spacemesh.service".into())
+    );
+    assert_eq!(
+      "windows:Spacemesh".parse::<NodeService>().unwrap(),
+      NodeService::Windows("Spacemesh".into())
+    );
+    assert_eq!(
+      "launchd:io.spacemesh.node".parse::<NodeService>().unwrap(),
+      NodeService::Launchd("io.spacemesh.node".into())
+    );
+    assert!("systemd:".parse::<NodeService>().is_err());
+    assert!("upstart:spacemesh".parse::<NodeService>().is_err());
+    assert!("spacemesh".parse::<NodeService>().is_err());
+  }
+
+  #[test]
+  fn detects_locked_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute_batch("CREATE TABLE layers (id INTEGER)")
+      .unwrap();
+    assert!(!is_db_locked(&db_path).unwrap());
+
+    conn.execute_batch("BEGIN EXCLUSIVE").unwrap();
+    assert!(is_db_locked(&db_path).unwrap());
+
+    conn.execute_batch("COMMIT").unwrap();
+    assert!(!is_db_locked(&db_path).unwrap());
+  }
+}
@@ -0,0 +1,420 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use reqwest::{redirect, Client, StatusCode};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+use crate::cert_pin;
+use crate::download::{parse_content_range, CONNECT_TIMEOUT};
+use crate::http_trace::{self, SendTraced};
+use crate::read_error_response::read_error_response;
+use crate::status;
+use crate::transport;
+use crate::url_policy;
+
+/// Timeout of each range request.
+const RANGE_TIMEOUT: Duration = Duration::from_secs(600);
+/// Size of the range requests the default [`SnapshotSource::open`] makes.
+const OPEN_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// A snapshot as it's received, from the requested byte on.
+pub struct Body<'a> {
+  /// URL the data comes from, after the redirects.
+  pub url: Url,
+  /// Size of the whole snapshot, if known.
+  pub size: Option<u64>,
+  /// Version of the snapshot (its ETag over HTTP), if known.
+  pub etag: Option<String>,
+  pub chunks: BoxStream<'a, Result<Vec<u8>>>,
+}
+
+/// The source answered the request for a file with an error status.
+#[derive(Debug)]
+pub struct FileStatus {
+  pub url: String,
+  pub status: StatusCode,
+  /// Explanation sent along, if any.
+  pub message: String,
+}
+
+impl std::fmt::Display for FileStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Failed to download file {}: HTTP status {}",
+      self.url, self.status
+    )
+  }
+}
+
+impl std::error::Error for FileStatus {}
+
+/// Transport the snapshots are fetched with: the full downloads, the partial
+/// ones (chunks of delta downloads and repaired parts) and the diffs of the
+/// incremental restore all go through it, so code embedding quicksync can plug
+/// in another one (e.g. a proxy-aware fetcher).
+pub trait SnapshotSource: Send + Sync {
+  /// Size of the snapshot at `url`.
+  fn size<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<u64>>;
+
+  /// Fetches `len` bytes of the snapshot at `url` starting at `start`.
+  fn fetch_range<'a>(
+    &'a self,
+    url: &'a Url,
+    start: u64,
+    len: u64,
+  ) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+  /// Opens the snapshot at `url` to receive it from byte `from` on, or whole
+  /// without `from`. By default it's fetched with [`Self::fetch_range`], a few
+  /// megabytes at a time.
+  fn open<'a>(&'a self, url: &'a Url, from: Option<u64>) -> BoxFuture<'a, Result<Body<'a>>> {
+    Box::pin(async move {
+      let size = self.size(url).await?;
+      let start = from.unwrap_or(0);
+      anyhow::ensure!(
+        start <= size,
+        "requested data from byte {start}, but the snapshot has {size} bytes"
+      );
+      let chunks = futures::stream::try_unfold(start, move |done| async move {
+        if done == size {
+          return anyhow::Ok(None);
+        }
+        let len = OPEN_CHUNK_SIZE.min(size - done);
+        let data = self.fetch_range(url, done, len).await?;
+        anyhow::Ok(Some((data, done + len)))
+      });
+      Ok(Body {
+        url: url.clone(),
+        size: Some(size),
+        etag: None,
+        chunks: chunks.boxed(),
+      })
+    })
+  }
+}
+
+/// Downloads the whole file at `url` from `source` into `path`, e.g. a diff
+/// of the incremental restore.
+pub async fn fetch_file(source: &dyn SnapshotSource, url: &Url, path: &Path) -> Result<()> {
+  let mut body = source.open(url, None).await?;
+  let mut file = File::create(path).context("Failed to create file")?;
+  while let Some(chunk) = body.chunks.next().await {
+    let chunk = chunk.context("Failed to read response")?;
+    file
+      .write_all(&chunk)
+      .context("Failed to copy response to file")?;
+  }
+  Ok(())
+}
+
+/// Fetches over HTTP.
+pub struct HttpSource {
+  client: Client,
+  /// Without an overall timeout, which would also limit the time to receive
+  /// a whole (huge) file. Stalls are detected per chunk instead.
+  stream_client: Client,
+}
+
+impl HttpSource {
+  pub fn new() -> Result<Self> {
+    let client = transport::builder()
+      .redirect(url_policy::redirect_policy())
+      .timeout(RANGE_TIMEOUT)
+      .build()?;
+    // Redirects are followed by `open`, so each one is checked before
+    // anything is received
+    let stream_client = transport::builder()
+      .connect_timeout(CONNECT_TIMEOUT)
+      .redirect(redirect::Policy::none())
+      .build()?;
+    Ok(Self {
+      client,
+      stream_client,
+    })
+  }
+}
+
+impl SnapshotSource for HttpSource {
+  fn size<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<u64>> {
+    Box::pin(async move {
+      let response = self
+        .client
+        .get(url.clone())
+        .header("Range", "bytes=0-0")
+        .send_traced()
+        .await?;
+      anyhow::ensure!(
+        response.status() == StatusCode::PARTIAL_CONTENT,
+        "expected {}, but got {}",
+        StatusCode::PARTIAL_CONTENT,
+        response.status()
+      );
+      response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(parse_content_range)
+        .and_then(|(_, total)| total)
+        .with_context(|| format!("the size of {url} is unknown"))
+    })
+  }
+
+  fn fetch_range<'a>(
+    &'a self,
+    url: &'a Url,
+    start: u64,
+    len: u64,
+  ) -> BoxFuture<'a, Result<Vec<u8>>> {
+    Box::pin(async move {
+      // An empty range can't be requested
+      if len == 0 {
+        return Ok(Vec::new());
+      }
+      let response = self
+        .client
+        .get(url.clone())
+        .header("Range", format!("bytes={start}-{}", start + len - 1))
+        .send_traced()
+        .await?;
+      anyhow::ensure!(
+        response.status() == StatusCode::PARTIAL_CONTENT,
+        "expected {}, but got {}",
+        StatusCode::PARTIAL_CONTENT,
+        response.status()
+      );
+      let data = response.bytes().await?;
+      anyhow::ensure!(
+        data.len() as u64 == len,
+        "expected {len} bytes, but got {}",
+        data.len()
+      );
+      Ok(data.to_vec())
+    })
+  }
+
+  fn open<'a>(&'a self, url: &'a Url, from: Option<u64>) -> BoxFuture<'a, Result<Body<'a>>> {
+    Box::pin(async move {
+      url_policy::check(url)?;
+      let mut request_url = url.clone();
+      let mut previous = Vec::new();
+      let response = loop {
+        let mut request = self.stream_client.get(request_url.clone());
+        if let Some(from) = from {
+          request = request.header("Range", format!("bytes={from}-"));
+        }
+        let response = tokio::time::timeout(CONNECT_TIMEOUT, request.send_traced())
+          .await
+          .map_err(|_| anyhow!("timed out waiting for response from {url}"))??;
+        let code = response.status();
+        if !code.is_redirection() || code == StatusCode::NOT_MODIFIED {
+          break response;
+        }
+        let location = response
+          .headers()
+          .get(reqwest::header::LOCATION)
+          .and_then(|location| location.to_str().ok())
+          .ok_or_else(|| anyhow!("{code} from {request_url} without a location"))?;
+        let next = request_url.join(location)?;
+        previous.push(request_url);
+        url_policy::check_redirect(&previous, code, &next)?;
+        status::print_line(format_args!(
+          "Redirected ({code}) to {}",
+          http_trace::redact_url(&next)
+        ));
+        request_url = next;
+      };
+      cert_pin::check(&response)?;
+
+      let code = response.status();
+      if !code.is_success() {
+        return Err(
+          FileStatus {
+            url: url.to_string(),
+            status: code,
+            message: read_error_response(response).await,
+          }
+          .into(),
+        );
+      }
+      anyhow::ensure!(
+        from.is_none() || code == StatusCode::PARTIAL_CONTENT,
+        "expected {}, but got {}",
+        StatusCode::PARTIAL_CONTENT,
+        code
+      );
+      let header = |name| {
+        response
+          .headers()
+          .get(name)
+          .and_then(|value| value.to_str().ok())
+          .map(str::to_string)
+      };
+      let etag = header(reqwest::header::ETAG);
+      let content_len =
+        header(reqwest::header::CONTENT_LENGTH).and_then(|len| len.parse::<u64>().ok());
+      let content_range = header(reqwest::header::CONTENT_RANGE)
+        .map(|range| {
+          parse_content_range(&range).ok_or_else(|| anyhow!("invalid Content-Range: {range}"))
+        })
+        .transpose()?;
+      let offset = from.unwrap_or(0);
+      let range_total = match content_range {
+        Some((start, total)) => {
+          anyhow::ensure!(
+            start == offset,
+            "requested data from byte {offset}, but got it from {start}"
+          );
+          total
+        }
+        None => None,
+      };
+      Ok(Body {
+        url: response.url().clone(),
+        size: range_total.or(content_len.map(|len| len + offset)),
+        etag,
+        chunks: response
+          .bytes_stream()
+          .map(|chunk| anyhow::Ok(chunk?.to_vec()))
+          .boxed(),
+      })
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn fetching_ranges_over_http() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/1/100.sql.zst")
+      .match_header("Range", "bytes=10-13")
+      .with_status(206)
+      .with_body("abcd")
+      .create_async()
+      .await;
+    let short = server
+      .mock("GET", "/1/100.sql.zst")
+      .match_header("Range", "bytes=0-9")
+      .with_status(206)
+      .with_body("abc")
+      .create_async()
+      .await;
+
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    let source = HttpSource::new().unwrap();
+    assert_eq!(source.fetch_range(&url, 10, 4).await.unwrap(), b"abcd");
+    assert!(source.fetch_range(&url, 0, 10).await.is_err());
+    mock.assert_async().await;
+    short.assert_async().await;
+    assert!(source.fetch_range(&url, 5, 0).await.unwrap().is_empty());
+  }
+
+  /// A snapshot in memory, as an embedder's own transport.
+  struct Memory(Vec<u8>);
+
+  impl SnapshotSource for Memory {
+    fn size<'a>(&'a self, _: &'a Url) -> BoxFuture<'a, Result<u64>> {
+      Box::pin(async move { Ok(self.0.len() as u64) })
+    }
+
+    fn fetch_range<'a>(
+      &'a self,
+      _: &'a Url,
+      start: u64,
+      len: u64,
+    ) -> BoxFuture<'a, Result<Vec<u8>>> {
+      Box::pin(async move { Ok(self.0[start as usize..(start + len) as usize].to_vec()) })
+    }
+  }
+
+  #[tokio::test]
+  async fn fetching_files_from_custom_sources() {
+    let snapshot: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let source = Memory(snapshot.clone());
+    let url = Url::parse("memory:///1/100.sql.zst").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    // resumed after the first part was downloaded
+    let mut file = std::io::Cursor::new(snapshot[..1000].to_vec());
+    crate::download::download_with_retries(
+      &source,
+      url.as_str(),
+      &mut file,
+      &dir.path().join("redirect.txt"),
+      crate::download::RetryBudget {
+        max_retries: 0,
+        max_total: None,
+        delay: Duration::ZERO,
+      },
+      1024,
+      None,
+      &mut crate::progress::Reporter::new(vec![]),
+    )
+    .await
+    .unwrap();
+    assert_eq!(file.into_inner(), snapshot);
+
+    let path = dir.path().join("100.sql.zst");
+    fetch_file(&source, &url, &path).await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), snapshot);
+  }
+
+  #[tokio::test]
+  async fn opening_over_http() {
+    let mut server = mockito::Server::new_async().await;
+    let whole = server
+      .mock("GET", "/1/100.sql.zst")
+      .with_status(200)
+      .with_body("abcd")
+      .expect(2)
+      .create_async()
+      .await;
+    let missing = server
+      .mock("GET", "/1/200.sql.zst")
+      .with_status(404)
+      .create_async()
+      .await;
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    let source = HttpSource::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("100.sql.zst");
+    fetch_file(&source, &url, &path).await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"abcd");
+    // A range is only served partially
+    let err = source.open(&url, Some(0)).await.err().unwrap();
+    assert_eq!(
+      err.to_string(),
+      "expected 206 Partial Content, but got 200 OK"
+    );
+
+    let url = url.join("200.sql.zst").unwrap();
+    let err = fetch_file(&source, &url, &path).await.unwrap_err();
+    let status = err.downcast_ref::<FileStatus>().unwrap();
+    assert_eq!(status.status, StatusCode::NOT_FOUND);
+    whole.assert_async().await;
+    missing.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn sizing_over_http() {
+    let mut server = mockito::Server::new_async().await;
+    server
+      .mock("GET", "/1/100.sql.zst")
+      .match_header("Range", "bytes=0-0")
+      .with_status(206)
+      .with_header("Content-Range", "bytes 0-0/1234")
+      .with_body("a")
+      .create_async()
+      .await;
+    let url = Url::parse(&format!("{}/1/100.sql.zst", server.url())).unwrap();
+    assert_eq!(HttpSource::new().unwrap().size(&url).await.unwrap(), 1234);
+  }
+}
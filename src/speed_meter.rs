@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Measures transfer speed over a sliding time window.
+///
+/// Transferred bytes are accumulated into buckets of `interval` length,
+/// only the buckets from the last `window` are used to calculate the speed.
+/// It makes the speed (and ETA) react to slowdowns and stalls instead of
+/// averaging them out over the whole transfer.
+pub struct SpeedMeter {
+  window: Duration,
+  interval: Duration,
+  buckets: VecDeque<(Instant, u64)>,
+}
+
+impl SpeedMeter {
+  pub fn new(window: Duration, interval: Duration) -> Self {
+    SpeedMeter {
+      window,
+      interval,
+      buckets: VecDeque::new(),
+    }
+  }
+
+  pub fn record(&mut self, now: Instant, bytes: u64) {
+    match self.buckets.back_mut() {
+      Some((start, total)) if now.duration_since(*start) < self.interval => {
+        *total += bytes;
+      }
+      _ => self.buckets.push_back((now, bytes)),
+    }
+    while self
+      .buckets
+      .front()
+      .is_some_and(|(start, _)| now.duration_since(*start) > self.window)
+    {
+      self.buckets.pop_front();
+    }
+  }
+
+  /// Average speed in bytes per second over the window.
+  /// Returns `None` until at least one interval has been measured.
+  pub fn speed(&self, now: Instant) -> Option<f64> {
+    let (first, _) = self.buckets.front()?;
+    let elapsed = now.duration_since(*first);
+    if elapsed < self.interval {
+      return None;
+    }
+    let bytes: u64 = self.buckets.iter().map(|(_, b)| b).sum();
+    Some(bytes as f64 / elapsed.as_secs_f64())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SEC: Duration = Duration::from_secs(1);
+
+  #[test]
+  fn unknown_speed_before_first_interval() {
+    let start = Instant::now();
+    let mut meter = SpeedMeter::new(30 * SEC, SEC);
+    assert_eq!(meter.speed(start), None);
+    meter.record(start, 100);
+    assert_eq!(meter.speed(start + SEC / 2), None);
+    assert_eq!(meter.speed(start + SEC), Some(100.0));
+  }
+
+  #[test]
+  fn speed_reflects_only_the_window() {
+    let start = Instant::now();
+    let mut meter = SpeedMeter::new(10 * SEC, SEC);
+    // fast at the beginning
+    for i in 0..10 {
+      meter.record(start + i * SEC, 1000);
+    }
+    // then slow
+    for i in 10..30 {
+      meter.record(start + i * SEC, 10);
+    }
+    let speed = meter.speed(start + 30 * SEC).unwrap();
+    assert!((speed - 10.0).abs() < 1.0, "speed: {speed}");
+  }
+
+  #[test]
+  fn stall_lowers_speed() {
+    let start = Instant::now();
+    let mut meter = SpeedMeter::new(30 * SEC, SEC);
+    for i in 0..10 {
+      meter.record(start + i * SEC, 100);
+    }
+    let before = meter.speed(start + 10 * SEC).unwrap();
+    let after = meter.speed(start + 20 * SEC).unwrap();
+    assert!(after < before);
+  }
+}
@@ -1,9 +1,33 @@
 use anyhow::{Context, Result};
-use rusqlite::{params, Connection};
-use std::path::PathBuf;
+use rusqlite::{params, Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use url::Url;
 
-pub fn get_last_layer_from_db(db_path: &PathBuf) -> Result<i32> {
-  let conn = Connection::open(db_path).context("Failed to connect to db")?;
+/// Opens the database for reading only, so it's safe to read while the node
+/// is running. The node keeps `state.sql-wal` while it has the database open,
+/// so a database without it is opened as immutable, without taking any locks.
+pub fn open_read_only(db_path: &Path) -> Result<Connection> {
+  let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+  let immutable = if wal_path(db_path).exists() {
+    None
+  } else {
+    db_path
+      .canonicalize()
+      .ok()
+      .and_then(|path| Url::from_file_path(path).ok())
+  };
+  let conn = match immutable {
+    Some(mut uri) => {
+      uri.set_query(Some("immutable=1"));
+      Connection::open_with_flags(uri.as_str(), flags | OpenFlags::SQLITE_OPEN_URI)
+    }
+    None => Connection::open_with_flags(db_path, flags),
+  };
+  conn.with_context(|| format!("opening {} for reading", db_path.display()))
+}
+
+pub fn get_last_layer_from_db(db_path: &Path) -> Result<i32> {
+  let conn = open_read_only(db_path)?;
 
   let mut stmt = conn.prepare("SELECT * FROM layers ORDER BY id DESC LIMIT 1")?;
   let mut layer_iter = stmt.query_map(params![], |row| row.get::<_, i32>(0))?;
@@ -15,3 +39,176 @@ pub fn get_last_layer_from_db(db_path: &PathBuf) -> Result<i32> {
     Ok(0)
   }
 }
+
+/// What the database holds, as reported by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbStatus {
+  /// Latest layer in the database, applied or not.
+  pub latest_layer: i32,
+  /// Latest layer with an applied block.
+  pub latest_applied_layer: i32,
+  /// Latest layer processed (verified) by the tortoise, unknown in databases
+  /// without the `processed` column.
+  pub latest_verified_layer: Option<i32>,
+  /// Size of the database file and its write-ahead log.
+  pub db_size: u64,
+  /// Schema version (`PRAGMA user_version`).
+  pub user_version: i32,
+}
+
+/// Layers not trusted to be fully synced on top of the ones the node hasn't
+/// verified.
+pub const UNTRUSTED_LAYERS_MARGIN: u32 = 10;
+
+impl DbStatus {
+  /// Number of the latest layers not trusted to be fully synced: the applied
+  /// layers the tortoise hasn't verified yet (many after a crash mid-sync),
+  /// plus a margin.
+  pub fn untrusted_layers(&self) -> u32 {
+    let unverified = match self.latest_verified_layer {
+      // A node that hasn't verified any layer doesn't track it
+      Some(verified) if verified > 0 => self.latest_applied_layer - verified,
+      _ => 0,
+    };
+    u32::try_from(unverified).unwrap_or(0) + UNTRUSTED_LAYERS_MARGIN
+  }
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+  Ok(
+    conn.query_row(
+      "SELECT count(*) FROM pragma_table_info(?1) WHERE name = ?2",
+      params![table, column],
+      |row| row.get::<_, i64>(0),
+    )? > 0,
+  )
+}
+
+/// Reads the status of the database, including the changes in `state.sql-wal`
+/// that aren't checkpointed yet. Layers are 0 if there are none.
+pub fn get_db_status(db_path: &Path) -> Result<DbStatus> {
+  let conn = open_read_only(db_path)?;
+  let max_layer = |condition: &str| -> Result<i32> {
+    let layer: Option<i32> = conn.query_row(
+      &format!("SELECT max(id) FROM layers WHERE {condition}"),
+      [],
+      |row| row.get(0),
+    )?;
+    Ok(layer.unwrap_or(0))
+  };
+  let latest_verified_layer = if has_column(&conn, "layers", "processed")? {
+    Some(max_layer("processed = 1")?)
+  } else {
+    None
+  };
+  Ok(DbStatus {
+    latest_layer: max_layer("1")?,
+    latest_applied_layer: max_layer("applied_block IS NOT null")?,
+    latest_verified_layer,
+    db_size: std::fs::metadata(db_path).map_or(0, |m| m.len()) + wal_size(db_path),
+    user_version: conn.query_row("PRAGMA user_version", [], |row| row.get(0))?,
+  })
+}
+
+fn wal_path(db_path: &Path) -> PathBuf {
+  let mut wal_path = db_path.as_os_str().to_owned();
+  wal_path.push("-wal");
+  wal_path.into()
+}
+
+/// Size of the write-ahead log of the database, 0 if there is none.
+pub fn wal_size(db_path: &Path) -> u64 {
+  std::fs::metadata(wal_path(db_path)).map_or(0, |m| m.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reading_applied_layer_from_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute_batch(
+        "PRAGMA journal_mode=WAL;
+         PRAGMA wal_autocheckpoint=0;
+         CREATE TABLE layers (id INTEGER PRIMARY KEY, applied_block INTEGER);
+         INSERT INTO layers (id, applied_block) VALUES (1, 10), (2, 20), (3, NULL);",
+      )
+      .unwrap();
+    // the connection stays open, so the rows are only in the WAL
+    assert!(wal_size(&db_path) > 0);
+    assert_eq!(get_last_layer_from_db(&db_path).unwrap(), 3);
+    assert_eq!(get_db_status(&db_path).unwrap().latest_applied_layer, 2);
+    assert_eq!(get_db_status(&db_path).unwrap().latest_verified_layer, None);
+    drop(conn);
+  }
+
+  #[test]
+  fn deriving_untrusted_layers() {
+    let mut status = DbStatus {
+      latest_layer: 120,
+      latest_applied_layer: 110,
+      latest_verified_layer: Some(60),
+      db_size: 0,
+      user_version: 1,
+    };
+    assert_eq!(status.untrusted_layers(), 60);
+    status.latest_verified_layer = Some(110);
+    assert_eq!(status.untrusted_layers(), UNTRUSTED_LAYERS_MARGIN);
+    status.latest_verified_layer = Some(0);
+    assert_eq!(status.untrusted_layers(), UNTRUSTED_LAYERS_MARGIN);
+    status.latest_verified_layer = None;
+    assert_eq!(status.untrusted_layers(), UNTRUSTED_LAYERS_MARGIN);
+  }
+
+  #[test]
+  fn opening_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    Connection::open(&db_path)
+      .unwrap()
+      .execute_batch(
+        "CREATE TABLE layers (id INTEGER PRIMARY KEY, applied_block INTEGER);
+         INSERT INTO layers (id, applied_block) VALUES (1, 10);",
+      )
+      .unwrap();
+    let conn = open_read_only(&db_path).unwrap();
+    assert!(conn
+      .execute("INSERT INTO layers (id) VALUES (2)", [])
+      .is_err());
+    assert_eq!(get_last_layer_from_db(&db_path).unwrap(), 1);
+    // Missing databases aren't created
+    assert!(open_read_only(&dir.path().join("missing.sql")).is_err());
+    assert!(!dir.path().join("missing.sql").exists());
+  }
+
+  #[test]
+  fn reading_db_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    let conn = Connection::open(&db_path).unwrap();
+    conn
+      .execute_batch(
+        "PRAGMA user_version=8;
+         CREATE TABLE layers (id INTEGER PRIMARY KEY, processed SMALLINT, applied_block INTEGER);
+         INSERT INTO layers (id, processed, applied_block)
+           VALUES (1, 1, 10), (2, 1, 20), (3, 1, NULL), (4, 0, NULL), (5, 0, NULL);",
+      )
+      .unwrap();
+    drop(conn);
+    let status = get_db_status(&db_path).unwrap();
+    assert_eq!(
+      status,
+      DbStatus {
+        latest_layer: 5,
+        latest_applied_layer: 2,
+        latest_verified_layer: Some(3),
+        db_size: std::fs::metadata(&db_path).unwrap().len(),
+        user_version: 8,
+      }
+    );
+  }
+}
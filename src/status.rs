@@ -0,0 +1,96 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::events::Stage;
+
+/// Whether progress is shown on a single status line and in the terminal title.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether the cursor is at the end of the status line.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Shows progress in place on a single status line and in the terminal title,
+/// instead of a line per update. Only for terminals.
+pub fn enable() {
+  ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn label(stage: Stage) -> &'static str {
+  match stage {
+    Stage::CheckUpToDate => "checking",
+    Stage::Download => "downloading",
+    Stage::VerifyArchive => "verifying the archive",
+    Stage::Unpack => "unpacking",
+    Stage::VerifyDb => "verifying the database",
+    Stage::Install => "installing",
+    Stage::Restore => "restoring",
+    Stage::Wait => "waiting",
+    Stage::Done => "done",
+  }
+}
+
+/// Terminal title with the stage and the percentage done (OSC 0).
+fn title(stage: Stage, percent: Option<f64>) -> String {
+  match percent {
+    Some(percent) => format!("\x1b]0;quicksync: {} {percent:.0}%\x07", label(stage)),
+    None => format!("\x1b]0;quicksync: {}\x07", label(stage)),
+  }
+}
+
+/// Reports the progress of a stage with the `line` describing it, `done` out
+/// of `total` (if known).
+pub fn progress(stage: Stage, done: u64, total: Option<u64>, line: &str) {
+  // A single line can't show the progress of several jobs
+  if crate::events::job().is_some() || !ENABLED.load(Ordering::Relaxed) {
+    print_line(line);
+    return;
+  }
+  let percent = total.map(|total| done as f64 / total.max(1) as f64 * 100.0);
+  let mut stdout = std::io::stdout().lock();
+  // Return to the start of the line and clear it
+  let _ = write!(stdout, "{}\r\x1b[2K{line}", title(stage, percent));
+  let _ = stdout.flush();
+  ACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Prints the `line` below the status line, prefixed with the job if the run
+/// has several, so it's neither written over nor mixed up with another job's.
+pub fn print_line(line: impl std::fmt::Display) {
+  end();
+  match crate::events::job() {
+    Some(job) => println!("[{job}] {line}"),
+    None => println!("{line}"),
+  }
+}
+
+/// Moves past the status line, so the next output isn't written over it.
+pub fn end() {
+  if ACTIVE.swap(false, Ordering::Relaxed) {
+    println!();
+  }
+}
+
+/// Ends the status line and clears the terminal title at exit.
+pub fn reset() {
+  end();
+  if ENABLED.load(Ordering::Relaxed) {
+    print!("\x1b]0;\x07");
+    let _ = std::io::stdout().flush();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn titles() {
+    assert_eq!(
+      title(Stage::Download, Some(42.4)),
+      "\x1b]0;quicksync: downloading 42%\x07"
+    );
+    assert_eq!(
+      title(Stage::Unpack, None),
+      "\x1b]0;quicksync: unpacking\x07"
+    );
+  }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::variant::Variant;
+
+/// Record of the last snapshot installed into node-data.
+pub const FILE_NAME: &str = "quicksync-done.json";
+
+/// Snapshot installed by a completed `download`, which a rerun with
+/// `--idempotent` doesn't download again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncMarker {
+  /// Download URL and variant the snapshot was looked up with.
+  pub download_url: String,
+  pub variant: Variant,
+  pub snapshot_url: String,
+  pub layer: u64,
+  /// MD5 of the installed database, if the server publishes it.
+  pub db_md5: Option<String>,
+  /// Size and modification time of the installed database, to tell if it's
+  /// still the one in node-data.
+  pub db_size: u64,
+  pub db_modified: DateTime<Utc>,
+  pub completed_at: DateTime<Utc>,
+}
+
+fn path(node_data: &Path) -> PathBuf {
+  node_data.join(FILE_NAME)
+}
+
+/// Size and modification time of the database in node-data.
+pub fn db_state(node_data: &Path) -> Result<(u64, DateTime<Utc>)> {
+  let db_path = node_data.join("state.sql");
+  let metadata =
+    std::fs::metadata(&db_path).with_context(|| format!("reading {}", db_path.display()))?;
+  Ok((metadata.len(), metadata.modified()?.into()))
+}
+
+impl SyncMarker {
+  /// The marker in node-data, if a download completed there.
+  pub fn load(node_data: &Path) -> Option<Self> {
+    let content = std::fs::read_to_string(path(node_data)).ok()?;
+    serde_json::from_str(&content).ok()
+  }
+
+  /// Whether the marker is for the snapshots at `download_url` of the
+  /// `variant`, and the database it installed is still in node-data.
+  pub fn matches(&self, node_data: &Path, download_url: &Url, variant: Variant) -> bool {
+    self.download_url == download_url.as_str()
+      && self.variant == variant
+      && db_state(node_data).is_ok_and(|state| state == (self.db_size, self.db_modified))
+  }
+
+  /// Writes the marker into node-data, atomically.
+  pub fn save(&self, node_data: &Path) -> Result<()> {
+    let path = path(node_data);
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string_pretty(self)?)
+      .and_then(|()| std::fs::rename(&tmp_path, &path))
+      .with_context(|| format!("writing {}", path.display()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn saving_and_loading() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(SyncMarker::load(dir.path()), None);
+    std::fs::write(dir.path().join("state.sql"), "database").unwrap();
+    let (db_size, db_modified) = db_state(dir.path()).unwrap();
+    let download_url = Url::parse("https://quicksync.spacemesh.network/").unwrap();
+    let marker = SyncMarker {
+      download_url: download_url.to_string(),
+      variant: Variant::Archival,
+      snapshot_url: "https://quicksync.spacemesh.network/v1.7.0/state_123456.sql.zst".into(),
+      layer: 123456,
+      db_md5: Some("d41d8cd98f00b204e9800998ecf8427e".into()),
+      db_size,
+      db_modified,
+      completed_at: Utc::now(),
+    };
+    marker.save(dir.path()).unwrap();
+    assert_eq!(SyncMarker::load(dir.path()), Some(marker.clone()));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+    assert!(marker.matches(dir.path(), &download_url, Variant::Archival));
+    assert!(!marker.matches(dir.path(), &download_url, Variant::Pruned));
+    let mirror = Url::parse("https://mirror.example.com/").unwrap();
+    assert!(!marker.matches(dir.path(), &mirror, Variant::Archival));
+    // Another database replaced the installed one
+    std::fs::write(dir.path().join("state.sql"), "another database").unwrap();
+    assert!(!marker.matches(dir.path(), &download_url, Variant::Archival));
+  }
+}
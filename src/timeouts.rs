@@ -0,0 +1,106 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::events::{self, Stage};
+use crate::exit_error::ExitError;
+
+/// Exit code of a run that exceeded `--stage-timeout` or `--overall-timeout`.
+pub const TIMEOUT_EXIT_CODE: i32 = 19;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time budgets of the run.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+  /// Of each stage of the run.
+  pub stage: Option<Duration>,
+  /// Of the whole run.
+  pub overall: Option<Duration>,
+}
+
+impl Timeouts {
+  /// Describes the budget exceeded at `now`, if any.
+  fn exceeded(
+    &self,
+    started: Instant,
+    stage: Option<(Stage, Instant)>,
+    now: Instant,
+  ) -> Option<String> {
+    if let Some(overall) = self.overall.filter(|t| now - started >= *t) {
+      return Some(format!("The run took longer than {overall:?}"));
+    }
+    match (self.stage, stage) {
+      // Seeding after the run and waiting between polls aren't work
+      (_, Some((Stage::Done | Stage::Wait, _))) => None,
+      (Some(timeout), Some((stage, since))) if now - since >= timeout => {
+        Some(format!("The {stage:?} stage took longer than {timeout:?}"))
+      }
+      _ => None,
+    }
+  }
+
+  /// Completes with the error to abort the run with once a budget is exceeded.
+  /// The run is aborted like when interrupted, so it can be resumed.
+  pub async fn expired(self) -> anyhow::Error {
+    let started = Instant::now();
+    // The stage of the run, or of each of its jobs, and since when
+    let mut stages: Vec<(Option<String>, Stage, Instant)> = Vec::new();
+    let mut check = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+      check.tick().await;
+      let now = Instant::now();
+      for (job, current) in events::current_stages() {
+        match stages.iter_mut().find(|(j, _, _)| *j == job) {
+          Some((_, stage, _)) if *stage == current => {}
+          Some(entry) => *entry = (job, current, now),
+          None => stages.push((job, current, now)),
+        }
+      }
+      let exceeded = match stages.as_slice() {
+        [] => self.exceeded(started, None, now),
+        _ => stages.iter().find_map(|(job, stage, since)| {
+          let message = self.exceeded(started, Some((*stage, *since)), now)?;
+          Some(match job {
+            Some(job) => format!("{job}: {message}"),
+            None => message,
+          })
+        }),
+      };
+      if let Some(message) = exceeded {
+        return ExitError::new(TIMEOUT_EXIT_CODE, message).into();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exceeding_timeouts() {
+    let started = Instant::now();
+    let timeouts = Timeouts {
+      stage: Some(Duration::from_secs(60)),
+      overall: Some(Duration::from_secs(300)),
+    };
+    let unpacking = Some((Stage::Unpack, started + Duration::from_secs(200)));
+    assert!(timeouts
+      .exceeded(started, unpacking, started + Duration::from_secs(250))
+      .is_none());
+    assert_eq!(
+      timeouts.exceeded(started, unpacking, started + Duration::from_secs(260)),
+      Some("The Unpack stage took longer than 60s".to_string())
+    );
+    assert_eq!(
+      timeouts.exceeded(started, None, started + Duration::from_secs(300)),
+      Some("The run took longer than 300s".to_string())
+    );
+    assert!(Timeouts::default()
+      .exceeded(started, unpacking, started + Duration::from_secs(3600))
+      .is_none());
+    let waiting = Some((Stage::Wait, started));
+    assert!(timeouts
+      .exceeded(started, waiting, started + Duration::from_secs(120))
+      .is_none());
+  }
+}
@@ -0,0 +1,232 @@
+use anyhow::Result;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, ClientBuilder};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+
+use crate::cert_pin;
+use crate::parsers::parse_resolve;
+use crate::user_agent::APP_USER_AGENT;
+
+/// Transport options shared by all HTTP clients, set once at start.
+static TRANSPORT: OnceLock<Transport> = OnceLock::new();
+
+/// HTTP version the servers are talked to with.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+  /// Negotiated with the server (HTTP/2 over TLS if it supports it)
+  #[default]
+  Auto,
+  Http1,
+  /// HTTP/2 only, multiplexing the requests over one connection
+  Http2,
+  /// HTTP/3 over QUIC (only in builds with the `http3` feature)
+  Http3,
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct Transport {
+  /// HTTP version to talk to the snapshot servers with. HTTP/2 and HTTP/3 can
+  /// be much faster on lossy links
+  #[clap(long, global = true, value_enum, default_value_t)]
+  pub http_version: HttpVersion,
+  /// Connect over IPv4 only, e.g. on networks with a broken IPv6
+  #[clap(long, global = true, conflicts_with = "ipv6")]
+  pub ipv4: bool,
+  /// Connect over IPv6 only
+  #[clap(long, global = true)]
+  pub ipv6: bool,
+  /// Connect to the given address instead of looking the host up
+  /// (`host:ip`, can be repeated)
+  #[clap(long = "resolve", global = true, value_parser = parse_resolve)]
+  pub overrides: Vec<(String, IpAddr)>,
+}
+
+/// Looks hosts up with the system resolver, keeping the addresses of one
+/// IP family.
+struct FamilyResolver {
+  ipv6: bool,
+}
+
+impl Resolve for FamilyResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let ipv6 = self.ipv6;
+    Box::pin(async move {
+      let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .filter(|addr| addr.is_ipv6() == ipv6)
+        .collect();
+      if addrs.is_empty() {
+        let family = if ipv6 { "IPv6" } else { "IPv4" };
+        return Err(format!("{} has no {family} address", name.as_str()).into());
+      }
+      Ok(Box::new(addrs.into_iter()) as Addrs)
+    })
+  }
+}
+
+impl Transport {
+  /// Applies the options to all HTTP clients built afterwards.
+  pub fn apply(&self) -> Result<()> {
+    #[cfg(not(feature = "http3"))]
+    anyhow::ensure!(
+      self.http_version != HttpVersion::Http3,
+      "This build of quicksync doesn't support HTTP/3"
+    );
+    TRANSPORT
+      .set(self.clone())
+      .map_err(|_| anyhow::anyhow!("transport options are already set"))
+  }
+
+  fn configure(&self, builder: ClientBuilder) -> ClientBuilder {
+    let mut builder = match self.http_version {
+      HttpVersion::Auto => builder,
+      HttpVersion::Http1 => builder.http1_only(),
+      // Grows the flow-control window with the bandwidth-delay product
+      HttpVersion::Http2 => builder.http2_prior_knowledge().http2_adaptive_window(true),
+      #[cfg(feature = "http3")]
+      HttpVersion::Http3 => builder.http3_prior_knowledge(),
+      #[cfg(not(feature = "http3"))]
+      HttpVersion::Http3 => builder,
+    };
+    if self.ipv4 || self.ipv6 {
+      builder = builder.dns_resolver(Arc::new(FamilyResolver { ipv6: self.ipv6 }));
+    }
+    // The port of the URL is used with port 0
+    let mut overrides: BTreeMap<&str, Vec<SocketAddr>> = BTreeMap::new();
+    for (host, ip) in &self.overrides {
+      overrides
+        .entry(host)
+        .or_default()
+        .push(SocketAddr::new(*ip, 0));
+    }
+    for (host, addrs) in overrides {
+      builder = builder.resolve_to_addrs(host, &addrs);
+    }
+    builder
+  }
+}
+
+/// The transport options set at start, the defaults if none were.
+pub fn options() -> Transport {
+  TRANSPORT.get().cloned().unwrap_or_default()
+}
+
+/// Builder of an HTTP client with the user agent and the transport options.
+/// Responses are transferred uncompressed, so the ranges and the sizes are
+/// those of the files.
+pub fn builder() -> ClientBuilder {
+  let builder = Client::builder()
+    .user_agent(APP_USER_AGENT)
+    .no_gzip()
+    .no_deflate()
+    .tls_info(cert_pin::enabled());
+  match TRANSPORT.get() {
+    Some(transport) => transport.configure(builder),
+    None => builder,
+  }
+}
+
+/// Builder of an HTTP client for the small text files fetched over and over
+/// (metadata, restore scripts, checksums), which accepts them compressed with
+/// gzip or deflate.
+pub fn small_files_builder() -> ClientBuilder {
+  builder().gzip(true).deflate(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn forcing_http1() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/1/metadata.csv")
+      .with_body("1,10,abcd")
+      .create_async()
+      .await;
+    let transport = Transport {
+      http_version: HttpVersion::Http1,
+      ..Default::default()
+    };
+    let client = transport.configure(builder()).build().unwrap();
+    let response = client
+      .get(format!("{}/1/metadata.csv", server.url()))
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+    assert_eq!(response.text().await.unwrap(), "1,10,abcd");
+    mock.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn compressing_small_files_only() {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"1,10,abcd").unwrap();
+    let mut server = mockito::Server::new_async().await;
+    let compressed = server
+      .mock("GET", "/1/metadata.csv")
+      .match_header("accept-encoding", mockito::Matcher::Regex("gzip".into()))
+      .with_header("content-encoding", "gzip")
+      .with_body(encoder.finish().unwrap())
+      .create_async()
+      .await;
+    let identity = server
+      .mock("GET", "/state.zst")
+      .match_header("accept-encoding", mockito::Matcher::Missing)
+      .with_body("archive")
+      .create_async()
+      .await;
+
+    let url = |path| format!("{}{path}", server.url());
+    let client = small_files_builder().build().unwrap();
+    let response = client.get(url("/1/metadata.csv")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "1,10,abcd");
+    let client = builder().build().unwrap();
+    let response = client.get(url("/state.zst")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "archive");
+    compressed.assert_async().await;
+    identity.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn resolving_one_family() {
+    let resolve = |ipv6, host: &str| FamilyResolver { ipv6 }.resolve(host.parse::<Name>().unwrap());
+    let addrs: Vec<SocketAddr> = resolve(false, "127.0.0.1").await.unwrap().collect();
+    assert_eq!(addrs, ["127.0.0.1:0".parse().unwrap()]);
+    assert!(resolve(true, "127.0.0.1").await.is_err());
+  }
+
+  #[tokio::test]
+  async fn overriding_addresses() {
+    let mut server = mockito::Server::new_async().await;
+    let mock = server
+      .mock("GET", "/1/metadata.csv")
+      .with_body("1,10,abcd")
+      .create_async()
+      .await;
+    let port = server.socket_address().port();
+    let transport = Transport {
+      overrides: vec![(
+        "quicksync.spacemesh.network".to_string(),
+        server.socket_address().ip(),
+      )],
+      ..Default::default()
+    };
+    let client = transport.configure(builder()).build().unwrap();
+    let response = client
+      .get(format!(
+        "http://quicksync.spacemesh.network:{port}/1/metadata.csv"
+      ))
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(response.text().await.unwrap(), "1,10,abcd");
+    mock.assert_async().await;
+  }
+}
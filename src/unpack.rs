@@ -1,31 +1,129 @@
 use anyhow::{Context, Result};
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
 use std::path::Path;
 use zstd::stream::read::Decoder;
 
+use crate::failpoints;
+use crate::io_tuning::{IoOptions, NoCacheFile};
 use crate::reader_with_bytes::ReaderWithBytes;
+use crate::seekable;
+use crate::status;
 
-pub(crate) fn unpack(archive_path: &Path, outpath: &Path) -> Result<()> {
-  let file = File::open(archive_path).context(format!(
+/// Extensions of the archives published by snapshot mirrors, after `.sql`.
+pub const ARCHIVE_EXTENSIONS: &[&str] = &["zst", "zip", "gz", "xz", "lz4"];
+
+/// Compression of an archive, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+  Zstd,
+  Zip,
+  Gzip,
+  Xz,
+  Lz4,
+}
+
+impl Format {
+  fn detect(magic: &[u8]) -> Option<Self> {
+    match magic {
+      [0x28, 0xB5, 0x2F, 0xFD, ..] => Some(Format::Zstd),
+      [b'P', b'K', 0x03, 0x04, ..] => Some(Format::Zip),
+      [0x1F, 0x8B, ..] => Some(Format::Gzip),
+      [0xFD, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(Format::Xz),
+      [0x04, 0x22, 0x4D, 0x18, ..] => Some(Format::Lz4),
+      _ => None,
+    }
+  }
+}
+
+/// Index of the database in a zip archive: its only file or the one ending with `.sql`.
+fn zip_entry<R: Read + Seek>(archive: &zip::ZipArchive<R>) -> Result<usize> {
+  if archive.len() == 1 {
+    return Ok(0);
+  }
+  (0..archive.len())
+    .find(|&i| {
+      archive
+        .name_for_index(i)
+        .is_some_and(|name| name.ends_with(".sql"))
+    })
+    .context("the zip archive has no .sql file")
+}
+
+/// Whether the file at `path` is compressed in one of the supported formats.
+pub fn is_compressed(path: &Path) -> Result<bool> {
+  let mut magic = Vec::with_capacity(6);
+  File::open(path)
+    .with_context(|| format!("opening {}", path.display()))?
+    .take(6)
+    .read_to_end(&mut magic)?;
+  Ok(Format::detect(&magic).is_some())
+}
+
+/// Detects the compression of the file at `path`, read by `reader`, and
+/// passes the decompressed data to `f`.
+pub fn with_decoder<T>(
+  mut reader: BufReader<File>,
+  path: &Path,
+  f: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+  let format = Format::detect(reader.fill_buf()?).with_context(|| {
+    format!(
+      "Unknown archive format of {}, expected zstd, zip, gzip, xz or lz4",
+      path.display()
+    )
+  })?;
+  match format {
+    Format::Zstd => {
+      let mut decoder = Decoder::with_buffer(reader)?;
+      decoder.window_log_max(31)?;
+      f(&mut decoder)
+    }
+    Format::Zip => {
+      let mut zip = zip::ZipArchive::new(reader).context("reading the zip archive")?;
+      let index = zip_entry(&zip)?;
+      f(&mut zip.by_index(index)?)
+    }
+    Format::Gzip => f(&mut flate2::bufread::MultiGzDecoder::new(reader)),
+    Format::Xz => f(&mut xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+    Format::Lz4 => f(&mut lz4_flex::frame::FrameDecoder::new(reader)),
+  }
+}
+
+/// Unpacks the zstd, zip, gzip, xz or lz4 archive at `archive_path` into `outpath`.
+/// Unpacking a seekable zstd archive resumes where an interrupted one stopped.
+pub fn unpack(archive_path: &Path, outpath: &Path, io: IoOptions) -> Result<()> {
+  let mut file = File::open(archive_path).context(format!(
     "Failed to open archive at path: {:?}",
     archive_path
   ))?;
-  let reader = BufReader::new(file);
-  let mut decoder = Decoder::new(reader)?;
-
-  decoder.window_log_max(31)?;
   if let Some(p) = outpath.parent() {
     std::fs::create_dir_all(p).with_context(|| format!("creating directory: {}", p.display()))?;
   }
-  let outfile = File::create(outpath)
-    .with_context(|| format!("creating file to unpack into at: {}", outpath.display()))?;
-  let mut writer = BufWriter::new(outfile);
+  let frames = seekable::read_seek_table(&mut file).unwrap_or_else(|e| {
+    status::print_line(format_args!(
+      "Ignoring the seek table of the archive: {e:#}"
+    ));
+    None
+  });
+  if let Some(frames) = frames {
+    return seekable::unpack(file, &frames, outpath, io);
+  }
+  file.rewind()?;
 
-  let mut reader = ReaderWithBytes::new(decoder);
+  let reader = BufReader::with_capacity(io.buffer_size, file);
+  with_decoder(reader, archive_path, |decoder| {
+    let outfile = File::create(outpath)
+      .with_context(|| format!("creating file to unpack into at: {}", outpath.display()))?;
+    let outfile = NoCacheFile::new(outfile, io.no_page_cache)?;
+    let mut writer = BufWriter::with_capacity(io.buffer_size, outfile);
 
-  std::io::copy(&mut reader, &mut writer)?;
-  Ok(())
+    let mut reader = ReaderWithBytes::new(decoder);
+
+    std::io::copy(&mut reader, &mut failpoints::UnpackedWriter(&mut writer))?;
+    writer.flush()?;
+    Ok(())
+  })
 }
 
 #[cfg(test)]
@@ -34,6 +132,7 @@ mod tests {
   use std::io::{Read, Write};
 
   use super::unpack;
+  use crate::io_tuning::IoOptions;
 
   #[test]
   fn unpack_zst() {
@@ -47,7 +146,11 @@ mod tests {
 
     // unpack the archive
     let output_filepath = tempdir.path().join("state.sql");
-    unpack(&archive_path, &output_filepath).unwrap();
+    let io = IoOptions {
+      no_page_cache: true,
+      ..IoOptions::for_tests()
+    };
+    unpack(&archive_path, &output_filepath, io).unwrap();
 
     // check the output
     let mut output_file = File::open(&output_filepath).unwrap();
@@ -55,4 +158,54 @@ mod tests {
     output_file.read_to_string(&mut output).unwrap();
     assert_eq!(output, "Hello, World!\n");
   }
+
+  fn unpack_to_string(archive_path: &std::path::Path) -> String {
+    let output_filepath = archive_path.with_extension("sql");
+    let io = IoOptions::for_tests();
+    unpack(archive_path, &output_filepath, io).unwrap();
+    std::fs::read_to_string(&output_filepath).unwrap()
+  }
+
+  #[test]
+  fn unpack_other_formats() {
+    let tempdir = tempfile::tempdir().unwrap();
+
+    // the format is detected from the content, not the name
+    let gzip_path = tempdir.path().join("gzip.zst");
+    let mut encoder = flate2::write::GzEncoder::new(
+      File::create(&gzip_path).unwrap(),
+      flate2::Compression::default(),
+    );
+    encoder.write_all(b"Hello, gzip!\n").unwrap();
+    encoder.finish().unwrap();
+    assert_eq!(unpack_to_string(&gzip_path), "Hello, gzip!\n");
+
+    let xz_path = tempdir.path().join("xz.zst");
+    let mut encoder = xz2::write::XzEncoder::new(File::create(&xz_path).unwrap(), 6);
+    encoder.write_all(b"Hello, xz!\n").unwrap();
+    encoder.finish().unwrap();
+    assert_eq!(unpack_to_string(&xz_path), "Hello, xz!\n");
+
+    let zip_path = tempdir.path().join("zip.zst");
+    let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+    let options = zip::write::SimpleFileOptions::default();
+    writer.start_file("README", options).unwrap();
+    writer.write_all(b"not the database").unwrap();
+    writer.start_file("100.sql", options).unwrap();
+    writer.write_all(b"Hello, zip!\n").unwrap();
+    writer.finish().unwrap();
+    assert_eq!(unpack_to_string(&zip_path), "Hello, zip!\n");
+
+    let lz4_path = tempdir.path().join("lz4.zst");
+    let mut encoder = lz4_flex::frame::FrameEncoder::new(File::create(&lz4_path).unwrap());
+    encoder.write_all(b"Hello, lz4!\n").unwrap();
+    encoder.finish().unwrap();
+    assert_eq!(unpack_to_string(&lz4_path), "Hello, lz4!\n");
+
+    let unknown_path = tempdir.path().join("unknown.zst");
+    std::fs::write(&unknown_path, b"plain text").unwrap();
+    let io = IoOptions::for_tests();
+    let err = unpack(&unknown_path, &tempdir.path().join("out.sql"), io).unwrap_err();
+    assert!(err.to_string().contains("Unknown archive format"));
+  }
 }
@@ -0,0 +1,210 @@
+use anyhow::Result;
+use reqwest::{redirect, StatusCode};
+use std::sync::{Mutex, OnceLock};
+use url::{Origin, Url};
+
+use crate::exit_error::ExitError;
+use crate::http_trace;
+
+pub const REFUSED_URL_EXIT_CODE: i32 = 18;
+/// Domains snapshots are downloaded from by default, with their subdomains.
+const DEFAULT_HOSTS: &[&str] = &["spacemesh.network"];
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Allowed hosts, set once URLs are checked.
+static ALLOWED_HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+/// Redirects followed at most for a request, set at start.
+static MAX_REDIRECTS: OnceLock<usize> = OnceLock::new();
+/// Sources found on the LAN allowed over plain HTTP, by origin.
+static LAN_SOURCES: Mutex<Vec<Origin>> = Mutex::new(Vec::new());
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct UrlPolicy {
+  /// Allow downloading over plain HTTP and from any host (including redirects
+  /// and the URL saved in `state.url`)
+  #[clap(long, global = true)]
+  pub allow_insecure_url: bool,
+  /// Another host (with its subdomains) to allow downloading from, in addition
+  /// to spacemesh.network and the hosts of the given URLs (can be repeated)
+  #[clap(long = "allow-host", global = true)]
+  pub allowed_hosts: Vec<String>,
+  /// Redirects to follow at most for a request, each one to an allowed URL
+  #[clap(long, global = true, default_value_t = DEFAULT_MAX_REDIRECTS)]
+  pub max_redirects: usize,
+}
+
+impl UrlPolicy {
+  /// Applies the limit of redirects to all requests made afterwards.
+  pub fn apply_max_redirects(&self) {
+    let _ = MAX_REDIRECTS.set(self.max_redirects);
+  }
+
+  /// Starts refusing insecure URLs and hosts that aren't allowed,
+  /// unless `--allow-insecure-url` is passed. The hosts of `urls` are allowed.
+  pub fn enforce<'a>(&self, urls: impl IntoIterator<Item = &'a Url>) -> Result<()> {
+    if self.allow_insecure_url {
+      return Ok(());
+    }
+    let urls: Vec<&Url> = urls.into_iter().collect();
+    let hosts = DEFAULT_HOSTS
+      .iter()
+      .map(|h| h.to_string())
+      .chain(self.allowed_hosts.iter().map(|h| h.to_lowercase()))
+      .chain(
+        urls
+          .iter()
+          .filter_map(|u| u.host_str())
+          .map(str::to_lowercase),
+      )
+      .collect();
+    ALLOWED_HOSTS
+      .set(hosts)
+      .map_err(|_| anyhow::anyhow!("URL policy is already set"))?;
+    urls.into_iter().try_for_each(check)
+  }
+}
+
+fn violation(allowed: &[String], url: &Url) -> Option<String> {
+  if url.scheme() != "https" {
+    return Some(format!("{} is not allowed, only https", url.scheme()));
+  }
+  let host = url.host_str().unwrap_or_default().to_lowercase();
+  let is_allowed = allowed
+    .iter()
+    .any(|a| host == *a || host.ends_with(&format!(".{a}")));
+  (!is_allowed).then(|| format!("host {host} is not allowed"))
+}
+
+/// Allows the source found on the LAN at `url` (only its scheme, address and
+/// port) over plain HTTP, without allowing any other insecure URL.
+pub fn allow_lan_source(url: &Url) {
+  LAN_SOURCES.lock().unwrap().push(url.origin());
+}
+
+/// Fails if the URL is insecure or its host isn't allowed.
+pub fn check(url: &Url) -> Result<()> {
+  let Some(allowed) = ALLOWED_HOSTS.get() else {
+    return Ok(());
+  };
+  if LAN_SOURCES.lock().unwrap().contains(&url.origin()) {
+    return Ok(());
+  }
+  match violation(allowed, url) {
+    None => Ok(()),
+    Some(reason) => Err(
+      ExitError::new(
+        REFUSED_URL_EXIT_CODE,
+        format!(
+          "Refusing to download from {url}: {reason}. \
+           Use --allow-host or --allow-insecure-url to allow it"
+        ),
+      )
+      .into(),
+    ),
+  }
+}
+
+pub fn max_redirects() -> usize {
+  MAX_REDIRECTS
+    .get()
+    .copied()
+    .unwrap_or(DEFAULT_MAX_REDIRECTS)
+}
+
+/// Fails if the redirect to `to`, after requesting the URLs in `previous`
+/// (the first one and the redirects followed so far), would be one more than
+/// `--max-redirects` or to a URL that isn't allowed. Traces it otherwise.
+pub fn check_redirect(previous: &[Url], status: StatusCode, to: &Url) -> Result<()> {
+  if previous.len() > max_redirects() {
+    return Err(
+      ExitError::new(
+        REFUSED_URL_EXIT_CODE,
+        format!(
+          "Refusing to follow more than {} redirects, the last one to {}. \
+           Use --max-redirects to allow more",
+          max_redirects(),
+          http_trace::redact_url(to)
+        ),
+      )
+      .into(),
+    );
+  }
+  check(to)?;
+  http_trace::redirect(status, previous.last(), to);
+  Ok(())
+}
+
+/// Redirect policy following at most `--max-redirects` redirects, only to
+/// allowed URLs. A refused redirect keeps its exit code.
+pub fn redirect_policy() -> redirect::Policy {
+  redirect::Policy::custom(|attempt| {
+    match check_redirect(attempt.previous(), attempt.status(), attempt.url()) {
+      Ok(()) => attempt.follow(),
+      Err(e) => match e.downcast::<ExitError>() {
+        Ok(refused) => attempt.error(refused),
+        Err(e) => attempt.error(e.to_string()),
+      },
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{check_redirect, redirect_policy, violation, DEFAULT_MAX_REDIRECTS};
+  use crate::exit_error::ExitError;
+  use reqwest::StatusCode;
+  use url::Url;
+
+  #[test]
+  fn checking_urls() {
+    let allowed = vec!["spacemesh.network".to_string(), "mirror.org".to_string()];
+    let check = |url: &str| violation(&allowed, &Url::parse(url).unwrap());
+    assert_eq!(
+      check("https://quicksync.spacemesh.network/1/100.sql.zst"),
+      None
+    );
+    assert_eq!(check("https://MIRROR.org/state.zst"), None);
+    assert_eq!(
+      check("http://quicksync.spacemesh.network/"),
+      Some("http is not allowed, only https".to_string())
+    );
+    assert_eq!(
+      check("https://evilspacemesh.network/"),
+      Some("host evilspacemesh.network is not allowed".to_string())
+    );
+  }
+
+  #[test]
+  fn limiting_redirects() {
+    let to = Url::parse("https://cdn.spacemesh.network/state.zst?sig=abc").unwrap();
+    let found = StatusCode::FOUND;
+    let previous = vec![to.clone(); DEFAULT_MAX_REDIRECTS];
+    assert!(check_redirect(&previous, found, &to).is_ok());
+    let previous = vec![to.clone(); DEFAULT_MAX_REDIRECTS + 1];
+    let err = check_redirect(&previous, found, &to).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Refusing to follow more than 10 redirects, the last one to \
+       https://cdn.spacemesh.network/state.zst?sig=redacted. Use --max-redirects to allow more"
+    );
+  }
+
+  #[tokio::test]
+  async fn refusing_redirects_with_the_exit_code() {
+    let mut server = mockito::Server::new_async().await;
+    let url = format!("{}/loop", server.url());
+    server
+      .mock("GET", "/loop")
+      .with_status(302)
+      .with_header("Location", &url)
+      .expect(DEFAULT_MAX_REDIRECTS + 1)
+      .create_async()
+      .await;
+    let client = reqwest::Client::builder()
+      .redirect(redirect_policy())
+      .build()
+      .unwrap();
+    let err = anyhow::Error::from(client.get(&url).send().await.unwrap_err());
+    assert_eq!(ExitError::find(&err).unwrap().code, 18);
+  }
+}
@@ -1,45 +1,71 @@
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, Duration, Utc};
+use anyhow::{anyhow, Context, Result};
 use regex::Regex;
-use reqwest::{blocking::Client, redirect};
+use reqwest::header::{LOCATION, RANGE};
+use reqwest::{redirect, Client};
+use serde::Deserialize;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
 use url::Url;
 
-use crate::user_agent::APP_USER_AGENT;
+use crate::file_in_use;
+use crate::http_trace::SendTraced;
+use crate::transport;
+use crate::url_policy;
+use crate::variant::Variant;
 
-pub fn strip_trailing_newline(input: &str) -> &str {
-  input.trim_end()
+/// Path the file would be backed up to by [`backup_file`].
+pub fn backup_path(original_path: &Path) -> PathBuf {
+  backup_path_except(original_path, &[])
 }
 
-pub fn calculate_latest_layer(
-  genesis_time: DateTime<Utc>,
-  layer_duration: Duration,
-) -> Result<i64> {
-  let delta = Utc::now() - genesis_time;
-  Ok(delta.num_milliseconds() / layer_duration.num_milliseconds())
-}
-
-pub fn backup_file(original_path: &Path) -> Result<PathBuf> {
-  if !original_path.exists() {
-    anyhow::bail!("No file to make a backup");
-  }
-
+/// The backup path of `original_path`, also skipping the `taken` paths, of
+/// backups not made yet.
+pub fn backup_path_except(original_path: &Path, taken: &[PathBuf]) -> PathBuf {
   let mut backup_path = original_path.with_extension("sql.bak");
   let mut counter = 1;
 
-  while backup_path.exists() {
+  while backup_path.exists() || taken.contains(&backup_path) {
     let new_name = format!("state.sql.bak.{}", counter);
     backup_path = original_path.with_file_name(new_name);
     counter += 1;
   }
+  backup_path
+}
 
-  std::fs::rename(original_path, &backup_path)?;
+pub fn backup_file(original_path: &Path) -> Result<PathBuf> {
+  if !original_path.exists() {
+    anyhow::bail!("No file to make a backup");
+  }
+
+  let backup_path = backup_path(original_path);
+  file_in_use::rename(original_path, &backup_path)?;
 
   Ok(backup_path)
 }
 
-fn extract_number_from_url(url: &Url) -> Result<u64> {
-  let re = Regex::new(r"/(\d+)\.sql\.zst$")?;
+/// Asks a yes/no question on the terminal, `default` is the answer to Enter.
+pub fn ask(question: &str, default: bool) -> Result<bool> {
+  print!("{question} {} ", if default { "[Y/n]" } else { "[y/N]" });
+  std::io::stdout().flush()?;
+  let mut answer = String::new();
+  std::io::stdin().lock().read_line(&mut answer)?;
+  Ok(match answer.trim().to_lowercase().as_str() {
+    "" => default,
+    answer => matches!(answer, "y" | "yes"),
+  })
+}
+
+/// Picks a random delay between zero and `max`.
+pub fn random_delay(max: std::time::Duration) -> std::time::Duration {
+  if max.is_zero() {
+    return max;
+  }
+  max.mul_f64(rand::random::<f64>())
+}
+
+pub fn extract_number_from_url(url: &Url) -> Result<u64> {
+  // Variants other than archival have a suffix, e.g. `61579_pruned.sql.zst`
+  let re = Regex::new(r"/(\d+)(?:_[a-z]+)?\.sql\.zst$")?;
   let path = url.path();
   let caps = re
     .captures(path)
@@ -54,9 +80,60 @@ fn extract_number_from_url(url: &Url) -> Result<u64> {
   Ok(number)
 }
 
-pub fn fetch_latest_available_layer(download_url: &Url, go_version: &str) -> Result<u64> {
-  let client = Client::builder()
-    .user_agent(APP_USER_AGENT)
+/// Header with the layer of the snapshot, for servers serving it directly.
+const SNAPSHOT_LAYER_HEADER: &str = "X-Snapshot-Layer";
+
+/// The latest snapshot: the URL of its archive and its layer.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+  pub url: Url,
+  pub layer: u64,
+}
+
+impl Snapshot {
+  fn from_url(url: Url) -> Result<Self> {
+    let layer = extract_number_from_url(&url)?;
+    Ok(Self { url, layer })
+  }
+}
+
+/// Manifest of the latest snapshot, published as `{file name}.json`
+/// (e.g. `state.zst.json`) next to the download URL. Same as the metadata
+/// entry written by `export`.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+  layer: u64,
+  /// URL of the archive, relative to the manifest.
+  archive: String,
+}
+
+async fn fetch_manifest(client: &Client, url: &Url) -> Result<Option<Snapshot>> {
+  let manifest_url = Url::parse(&format!("{url}.json"))?;
+  let response = client.get(manifest_url.clone()).send_traced().await?;
+  if !response.status().is_success() {
+    return Ok(None);
+  }
+  let manifest: Manifest = response
+    .json()
+    .await
+    .with_context(|| format!("parsing snapshot manifest {manifest_url}"))?;
+  let url = manifest_url.join(&manifest.archive)?;
+  url_policy::check(&url)?;
+  Ok(Some(Snapshot {
+    url,
+    layer: manifest.layer,
+  }))
+}
+
+/// Resolves the latest snapshot. The download URL normally redirects to it,
+/// otherwise the snapshot is looked up by the `X-Snapshot-Layer` header,
+/// the manifest, or the URL reached by following the redirects of a GET.
+pub async fn resolve_snapshot(
+  download_url: &Url,
+  go_version: &str,
+  variant: Variant,
+) -> Result<Snapshot> {
+  let client = transport::builder()
     .redirect(redirect::Policy::none())
     .timeout(std::time::Duration::from_secs(30))
     .build()?;
@@ -64,16 +141,85 @@ pub fn fetch_latest_available_layer(download_url: &Url, go_version: &str) -> Res
   let mut url = download_url.clone();
   url
     .path_segments_mut()
-    .unwrap()
-    .extend(&[go_version, "state.zst"]);
+    .map_err(|_| anyhow!("Cannot compose the snapshot URL from {download_url}"))?
+    .extend(&[go_version, variant.file_name()]);
+
+  let response = client.head(url.clone()).send_traced().await?;
+  let status = response.status();
+  let header_layer = response
+    .headers()
+    .get(SNAPSHOT_LAYER_HEADER)
+    .and_then(|v| v.to_str().ok()?.trim().parse::<u64>().ok());
 
-  let response = client.head(url).send()?;
+  if let Some(location) = response.headers().get(LOCATION) {
+    let snapshot_url = url.join(location.to_str()?)?;
+    url_policy::check(&snapshot_url)?;
+    return match header_layer {
+      Some(layer) => Ok(Snapshot {
+        url: snapshot_url,
+        layer,
+      }),
+      None => Snapshot::from_url(snapshot_url),
+    };
+  }
+  if let (true, Some(layer)) = (status.is_success(), header_layer) {
+    // The server serves the latest snapshot directly
+    return Ok(Snapshot { url, layer });
+  }
+  if let Some(snapshot) = fetch_manifest(&client, &url).await? {
+    return Ok(snapshot);
+  }
+
+  let following = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .timeout(std::time::Duration::from_secs(30))
+    .build()?;
+  let response = following
+    .get(url.clone())
+    .header(RANGE, "bytes=0-0")
+    .send_traced()
+    .await?;
+  if response.status().is_success() {
+    if let Ok(snapshot) = Snapshot::from_url(response.url().clone()) {
+      return Ok(snapshot);
+    }
+  }
+  anyhow::bail!(
+    "Cannot find the latest snapshot at {url}: the server responded with {status} \
+     without a redirect, {SNAPSHOT_LAYER_HEADER} header or manifest"
+  )
+}
 
-  let location = response.headers().get("location").unwrap().to_str()?;
-  let final_url = Url::parse(location)?;
-  let num = extract_number_from_url(&final_url)?;
+/// The latest snapshot available for download.
+#[derive(Debug)]
+pub struct SnapshotInfo {
+  pub url: Url,
+  pub layer: u64,
+  /// Size of the archive, if the server reports it.
+  pub size: Option<u64>,
+}
 
-  Ok(num)
+/// Resolves the latest snapshot and asks the server for the size of its archive.
+pub async fn fetch_snapshot_info(
+  download_url: &Url,
+  go_version: &str,
+  variant: Variant,
+) -> Result<SnapshotInfo> {
+  let Snapshot { url, layer } = resolve_snapshot(download_url, go_version, variant).await?;
+  let client = transport::builder()
+    .redirect(url_policy::redirect_policy())
+    .timeout(std::time::Duration::from_secs(30))
+    .build()?;
+  let response = client.head(url.clone()).send_traced().await?;
+  let size = if response.status().is_success() {
+    response
+      .headers()
+      .get(reqwest::header::CONTENT_LENGTH)
+      .and_then(|v| v.to_str().ok()?.parse().ok())
+  } else {
+    None
+  };
+  Ok(SnapshotInfo { url, layer, size })
 }
 
 #[cfg(test)]
@@ -81,12 +227,112 @@ mod tests {
   use super::*;
   use url::Url;
 
+  #[test]
+  fn random_delay_is_within_max() {
+    let max = std::time::Duration::from_secs(600);
+    for _ in 0..100 {
+      assert!(random_delay(max) <= max);
+    }
+    assert!(random_delay(std::time::Duration::ZERO).is_zero());
+  }
+
   #[test]
   fn test_extract_number_valid() {
     let url = Url::parse("https://quicksync-downloads.spacemesh.network/10/61579.sql.zst").unwrap();
     assert_eq!(extract_number_from_url(&url).unwrap(), 61579);
   }
 
+  #[test]
+  fn test_extract_number_variant() {
+    let url =
+      Url::parse("https://quicksync-downloads.spacemesh.network/10/61579_pruned.sql.zst").unwrap();
+    assert_eq!(extract_number_from_url(&url).unwrap(), 61579);
+  }
+
+  #[tokio::test]
+  async fn fetches_snapshot_info() {
+    let mut server = mockito::Server::new_async().await;
+    let location = format!("{}/v1.7.0/61579_pruned.sql.zst", server.url());
+    let redirect = server
+      .mock("HEAD", "/v1.7.0/state_pruned.zst")
+      .with_status(302)
+      .with_header("location", &location)
+      .create_async()
+      .await;
+    let snapshot = server
+      .mock("HEAD", "/v1.7.0/61579_pruned.sql.zst")
+      .with_body(vec![0u8; 1234])
+      .create_async()
+      .await;
+
+    let url = Url::parse(&server.url()).unwrap();
+    let info = fetch_snapshot_info(&url, "v1.7.0", Variant::Pruned)
+      .await
+      .unwrap();
+    assert_eq!(info.url.as_str(), location);
+    assert_eq!(info.layer, 61579);
+    assert_eq!(info.size, Some(1234));
+    redirect.assert_async().await;
+    snapshot.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn resolves_snapshot_without_location() {
+    let mut server = mockito::Server::new_async().await;
+    let url = Url::parse(&server.url()).unwrap();
+
+    // served directly, with the layer in a header
+    let direct = server
+      .mock("HEAD", "/v1.7.0/state.zst")
+      .with_header("X-Snapshot-Layer", "61579")
+      .create_async()
+      .await;
+    let snapshot = resolve_snapshot(&url, "v1.7.0", Variant::Archival)
+      .await
+      .unwrap();
+    assert_eq!(snapshot.layer, 61579);
+    assert_eq!(snapshot.url.path(), "/v1.7.0/state.zst");
+    direct.assert_async().await;
+    direct.remove_async().await;
+
+    // described by a manifest
+    let head = server
+      .mock("HEAD", "/v1.7.0/state.zst")
+      .with_status(200)
+      .expect(2)
+      .create_async()
+      .await;
+    let manifest = server
+      .mock("GET", "/v1.7.0/state.zst.json")
+      .with_body(r#"{"layer": 61580, "archive": "61580.sql.zst", "db_size": 1}"#)
+      .create_async()
+      .await;
+    let snapshot = resolve_snapshot(&url, "v1.7.0", Variant::Archival)
+      .await
+      .unwrap();
+    assert_eq!(snapshot.layer, 61580);
+    assert_eq!(snapshot.url.path(), "/v1.7.0/61580.sql.zst");
+    manifest.assert_async().await;
+    manifest.remove_async().await;
+
+    // an error page
+    server
+      .mock("GET", "/v1.7.0/state.zst.json")
+      .with_status(404)
+      .create_async()
+      .await;
+    server
+      .mock("GET", "/v1.7.0/state.zst")
+      .with_status(404)
+      .create_async()
+      .await;
+    let err = resolve_snapshot(&url, "v1.7.0", Variant::Archival)
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("Cannot find the latest snapshot"));
+    head.assert_async().await;
+  }
+
   #[test]
   fn test_extract_number_invalid() {
     let url = Url::parse("https://quicksync.spacemesh.network/state.zst").unwrap();
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::file_in_use;
+use crate::service::is_db_locked;
+use crate::utils::backup_file;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Estimate {
+  page_size: u64,
+  page_count: u64,
+  freelist_count: u64,
+}
+
+impl Estimate {
+  fn read(conn: &Connection) -> Result<Self> {
+    let pragma = |name: &str| {
+      conn
+        .query_row(&format!("PRAGMA {name}"), [], |row| row.get::<_, u64>(0))
+        .with_context(|| format!("reading {name}"))
+    };
+    Ok(Self {
+      page_size: pragma("page_size")?,
+      page_count: pragma("page_count")?,
+      freelist_count: pragma("freelist_count")?,
+    })
+  }
+
+  /// Space returned to the file system by vacuuming.
+  fn reclaimable(&self) -> u64 {
+    self.freelist_count * self.page_size
+  }
+
+  /// Size of the database after vacuuming.
+  fn expected_size(&self) -> u64 {
+    (self.page_count - self.freelist_count) * self.page_size
+  }
+}
+
+fn mb(bytes: u64) -> f64 {
+  bytes as f64 / 1_024_000.00
+}
+
+/// Periodically reports how much of the vacuumed copy has been written
+/// until the returned flag is set.
+fn report_progress(path: PathBuf, expected_size: u64) -> Arc<AtomicBool> {
+  let done = Arc::new(AtomicBool::new(false));
+  let done_clone = done.clone();
+  std::thread::spawn(move || {
+    let mut last_report = Instant::now();
+    while !done_clone.load(Ordering::Relaxed) {
+      std::thread::sleep(Duration::from_millis(100));
+      if last_report.elapsed() < PROGRESS_INTERVAL {
+        continue;
+      }
+      last_report = Instant::now();
+      let written = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+      println!(
+        "Vacuuming... {:.2}% ({:.2} MB/{:.2} MB)",
+        written as f64 / expected_size.max(1) as f64 * 100.0,
+        mb(written),
+        mb(expected_size)
+      );
+    }
+  });
+  done
+}
+
+/// Rebuilds the database to return the free pages to the file system.
+///
+/// By default the database is vacuumed into a new file, which then atomically
+/// replaces the original one (kept as a backup). With `in_place` the database
+/// is vacuumed directly, which needs less disk space but reports no progress.
+pub fn vacuum(db_path: &Path, in_place: bool) -> Result<()> {
+  anyhow::ensure!(
+    !is_db_locked(db_path)?,
+    "database is in use, stop the node before vacuuming"
+  );
+  let conn = Connection::open(db_path).context("opening database")?;
+  let estimate = Estimate::read(&conn)?;
+  println!(
+    "Database size: {:.2} MB, expected to reclaim: {:.2} MB",
+    mb(estimate.page_count * estimate.page_size),
+    mb(estimate.reclaimable())
+  );
+
+  let start = Instant::now();
+  if in_place {
+    println!("Vacuuming the database in place, it may take some time...");
+    conn.execute_batch("VACUUM").context("vacuuming database")?;
+    conn.close().map_err(|(_, e)| e)?;
+  } else {
+    let vacuumed_path = db_path.with_extension("sql.vacuum");
+    if vacuumed_path.try_exists()? {
+      std::fs::remove_file(&vacuumed_path)
+        .with_context(|| format!("removing stale {}", vacuumed_path.display()))?;
+    }
+    println!(
+      "Vacuuming the database into {}, it needs {:.2} MB of free space...",
+      vacuumed_path.display(),
+      mb(estimate.expected_size())
+    );
+    let done = report_progress(vacuumed_path.clone(), estimate.expected_size());
+    let result = conn.execute("VACUUM INTO ?", [vacuumed_path.to_string_lossy()]);
+    done.store(true, Ordering::Relaxed);
+    result.context("vacuuming database")?;
+
+    // Make sure nothing is left in the WAL of the original database,
+    // otherwise it would be applied on top of the vacuumed one.
+    conn
+      .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+      .context("checkpointing WAL")?;
+    conn.close().map_err(|(_, e)| e)?;
+    for suffix in ["-wal", "-shm"] {
+      let mut path = db_path.as_os_str().to_owned();
+      path.push(suffix);
+      let path = PathBuf::from(path);
+      if path.try_exists()? {
+        std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+      }
+    }
+
+    let backup = backup_file(db_path)?;
+    println!("Original database backed up to: {}", backup.display());
+    file_in_use::rename(&vacuumed_path, db_path).context("replacing database with vacuumed one")?;
+  }
+
+  let size_after = std::fs::metadata(db_path)?.len();
+  println!(
+    "Database vacuumed in {:?}, new size: {:.2} MB",
+    start.elapsed(),
+    mb(size_after)
+  );
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn create_bloated_db(path: &Path) {
+    let conn = Connection::open(path).unwrap();
+    conn
+      .execute_batch(
+        r#"
+        CREATE TABLE layers (id INTEGER PRIMARY KEY, data BLOB);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+        INSERT INTO layers SELECT i, randomblob(1024) FROM n;
+        DELETE FROM layers WHERE id > 10;
+        "#,
+      )
+      .unwrap();
+  }
+
+  fn count_layers(path: &Path) -> u32 {
+    let conn = Connection::open(path).unwrap();
+    conn
+      .query_row("SELECT count(*) FROM layers", [], |row| row.get(0))
+      .unwrap()
+  }
+
+  #[test]
+  fn vacuums_into_new_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    create_bloated_db(&db_path);
+    let size_before = std::fs::metadata(&db_path).unwrap().len();
+
+    vacuum(&db_path, false).unwrap();
+
+    assert!(std::fs::metadata(&db_path).unwrap().len() < size_before);
+    assert_eq!(count_layers(&db_path), 10);
+    assert!(dir.path().join("state.sql.bak").exists());
+    assert!(!dir.path().join("state.sql.vacuum").exists());
+  }
+
+  #[test]
+  fn vacuums_in_place() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("state.sql");
+    create_bloated_db(&db_path);
+    let size_before = std::fs::metadata(&db_path).unwrap().len();
+
+    vacuum(&db_path, true).unwrap();
+
+    assert!(std::fs::metadata(&db_path).unwrap().len() < size_before);
+    assert_eq!(count_layers(&db_path), 10);
+    assert!(!dir.path().join("state.sql.bak").exists());
+  }
+}
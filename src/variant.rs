@@ -0,0 +1,22 @@
+/// Flavor of the published snapshot.
+#[derive(
+  clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+  /// Full database with all historical data
+  #[default]
+  Archival,
+  /// Database without historical transaction results, for nodes with small disks
+  Pruned,
+}
+
+impl Variant {
+  /// Name of the snapshot file published for the node version.
+  pub fn file_name(&self) -> &'static str {
+    match self {
+      Variant::Archival => "state.zst",
+      Variant::Pruned => "state_pruned.zst",
+    }
+  }
+}